@@ -196,5 +196,107 @@ pub fn codec_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, codec_benchmark);
+pub fn reorder_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reorder");
+    let cnt = 100_000;
+
+    for gap in [1, 8, 64] {
+        let indices = micro_bench::reorder::gen_indices(cnt, gap);
+        group.throughput(Throughput::Elements(cnt as u64));
+        group.bench_function(format!("ring_gap_{gap}"), |bencher| {
+            bencher.iter(|| micro_bench::reorder::reorder_ring(&indices, 256));
+        });
+        group.bench_function(format!("map_gap_{gap}"), |bencher| {
+            bencher.iter(|| micro_bench::reorder::reorder_map(&indices));
+        });
+    }
+
+    group.finish();
+}
+
+pub fn freeze_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("freeze");
+
+    for frame_cnt in [1, 16] {
+        let bench = micro_bench::frames::FreezeBench::new(frame_cnt);
+        group.throughput(Throughput::Elements(frame_cnt as u64));
+        group.bench_function(format!("in_place_{frame_cnt}_frames"), |bencher| {
+            bencher.iter_batched(
+                || bench.clone(),
+                micro_bench::frames::FreezeBench::freeze_in_place,
+                BatchSize::SmallInput,
+            );
+        });
+        group.bench_function(format!("collect_new_{frame_cnt}_frames"), |bencher| {
+            bencher.iter_batched(
+                || bench.clone(),
+                micro_bench::frames::FreezeBench::freeze_collect_new,
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+pub fn reactor_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reactor");
+    let count = 1000;
+    let window = Duration::from_millis(50);
+
+    group.sample_size(10);
+    for granularity in [
+        Duration::ZERO,
+        Duration::from_millis(1),
+        Duration::from_millis(10),
+    ] {
+        let reactor = raknet_rs::Reactor::spawn(granularity);
+        // the timers of the same tick fire in one wakeup
+        println!(
+            "granularity: {granularity:?}, timers: {count}, wakeups: {}",
+            micro_bench::reactor::fire_timers(reactor, count, window)
+        );
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_function(format!("fire_timers_{granularity:?}"), |bencher| {
+            bencher.iter(|| micro_bench::reactor::fire_timers(reactor, count, window));
+        });
+    }
+
+    group.finish();
+}
+
+pub fn receive_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("receive");
+    let datagram_cnt = 1000;
+
+    for body_size in [256, 1024] {
+        let bench = micro_bench::frames::ReceiveBench::new(body_size, datagram_cnt);
+        group.throughput(Throughput::Bytes((body_size * datagram_cnt) as u64));
+        group.bench_function(format!("zero_copy_{body_size}_bytes"), |bencher| {
+            bencher.iter_batched(
+                || bench.clone(),
+                micro_bench::frames::ReceiveBench::receive_zero_copy,
+                BatchSize::SmallInput,
+            );
+        });
+        group.bench_function(format!("copied_{body_size}_bytes"), |bencher| {
+            bencher.iter_batched(
+                || bench.clone(),
+                micro_bench::frames::ReceiveBench::receive_copied,
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    codec_benchmark,
+    reorder_benchmark,
+    freeze_benchmark,
+    reactor_benchmark,
+    receive_benchmark
+);
 criterion_main!(benches);
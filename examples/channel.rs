@@ -0,0 +1,118 @@
+//! Run the connections over an in-process channel instead of the UDP. Any datagram transport,
+//! e.g. the WebTransport datagrams, is adapted by implementing `AsyncUdpSocket` in the same way.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::{SinkExt, StreamExt};
+use raknet_rs::client::{self, ConnectTo};
+use raknet_rs::runtime::{AsyncUdpSocket, Tokio};
+use raknet_rs::server::{self, MakeIncoming};
+
+type Datagram = (Bytes, SocketAddr);
+
+/// The in-process network routing the datagrams by the address
+#[derive(Default, Clone)]
+struct Network(Arc<Mutex<HashMap<SocketAddr, UnboundedSender<Datagram>>>>);
+
+impl Network {
+    fn bind(&self, addr: SocketAddr) -> ChannelSocket {
+        let (tx, rx) = mpsc::unbounded();
+        self.0.lock().unwrap().insert(addr, tx);
+        ChannelSocket {
+            addr,
+            rx: Mutex::new(rx),
+            network: self.clone(),
+        }
+    }
+}
+
+struct ChannelSocket {
+    addr: SocketAddr,
+    rx: Mutex<UnboundedReceiver<Datagram>>,
+    network: Network,
+}
+
+impl AsyncUdpSocket for ChannelSocket {
+    type Runtime = Tokio;
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut BytesMut,
+    ) -> Poll<io::Result<SocketAddr>> {
+        // the network holds the senders, the channel never ends
+        let Some((datagram, from)) = ready!(self.rx.lock().unwrap().poll_next_unpin(cx)) else {
+            return Poll::Pending;
+        };
+        // exactly one datagram per call, truncated to the spare capacity like the UDP
+        let len = datagram.len().min(buf.capacity() - buf.len());
+        buf.put_slice(&datagram[..len]);
+        Poll::Ready(Ok(from))
+    }
+
+    fn poll_send_to(
+        &self,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        // the datagrams to nowhere are dropped silently like the UDP
+        if let Some(tx) = self.network.0.lock().unwrap().get(&target) {
+            let _ = tx.unbounded_send((Bytes::copy_from_slice(buf), self.addr));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let network = Network::default();
+    let server_addr: SocketAddr = "10.0.0.1:19132".parse()?;
+    let mut incoming = network.bind(server_addr).make_incoming(
+        server::Config::new()
+            .server_guid(114514)
+            .advertisement(&b"Hello, I am channel server"[..])
+            .min_mtu(500)
+            .max_mtu(1400)
+            .support_version(vec![9, 11, 13])
+            .max_pending(64),
+    );
+    tokio::spawn(async move {
+        while let Some(io) = incoming.next().await {
+            tokio::spawn(async move {
+                tokio::pin!(io);
+                while let Some(data) = io.next().await {
+                    io.send(data).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let io = network
+        .bind("10.0.0.2:19133".parse()?)
+        .connect_to(
+            server_addr,
+            client::Config::new()
+                .mtu(1000)
+                .client_guid(1919810)
+                .protocol_version(11),
+        )
+        .await?;
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"hello over the channel"))
+        .await?;
+    let echoed = io.next().await.unwrap();
+    println!("[client] echoed '{}'", String::from_utf8_lossy(&echoed));
+    Ok(())
+}
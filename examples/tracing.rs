@@ -27,7 +27,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut incoming = socket.make_incoming(
         server::Config::new()
             .send_buf_cap(1024)
-            .sever_guid(114514)
+            .server_guid(114514)
             .advertisement(&b"Hello, I am proxy server"[..])
             .min_mtu(500)
             .max_mtu(1400)
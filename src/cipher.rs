@@ -0,0 +1,93 @@
+use std::fmt;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+/// The cipher encrypting the user data of the connections, it is supplied by the user, e.g. an
+/// ECDH key exchange followed by an AEAD.
+///
+/// The keys are exchanged in the online handshake: the client sends its key in the
+/// `ConnectionRequest` with the `use_encryption` flag, and the server replies its key in the
+/// `ConnectionRequestAccepted`. Then both sides encrypt the user data by the established
+/// [`CipherSession`]. The internal packets, e.g. the pings and the acknowledgements, are not
+/// encrypted.
+///
+/// Both sides must enable the encryption, the server rejects the connection request without it
+/// and the client fails with [`Error::ConnectionRequestFailed`] if the server does not enable it.
+///
+/// [`Error::ConnectionRequestFailed`]: crate::errors::Error::ConnectionRequestFailed
+pub trait Cipher: Send + Sync + 'static {
+    /// Start the key exchange of a new connection
+    fn key_exchange(&self) -> Box<dyn KeyExchange>;
+}
+
+/// The key exchange of a connection
+pub trait KeyExchange: Send + Sync {
+    /// The key sent to the peer, e.g. the ephemeral public key
+    fn local_key(&self) -> Bytes;
+
+    /// Establish the session by the key of the peer, `None` rejects the connection
+    fn establish(self: Box<Self>, peer_key: &[u8]) -> Option<Box<dyn CipherSession>>;
+}
+
+/// The session encrypting the user data of a connection. Each datagram may be lost, duplicated or
+/// reordered, so each ciphertext must be decryptable on its own, e.g. carrying its nonce.
+pub trait CipherSession: Send + Sync {
+    /// Encrypt the user data
+    fn encrypt(&self, plaintext: &[u8]) -> Bytes;
+
+    /// Decrypt the user data, `None` if it is not authentic
+    fn decrypt(&self, ciphertext: &[u8]) -> Option<Bytes>;
+}
+
+/// The cipher held by the configs
+#[derive(Clone)]
+pub(crate) struct Encryption(pub(crate) Arc<dyn Cipher>);
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Encryption")
+    }
+}
+
+/// The toy cipher xor-ing the user data by the keys of both sides, only for the tests
+#[cfg(test)]
+pub(crate) struct XorCipher(pub(crate) u8);
+
+#[cfg(test)]
+impl Cipher for XorCipher {
+    fn key_exchange(&self) -> Box<dyn KeyExchange> {
+        Box::new(XorExchange(self.0))
+    }
+}
+
+#[cfg(test)]
+struct XorExchange(u8);
+
+#[cfg(test)]
+impl KeyExchange for XorExchange {
+    fn local_key(&self) -> Bytes {
+        Bytes::copy_from_slice(&[self.0])
+    }
+
+    fn establish(self: Box<Self>, peer_key: &[u8]) -> Option<Box<dyn CipherSession>> {
+        match peer_key {
+            [peer] => Some(Box::new(XorSession(self.0 ^ peer))),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) struct XorSession(pub(crate) u8);
+
+#[cfg(test)]
+impl CipherSession for XorSession {
+    fn encrypt(&self, plaintext: &[u8]) -> Bytes {
+        plaintext.iter().map(|b| b ^ self.0).collect()
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Option<Bytes> {
+        Some(ciphertext.iter().map(|b| b ^ self.0).collect())
+    }
+}
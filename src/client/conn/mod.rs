@@ -1,15 +1,24 @@
-use std::net::ToSocketAddrs;
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::Stream;
 
 use super::handler::offline;
-use crate::errors::Error;
-use crate::io::{Ping, IO};
-use crate::{codec, RoleContext};
+use crate::errors::{CodecError, Error};
+use crate::io::{OrderGapPolicy, Ping, RawFrameSet, IO};
+use crate::packet::{MAX_ORDER_CHANNELS, MIN_MTU};
+use crate::resend_map::{MAX_RTO, MIN_RTO};
+use crate::{codec, Reactor, Reliability, RoleContext};
+
+/// Connection implementation over the UDP socket of any async runtime
+mod udp;
 
-/// Connection implementation by using tokio's UDP framework
-#[cfg(feature = "tokio-udp")]
-mod tokio;
+pub use self::udp::discover;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct Config {
     /// The send buffer of each IO polled by the incoming
     send_buf_cap: usize,
@@ -19,6 +28,8 @@ pub struct Config {
     client_guid: u64,
     /// Raknet protocol version, default is 9
     protocol_version: u8,
+    /// The protocol versions allowed to retry with if the server is incompatible
+    version_fallback: Vec<u8>,
     /// Limit the max size of a parted frames set, 0 means no limit
     /// It will abort the split frame if the `parted_size` reaches limit.
     /// The maximum number of inflight parted frames is `max_parted_size` * `max_parted_count`
@@ -29,6 +40,95 @@ pub struct Config {
     max_parted_count: usize,
     /// Maximum ordered channel, the value should be less than 256
     max_channels: usize,
+    /// The default reliabilities of the messages sent on each order channel
+    channel_reliabilities: HashMap<u8, Reliability>,
+    /// The timeout of the online handshake, `None` means no timeout
+    online_handshake_timeout: Option<Duration>,
+    /// The timeout of receiving nothing while awaiting acknowledgement, `None` means the
+    /// watchdog is disabled
+    stall_timeout: Option<Duration>,
+    /// The grace period of closing the stalled connection, `None` means never close it
+    stall_close_grace: Option<Duration>,
+    /// The timeout of receiving nothing from the peer, `None` means the keepalive is disabled
+    idle_timeout: Option<Duration>,
+    /// The maximum count of reliable messages awaiting acknowledgement, `None` means no limit
+    max_inflight_messages: Option<usize>,
+    /// The lower bound of the RTO computed from the RTT estimation
+    min_rto: Duration,
+    /// The upper bound of the RTO computed from the RTT estimation
+    max_rto: Duration,
+    /// The scaling of the base RTO under the connection-wide loss, 0 means disabled
+    rto_loss_scale: f64,
+    /// The maximum times of resending the stale frames, `None` means no limit
+    max_retries: Option<u32>,
+    /// Resend the frame set once a frame set sent this count later is acknowledged, 0 means
+    /// disabled
+    fast_retransmit_threshold: u32,
+    /// Limit the frame sets awaiting acknowledgement by the NewReno congestion window
+    congestion_control: bool,
+    /// The DSCP marked on the outgoing datagrams, `None` means the socket default
+    dscp: Option<u8>,
+    /// The channel receiving the delivery receipts, `None` means the receipts are disabled
+    delivery_receipts: Option<flume::Sender<u64>>,
+    /// The verifier of the server advertisement, `None` means the server is not pinged
+    verify_advertisement: Option<offline::Verifier>,
+    /// The handling of the ordered frames stuck behind a missing index when the connection is
+    /// closing
+    order_gap_policy: OrderGapPolicy,
+    /// The count of the reliable frame indices tracked by the deduplication past the first
+    /// missing one
+    dedup_window: usize,
+    /// The callback observing the errors of decoding the frames, `None` means they are only
+    /// logged
+    on_codec_error: Option<codec::OnCodecError>,
+    /// The cap of the outgoing datagram size below the mtu, `None` means no cap
+    max_datagram_size: Option<u16>,
+    /// The lower mtus stepped down through when the open connection request is not replied,
+    /// empty means the path mtu discovery is disabled
+    mtu_ladder: Vec<u16>,
+    /// The timeout of waiting for the reply of each mtu probed
+    mtu_probe_timeout: Duration,
+    /// The resends of each open connection request not replied, zero means waiting forever
+    connect_retries: u32,
+    /// The first backoff of resending the open connection requests, doubled on each resend
+    connect_retry_backoff: Duration,
+    /// Disconnect the lingering session and retry the handshake once if the server replies
+    /// `AlreadyConnected`
+    recover_already_connected: bool,
+    /// The delay of holding the outgoing acknowledgements, `None` means they are sent on each
+    /// flush
+    ack_delay: Option<Duration>,
+    /// The count of the received frame sets sending the held acknowledgements before the delay
+    ack_threshold: usize,
+    /// The reorder window of the frame sets and the delay before nacking the missing ones,
+    /// `None` means they are nacked at once
+    nack_reorder_window: Option<(u32, Duration)>,
+    /// Coalesce the messages buffered between the flushes into the frame sets filled up to the
+    /// mtu
+    coalesce: bool,
+    /// The interval of flushing automatically while the IO is polled, `None` means the caller
+    /// flushes manually
+    auto_flush_interval: Option<Duration>,
+    /// The timeout of closing the connection, `None` means waiting until the disconnect
+    /// notification is acknowledged
+    close_timeout: Option<Duration>,
+    /// The reactor driving the timers, `None` means the global one
+    reactor: Option<&'static Reactor>,
+    /// Propagate the trace ids of the messages to the peer
+    propagate_trace_id: bool,
+    /// The threshold of compressing frame bodies, `None` means the compression is disabled
+    #[cfg(feature = "compression")]
+    compression_threshold: Option<usize>,
+    /// The cipher encrypting the user data, `None` means the encryption is disabled
+    #[cfg(feature = "encryption")]
+    encryption: Option<crate::cipher::Encryption>,
+    /// The chaos injected into the outgoing datagrams, `None` means reading it from the
+    /// environment
+    #[cfg(feature = "chaos")]
+    chaos: Option<crate::Chaos>,
+    /// The id labeling the counters of the connection, `None` means the peer address
+    #[cfg(feature = "metrics")]
+    metrics_id: Option<String>,
 }
 
 impl Default for Config {
@@ -44,13 +144,59 @@ impl Config {
             mtu: 1400,
             client_guid: rand::random(),
             protocol_version: 9,
+            version_fallback: vec![],
             max_parted_size: 256,
             max_parted_count: 256,
             max_channels: 1,
+            channel_reliabilities: HashMap::new(),
+            online_handshake_timeout: None,
+            stall_timeout: None,
+            stall_close_grace: None,
+            idle_timeout: None,
+            max_inflight_messages: None,
+            min_rto: MIN_RTO,
+            max_rto: MAX_RTO,
+            rto_loss_scale: 0.0,
+            max_retries: None,
+            fast_retransmit_threshold: 0,
+            congestion_control: false,
+            dscp: None,
+            delivery_receipts: None,
+            verify_advertisement: None,
+            order_gap_policy: OrderGapPolicy::Discard,
+            dedup_window: 65536,
+            on_codec_error: None,
+            max_datagram_size: None,
+            mtu_ladder: vec![],
+            mtu_probe_timeout: Duration::from_secs(1),
+            connect_retries: 0,
+            connect_retry_backoff: Duration::from_millis(500),
+            recover_already_connected: false,
+            ack_delay: None,
+            ack_threshold: 32,
+            nack_reorder_window: None,
+            coalesce: false,
+            auto_flush_interval: None,
+            close_timeout: None,
+            reactor: None,
+            propagate_trace_id: false,
+            #[cfg(feature = "compression")]
+            compression_threshold: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            #[cfg(feature = "metrics")]
+            metrics_id: None,
         }
     }
 
-    /// Set the send buffer capacity of each IO polled by the incoming
+    /// Set the send buffer capacity of the IO
+    /// The default value is 1024
+    ///
+    /// It bounds the buffered frames along with the frames awaiting acknowledgement, the IO stops
+    /// accepting messages until the peer acknowledges some of them. A message split into more
+    /// frames than the cap is still accepted once the previous ones are all acknowledged.
     pub fn send_buf_cap(mut self, send_buf_cap: usize) -> Self {
         self.send_buf_cap = send_buf_cap;
         self
@@ -58,6 +204,10 @@ impl Config {
 
     /// Give the mtu of the connection
     pub fn mtu(mut self, mtu: u16) -> Self {
+        assert!(
+            self.max_datagram_size.map_or(true, |size| size <= mtu),
+            "max_datagram_size should not exceed the mtu"
+        );
         self.mtu = mtu;
         self
     }
@@ -74,6 +224,15 @@ impl Config {
         self
     }
 
+    /// Set the protocol versions allowed to retry with when the server replies it is incompatible
+    /// with the protocol version. The client retries with the version advertised by the server
+    /// if it is in the list, otherwise it fails with [`Error::IncompatibleProtocol`].
+    /// The default value is empty
+    pub fn version_fallback(mut self, versions: Vec<u8>) -> Self {
+        self.version_fallback = versions;
+        self
+    }
+
     /// Set the maximum parted size
     /// The default value is 256
     /// The maximum number of inflight parted frames is `max_parted_size`*`max_parted_count`nt
@@ -90,22 +249,430 @@ impl Config {
         self
     }
 
-    /// Set the maximum channels
+    /// Set the maximum channels, the sequenced and ordered messages on different channels do not
+    /// block each other
     /// The default value is 1
-    /// The maximum value should be less than 256
+    /// The maximum value is 32
     /// # Panics
-    /// Panics if the channels is greater than 256
+    /// Panics if the channels is 0 or greater than 32
     pub fn max_channels(mut self, channels: usize) -> Self {
-        assert!(channels < 256, "max_channels should be less than 256");
+        assert!(
+            (1..=MAX_ORDER_CHANNELS).contains(&channels),
+            "max_channels should be in [1, {MAX_ORDER_CHANNELS}]"
+        );
+        assert!(
+            self.channel_reliabilities
+                .keys()
+                .all(|&channel| usize::from(channel) < channels),
+            "max_channels should cover the channels with default reliability"
+        );
         self.max_channels = channels;
         self
     }
 
+    /// Set the default reliability of the messages sent on the order channel, it takes precedence
+    /// over the default reliability of the connection when the channel is the default order
+    /// channel, e.g. channel 0 reliable ordered for the state and channel 1 unreliable sequenced
+    /// for the position.
+    /// The default value is the default reliability of the connection
+    /// # Panics
+    /// Panics if the channel is not less than `max_channels`
+    pub fn channel_reliability(mut self, channel: u8, reliability: Reliability) -> Self {
+        assert!(
+            usize::from(channel) < self.max_channels,
+            "channel should be less than max_channels"
+        );
+        self.channel_reliabilities.insert(channel, reliability);
+        self
+    }
+
+    /// Set the timeout of the online handshake, the connection fails with
+    /// [`Error::HandshakeTimeout`] if the server does not accept the connection in time
+    /// The default value is no timeout
+    pub fn online_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.online_handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Enable the watchdog of each connection. A connection is deemed stalled if nothing is
+    /// received from the peer within the timeout while awaiting acknowledgement, the stall is
+    /// counted in [`Anomalies::stalls`].
+    /// The default value is disabled
+    ///
+    /// [`Anomalies::stalls`]: crate::io::Anomalies::stalls
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.stall_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the grace period of closing the stalled connection detected by the watchdog, the
+    /// connection fails with [`Error::ConnectionStalled`] if it is still stalling after that.
+    /// It takes effect only if [`Config::stall_timeout`] is set.
+    /// The default value is never closing it
+    pub fn stall_close_grace(mut self, grace: Duration) -> Self {
+        self.stall_close_grace = Some(grace);
+        self
+    }
+
+    /// Enable the keepalive of each connection. The peer is pinged while nothing is received from
+    /// it, and the connection fails with [`Error::IdleTimeout`] if nothing is received within
+    /// the timeout, then the IO yields the end of the stream. The pings are sent on the flush.
+    /// The default value is disabled
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the maximum count of reliable messages awaiting acknowledgement. Sending applies
+    /// backpressure once the limit is reached until all frames of an earlier message are
+    /// acknowledged, regardless of how many frames each message is split into.
+    /// The default value is no limit
+    /// # Panics
+    /// Panics if the limit is 0
+    pub fn max_inflight_messages(mut self, max: usize) -> Self {
+        assert!(max > 0, "max_inflight_messages should be greater than 0");
+        self.max_inflight_messages = Some(max);
+        self
+    }
+
+    /// Set the lower bound of the RTO computed from the RTT estimation. It keeps the frame sets
+    /// from being resent too eagerly on a fast link.
+    /// The default value is 200ms
+    /// # Panics
+    /// Panics if it is larger than `max_rto`
+    pub fn min_rto(mut self, min: Duration) -> Self {
+        assert!(min <= self.max_rto, "min_rto should not be larger than max_rto");
+        self.min_rto = min;
+        self
+    }
+
+    /// Set the upper bound of the RTO computed from the RTT estimation, including the scaling
+    /// under the connection-wide loss.
+    /// The default value is 10s
+    /// # Panics
+    /// Panics if it is less than `min_rto`
+    pub fn max_rto(mut self, max: Duration) -> Self {
+        assert!(max >= self.min_rto, "max_rto should not be less than min_rto");
+        self.max_rto = max;
+        self
+    }
+
+    /// Set the scaling of the base RTO under the connection-wide loss. The base RTO is
+    /// multiplied by `1 + scale * loss_rate`, where the loss rate is smoothed over the recent
+    /// frame sets, so that it grows under sustained loss and recovers after that.
+    /// The default value is 0, i.e. the base RTO does not scale
+    /// # Panics
+    /// Panics if the scale is negative or not finite
+    pub fn rto_loss_scale(mut self, scale: f64) -> Self {
+        assert!(
+            scale.is_finite() && scale >= 0.0,
+            "rto_loss_scale should be a non-negative number"
+        );
+        self.rto_loss_scale = scale;
+        self
+    }
+
+    /// Set the maximum times of resending the frames that go stale, i.e. not acknowledged within
+    /// the RTO. The connection fails with [`Error::PeerUnreachable`] once they go stale again
+    /// instead of being resent, so the dead peer is detected deterministically. The resending on
+    /// the NACK or the fast retransmit does not count.
+    /// The default value is no limit
+    pub fn max_retries(mut self, max: u32) -> Self {
+        self.max_retries = Some(max);
+        self
+    }
+
+    /// Set the fast retransmit threshold. A frame set is resent immediately once a frame set sent
+    /// `threshold` later is acknowledged, without waiting for the NACK from the peer or the RTO.
+    /// The frame sets in the NACK are always resent immediately.
+    /// The default value is 0, i.e. the fast retransmit is disabled
+    pub fn fast_retransmit_threshold(mut self, threshold: u32) -> Self {
+        self.fast_retransmit_threshold = threshold;
+        self
+    }
+
+    /// Enable the NewReno congestion control. The reliable frame sets awaiting acknowledgement
+    /// are limited by the congestion window, which grows on the acknowledgements and halves on
+    /// the losses (the NACKs or the RTOs), so the in-flight data is bounded on congested links.
+    /// The sending blocks when the window and the send buffer are both full.
+    /// The default value is false
+    pub fn congestion_control(mut self, enabled: bool) -> Self {
+        self.congestion_control = enabled;
+        self
+    }
+
+    /// Set the DSCP marked on the outgoing datagrams for the networks classifying the traffic by
+    /// it. It is applied on the socket, and ignored silently where it is unsupported.
+    /// The default value is the socket default
+    /// # Panics
+    /// Panics if the dscp is not less than 64
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        assert!(dscp < 64, "dscp should be less than 64");
+        self.dscp = Some(dscp);
+        self
+    }
+
+    /// Enable the delivery receipts. Each reliable message sent is assigned an id, counting from 0
+    /// in the order of sending, and the id is pushed into the sender once all frames of the
+    /// message are acknowledged. So is each unreliable message requesting it by
+    /// [`Message::set_ack_receipt`], on a best-effort basis. The receipt is dropped if the bounded
+    /// channel is full.
+    /// The default value is disabled
+    ///
+    /// [`Message::set_ack_receipt`]: crate::Message::set_ack_receipt
+    pub fn delivery_receipts(mut self, sender: flume::Sender<u64>) -> Self {
+        self.delivery_receipts = Some(sender);
+        self
+    }
+
+    /// Set the verifier of the advertisement of the server. The client pings the server before the
+    /// offline handshake and consults the verifier with the advertisement in the pong, the
+    /// connection fails with [`Error::AdvertisementRejected`] if it returns false.
+    /// The default value is no verification
+    pub fn verify_advertisement(
+        mut self,
+        f: impl Fn(&[u8]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.verify_advertisement = Some(offline::Verifier(Arc::new(f)));
+        self
+    }
+
+    /// Set the handling of the ordered frames stuck behind a missing ordered frame index when the
+    /// connection is closed by the peer, the missing one never arrives if the peer gave up
+    /// resending it.
+    /// The default value is [`OrderGapPolicy::Discard`]
+    pub fn order_gap_policy(mut self, policy: OrderGapPolicy) -> Self {
+        self.order_gap_policy = policy;
+        self
+    }
+
+    /// Set the maximum size of the outgoing datagrams. It caps the frame sets and the fragments
+    /// below the negotiated mtu without renegotiating it, working around the paths fragmenting
+    /// the datagrams smaller than the mtu. It takes effect only if it is below the negotiated mtu.
+    /// The default value is no cap
+    /// # Panics
+    /// Panics if the size is less than 576 or exceeds the mtu
+    pub fn max_datagram_size(mut self, size: u16) -> Self {
+        assert!(size >= MIN_MTU, "max_datagram_size should not be less than {MIN_MTU}");
+        assert!(size <= self.mtu, "max_datagram_size should not exceed the mtu");
+        self.max_datagram_size = Some(size);
+        self
+    }
+
+    /// Enable the path mtu discovery. The open connection request is padded to the mtu, and if it
+    /// is not replied within the probe timeout, it steps down through the ladder below the `mtu`,
+    /// e.g. `vec![1492, 1200, 576]`. The connection fails with [`Error::HandshakeTimeout`] after
+    /// the last one. The discovered mtu is read by [`IO::peer_mtu`]. Once connected, the mtu
+    /// steps down further if the frame sets keep going stale for their size, and the server
+    /// follows it. The frames already sent are still resent in their sizes.
+    /// The default value is disabled
+    /// # Panics
+    /// Panics if any mtu of the ladder is less than 576
+    pub fn mtu_ladder(mut self, ladder: Vec<u16>) -> Self {
+        assert!(
+            ladder.iter().all(|&mtu| mtu >= MIN_MTU),
+            "the mtu ladder should not be less than {MIN_MTU}"
+        );
+        self.mtu_ladder = ladder;
+        self
+    }
+
+    /// Set the timeout of waiting for the reply of each mtu probed by the path mtu discovery
+    /// The default value is 1s
+    pub fn mtu_probe_timeout(mut self, timeout: Duration) -> Self {
+        self.mtu_probe_timeout = timeout;
+        self
+    }
+
+    /// Resend the open connection requests not replied within the backoff, so the handshake
+    /// survives the transient packet loss. The backoff is doubled on each resend, and the
+    /// connection fails with [`Error::HandshakeTimeout`] after the last one. The server replying
+    /// `IncompatibleProtocol` or `NoFreeIncomingConnections` fails it without resending.
+    /// The default value is 0, waiting for the replies without resending
+    pub fn connect_retries(mut self, retries: u32) -> Self {
+        self.connect_retries = retries;
+        self
+    }
+
+    /// Set the first backoff of resending the open connection requests
+    /// The default value is 500ms
+    pub fn connect_retry_backoff(mut self, backoff: Duration) -> Self {
+        self.connect_retry_backoff = backoff;
+        self
+    }
+
+    /// Recover from the server replying `AlreadyConnected`, which happens when a previous session
+    /// of the same address is still lingering on the server, e.g. after the client restarted on
+    /// the same port. A `DisconnectNotification` is sent to close that session, and the handshake
+    /// is retried once after the `connect_retry_backoff`. Otherwise the connection fails with
+    /// [`Error::AlreadyConnected`].
+    /// The default value is false
+    pub fn recover_already_connected(mut self, recover: bool) -> Self {
+        self.recover_already_connected = recover;
+        self
+    }
+
+    /// Hold the outgoing acknowledgements for the delay, coalescing the received frame sets into
+    /// fewer acks on the high-throughput connections. They are sent by the first flush after the
+    /// delay or once [`Config::ack_threshold`] frame sets are received, whichever comes first.
+    /// The delay is capped at half of the RTO to keep the peer from resending spuriously.
+    /// The default value is disabled, the acks are sent on each flush
+    pub fn ack_delay(mut self, delay: Duration) -> Self {
+        self.ack_delay = Some(delay);
+        self
+    }
+
+    /// Set the count of the received frame sets sending the held acknowledgements before the
+    /// delay elapses. It takes effect only if [`Config::ack_delay`] is set.
+    /// The default value is 32
+    /// # Panics
+    /// Panics if the threshold is 0
+    pub fn ack_threshold(mut self, threshold: usize) -> Self {
+        assert!(threshold > 0, "ack_threshold should be greater than 0");
+        self.ack_threshold = threshold;
+        self
+    }
+
+    /// Tolerate the reordering of the incoming frame sets before nacking the missing ones. A
+    /// missing frame set is nacked once more than `frame_sets` frame sets are received after it
+    /// or the delay elapses since it is detected, whichever comes first, so that the reordered
+    /// ones are not resent by the peer. The nacks are sent on the flush.
+    /// The default value is disabled, the missing frame sets are nacked at once
+    pub fn nack_reorder_window(mut self, frame_sets: u32, delay: Duration) -> Self {
+        self.nack_reorder_window = Some((frame_sets, delay));
+        self
+    }
+
+    /// Coalesce the messages buffered between the flushes into the frame sets filled up to the
+    /// mtu. Without it, the buffered messages are sent when the sink is polled for the next one,
+    /// i.e. feeding N tiny messages produces N datagrams. The frame sets are sent once they are
+    /// filled, on the flush, or when an [`Priority::Immediate`] message is buffered.
+    /// The default value is disabled
+    ///
+    /// [`Priority::Immediate`]: crate::Priority::Immediate
+    pub fn coalesce(mut self, coalesce: bool) -> Self {
+        self.coalesce = coalesce;
+        self
+    }
+
+    /// Flush the buffered messages and the pending acknowledgements every interval while the IO
+    /// is polled, e.g. awaiting the next message, so that the caller need not drive the flush
+    /// periodically. The timer is registered in the shared reactor instead of spawning a task.
+    /// The default value is disabled, the caller flushes manually
+    pub fn auto_flush_interval(mut self, interval: Duration) -> Self {
+        self.auto_flush_interval = Some(interval);
+        self
+    }
+
+    /// Set the timeout of closing the connection by [`SinkExt::close`]. The disconnect
+    /// notification is sent after the buffered frames are acknowledged, and the closing resolves
+    /// once the peer acknowledges it or the timeout (e.g. 2MSL) elapses, so that a peer gone away
+    /// never blocks it. [`IO::close_with_timeout`] tells whether it is acknowledged.
+    /// The default value is waiting until it is acknowledged
+    ///
+    /// [`SinkExt::close`]: futures::SinkExt::close
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the reactor driving the timers of the connection, e.g. a dedicated one spawned by
+    /// [`Reactor::spawn`] with a coarse granularity to wake up less often under many connections.
+    /// The default value is the global reactor with the finest granularity
+    pub fn reactor(mut self, reactor: &'static Reactor) -> Self {
+        self.reactor = Some(reactor);
+        self
+    }
+
+    /// Set the count of the reliable frame indices tracked past the first missing one to drop the
+    /// redelivered reliable frames, e.g. resent after the acknowledgement is lost. An index
+    /// beyond the window gives up the oldest missing ones, which are dropped if they arrive
+    /// later, so it should cover the indices sent within a few RTOs.
+    /// The default value is 65536
+    /// # Panics
+    /// Panics if it is 0
+    pub fn dedup_window(mut self, window: usize) -> Self {
+        assert!(window > 0, "dedup_window should not be 0");
+        self.dedup_window = window;
+        self
+    }
+
+    /// Set the callback invoked with the errors of decoding the frames of the connection along
+    /// with the peer address, e.g. to count and classify the malformed packets. It is invoked
+    /// while polling the connection, so it should return quickly without blocking. The errors
+    /// are logged regardless.
+    /// The default value is none
+    pub fn on_codec_error(
+        mut self,
+        f: impl Fn(&CodecError, SocketAddr) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_codec_error = Some(codec::OnCodecError(Arc::new(f)));
+        self
+    }
+
+    /// Propagate the trace ids attached by [`Message::with_trace_id`] to the peer if the peer also
+    /// enables it, the peer reports them by [`TraceInfo::last_trace_id`]. It is negotiated in the
+    /// online handshake.
+    /// The default value is false
+    ///
+    /// [`Message::with_trace_id`]: crate::Message::with_trace_id
+    /// [`TraceInfo::last_trace_id`]: crate::io::TraceInfo::last_trace_id
+    pub fn propagate_trace_id(mut self, enabled: bool) -> Self {
+        self.propagate_trace_id = enabled;
+        self
+    }
+
+    /// Enable compressing the frame bodies not smaller than the threshold if the peer also enables
+    /// it. The compression is negotiated in the online handshake. The threshold is at least 64
+    /// bytes to avoid expanding tiny frame bodies.
+    /// The default value is disabled
+    #[cfg(feature = "compression")]
+    pub fn compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = Some(threshold.max(crate::codec::MIN_COMPRESSION_THRESHOLD));
+        self
+    }
+
+    /// Encrypt the user data by the cipher, the keys are exchanged in the online handshake. The
+    /// server must also enable it, otherwise the connection request fails.
+    /// The default value is disabled
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, cipher: impl crate::cipher::Cipher) -> Self {
+        self.encryption = Some(crate::cipher::Encryption(Arc::new(cipher)));
+        self
+    }
+
+    /// Inject the chaos into the outgoing connected datagrams for the resilience testing. It
+    /// falls back to [`Chaos::from_env`] if it is not set.
+    /// The default value is no chaos
+    ///
+    /// [`Chaos::from_env`]: crate::Chaos::from_env
+    #[cfg(feature = "chaos")]
+    pub fn chaos(mut self, chaos: crate::Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Record the counters of the connection into the `metrics` crate, labeled by the id as
+    /// `connection`.
+    /// The default value is the address of the server
+    #[cfg(feature = "metrics")]
+    pub fn metrics_id(mut self, id: impl Into<String>) -> Self {
+        self.metrics_id = Some(id.into());
+        self
+    }
+
     fn offline_config(&self) -> offline::Config {
         offline::Config {
             client_guid: self.client_guid,
             mtu: self.mtu,
             protocol_version: self.protocol_version,
+            version_fallback: self.version_fallback.clone(),
+            verify_advertisement: self.verify_advertisement.clone(),
+            mtu_ladder: self.mtu_ladder.clone(),
+            mtu_probe_timeout: self.mtu_probe_timeout,
+            connect_retries: self.connect_retries,
+            connect_retry_backoff: self.connect_retry_backoff,
+            reactor: self.reactor(),
         }
     }
 
@@ -114,21 +681,69 @@ impl Config {
             max_parted_count: self.max_parted_count,
             max_parted_size: self.max_parted_size,
             max_channels: self.max_channels,
+            reassembly_quota: None,
+            game_packet_ids: None,
+            order_gap_policy: self.order_gap_policy,
+            dedup_window: self.dedup_window,
+            on_error: self.on_codec_error.clone(),
         }
     }
 
     fn client_role(&self) -> RoleContext {
         RoleContext::Client {
             guid: self.client_guid,
+            reactor: self.reactor(),
         }
     }
+
+    fn reactor(&self) -> &'static Reactor {
+        self.reactor.unwrap_or_else(Reactor::get)
+    }
 }
 
 pub trait ConnectTo: Sized {
+    /// Connect to the server. If the address resolves to several ones, the handshake is raced
+    /// among the first 3 of the same family as the socket, the first replied wins and the others
+    /// are abandoned. The winner is read by [`IO::peer_addr`].
     #[allow(async_fn_in_trait)] // No need to consider the auto trait for now.
     async fn connect_to(
         self,
         addr: impl ToSocketAddrs,
         config: Config,
-    ) -> Result<impl IO + Ping, Error>;
+    ) -> Result<impl IO + Ping + RawFrameSet, Error>;
+
+    /// Connect with the first message sent along with the online handshake, so that the server
+    /// receives it right after the handshake completes without an extra round trip. The message
+    /// is sent by the default reliability ahead of the others, through the same layers as them.
+    /// Only use it for idempotent messages.
+    #[allow(async_fn_in_trait)]
+    async fn connect_to_with_data(
+        self,
+        addr: impl ToSocketAddrs,
+        config: Config,
+        first_msg: Bytes,
+    ) -> Result<impl IO + Ping + RawFrameSet, Error>;
+}
+
+/// Query the advertisement of the servers by the unconnected ping without connecting, e.g. the
+/// MOTD and the player count which can be decoded by [`Motd::parse`].
+///
+/// [`Motd::parse`]: crate::motd::Motd::parse
+pub trait PingServer: Sized {
+    /// Ping the server and return the advertisement in the first pong.
+    ///
+    /// # Errors
+    /// Returns [`Error::PingTimeout`] if the server does not answer within the timeout.
+    #[allow(async_fn_in_trait)]
+    async fn ping_server(self, addr: impl ToSocketAddrs, timeout: Duration)
+        -> Result<Bytes, Error>;
+
+    /// Ping the address and yield the address and the advertisement of every server answering it
+    /// until the timeout, e.g. the servers in the LAN answering the broadcast address. The
+    /// broadcast is only permitted if the socket is set with `SO_BROADCAST`.
+    fn ping_servers(
+        self,
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> impl Stream<Item = Result<(SocketAddr, Bytes), Error>>;
 }
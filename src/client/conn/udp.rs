@@ -0,0 +1,358 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{future, SinkExt, Stream, StreamExt};
+use log::debug;
+use minitrace::Span;
+
+use super::{ConnectTo, PingServer};
+use crate::client::handler::offline::OfflineHandler;
+use crate::client::handler::online::HandleOnline;
+use crate::client::handler::ping::Pongs;
+#[cfg(feature = "chaos")]
+use crate::codec::chaos::ChaosSocket;
+use crate::codec::frame::Framed;
+use crate::codec::{Decoded, Encoded};
+use crate::errors::Error;
+use crate::guard::HandleOutgoing;
+use crate::io::{Ping, RawFrameSet, SeparatedIO, IO};
+use crate::link::{Router, TransferLink};
+use crate::packet::connected::{self, Flags, Frame, FrameSet};
+use crate::packet::{PackType, Packet};
+use crate::resend_map::ResendMap;
+use crate::runtime::{AsyncUdpSocket, Runtime};
+use crate::state::{IncomingStateManage, OutgoingStateManage};
+use crate::utils::TraceStreamExt;
+use crate::{PeerContext, Reliability, RoleContext};
+
+impl<S: AsyncUdpSocket> ConnectTo for S {
+    async fn connect_to(
+        self,
+        addrs: impl ToSocketAddrs,
+        config: super::Config,
+    ) -> Result<impl IO + Ping + RawFrameSet, Error> {
+        connect(self, addrs, config, None).await
+    }
+
+    async fn connect_to_with_data(
+        self,
+        addrs: impl ToSocketAddrs,
+        config: super::Config,
+        first_msg: Bytes,
+    ) -> Result<impl IO + Ping + RawFrameSet, Error> {
+        connect(self, addrs, config, Some(first_msg)).await
+    }
+}
+
+/// The pong is not bounded by the mtu, read it up to the default max mtu of the server
+const MAX_PONG_SIZE: usize = 1500;
+
+/// The count of the resolved addresses raced by the handshake, the first one replied wins
+const MAX_RACING_ADDRS: usize = 3;
+
+impl<S: AsyncUdpSocket> PingServer for S {
+    async fn ping_server(
+        self,
+        addrs: impl ToSocketAddrs,
+        timeout: Duration,
+    ) -> Result<Bytes, Error> {
+        let local_addr = self.local_addr()?;
+        let Some(addr) = addrs
+            .to_socket_addrs()?
+            .min_by_key(|addr| addr.is_ipv4() != local_addr.is_ipv4())
+        else {
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "invalid address").into());
+        };
+        let pongs = self.ping_servers(addr, timeout);
+        futures::pin_mut!(pongs);
+        while let Some(pong) = pongs.next().await {
+            let (from, data) = pong?;
+            if from == addr {
+                return Ok(data);
+            }
+        }
+        Err(Error::PingTimeout)
+    }
+
+    fn ping_servers(
+        self,
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> impl Stream<Item = Result<(SocketAddr, Bytes), Error>> {
+        Pongs::new(
+            Framed::new(Arc::new(self), MAX_PONG_SIZE),
+            addr,
+            Instant::now() + timeout,
+        )
+        .map(|res| res.map(|(addr, _, data)| (addr, data)))
+    }
+}
+
+/// Discover the servers listening on the port in the local network. It binds a socket of the
+/// runtime with the broadcast enabled, pings the broadcast address and yields the address and the
+/// advertisement of every server answering within the duration, once per server guid.
+///
+/// ```no_run
+/// # async fn discover() -> std::io::Result<()> {
+/// use futures::StreamExt;
+///
+/// let servers = raknet_rs::client::discover::<tokio::net::UdpSocket>(
+///     19132,
+///     std::time::Duration::from_secs(1),
+/// )?;
+/// futures::pin_mut!(servers);
+/// while let Some((addr, advertisement)) = servers.next().await {
+///     println!("{addr}: {advertisement:?}");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+/// Returns the error of binding the socket or enabling the broadcast.
+pub fn discover<S>(
+    port: u16,
+    duration: Duration,
+) -> io::Result<impl Stream<Item = (SocketAddr, Bytes)>>
+where
+    S: AsyncUdpSocket + TryFrom<UdpSocket, Error = io::Error>,
+{
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    let socket = S::try_from(socket)?;
+    let mut guids = HashSet::new();
+    let pongs = Pongs::new(
+        Framed::new(Arc::new(socket), MAX_PONG_SIZE),
+        SocketAddr::from((Ipv4Addr::BROADCAST, port)),
+        Instant::now() + duration,
+    )
+    .filter_map(move |res| {
+        let pong = match res {
+            // a server on several interfaces answers more than once
+            Ok((addr, guid, data)) => guids.insert(guid).then_some((addr, data)),
+            Err(err) => {
+                debug!("failed to broadcast the ping: {err}");
+                None
+            }
+        };
+        future::ready(pong)
+    });
+    Ok(pongs)
+}
+
+async fn connect<S: AsyncUdpSocket>(
+    socket: S,
+    addrs: impl ToSocketAddrs,
+    config: super::Config,
+    first_msg: Option<Bytes>,
+) -> Result<impl IO + Ping + RawFrameSet, Error> {
+    if let Some(dscp) = config.dscp
+        && let Err(err) = socket.set_dscp(dscp)
+    {
+        debug!("failed to set dscp {dscp} on the socket: {err}");
+    }
+    // release the handshake state if the future is dropped before connected
+    let guard = CancelGuard::new(config.client_role());
+    let local_addr = socket.local_addr()?;
+    let socket = Arc::new(socket);
+    // the socket is not connected, race the first addresses of the same family as it, or of the
+    // other family if there is none
+    let mut addrs: Vec<_> = addrs.to_socket_addrs()?.collect();
+    addrs.sort_by_key(|addr| addr.is_ipv4() != local_addr.is_ipv4());
+    let Some(family) = addrs.first().map(SocketAddr::is_ipv4) else {
+        return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "invalid address").into());
+    };
+    addrs.retain(|addr| addr.is_ipv4() == family);
+    addrs.truncate(MAX_RACING_ADDRS);
+
+    let handshake = |addrs| {
+        OfflineHandler::new(
+            Framed::new(Arc::clone(&socket), config.mtu as usize),
+            addrs,
+            config.offline_config(),
+        )
+    };
+    let (mut incoming, addr, mtu, server_guid) = match handshake(addrs.clone()).await {
+        Err(Error::AlreadyConnected) if config.recover_already_connected => {
+            let role = config.client_role();
+            debug!("[{role}] already connected, disconnect the lingering session and retry");
+            notify_disconnect(&socket, &addrs, config.mtu).await?;
+            // give the server a moment to close the session
+            let deadline = Instant::now() + config.connect_retry_backoff;
+            future::poll_fn(|cx| role.reactor().poll_deadline(role.guid(), deadline, cx)).await;
+            handshake(addrs).await?
+        }
+        res => res?,
+    };
+
+    let link = TransferLink::new_arc(config.client_role());
+    link.set_peer_guid(server_guid);
+    link.set_peer_addr(addr);
+    link.set_negotiated_mtu(mtu);
+    if let Some(timeout) = config.stall_timeout {
+        link.enable_watchdog(timeout, config.stall_close_grace);
+    }
+    if let Some(timeout) = config.idle_timeout {
+        link.enable_keepalive(timeout);
+    }
+    if let Some(size) = config.max_datagram_size {
+        link.cap_datagram_size(size);
+    }
+    if let Some(delay) = config.ack_delay {
+        link.enable_ack_delay(delay, config.ack_threshold);
+    }
+    if let Some((frame_sets, delay)) = config.nack_reorder_window {
+        link.enable_reorder_window(frame_sets, delay);
+    }
+    if let Some(sender) = config.delivery_receipts.clone() {
+        link.enable_receipts(sender);
+    }
+    if config.propagate_trace_id {
+        link.enable_trace_id_propagation();
+    }
+    if !config.mtu_ladder.is_empty() {
+        link.enable_mtu_ladder(config.mtu_ladder.clone());
+    }
+    #[cfg(feature = "compression")]
+    if let Some(threshold) = config.compression_threshold {
+        link.enable_compression(threshold);
+    }
+    #[cfg(feature = "encryption")]
+    if let Some(encryption) = &config.encryption {
+        link.enable_encryption(Arc::clone(&encryption.0));
+    }
+    #[cfg(feature = "metrics")]
+    link.enable_metrics(
+        config
+            .metrics_id
+            .clone()
+            .unwrap_or_else(|| addr.to_string()),
+    );
+    #[cfg(feature = "chaos")]
+    let dst_socket = ChaosSocket::new(Arc::clone(&socket), config.chaos);
+    #[cfg(not(feature = "chaos"))]
+    let dst_socket = Arc::clone(&socket);
+    let dst = Framed::new(dst_socket, config.mtu as usize)
+        .handle_outgoing(
+            Arc::clone(&link),
+            config.send_buf_cap,
+            config.max_inflight_messages,
+            ResendMap::new(config.client_role())
+                .with_rto_bounds(config.min_rto, config.max_rto)
+                .with_rto_loss_scale(config.rto_loss_scale)
+                .with_max_retries(config.max_retries)
+                .with_fast_retransmit_threshold(config.fast_retransmit_threshold)
+                .with_congestion_control(config.congestion_control),
+            PeerContext {
+                addr,
+                mtu,
+                guid: Some(server_guid),
+            },
+            config.client_role(),
+        )
+        .with_coalesce(config.coalesce)
+        .frame_encoded(mtu, config.codec_config(), Arc::clone(&link))
+        .manage_outgoing_state(None, Arc::clone(&link));
+
+    let (mut router, route) = Router::new(Arc::clone(&link));
+
+    S::Runtime::spawn(async move {
+        while let Some(pack) = incoming.next().await {
+            if !router.deliver(pack) {
+                // the connection was dropped, release the socket
+                break;
+            }
+        }
+    });
+
+    let src = route
+        .frame_decoded(
+            config.codec_config(),
+            Arc::clone(&link),
+            config.client_role(),
+        )
+        .manage_incoming_state(Arc::clone(&link))
+        .handle_online(
+            addr,
+            local_addr,
+            config.client_guid,
+            config.online_handshake_timeout,
+            Arc::clone(&link),
+        )
+        .enter_on_item(Span::noop);
+
+    guard.disarm();
+    Ok(SeparatedIO::new(src, dst, link, socket)
+        .with_channel_reliabilities(config.channel_reliabilities.clone())
+        .with_first_msg(first_msg)
+        .with_auto_flush(config.auto_flush_interval)
+        .with_close_timeout(config.close_timeout))
+}
+
+/// Send an unreliable `DisconnectNotification` to the addresses, it closes the lingering session
+/// of this address on the server regardless of its sequence numbers
+async fn notify_disconnect<S: AsyncUdpSocket>(
+    socket: &Arc<S>,
+    addrs: &[SocketAddr],
+    mtu: u16,
+) -> Result<(), Error> {
+    let mut frame = pin!(Framed::new(Arc::clone(socket), mtu as usize));
+    let frames = [Frame {
+        flags: Flags::new(Reliability::Unreliable, false),
+        reliable_frame_index: None,
+        seq_frame_index: None,
+        ordered: None,
+        fragment: None,
+        body: Bytes::from_static(&[PackType::DisconnectNotification as u8]),
+    }];
+    for &addr in addrs {
+        let frame_set = FrameSet {
+            seq_num: 0.into(),
+            set: &frames[..],
+        };
+        frame
+            .send((
+                Packet::Connected(connected::Packet::FrameSet(frame_set)),
+                addr,
+            ))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Cancel the timers registered by the handshake when the connecting future is dropped or
+/// failed.
+struct CancelGuard {
+    role: RoleContext,
+    armed: bool,
+}
+
+impl CancelGuard {
+    fn new(role: RoleContext) -> Self {
+        Self { role, armed: true }
+    }
+
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if self.armed {
+            // the guid is shared by the connections of the same config, wake up the timers of the
+            // others to register them again
+            self.role
+                .reactor()
+                .cancel_all_timers(self.role.guid())
+                .for_each(Waker::wake);
+        }
+    }
+}
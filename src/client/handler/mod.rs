@@ -1,2 +1,3 @@
 pub(crate) mod offline;
 pub(crate) mod online;
+pub(crate) mod ping;
@@ -1,30 +1,71 @@
+use std::collections::VecDeque;
+use std::fmt;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
 
 use futures::{Future, Sink, SinkExt, Stream, StreamExt};
 use log::debug;
 use pin_project_lite::pin_project;
 
-use crate::errors::{CodecError, Error};
+use crate::errors::{CodecError, Error, HandshakePhase};
 use crate::packet::connected::{self, FramesMut};
 use crate::packet::{unconnected, Packet};
+use crate::utils::{timestamp, Reactor};
 use crate::RoleContext;
 
-#[derive(Debug, Clone, Copy)]
+/// The verifier of the advertisement in the pong of the server
+#[derive(Clone)]
+pub(crate) struct Verifier(pub(crate) Arc<dyn Fn(&[u8]) -> bool + Send + Sync>);
+
+impl Verifier {
+    fn verify(&self, advertisement: &[u8]) -> bool {
+        (self.0)(advertisement)
+    }
+}
+
+impl fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Verifier")
+    }
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct Config {
     pub(crate) mtu: u16,
     pub(crate) client_guid: u64,
     pub(crate) protocol_version: u8,
+    pub(crate) version_fallback: Vec<u8>,
+    pub(crate) verify_advertisement: Option<Verifier>,
+    // the lower mtus probed one by one, empty means the path mtu discovery is disabled
+    pub(crate) mtu_ladder: Vec<u16>,
+    pub(crate) mtu_probe_timeout: Duration,
+    // the open connection requests are resent if not replied, zero means waiting forever
+    pub(crate) connect_retries: u32,
+    pub(crate) connect_retry_backoff: Duration,
+    pub(crate) reactor: &'static Reactor,
 }
 
 pin_project! {
     pub(crate) struct OfflineHandler<F> {
         frame: Option<F>,
         state: State,
-        server_addr: SocketAddr,
+        // the addresses of the server raced until one of them replies, only the winner is kept
+        server_addrs: Vec<SocketAddr>,
+        // the count of the addresses the packet being sent is sent to
+        sent: usize,
         config: Config,
         role: RoleContext,
+        // the mtu being probed and the lower ones to step down through
+        mtu: u16,
+        probes: VecDeque<u16>,
+        // the deadline of waiting for the reply of the mtu being probed or the request resent
+        deadline: Option<Instant>,
+        // the resends left and the backoff of the request being sent
+        retries: u32,
+        backoff: Duration,
     }
 }
 
@@ -34,30 +75,59 @@ where
         + Sink<(unconnected::Packet, SocketAddr), Error = CodecError>
         + Unpin,
 {
-    pub(crate) fn new(frame: F, server_addr: SocketAddr, config: Config) -> Self {
-        Self {
-            frame: Some(frame),
-            state: State::SendOpenConnReq1(unconnected::Packet::OpenConnectionRequest1 {
+    pub(crate) fn new(frame: F, server_addrs: Vec<SocketAddr>, config: Config) -> Self {
+        debug_assert!(!server_addrs.is_empty(), "no address of the server");
+        let state = if config.verify_advertisement.is_some() {
+            // ping the server first to verify its advertisement
+            State::SendUnconnectedPing(unconnected::Packet::UnconnectedPing {
+                send_timestamp: timestamp(),
+                magic: (),
+                client_guid: config.client_guid,
+            })
+        } else {
+            State::SendOpenConnReq1(unconnected::Packet::OpenConnectionRequest1 {
                 magic: (),
                 protocol_version: config.protocol_version,
                 mtu: config.mtu,
-            }),
-            server_addr,
+            })
+        };
+        let mut probes: Vec<_> = config
+            .mtu_ladder
+            .iter()
+            .copied()
+            .filter(|&mtu| mtu < config.mtu)
+            .collect();
+        probes.sort_unstable_by(|a, b| b.cmp(a));
+        probes.dedup();
+        Self {
+            frame: Some(frame),
+            state,
+            server_addrs,
+            sent: 0,
             role: RoleContext::Client {
                 guid: config.client_guid,
+                reactor: config.reactor,
             },
+            mtu: config.mtu,
+            probes: probes.into(),
+            deadline: None,
+            retries: config.connect_retries,
+            backoff: config.connect_retry_backoff,
             config,
         }
     }
 }
 
 enum State {
+    SendUnconnectedPing(unconnected::Packet),
+    SendUnconnectedPingFlush,
+    WaitUnconnectedPong,
     SendOpenConnReq1(unconnected::Packet),
     SendOpenConnReq1Flush,
     WaitOpenConnReply1,
     SendOpenConnReq2(unconnected::Packet),
-    SendOpenConnReq2Flush,
-    WaitOpenConnReply2,
+    SendOpenConnReq2Flush(unconnected::Packet),
+    WaitOpenConnReply2(unconnected::Packet),
 }
 
 impl<F> Future for OfflineHandler<F>
@@ -66,28 +136,79 @@ where
         + Sink<(unconnected::Packet, SocketAddr), Error = CodecError>
         + Unpin,
 {
-    type Output = Result<impl Stream<Item = connected::Packet<FramesMut>>, Error>;
+    /// The connected packets from the server, the address of the server replied, the mtu
+    /// negotiated in the handshake and the guid of the server
+    type Output = Result<
+        (
+            impl Stream<Item = connected::Packet<FramesMut>>,
+            SocketAddr,
+            u16,
+            u64,
+        ),
+        Error,
+    >;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
         let frame = this.frame.as_mut().unwrap();
         loop {
             match this.state {
-                State::SendOpenConnReq1(pack) => {
-                    if let Err(err) = ready!(frame.poll_ready_unpin(cx)) {
+                State::SendUnconnectedPing(pack) => {
+                    ready!(poll_send_each(
+                        frame,
+                        pack,
+                        this.server_addrs,
+                        this.sent,
+                        *this.role,
+                        cx
+                    ));
+                    *this.state = State::SendUnconnectedPingFlush;
+                }
+                State::SendUnconnectedPingFlush => {
+                    if let Err(err) = ready!(frame.poll_flush_unpin(cx)) {
                         debug!(
-                            "[{}] SendingOpenConnectionRequest1 poll_ready error: {err}, retrying",
+                            "[{}] SendingUnconnectedPing poll_flush error: {err}, retrying",
                             this.role
                         );
                         continue;
                     }
-                    if let Err(err) = frame.start_send_unpin((pack.clone(), *this.server_addr)) {
-                        debug!(
-                            "[{}] SendingOpenConnectionRequest1 start_send error: {err}, retrying",
-                            this.role
-                        );
+                    *this.state = State::WaitUnconnectedPong;
+                }
+                State::WaitUnconnectedPong => {
+                    let Some((pack, addr)) = ready!(frame.poll_next_unpin(cx)) else {
+                        return Poll::Ready(Err(Error::ConnectionClosed));
+                    };
+                    if !this.server_addrs.contains(&addr) {
                         continue;
                     }
+                    let Packet::Unconnected(unconnected::Packet::UnconnectedPong { data, .. }) =
+                        pack
+                    else {
+                        continue;
+                    };
+                    // the first address answering wins the race
+                    this.server_addrs.retain(|&server_addr| server_addr == addr);
+                    let verifier = this.config.verify_advertisement.as_ref().unwrap();
+                    if !verifier.verify(&data) {
+                        debug!("[{}] the advertisement of the server is rejected", this.role);
+                        return Poll::Ready(Err(Error::AdvertisementRejected));
+                    }
+                    *this.state =
+                        State::SendOpenConnReq1(unconnected::Packet::OpenConnectionRequest1 {
+                            magic: (),
+                            protocol_version: this.config.protocol_version,
+                            mtu: *this.mtu,
+                        });
+                }
+                State::SendOpenConnReq1(pack) => {
+                    ready!(poll_send_each(
+                        frame,
+                        pack,
+                        this.server_addrs,
+                        this.sent,
+                        *this.role,
+                        cx
+                    ));
                     *this.state = State::SendOpenConnReq1Flush;
                 }
                 State::SendOpenConnReq1Flush => {
@@ -98,28 +219,129 @@ where
                         );
                         continue;
                     }
+                    // the probes step down first, the last mtu is resent with the backoff
+                    let timeout = if !this.probes.is_empty() {
+                        Some(this.config.mtu_probe_timeout)
+                    } else if this.config.connect_retries > 0 {
+                        Some(*this.backoff)
+                    } else if !this.config.mtu_ladder.is_empty() {
+                        Some(this.config.mtu_probe_timeout)
+                    } else {
+                        None
+                    };
+                    *this.deadline = timeout.map(|timeout| Instant::now() + timeout);
                     *this.state = State::WaitOpenConnReply1;
                 }
                 State::WaitOpenConnReply1 => {
-                    // TODO: Add timeout
+                    if let Some(deadline) = *this.deadline
+                        && this
+                            .role
+                            .reactor()
+                            .poll_deadline(this.config.client_guid, deadline, cx)
+                            .is_ready()
+                    {
+                        // the request padded to the mtu may be dropped by the path
+                        if let Some(mtu) = this.probes.pop_front() {
+                            debug!(
+                                "[{}] no reply to the mtu {}, step down to {mtu}",
+                                this.role, *this.mtu
+                            );
+                            *this.mtu = mtu;
+                        } else if *this.retries > 0 {
+                            debug!(
+                                "[{}] no reply to OpenConnectionRequest1 in {:?}, resending",
+                                this.role, *this.backoff
+                            );
+                            *this.retries -= 1;
+                            *this.backoff = this.backoff.saturating_mul(2);
+                        } else {
+                            return Poll::Ready(Err(Error::HandshakeTimeout {
+                                phase: HandshakePhase::Offline,
+                            }));
+                        }
+                        *this.state =
+                            State::SendOpenConnReq1(unconnected::Packet::OpenConnectionRequest1 {
+                                magic: (),
+                                protocol_version: this.config.protocol_version,
+                                mtu: *this.mtu,
+                            });
+                        continue;
+                    }
                     let Some((pack, addr)) = ready!(frame.poll_next_unpin(cx)) else {
                         return Poll::Ready(Err(Error::ConnectionClosed));
                     };
-                    if addr != *this.server_addr {
+                    if !this.server_addrs.contains(&addr) {
                         continue;
                     }
                     let next = match pack {
                         Packet::Unconnected(unconnected::Packet::OpenConnectionReply1 {
+                            cookie,
                             mtu,
                             ..
                         }) => unconnected::Packet::OpenConnectionRequest2 {
                             magic: (),
-                            server_address: *this.server_addr,
+                            // echo the cookie back if the server requires it
+                            cookie,
+                            server_address: addr,
                             mtu,
                             client_guid: this.config.client_guid,
                         },
+                        Packet::Unconnected(unconnected::Packet::IpRecentlyConnected {
+                            retry_after_ms,
+                            ..
+                        }) => {
+                            return Poll::Ready(Err(Error::RecentlyConnected {
+                                retry_after: retry_after_ms
+                                    .map(|ms| Duration::from_millis(u64::from(ms))),
+                            }));
+                        }
+                        Packet::Unconnected(unconnected::Packet::ConnectionBanned { .. }) => {
+                            return Poll::Ready(Err(Error::ConnectionBanned));
+                        }
+                        Packet::Unconnected(unconnected::Packet::AlreadyConnected { .. }) => {
+                            return Poll::Ready(Err(Error::AlreadyConnected));
+                        }
+                        Packet::Unconnected(unconnected::Packet::IncompatibleProtocol {
+                            server_protocol,
+                            ..
+                        }) => {
+                            if server_protocol == this.config.protocol_version
+                                || !this.config.version_fallback.contains(&server_protocol)
+                            {
+                                return Poll::Ready(Err(Error::IncompatibleProtocol {
+                                    server_protocol,
+                                }));
+                            }
+                            debug!(
+                                "[{}] retry with the protocol version {server_protocol} of server",
+                                this.role
+                            );
+                            this.config.protocol_version = server_protocol;
+                            *this.state = State::SendOpenConnReq1(
+                                unconnected::Packet::OpenConnectionRequest1 {
+                                    magic: (),
+                                    protocol_version: server_protocol,
+                                    mtu: *this.mtu,
+                                },
+                            );
+                            continue;
+                        }
                         _ => continue,
                     };
+                    if this.server_addrs.len() > 1 {
+                        debug!("[{}] {addr} won the race of the addresses", this.role);
+                        this.server_addrs.retain(|&server_addr| server_addr == addr);
+                    }
+                    if this.deadline.take().is_some() {
+                        // the mtu is discovered, nobody awaits the deadline any more
+                        this.role
+                            .reactor()
+                            .cancel_all_timers(this.config.client_guid)
+                            .for_each(drop);
+                    }
+                    // the second request is resent as many times as the first one
+                    *this.retries = this.config.connect_retries;
+                    *this.backoff = this.config.connect_retry_backoff;
                     *this.state = State::SendOpenConnReq2(next);
                 }
                 State::SendOpenConnReq2(pack) => {
@@ -130,16 +352,16 @@ where
                         );
                         continue;
                     }
-                    if let Err(err) = frame.start_send_unpin((pack.clone(), *this.server_addr)) {
+                    if let Err(err) = frame.start_send_unpin((pack.clone(), this.server_addrs[0])) {
                         debug!(
                             "[{}] SendOpenConnectionRequest2 start_send error: {err}, retrying",
                             this.role
                         );
                         continue;
                     }
-                    *this.state = State::SendOpenConnReq2Flush;
+                    *this.state = State::SendOpenConnReq2Flush(pack.clone());
                 }
-                State::SendOpenConnReq2Flush => {
+                State::SendOpenConnReq2Flush(pack) => {
                     if let Err(err) = ready!(frame.poll_flush_unpin(cx)) {
                         debug!(
                             "[{}] SendOpenConnectionRequest2 poll_flush error: {err}, retrying",
@@ -147,32 +369,115 @@ where
                         );
                         continue;
                     }
-                    *this.state = State::WaitOpenConnReply2;
+                    if this.config.connect_retries > 0 {
+                        *this.deadline = Some(Instant::now() + *this.backoff);
+                    }
+                    *this.state = State::WaitOpenConnReply2(pack.clone());
                 }
-                State::WaitOpenConnReply2 => {
-                    // TODO: Add timeout
+                State::WaitOpenConnReply2(pack) => {
+                    if let Some(deadline) = *this.deadline
+                        && this
+                            .role
+                            .reactor()
+                            .poll_deadline(this.config.client_guid, deadline, cx)
+                            .is_ready()
+                    {
+                        if *this.retries == 0 {
+                            return Poll::Ready(Err(Error::HandshakeTimeout {
+                                phase: HandshakePhase::Offline,
+                            }));
+                        }
+                        debug!(
+                            "[{}] no reply to OpenConnectionRequest2 in {:?}, resending",
+                            this.role, *this.backoff
+                        );
+                        *this.retries -= 1;
+                        *this.backoff = this.backoff.saturating_mul(2);
+                        *this.state = State::SendOpenConnReq2(pack.clone());
+                        continue;
+                    }
                     let Some((pack, addr)) = ready!(frame.poll_next_unpin(cx)) else {
                         return Poll::Ready(Err(Error::ConnectionClosed));
                     };
-                    if addr != *this.server_addr {
+                    if !this.server_addrs.contains(&addr) {
                         continue;
                     }
-                    match pack {
+                    let (mtu, server_guid) = match pack {
                         Packet::Unconnected(unconnected::Packet::OpenConnectionReply2 {
+                            mtu,
+                            server_guid,
+                            ..
+                        }) => (mtu, server_guid),
+                        Packet::Unconnected(unconnected::Packet::NoFreeIncomingConnections {
                             ..
-                        }) => {}
+                        }) => {
+                            return Poll::Ready(Err(Error::NoFreeIncomingConnections));
+                        }
+                        Packet::Unconnected(unconnected::Packet::ConnectionBanned { .. }) => {
+                            return Poll::Ready(Err(Error::ConnectionBanned));
+                        }
+                        Packet::Unconnected(unconnected::Packet::AlreadyConnected { .. }) => {
+                            return Poll::Ready(Err(Error::AlreadyConnected));
+                        }
                         _ => continue,
                     };
-                    return Poll::Ready(Ok(FilterConnected {
-                        frame: this.frame.take().unwrap(),
-                        server_addr: *this.server_addr,
-                    }));
+                    if this.deadline.take().is_some() {
+                        this.role
+                            .reactor()
+                            .cancel_all_timers(this.config.client_guid)
+                            .for_each(drop);
+                    }
+                    // the server may lower the mtu, never exceed the requested one
+                    let mtu = mtu.min(*this.mtu);
+                    debug!("[{}] negotiated mtu {mtu} with the server", this.role);
+                    return Poll::Ready(Ok((
+                        FilterConnected {
+                            frame: this.frame.take().unwrap(),
+                            server_addr: addr,
+                        },
+                        addr,
+                        mtu,
+                        server_guid,
+                    )));
                 }
             }
         }
     }
 }
 
+/// Send the packet to the addresses from the `sent`-th one, the errors are logged and retried
+fn poll_send_each<F>(
+    frame: &mut F,
+    pack: &unconnected::Packet,
+    addrs: &[SocketAddr],
+    sent: &mut usize,
+    role: RoleContext,
+    cx: &mut Context<'_>,
+) -> Poll<()>
+where
+    F: Sink<(unconnected::Packet, SocketAddr), Error = CodecError> + Unpin,
+{
+    while let Some(&addr) = addrs.get(*sent) {
+        if let Err(err) = ready!(frame.poll_ready_unpin(cx)) {
+            debug!(
+                "[{role}] sending {:?} poll_ready error: {err}, retrying",
+                pack.pack_type()
+            );
+            continue;
+        }
+        if let Err(err) = frame.start_send_unpin((pack.clone(), addr)) {
+            debug!(
+                "[{role}] sending {:?} start_send error: {err}, retrying",
+                pack.pack_type()
+            );
+            continue;
+        }
+        *sent += 1;
+    }
+    *sent = 0;
+    Poll::Ready(())
+}
+
 pin_project! {
     struct FilterConnected<F> {
         frame: F,
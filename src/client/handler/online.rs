@@ -1,14 +1,19 @@
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 use futures::Stream;
 use log::debug;
+use minitrace::collector::TraceId;
 use pin_project_lite::pin_project;
 
-use crate::link::SharedLink;
-use crate::packet::connected::FrameBody;
+use crate::errors::{Error, HandshakePhase};
+use crate::io::TraceInfo;
+use crate::link::{Anomaly, SharedLink};
+use crate::packet::connected::{self, FrameBody};
+use crate::packet::MIN_MTU;
 use crate::utils::timestamp;
 use crate::RoleContext;
 
@@ -16,7 +21,9 @@ pub(crate) trait HandleOnline: Sized {
     fn handle_online(
         self,
         addr: SocketAddr,
+        local_addr: SocketAddr,
         client_guid: u64,
+        timeout: Option<Duration>,
         link: SharedLink,
     ) -> OnlineHandler<Self>;
 }
@@ -28,20 +35,26 @@ where
     fn handle_online(
         self,
         addr: SocketAddr,
+        local_addr: SocketAddr,
         client_guid: u64,
+        timeout: Option<Duration>,
         link: SharedLink,
     ) -> OnlineHandler<Self> {
         link.send_frame_body(FrameBody::ConnectionRequest {
             client_guid,
             request_timestamp: timestamp(),
-            use_encryption: false,
+            use_encryption: link.encryption_enabled(),
+            capabilities: link.capabilities(),
+            key: link.start_key_exchange(),
         });
         OnlineHandler {
             frame: self,
             state: State::WaitConnRes,
             addr,
+            local_addr,
+            deadline: timeout.map(|dur| Instant::now() + dur),
+            role: link.role(),
             link,
-            role: RoleContext::Client { guid: client_guid },
         }
     }
 }
@@ -52,6 +65,8 @@ pin_project! {
         frame: F,
         state: State,
         addr: SocketAddr,
+        local_addr: SocketAddr,
+        deadline: Option<Instant>,
         link: SharedLink,
         role: RoleContext,
     }
@@ -60,6 +75,15 @@ pin_project! {
 enum State {
     WaitConnRes,
     Connected,
+    Failed,
+}
+
+impl<F> TraceInfo for OnlineHandler<F> {
+    fn last_trace_id(&self) -> Option<TraceId> {
+        self.link
+            .received_trace_id()
+            .map(|trace_id| TraceId(u128::from(trace_id)))
+    }
 }
 
 impl<F> Stream for OnlineHandler<F>
@@ -73,21 +97,51 @@ where
         loop {
             match this.state {
                 State::WaitConnRes => {
-                    let Some(body) = ready!(this.frame.as_mut().poll_next(cx)) else {
-                        return Poll::Ready(None);
+                    let body = match this.frame.as_mut().poll_next(cx) {
+                        Poll::Ready(Some(body)) => body,
+                        Poll::Ready(None) => return Poll::Ready(None),
+                        Poll::Pending => {
+                            let Some(deadline) = *this.deadline else {
+                                return Poll::Pending;
+                            };
+                            ready!(this.role.reactor().poll_deadline(
+                                this.role.guid(),
+                                deadline,
+                                cx
+                            ));
+                            this.link.fail(Error::HandshakeTimeout {
+                                phase: HandshakePhase::Online,
+                            });
+                            *this.state = State::Failed;
+                            continue;
+                        }
                     };
                     if let FrameBody::ConnectionRequestAccepted {
-                        system_addresses,
                         accepted_timestamp,
+                        capabilities,
+                        key,
                         ..
                     } = body
                     {
+                        if !this.link.establish_session(&key) {
+                            debug!(
+                                "[{}] server {addr:?} rejected the key exchange",
+                                this.role,
+                                addr = this.addr
+                            );
+                            this.link.fail(Error::ConnectionRequestFailed);
+                            *this.state = State::Failed;
+                            continue;
+                        }
+                        this.link.negotiate(capabilities);
+                        // tell the server our internal addresses
                         this.link.send_frame_body(FrameBody::NewIncomingConnection {
                             server_address: *this.addr,
-                            system_addresses,
+                            system_addresses: connected::system_addresses(*this.local_addr),
                             request_timestamp: timestamp(),
                             accepted_timestamp,
                         });
+                        this.link.mark_connected();
                         *this.state = State::Connected;
                         debug!(
                             "[{}] connected to server {addr:?}",
@@ -96,11 +150,28 @@ where
                         );
                         continue;
                     }
+                    if matches!(body, FrameBody::ConnectionRequestFailed) {
+                        debug!(
+                            "[{}] server {addr:?} rejected the connection request",
+                            this.role,
+                            addr = this.addr
+                        );
+                        this.link.fail(Error::ConnectionRequestFailed);
+                        *this.state = State::Failed;
+                        continue;
+                    }
+                    this.link.record_anomaly(Anomaly::UnexpectedPacket);
                     debug!("[{}] ignore packet {body:?} on WaitConnRes", this.role);
                 }
                 State::Connected => {
-                    let Some(body) = ready!(this.frame.as_mut().poll_next(cx)) else {
-                        return Poll::Ready(None);
+                    let body = match this.frame.as_mut().poll_next(cx) {
+                        Poll::Ready(Some(body)) => body,
+                        Poll::Ready(None) => return Poll::Ready(None),
+                        Poll::Pending => {
+                            ready!(this.link.poll_keepalive(cx));
+                            *this.state = State::Failed;
+                            continue;
+                        }
                     };
                     match body {
                         FrameBody::DetectLostConnections => {
@@ -108,12 +179,30 @@ where
                                 client_timestamp: timestamp(),
                             });
                         }
+                        FrameBody::ConnectedPing { client_timestamp } => {
+                            // the keepalive ping of the server
+                            this.link.send_frame_body(FrameBody::ConnectedPong {
+                                client_timestamp,
+                                server_timestamp: timestamp(),
+                            });
+                        }
+                        FrameBody::ConnectedPong {
+                            client_timestamp, ..
+                        } => {
+                            // the response of the ping
+                            this.link.receive_pong(client_timestamp);
+                        }
+                        FrameBody::MtuUpdate { mtu } if mtu >= MIN_MTU => {
+                            this.link.update_mtu(mtu);
+                        }
                         FrameBody::User(data) => return Poll::Ready(Some(data)),
                         _ => {
+                            this.link.record_anomaly(Anomaly::UnexpectedPacket);
                             debug!("[{}] ignore packet {body:?} on Connected", this.role);
                         }
                     }
                 }
+                State::Failed => return Poll::Ready(None),
             }
         }
     }
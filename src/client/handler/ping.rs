@@ -0,0 +1,121 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+
+use bytes::Bytes;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+
+use crate::errors::{CodecError, Error};
+use crate::packet::connected::FramesMut;
+use crate::packet::{unconnected, Packet};
+use crate::utils::{timestamp, Reactor};
+
+/// Send an `UnconnectedPing` to the target and yield the address, the guid and the advertisement
+/// of the servers answering it until the deadline. The target can be a broadcast address, all
+/// servers answering it are yielded.
+pub(crate) struct Pongs<F> {
+    frame: F,
+    target: SocketAddr,
+    state: State,
+    send_timestamp: i64,
+    // the random guid of the ping, the timer is registered with it
+    guid: u64,
+    deadline: Instant,
+}
+
+enum State {
+    SendPing,
+    SendPingFlush,
+    WaitPong,
+    Done,
+}
+
+impl<F> Pongs<F>
+where
+    F: Stream<Item = (Packet<FramesMut>, SocketAddr)>
+        + Sink<(unconnected::Packet, SocketAddr), Error = CodecError>
+        + Unpin,
+{
+    pub(crate) fn new(frame: F, target: SocketAddr, deadline: Instant) -> Self {
+        Self {
+            frame,
+            target,
+            state: State::SendPing,
+            send_timestamp: timestamp(),
+            guid: rand::random(),
+            deadline,
+        }
+    }
+}
+
+impl<F> Stream for Pongs<F>
+where
+    F: Stream<Item = (Packet<FramesMut>, SocketAddr)>
+        + Sink<(unconnected::Packet, SocketAddr), Error = CodecError>
+        + Unpin,
+{
+    type Item = Result<(SocketAddr, u64, Bytes), Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.state {
+                State::SendPing => {
+                    if let Err(err) = ready!(this.frame.poll_ready_unpin(cx)).and_then(|_| {
+                        this.frame.start_send_unpin((
+                            unconnected::Packet::UnconnectedPing {
+                                send_timestamp: this.send_timestamp,
+                                magic: (),
+                                client_guid: this.guid,
+                            },
+                            this.target,
+                        ))
+                    }) {
+                        this.state = State::Done;
+                        return Poll::Ready(Some(Err(err.into())));
+                    }
+                    this.state = State::SendPingFlush;
+                }
+                State::SendPingFlush => {
+                    if let Err(err) = ready!(this.frame.poll_flush_unpin(cx)) {
+                        this.state = State::Done;
+                        return Poll::Ready(Some(Err(err.into())));
+                    }
+                    this.state = State::WaitPong;
+                }
+                State::WaitPong => {
+                    if Reactor::get()
+                        .poll_deadline(this.guid, this.deadline, cx)
+                        .is_ready()
+                    {
+                        this.state = State::Done;
+                        return Poll::Ready(None);
+                    }
+                    let Some((pack, addr)) = ready!(this.frame.poll_next_unpin(cx)) else {
+                        this.state = State::Done;
+                        return Poll::Ready(None);
+                    };
+                    if let Packet::Unconnected(unconnected::Packet::UnconnectedPong {
+                        send_timestamp,
+                        server_guid,
+                        data,
+                        ..
+                    }) = pack
+                        && send_timestamp == this.send_timestamp
+                    {
+                        return Poll::Ready(Some(Ok((addr, server_guid, data))));
+                    }
+                }
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<F> Drop for Pongs<F> {
+    fn drop(&mut self) {
+        // nobody awaits the deadline any more
+        Reactor::get().cancel_all_timers(self.guid).for_each(drop);
+    }
+}
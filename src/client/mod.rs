@@ -1,6 +1,6 @@
 //! This is a basic client implementation, without any retry, do NOT use it directly
 
 mod conn;
-mod handler;
+pub(crate) mod handler;
 
 pub use self::conn::*;
@@ -0,0 +1,245 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::future::poll_fn;
+use log::trace;
+use rand::Rng;
+
+use super::AsyncSocket;
+use crate::runtime::AsyncUdpSocket;
+
+/// Chaos config, injecting faults into the outgoing connected datagrams of the real UDP path for
+/// the resilience testing. Each datagram is dropped, duplicated or delayed at the configured
+/// rates independently.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Chaos {
+    drop_rate: f64,
+    duplicate_rate: f64,
+    delay_rate: f64,
+    delay: Duration,
+}
+
+impl Chaos {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the chaos config from the environment variables, `None` if none of them is set:
+    /// - `RAKNET_CHAOS_DROP`: the drop rate
+    /// - `RAKNET_CHAOS_DUPLICATE`: the duplicate rate
+    /// - `RAKNET_CHAOS_DELAY`: the delay rate
+    /// - `RAKNET_CHAOS_DELAY_MS`: the delay in milliseconds
+    ///
+    /// # Panics
+    /// Panics if any of them is malformed or out of range
+    pub fn from_env() -> Option<Self> {
+        fn var<T: std::str::FromStr>(key: &str) -> Option<T> {
+            let value = std::env::var(key).ok()?;
+            Some(
+                value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("malformed {key}: {value}")),
+            )
+        }
+
+        let drop_rate = var("RAKNET_CHAOS_DROP");
+        let duplicate_rate = var("RAKNET_CHAOS_DUPLICATE");
+        let delay_rate = var("RAKNET_CHAOS_DELAY");
+        let delay = var("RAKNET_CHAOS_DELAY_MS").map(Duration::from_millis);
+        if drop_rate.is_none() && duplicate_rate.is_none() && delay_rate.is_none() {
+            return None;
+        }
+        Some(
+            Self::new()
+                .drop_rate(drop_rate.unwrap_or_default())
+                .duplicate_rate(duplicate_rate.unwrap_or_default())
+                .delay(delay_rate.unwrap_or_default(), delay.unwrap_or_default()),
+        )
+    }
+
+    /// Set the fraction of the datagrams dropped silently
+    /// The default value is 0
+    /// # Panics
+    /// Panics if the rate is not in [0, 1]
+    pub fn drop_rate(mut self, rate: f64) -> Self {
+        assert!((0.0..=1.0).contains(&rate), "drop_rate should be in [0, 1]");
+        self.drop_rate = rate;
+        self
+    }
+
+    /// Set the fraction of the datagrams sent twice
+    /// The default value is 0
+    /// # Panics
+    /// Panics if the rate is not in [0, 1]
+    pub fn duplicate_rate(mut self, rate: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&rate),
+            "duplicate_rate should be in [0, 1]"
+        );
+        self.duplicate_rate = rate;
+        self
+    }
+
+    /// Set the fraction of the datagrams sent after the delay, they are likely reordered
+    /// The default value is 0
+    /// # Panics
+    /// Panics if the rate is not in [0, 1]
+    pub fn delay(mut self, rate: f64, delay: Duration) -> Self {
+        assert!((0.0..=1.0).contains(&rate), "delay rate should be in [0, 1]");
+        self.delay_rate = rate;
+        self.delay = delay;
+        self
+    }
+
+    fn is_noop(&self) -> bool {
+        self.drop_rate == 0.0 && self.duplicate_rate == 0.0 && self.delay_rate == 0.0
+    }
+}
+
+/// The socket injecting the chaos into the sending datagrams
+pub(crate) struct ChaosSocket<S> {
+    socket: Arc<S>,
+    chaos: Chaos,
+}
+
+impl<S> ChaosSocket<S> {
+    /// Wrap the socket, the config from the environment is used if the chaos is not given
+    pub(crate) fn new(socket: Arc<S>, chaos: Option<Chaos>) -> Self {
+        Self {
+            socket,
+            chaos: chaos.or_else(Chaos::from_env).unwrap_or_default(),
+        }
+    }
+}
+
+impl<S: AsyncUdpSocket> AsyncSocket for ChaosSocket<S> {
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut BytesMut,
+    ) -> Poll<io::Result<SocketAddr>> {
+        self.socket.poll_recv_from(cx, buf)
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        if self.chaos.is_noop() {
+            return self.socket.poll_send_to(cx, buf, target);
+        }
+        let mut rng = rand::thread_rng();
+        if rng.gen_bool(self.chaos.drop_rate) {
+            trace!("[chaos] drop a datagram of {} bytes to {target}", buf.len());
+            return Poll::Ready(Ok(buf.len()));
+        }
+        if rng.gen_bool(self.chaos.delay_rate) {
+            trace!("[chaos] delay a datagram of {} bytes to {target}", buf.len());
+            let socket = Arc::clone(&self.socket);
+            let delay = self.chaos.delay;
+            let datagram = buf.to_vec();
+            // the timer of tokio is used regardless of the runtime of the socket
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                poll_fn(|cx| socket.poll_send_to(cx, &datagram, target))
+                    .await
+                    .ok();
+            });
+            return Poll::Ready(Ok(buf.len()));
+        }
+        let sent = ready!(self.socket.poll_send_to(cx, buf, target))?;
+        if rng.gen_bool(self.chaos.duplicate_rate) {
+            trace!("[chaos] duplicate a datagram of {} bytes to {target}", buf.len());
+            // best effort, the duplicated one is dropped if the socket is busy
+            if let Poll::Ready(Err(err)) = self.socket.poll_send_to(cx, buf, target) {
+                trace!("[chaos] failed to duplicate the datagram: {err}");
+            }
+        }
+        Poll::Ready(Ok(sent))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::future::poll_fn;
+    use tokio::net::UdpSocket;
+
+    use super::{Chaos, ChaosSocket};
+    use crate::codec::AsyncSocket;
+
+    #[tokio::test]
+    async fn test_chaos_drop_rate() {
+        const TOTAL: usize = 1000;
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = receiver.local_addr().unwrap();
+        let socket = ChaosSocket::new(
+            Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+            Some(Chaos::new().drop_rate(0.3)),
+        );
+        let recv = tokio::spawn(async move {
+            let mut buf = [0; 16];
+            let mut received = 0;
+            while tokio::time::timeout(Duration::from_millis(500), receiver.recv(&mut buf))
+                .await
+                .is_ok()
+            {
+                received += 1;
+            }
+            received
+        });
+        for i in 0..TOTAL {
+            let buf = (i as u32).to_be_bytes();
+            let sent = poll_fn(|cx| socket.poll_send_to(cx, &buf, target))
+                .await
+                .unwrap();
+            assert_eq!(sent, buf.len());
+            if i % 16 == 0 {
+                // do not overwhelm the receive buffer
+                tokio::task::yield_now().await;
+            }
+        }
+        let received = recv.await.unwrap();
+        // about 700 datagrams are expected
+        assert!((600..=800).contains(&received), "received {received}");
+    }
+
+    #[tokio::test]
+    async fn test_chaos_duplicate_and_delay() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = receiver.local_addr().unwrap();
+        let socket = ChaosSocket::new(
+            Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+            Some(Chaos::new().duplicate_rate(1.0)),
+        );
+        poll_fn(|cx| socket.poll_send_to(cx, b"hello", target))
+            .await
+            .unwrap();
+        let mut buf = [0; 16];
+        for _ in 0..2 {
+            let len = receiver.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"hello");
+        }
+
+        let socket = ChaosSocket::new(
+            Arc::clone(&socket.socket),
+            Some(Chaos::new().delay(1.0, Duration::from_millis(100))),
+        );
+        let start = std::time::Instant::now();
+        poll_fn(|cx| socket.poll_send_to(cx, b"hello", target))
+            .await
+            .unwrap();
+        let len = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -7,25 +7,28 @@ use minitrace::Event;
 use pin_project_lite::pin_project;
 
 use crate::errors::CodecError;
-use crate::packet::connected::{Frame, FrameBody, FrameSet};
+use crate::link::SharedLink;
+use crate::packet::connected::{Frame, FrameBody, FrameSet, CAPABILITY_MTU_UPDATE, HEADER_ESCAPED};
+use crate::packet::PackType;
 
 pin_project! {
     pub(crate) struct BodyDecoder<F> {
         #[pin]
-        frame: F
+        frame: F,
+        link: SharedLink,
     }
 }
 
 pub(crate) trait BodyDecoded: Sized {
-    fn body_decoded(self) -> BodyDecoder<Self>;
+    fn body_decoded(self, link: SharedLink) -> BodyDecoder<Self>;
 }
 
 impl<F> BodyDecoded for F
 where
     F: Stream<Item = Result<FrameSet<Frame>, CodecError>>,
 {
-    fn body_decoded(self) -> BodyDecoder<Self> {
-        BodyDecoder { frame: self }
+    fn body_decoded(self, link: SharedLink) -> BodyDecoder<Self> {
+        BodyDecoder { frame: self, link }
     }
 }
 
@@ -45,8 +48,30 @@ where
         let span = LocalSpan::enter_with_local_parent("codec.body_decoder")
             .with_properties(|| [("frame_seq_num", frame_set.seq_num.to_string())]);
 
-        match FrameBody::read(frame_set.set.body) {
+        // the reliable ordered messages are routed to the views of their order channels
+        let channel = frame_set
+            .set
+            .ordered
+            .filter(|_| !frame_set.set.flags.reliability.is_sequenced())
+            .map(|ordered| ordered.channel);
+        // the original raknet uses the id of the mtu update for the user data
+        let body = frame_set.set.body;
+        let body = if body.first() == Some(&(PackType::MtuUpdate as u8)) {
+            if !this.link.capability_applies(CAPABILITY_MTU_UPDATE) {
+                Ok(FrameBody::User(body))
+            } else if body.get(1) == Some(&HEADER_ESCAPED) {
+                Ok(FrameBody::User(body.slice(2..)))
+            } else {
+                FrameBody::read(body)
+            }
+        } else {
+            FrameBody::read(body)
+        };
+        match body {
             Ok(body) => {
+                if matches!(body, FrameBody::User(_)) {
+                    this.link.receive_order_channel(channel);
+                }
                 let _ = span.with_property(|| ("frame_type", format!("{:?}", body)));
                 Poll::Ready(Some(Ok(body)))
             }
@@ -0,0 +1,174 @@
+use std::io::Read;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use flate2::read::ZlibDecoder;
+use futures::{ready, Stream, StreamExt};
+use pin_project_lite::pin_project;
+
+use crate::errors::CodecError;
+use crate::link::SharedLink;
+use crate::packet::connected::{FrameBody, CAPABILITY_COMPRESSION, HEADER_ESCAPED, HEADER_WRAPPED};
+use crate::packet::PackType;
+
+/// The maximum size of a decompressed frame body, avoiding the decompression bomb
+const MAX_DECOMPRESSED_SIZE: u64 = 16 * 1024 * 1024;
+
+pin_project! {
+    // Decompress layer, decompress the frame bodies compressed by the peer
+    pub(crate) struct Decompress<F> {
+        #[pin]
+        frame: F,
+        link: SharedLink,
+    }
+}
+
+pub(crate) trait Decompressed: Sized {
+    fn decompressed(self, link: SharedLink) -> Decompress<Self>;
+}
+
+impl<F> Decompressed for F
+where
+    F: Stream<Item = Result<FrameBody, CodecError>>,
+{
+    fn decompressed(self, link: SharedLink) -> Decompress<Self> {
+        Decompress { frame: self, link }
+    }
+}
+
+fn decompress(data: &[u8]) -> Result<Bytes, CodecError> {
+    let mut decompressed = Vec::with_capacity(data.len() * 2);
+    ZlibDecoder::new(data)
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|err| CodecError::Decompress(err.to_string()))?;
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(CodecError::Decompress(format!(
+            "decompressed size exceed {MAX_DECOMPRESSED_SIZE}"
+        )));
+    }
+    if decompressed.is_empty() {
+        return Err(CodecError::Decompress("empty frame body".to_string()));
+    }
+    Ok(Bytes::from(decompressed))
+}
+
+/// Unwrap the user data following the [`PackType::Compressed`] header
+fn unwrap_body(data: &Bytes) -> Result<Bytes, CodecError> {
+    match data.get(1) {
+        Some(&HEADER_WRAPPED) => decompress(&data[2..]),
+        Some(&HEADER_ESCAPED) if data.len() > 2 => Ok(data.slice(2..)),
+        _ => Err(CodecError::Decompress(format!(
+            "invalid compressed frame body of {} bytes",
+            data.len()
+        ))),
+    }
+}
+
+impl<F> Stream for Decompress<F>
+where
+    F: Stream<Item = Result<FrameBody, CodecError>>,
+{
+    type Item = Result<FrameBody, CodecError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        let Some(body) = ready!(this.frame.poll_next_unpin(cx)?) else {
+            return Poll::Ready(None);
+        };
+        // the compressed frame bodies are only recognized if the compression is negotiated or
+        // offered before the negotiation, they carry the user data only
+        match body {
+            FrameBody::User(data)
+                if data.first() == Some(&(PackType::Compressed as u8))
+                    && this.link.capability_applies(CAPABILITY_COMPRESSION) =>
+            {
+                Poll::Ready(Some(unwrap_body(&data).map(FrameBody::User)))
+            }
+            body => Poll::Ready(Some(Ok(body))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::link::TransferLink;
+    use crate::RoleContext;
+
+    fn compressed(data: &[u8]) -> Bytes {
+        let mut encoder = ZlibEncoder::new(
+            vec![PackType::Compressed as u8, HEADER_WRAPPED],
+            Compression::fast(),
+        );
+        encoder.write_all(data).unwrap();
+        Bytes::from(encoder.finish().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_decompress_works() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let data = Bytes::from(vec![0xfe; 1024]);
+        let bodies = vec![
+            FrameBody::User(compressed(&data)),
+            FrameBody::User(Bytes::from_static(&[0xfe, 1, 2, 3])),
+            FrameBody::User(Bytes::from_static(&[
+                PackType::Compressed as u8,
+                HEADER_ESCAPED,
+                PackType::Compressed as u8,
+                1,
+            ])),
+            FrameBody::User(Bytes::from_static(&[PackType::Compressed as u8, 0xff])),
+        ];
+
+        // not negotiated
+        let not_negotiated: Vec<_> = futures::stream::iter(bodies.clone())
+            .map(Ok)
+            .decompressed(Arc::clone(&link))
+            .collect()
+            .await;
+        assert_eq!(not_negotiated[0].as_ref().unwrap(), &bodies[0]);
+
+        link.enable_compression(64);
+        link.negotiate(CAPABILITY_COMPRESSION);
+        let res: Vec<_> = futures::stream::iter(bodies)
+            .map(Ok)
+            .decompressed(link)
+            .collect()
+            .await;
+        assert_eq!(res[0].as_ref().unwrap(), &FrameBody::User(data));
+        assert_eq!(
+            res[1].as_ref().unwrap(),
+            &FrameBody::User(Bytes::from_static(&[0xfe, 1, 2, 3]))
+        );
+        assert_eq!(
+            res[2].as_ref().unwrap(),
+            &FrameBody::User(Bytes::from_static(&[PackType::Compressed as u8, 1]))
+        );
+        assert!(matches!(
+            res[3].as_ref().unwrap_err(),
+            CodecError::Decompress(_)
+        ));
+    }
+
+    #[test]
+    fn test_decompress_bomb() {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::best());
+        for _ in 0..=MAX_DECOMPRESSED_SIZE / 1024 {
+            encoder.write_all(&[0; 1024]).unwrap();
+        }
+        let data = encoder.finish().unwrap();
+        assert!(matches!(
+            decompress(&data).unwrap_err(),
+            CodecError::Decompress(_)
+        ));
+    }
+}
@@ -0,0 +1,147 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::{ready, Stream, StreamExt};
+use pin_project_lite::pin_project;
+
+use crate::cipher::CipherSession;
+use crate::errors::CodecError;
+use crate::link::SharedLink;
+use crate::packet::connected::{FrameBody, HEADER_WRAPPED};
+use crate::packet::PackType;
+
+pin_project! {
+    // Decrypt layer, decrypt the frame bodies encrypted by the peer. The plaintext user data is
+    // rejected once the encryption is required.
+    pub(crate) struct Decrypt<F> {
+        #[pin]
+        frame: F,
+        link: SharedLink,
+    }
+}
+
+pub(crate) trait Decrypted: Sized {
+    fn decrypted(self, link: SharedLink) -> Decrypt<Self>;
+}
+
+impl<F> Decrypted for F
+where
+    F: Stream<Item = Result<FrameBody, CodecError>>,
+{
+    fn decrypted(self, link: SharedLink) -> Decrypt<Self> {
+        Decrypt { frame: self, link }
+    }
+}
+
+fn decrypt(session: Option<&dyn CipherSession>, data: &[u8]) -> Result<Bytes, CodecError> {
+    let Some(session) = session else {
+        return Err(CodecError::Decrypt(
+            "user data before the encryption is established".to_string(),
+        ));
+    };
+    let decrypted = session
+        .decrypt(data)
+        .ok_or_else(|| CodecError::Decrypt("not authentic".to_string()))?;
+    if decrypted.is_empty() {
+        return Err(CodecError::Decrypt("empty frame body".to_string()));
+    }
+    Ok(decrypted)
+}
+
+/// Unwrap the user data following the [`PackType::Encrypted`] header, the plaintext is never
+/// sent once the encryption is required
+fn unwrap_body(session: Option<&dyn CipherSession>, data: &Bytes) -> Result<Bytes, CodecError> {
+    match data.get(..2) {
+        Some(&[header, HEADER_WRAPPED]) if header == PackType::Encrypted as u8 => {
+            decrypt(session, &data[2..])
+        }
+        _ => Err(CodecError::Decrypt("plaintext user data".to_string())),
+    }
+}
+
+impl<F> Stream for Decrypt<F>
+where
+    F: Stream<Item = Result<FrameBody, CodecError>>,
+{
+    type Item = Result<FrameBody, CodecError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        let Some(body) = ready!(this.frame.poll_next_unpin(cx)?) else {
+            return Poll::Ready(None);
+        };
+        match body {
+            FrameBody::User(data) if this.link.encryption_enabled() => Poll::Ready(Some(
+                unwrap_body(this.link.cipher_session(), &data).map(FrameBody::User),
+            )),
+            body => Poll::Ready(Some(Ok(body))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::cipher::{XorCipher, XorSession};
+    use crate::link::TransferLink;
+    use crate::packet::connected::HEADER_ESCAPED;
+    use crate::RoleContext;
+
+    fn encrypted(data: &[u8]) -> Bytes {
+        let mut buf = vec![PackType::Encrypted as u8, HEADER_WRAPPED];
+        buf.extend(XorSession(1 ^ 2).encrypt(data));
+        Bytes::from(buf)
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_works() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let data = Bytes::from_static(&[0xfe, 1, 2, 3]);
+        let mut escaped = vec![PackType::Encrypted as u8, HEADER_ESCAPED];
+        escaped.extend_from_slice(&data);
+        let bodies = vec![
+            FrameBody::User(encrypted(&data)),
+            FrameBody::User(data.clone()),
+            // forged by an attacker
+            FrameBody::User(Bytes::from(escaped)),
+        ];
+
+        // not required
+        let not_required: Vec<_> = futures::stream::iter(bodies.clone())
+            .map(Ok)
+            .decrypted(Arc::clone(&link))
+            .collect()
+            .await;
+        assert_eq!(not_required[0].as_ref().unwrap(), &bodies[0]);
+
+        link.enable_encryption(Arc::new(XorCipher(1)));
+        // not established
+        let not_established: Vec<_> = futures::stream::iter(bodies.clone())
+            .map(Ok)
+            .decrypted(Arc::clone(&link))
+            .collect()
+            .await;
+        for res in not_established {
+            assert!(matches!(res.unwrap_err(), CodecError::Decrypt(_)));
+        }
+
+        link.start_key_exchange();
+        assert!(link.establish_session(&[2]));
+        let res: Vec<_> = futures::stream::iter(bodies)
+            .map(Ok)
+            .decrypted(link)
+            .collect()
+            .await;
+        assert_eq!(res[0].as_ref().unwrap(), &FrameBody::User(data));
+        // plaintext, escaped or not
+        for plaintext in &res[1..] {
+            assert!(matches!(
+                plaintext.as_ref().unwrap_err(),
+                CodecError::Decrypt(_)
+            ));
+        }
+    }
+}
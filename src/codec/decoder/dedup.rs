@@ -9,24 +9,46 @@ use crate::errors::CodecError;
 use crate::packet::connected::{FrameSet, Frames};
 use crate::utils::{u24, BitVecQueue};
 
-/// The deduplication window. For each connect, the maximum size is
-/// 2 ^ (8 * 3) / 8 / 1024 / 1024 = 2MB.
-#[derive(Debug, Default)]
+/// The deduplication window. For each connect, the maximum size is bounded by `window` bits, at
+/// most 2 ^ (8 * 3) / 8 / 1024 / 1024 = 2MB.
+#[derive(Debug)]
 struct DuplicateWindow {
     /// First unreceived sequence number, start at 0
     first_unreceived: u24,
     /// Record the received status of sequence numbers start at `first_unreceived`
     /// `true` is received and `false` is unreceived
     received_status: BitVecQueue,
+    /// The count of the sequence numbers tracked from `first_unreceived`
+    window: usize,
 }
 
 impl DuplicateWindow {
+    fn new(window: usize) -> Self {
+        Self {
+            first_unreceived: 0.into(),
+            received_status: BitVecQueue::default(),
+            window,
+        }
+    }
+
     /// Check whether a sequence number is duplicated
     fn duplicate(&mut self, seq_num: u24) -> bool {
-        if seq_num < self.first_unreceived {
+        // the reliable frame index wraps around, the ones behind are received already
+        let diff = seq_num.serial_diff(self.first_unreceived);
+        if diff < 0 {
             return true;
         }
-        let gap = (seq_num - self.first_unreceived).to_usize();
+        let mut gap = diff as usize;
+        if gap >= self.window {
+            // slide the window to the sequence number, the unreceived ones falling out of it are
+            // given up and treated as duplicated
+            let slide = gap + 1 - self.window;
+            for _ in 0..slide.min(self.received_status.len()) {
+                self.received_status.pop_front();
+            }
+            self.first_unreceived = self.first_unreceived.wrapping_add(slide as u32);
+            gap = self.window - 1;
+        }
         if gap < self.received_status.len() {
             // received the sequence number that is recorded in received_status
             // check its status to determine whether it is duplicated
@@ -45,7 +67,7 @@ impl DuplicateWindow {
         }
         while let Some(true) = self.received_status.front() {
             self.received_status.pop_front();
-            self.first_unreceived += 1;
+            self.first_unreceived = self.first_unreceived.wrapping_add(1);
         }
         false
     }
@@ -63,17 +85,19 @@ pin_project! {
 }
 
 pub(crate) trait Deduplicated: Sized {
-    fn deduplicated(self) -> Dedup<Self>;
+    /// Drop the frames with the reliable frame indices received already, tracking `window`
+    /// indices past the first missing one
+    fn deduplicated(self, window: usize) -> Dedup<Self>;
 }
 
 impl<F, B> Deduplicated for F
 where
     F: Stream<Item = Result<FrameSet<Frames<B>>, CodecError>>,
 {
-    fn deduplicated(self) -> Dedup<Self> {
+    fn deduplicated(self, window: usize) -> Dedup<Self> {
         Dedup {
             frame: self,
-            window: DuplicateWindow::default(),
+            window: DuplicateWindow::new(window),
             span: None,
         }
     }
@@ -128,7 +152,7 @@ mod test {
 
     #[test]
     fn test_duplicate_windows_check_ordered() {
-        let mut window = DuplicateWindow::default();
+        let mut window = DuplicateWindow::new(usize::MAX);
         for i in 0..1024 {
             assert!(!window.duplicate(i.into()));
             assert_eq!(window.first_unreceived.to_u32(), i + 1);
@@ -138,7 +162,7 @@ mod test {
 
     #[test]
     fn test_duplicate_windows_check_ordered_dup() {
-        let mut window = DuplicateWindow::default();
+        let mut window = DuplicateWindow::new(usize::MAX);
         for i in 0..512 {
             assert!(!window.duplicate(i.into()));
             assert_eq!(window.first_unreceived.to_u32(), i + 1);
@@ -151,7 +175,7 @@ mod test {
 
     #[test]
     fn test_duplicate_windows_check_gap_dup() {
-        let mut window = DuplicateWindow::default();
+        let mut window = DuplicateWindow::new(usize::MAX);
         assert!(!window.duplicate(0.into()));
         assert!(!window.duplicate(1.into()));
         assert!(!window.duplicate(1000.into()));
@@ -163,9 +187,57 @@ mod test {
         assert_eq!(window.first_unreceived.to_u32(), 2);
     }
 
+    #[test]
+    fn test_duplicate_window_bounded() {
+        let mut window = DuplicateWindow::new(8);
+        assert!(!window.duplicate(0.into()));
+        assert!(!window.duplicate(2.into()));
+        assert!(!window.duplicate(8.into()));
+        assert_eq!(window.first_unreceived.to_u32(), 1);
+        // slide the window past 1 and 3
+        assert!(!window.duplicate(11.into()));
+        assert_eq!(window.first_unreceived.to_u32(), 4);
+        assert!(window.received_status.len() <= 8);
+        assert!(window.duplicate(1.into()));
+        assert!(window.duplicate(3.into()));
+        assert!(window.duplicate(8.into()));
+        assert!(window.duplicate(11.into()));
+        assert!(!window.duplicate(4.into()));
+
+        // far beyond the window
+        assert!(!window.duplicate(100_000.into()));
+        assert_eq!(window.first_unreceived.to_u32(), 99_993);
+        assert!(window.received_status.len() <= 8);
+        assert!(window.duplicate(100_000.into()));
+    }
+
+    #[test]
+    fn test_duplicate_window_wrap_around() {
+        let mut window = DuplicateWindow::new(8);
+        window.first_unreceived = u24::MAX - 1;
+        assert!(!window.duplicate(u24::MAX - 1));
+        assert!(!window.duplicate(0.into()));
+        assert_eq!(window.first_unreceived, u24::MAX);
+        assert!(!window.duplicate(u24::MAX));
+        assert_eq!(window.first_unreceived.to_u32(), 1);
+        assert!(window.duplicate(u24::MAX - 1));
+        assert!(window.duplicate(u24::MAX));
+        assert!(window.duplicate(0.into()));
+        assert!(!window.duplicate(1.into()));
+
+        // slide the window across the boundary
+        window.first_unreceived = u24::MAX - 2;
+        window.received_status = BitVecQueue::default();
+        assert!(!window.duplicate(10.into()));
+        assert_eq!(window.first_unreceived.to_u32(), 3);
+        assert!(window.duplicate(u24::MAX));
+        assert!(window.duplicate(10.into()));
+        assert!(!window.duplicate(3.into()));
+    }
+
     #[test]
     fn test_duplicate_window_clear_gap_map() {
-        let mut window = DuplicateWindow::default();
+        let mut window = DuplicateWindow::new(usize::MAX);
         for i in (0..256).step_by(2) {
             assert!(!window.duplicate(i.into()));
         }
@@ -206,7 +278,7 @@ mod test {
             }
         };
         tokio::pin!(frame);
-        let mut dedup = frame.map(Ok).deduplicated();
+        let mut dedup = frame.map(Ok).deduplicated(usize::MAX);
 
         assert_eq!(dedup.next().await.unwrap().unwrap(), frame_set(0..64));
         assert_eq!(
@@ -228,7 +300,7 @@ mod test {
             }
         };
         tokio::pin!(frame);
-        let mut dedup = frame.map(Ok).deduplicated();
+        let mut dedup = frame.map(Ok).deduplicated(usize::MAX);
         assert_eq!(
             dedup.next().await.unwrap().unwrap(),
             frame_set([0, 1, 2, 3])
@@ -236,6 +308,26 @@ mod test {
         assert!(dedup.next().await.is_none());
     }
 
+    #[tokio::test]
+    async fn test_dedup_replayed_reliable_frame() {
+        // the reliable frame 1 is resent after its acknowledgement is lost
+        let frame = {
+            #[stream]
+            async {
+                yield frame_set([0, 1]);
+                yield frame_set(2..10);
+                yield frame_set([1]);
+                yield frame_set([10]);
+            }
+        };
+        tokio::pin!(frame);
+        let mut dedup = frame.map(Ok).deduplicated(4);
+        assert_eq!(dedup.next().await.unwrap().unwrap(), frame_set([0, 1]));
+        assert_eq!(dedup.next().await.unwrap().unwrap(), frame_set(2..10));
+        assert_eq!(dedup.next().await.unwrap().unwrap(), frame_set([10]));
+        assert!(dedup.next().await.is_none());
+    }
+
     async fn test_dedup_fuzzing_with_scale(scale: usize) {
         let idx1 = std::iter::repeat_with(rand::random::<u32>)
             .map(|i| i % scale as u32)
@@ -258,7 +350,7 @@ mod test {
             }
         };
         tokio::pin!(frame);
-        let mut dedup = frame.map(Ok).deduplicated();
+        let mut dedup = frame.map(Ok).deduplicated(usize::MAX);
         assert_eq!(dedup.next().await.unwrap().unwrap(), frame_set(idx1_set));
 
         if diff.is_empty() {
@@ -2,6 +2,8 @@ use std::cmp::Reverse;
 use std::collections::{BinaryHeap, VecDeque};
 use std::num::NonZeroUsize;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use bytes::BufMut;
@@ -13,9 +15,54 @@ use pin_project_lite::pin_project;
 use crate::errors::CodecError;
 use crate::link::SharedLink;
 use crate::packet::connected::{Fragment, Frame, FrameMut, FrameSet, FramesMut};
+use crate::utils::metric;
 
 const DEFAULT_DEFRAGMENT_BUF_SIZE: usize = 512;
 
+/// The quota of parted frames sets being reassembled, shared across connections
+#[derive(Debug, Clone)]
+pub(crate) struct ReassemblyQuota {
+    used: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl ReassemblyQuota {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            used: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    fn try_acquire(&self) -> Option<QuotaPermit> {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                (used < self.max).then_some(used + 1)
+            })
+            .ok()?;
+        Some(QuotaPermit {
+            used: Arc::clone(&self.used),
+        })
+    }
+}
+
+/// Give back the quota when dropped
+struct QuotaPermit {
+    used: Arc<AtomicUsize>,
+}
+
+impl Drop for QuotaPermit {
+    fn drop(&mut self) {
+        self.used.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A parted frames set being reassembled
+struct Reassembling {
+    parts: BinaryHeap<FramePart>,
+    _permit: Option<QuotaPermit>,
+}
+
 /// Frame parts belonging to a same parted id
 struct FramePart {
     parted_index: Reverse<u32>,
@@ -53,7 +100,10 @@ pin_project! {
         limit_size: u32,
         // reassemble parts helper. [`LruCache`] used to protect from causing OOM due to malicious
         // users sending a large number of parted IDs.
-        parts: LruCache<u16, BinaryHeap<FramePart>>,
+        parts: LruCache<u16, Reassembling>,
+        // the quota shared with other connections, a new parted frames set will be rejected if
+        // the quota is exhausted.
+        quota: Option<ReassemblyQuota>,
         buffer: VecDeque<FrameSet<Frame>>,
         link: SharedLink,
         span: Option<Span>,
@@ -65,6 +115,7 @@ pub(crate) trait DeFragmented: Sized {
         self,
         limit_size: u32,
         limit_parted: usize,
+        quota: Option<ReassemblyQuota>,
         link: SharedLink,
     ) -> DeFragment<Self>;
 }
@@ -77,12 +128,14 @@ where
         self,
         limit_size: u32,
         limit_parted: usize,
+        quota: Option<ReassemblyQuota>,
         link: SharedLink,
     ) -> DeFragment<Self> {
         DeFragment {
             frame: self,
             limit_size,
             parts: LruCache::new(NonZeroUsize::new(limit_parted).expect("limit_parted > 0")),
+            quota,
             buffer: VecDeque::with_capacity(DEFAULT_DEFRAGMENT_BUF_SIZE),
             link,
             span: None,
@@ -141,10 +194,36 @@ where
                         Event::add_to_local_parent(err.clone(), || []);
                         return Poll::Ready(Some(Err(CodecError::PartedFrame(err))));
                     }
-                    let frames_queue = this.parts.get_or_insert_mut(parted_id, || {
-                        // init the PriorityQueue with the capacity defined by user.
-                        BinaryHeap::with_capacity(parted_size as usize)
-                    });
+                    if !this.parts.contains(&parted_id) {
+                        let permit = match this.quota {
+                            Some(quota) => {
+                                let Some(permit) = quota.try_acquire() else {
+                                    let err = format!(
+                                        "reassembly quota {} exhausted, reject parted_id {}",
+                                        quota.max, parted_id
+                                    );
+                                    Event::add_to_local_parent(err.clone(), || []);
+                                    return Poll::Ready(Some(Err(CodecError::PartedFrame(err))));
+                                };
+                                Some(permit)
+                            }
+                            None => None,
+                        };
+                        // the evicted one will give back its quota
+                        this.parts.push(
+                            parted_id,
+                            Reassembling {
+                                // init the PriorityQueue with the capacity defined by user.
+                                parts: BinaryHeap::with_capacity(parted_size as usize),
+                                _permit: permit,
+                            },
+                        );
+                    }
+                    let frames_queue = &mut this
+                        .parts
+                        .get_mut(&parted_id)
+                        .expect("parted_id should be set before")
+                        .parts;
                     frames_queue.push(FramePart {
                         parted_index: Reverse(parted_index),
                         frame,
@@ -159,6 +238,7 @@ where
                         .parts
                         .pop(&parted_id)
                         .expect("parted_id should be set before")
+                        .parts
                         .into_iter_sorted()
                         .map(|part| part.frame)
                         .reduce(|mut acc, next| {
@@ -169,6 +249,7 @@ where
                         .expect("there is at least one frame")
                         .reassembled()
                         .freeze();
+                    metric!(this.link.metrics(), fragments_assembled, 1);
 
                     this.buffer.push_back(FrameSet {
                         seq_num: frame_set.seq_num,
@@ -256,6 +337,7 @@ mod test {
         let mut frag = frame.map(Ok).defragmented(
             0,
             512,
+            None,
             TransferLink::new_arc(crate::RoleContext::test_server()),
         );
         let set = frag.next().await.unwrap().unwrap();
@@ -283,6 +365,7 @@ mod test {
         let mut frag = frame.map(Ok).defragmented(
             20,
             512,
+            None,
             TransferLink::new_arc(crate::RoleContext::test_server()),
         );
         assert!(matches!(
@@ -316,12 +399,13 @@ mod test {
         let mut frag = frame.map(Ok).defragmented(
             0,
             2,
+            None,
             TransferLink::new_arc(crate::RoleContext::test_server()),
         );
         assert!(frag.next().await.is_none());
         assert_eq!(frag.parts.len(), 2);
-        assert_eq!(frag.parts.peek(&0).unwrap().len(), 2);
-        assert_eq!(frag.parts.peek(&2).unwrap().len(), 2);
+        assert_eq!(frag.parts.peek(&0).unwrap().parts.len(), 2);
+        assert_eq!(frag.parts.peek(&2).unwrap().parts.len(), 2);
     }
 
     #[tokio::test]
@@ -344,6 +428,7 @@ mod test {
         let mut frag = frame.map(Ok).defragmented(
             0,
             2,
+            None,
             TransferLink::new_arc(crate::RoleContext::test_server()),
         );
 
@@ -358,6 +443,57 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn test_defragment_quota_saturated() {
+        let quota = ReassemblyQuota::new(2);
+
+        let frame1 = {
+            #[stream]
+            async {
+                yield frame_set([&(2, 0, 0, "h"), &(2, 1, 0, "o")]);
+            }
+        };
+        let frame2 = {
+            #[stream]
+            async {
+                yield frame_set([&(2, 0, 0, "n")]);
+                yield frame_set([&(2, 1, 0, "n"), &(2, 1, 1, "o")]);
+            }
+        };
+        tokio::pin!(frame1);
+        tokio::pin!(frame2);
+
+        let mut frag1 = frame1.map(Ok).defragmented(
+            0,
+            512,
+            Some(quota.clone()),
+            TransferLink::new_arc(crate::RoleContext::test_server()),
+        );
+        let mut frag2 = frame2.map(Ok).defragmented(
+            0,
+            512,
+            Some(quota.clone()),
+            TransferLink::new_arc(crate::RoleContext::test_server()),
+        );
+
+        // frag1 occupies all the quota
+        assert!(frag1.next().await.is_none());
+        assert_eq!(quota.used.load(Ordering::Relaxed), 2);
+
+        // new parted frames set of frag2 is rejected
+        assert!(matches!(
+            frag2.next().await.unwrap(),
+            Err(CodecError::PartedFrame(..))
+        ));
+
+        // quota is given back after frag1 is dropped
+        drop(frag1);
+        assert_eq!(quota.used.load(Ordering::Relaxed), 0);
+        let set = frag2.next().await.unwrap().unwrap();
+        assert_eq!(String::from_utf8(set.set.body.to_vec()).unwrap(), "no");
+        assert_eq!(quota.used.load(Ordering::Relaxed), 0);
+    }
+
     async fn test_defragment_fuzzing_with_scale(scale: usize) {
         let mut parted_slice = (0..scale).collect::<Vec<_>>();
         let final_body = parted_slice
@@ -385,6 +521,7 @@ mod test {
         let mut frag = frame.map(Ok).defragmented(
             0,
             1,
+            None,
             TransferLink::new_arc(crate::RoleContext::test_server()),
         );
 
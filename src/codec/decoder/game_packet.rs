@@ -0,0 +1,121 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{ready, Stream, StreamExt};
+use log::debug;
+use pin_project_lite::pin_project;
+
+use crate::errors::CodecError;
+use crate::link::{Anomaly, SharedLink};
+use crate::packet::connected::FrameBody;
+
+pin_project! {
+    // GamePacketFilter layer, drop the user packets whose game packet id (the first byte) is not
+    // allowed. The dropped packets are already acknowledged.
+    pub(crate) struct GamePacketFilter<F> {
+        #[pin]
+        frame: F,
+        // sorted
+        allowed: Option<Vec<u8>>,
+        link: SharedLink,
+    }
+}
+
+pub(crate) trait GamePacketFiltered: Sized {
+    fn game_packet_filtered(
+        self,
+        allowed: Option<Vec<u8>>,
+        link: SharedLink,
+    ) -> GamePacketFilter<Self>;
+}
+
+impl<F> GamePacketFiltered for F
+where
+    F: Stream<Item = Result<FrameBody, CodecError>>,
+{
+    fn game_packet_filtered(
+        self,
+        allowed: Option<Vec<u8>>,
+        link: SharedLink,
+    ) -> GamePacketFilter<Self> {
+        GamePacketFilter {
+            frame: self,
+            allowed,
+            link,
+        }
+    }
+}
+
+impl<F> Stream for GamePacketFilter<F>
+where
+    F: Stream<Item = Result<FrameBody, CodecError>>,
+{
+    type Item = Result<FrameBody, CodecError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        let Some(allowed) = this.allowed else {
+            return this.frame.poll_next(cx);
+        };
+        loop {
+            let Some(body) = ready!(this.frame.poll_next_unpin(cx)?) else {
+                return Poll::Ready(None);
+            };
+            if let FrameBody::User(data) = &body
+                && let Some(&id) = data.first()
+                && allowed.binary_search(&id).is_err()
+            {
+                this.link.record_anomaly(Anomaly::UnknownGamePacket);
+                debug!("drop the user packet with unknown game packet id 0x{id:02x}");
+                continue;
+            }
+            return Poll::Ready(Some(Ok(body)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::link::TransferLink;
+    use crate::RoleContext;
+
+    #[tokio::test]
+    async fn test_game_packet_filter_works() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let bodies = vec![
+            FrameBody::User(Bytes::from_static(&[0xfe, 1])),
+            FrameBody::User(Bytes::from_static(&[0x01, 2])),
+            FrameBody::DetectLostConnections,
+            FrameBody::User(Bytes::from_static(&[0x86, 3])),
+        ];
+
+        let all: Vec<_> = futures::stream::iter(bodies.clone())
+            .map(Ok)
+            .game_packet_filtered(None, Arc::clone(&link))
+            .collect()
+            .await;
+        assert_eq!(all.len(), 4);
+
+        let res: Vec<_> = futures::stream::iter(bodies)
+            .map(Ok)
+            .game_packet_filtered(Some(vec![0x86, 0xfe]), Arc::clone(&link))
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(
+            res,
+            vec![
+                FrameBody::User(Bytes::from_static(&[0xfe, 1])),
+                FrameBody::DetectLostConnections,
+                FrameBody::User(Bytes::from_static(&[0x86, 3])),
+            ]
+        );
+        assert_eq!(link.anomalies().unknown_game_packets, 1);
+    }
+}
@@ -1,7 +1,13 @@
 mod body;
+#[cfg(feature = "compression")]
+mod decompress;
+#[cfg(feature = "encryption")]
+mod decrypt;
 mod dedup;
 mod fragment;
+mod game_packet;
 mod ordered;
+mod trace_id;
 
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -11,9 +17,16 @@ use minitrace::Span;
 use pin_project_lite::pin_project;
 
 pub(super) use self::body::*;
+#[cfg(feature = "compression")]
+pub(super) use self::decompress::*;
+#[cfg(feature = "encryption")]
+pub(super) use self::decrypt::*;
 pub(super) use self::dedup::*;
+pub(crate) use self::fragment::ReassemblyQuota;
 pub(super) use self::fragment::*;
+pub(super) use self::game_packet::*;
 pub(super) use self::ordered::*;
+pub(super) use self::trace_id::*;
 
 pin_project! {
     // Trace stream pending span
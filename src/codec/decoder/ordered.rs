@@ -1,32 +1,22 @@
-use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use bytes::Buf;
 use futures::{ready, Stream, StreamExt};
-use log::warn;
+use log::{debug, warn};
 use minitrace::{Event, Span};
 use pin_project_lite::pin_project;
 
 use crate::errors::CodecError;
+use crate::io::OrderGapPolicy;
 use crate::packet::connected::{self, Frame, FrameSet};
-use crate::utils::u24;
+use crate::packet::{PackType, MAX_ORDER_CHANNELS};
+use crate::utils::{u24, Rejected, Ring};
 
-const INITIAL_ORDERING_MAP_CAP: usize = 64;
-
-struct Ordering<B> {
-    map: HashMap<u24, FrameSet<Frame<B>>>,
-    read: u24,
-}
-
-impl<B> Default for Ordering<B> {
-    fn default() -> Self {
-        Self {
-            map: HashMap::with_capacity(INITIAL_ORDERING_MAP_CAP),
-            read: 0.into(),
-        }
-    }
-}
+// the reorder window of each channel, the frames beyond it fall back to a map of the same
+// capacity, the ones beyond both are rejected
+const ORDERING_WINDOW: usize = 256;
 
 pin_project! {
     // Ordering layer, ordered the packets based on ordering_frame_index.
@@ -35,37 +25,84 @@ pin_project! {
         frame: F,
         // Max ordered channel that will be used in detailed protocol
         max_channels: usize,
-        ordering: Vec<Ordering<B>>,
+        ordering: Vec<Ring<FrameSet<Frame<B>>>>,
+        // the ordered frame index and the next sequence index of the last sequenced frame
+        // delivered on each channel
+        sequenced: Vec<Option<(u24, u24)>>,
+        // the handling of the frames stuck behind a missing index on teardown
+        gap_policy: OrderGapPolicy,
+        // the items released on teardown, yielded before anything else
+        released: VecDeque<Result<FrameSet<Frame<B>>, CodecError>>,
+        // the upstream is exhausted
+        terminated: bool,
         span: Option<Span>,
     }
 }
 
 pub(crate) trait Ordered<B: Buf>: Sized {
-    fn ordered(self, max_channels: usize) -> Order<Self, B>;
+    fn ordered(self, max_channels: usize, gap_policy: OrderGapPolicy) -> Order<Self, B>;
 }
 
 impl<F, B: Buf> Ordered<B> for F
 where
     F: Stream<Item = Result<FrameSet<Frame<B>>, CodecError>>,
 {
-    fn ordered(self, max_channels: usize) -> Order<Self, B> {
+    fn ordered(self, max_channels: usize, gap_policy: OrderGapPolicy) -> Order<Self, B> {
         assert!(
-            max_channels < usize::from(u8::MAX),
-            "max channels should not be larger than u8::MAX"
+            max_channels <= MAX_ORDER_CHANNELS,
+            "max channels should not be larger than {MAX_ORDER_CHANNELS}"
         );
         assert!(max_channels > 0, "max_channels > 0");
 
         Order {
             frame: self,
             max_channels,
-            ordering: std::iter::repeat_with(Ordering::default)
+            ordering: std::iter::repeat_with(|| Ring::new(ORDERING_WINDOW))
                 .take(max_channels)
                 .collect(),
+            sequenced: vec![None; max_channels],
+            gap_policy,
+            released: VecDeque::new(),
+            terminated: false,
             span: None,
         }
     }
 }
 
+/// Release the frames stuck in the reorder buffer according to the policy, the missing ones will
+/// never arrive since the connection is closing
+fn release<B>(
+    ordering: &mut [Ring<FrameSet<Frame<B>>>],
+    gap_policy: OrderGapPolicy,
+    released: &mut VecDeque<Result<FrameSet<Frame<B>>, CodecError>>,
+) {
+    let mut stuck = 0;
+    for (channel, ring) in ordering.iter_mut().enumerate() {
+        let frames = ring.drain();
+        if frames.is_empty() {
+            continue;
+        }
+        stuck += frames.len();
+        debug!(
+            "release {} ordered frames stuck on channel {channel} with {gap_policy:?}",
+            frames.len()
+        );
+        if gap_policy == OrderGapPolicy::Deliver {
+            released.extend(frames.into_iter().map(Ok));
+        }
+    }
+    if stuck > 0 && gap_policy == OrderGapPolicy::Fail {
+        released.push_back(Err(CodecError::OrderedFrame(format!(
+            "{stuck} frames stuck behind the missing ordered frame index on teardown"
+        ))));
+    }
+}
+
+/// Whether the frame set carries the disconnect notification, the peer sends nothing after it
+fn is_disconnect<B: Buf>(frame_set: &FrameSet<Frame<B>>) -> bool {
+    frame_set.set.body.chunk().first() == Some(&(PackType::DisconnectNotification as u8))
+}
+
 impl<F, B> Stream for Order<F, B>
 where
     F: Stream<Item = Result<FrameSet<Frame<B>>, CodecError>>,
@@ -75,6 +112,12 @@ where
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
         loop {
+            if let Some(item) = this.released.pop_front() {
+                return Poll::Ready(Some(item));
+            }
+            if *this.terminated {
+                return Poll::Ready(None);
+            }
             // empty each channel in order
             for channel in 0..*this.max_channels {
                 let ordering = this
@@ -82,15 +125,17 @@ where
                     .get_mut(channel)
                     .expect("channel < max_channels");
                 // check if we could read next
-                if let Some(next) = ordering.map.remove(&ordering.read) {
-                    ordering.read += 1;
+                if let Some(next) = ordering.pop_front() {
                     this.span.take();
                     return Poll::Ready(Some(Ok(next)));
                 }
             }
 
             let Some(frame_set) = ready!(this.frame.poll_next_unpin(cx)?) else {
-                return Poll::Ready(None);
+                // the router is dropped
+                *this.terminated = true;
+                release(this.ordering, *this.gap_policy, this.released);
+                continue;
             };
             this.span.get_or_insert_with(|| {
                 Span::enter_with_local_parent("codec.reorder").with_properties(|| {
@@ -98,7 +143,7 @@ where
                         "pending",
                         this.ordering
                             .iter()
-                            .fold(0, |acc, o| acc + o.map.len())
+                            .fold(0, |acc, o| acc + o.len())
                             .to_string(),
                     )]
                 })
@@ -118,16 +163,49 @@ where
                     .ordering
                     .get_mut(channel)
                     .expect("channel < max_channels");
-                if frame_index < ordering.read {
-                    warn!("ignore old ordered frame index {frame_index}");
-                    continue;
+                if let Some(seq_index) = frame_set.set.seq_frame_index {
+                    // the sequenced frame is delivered at once if it is the latest one after the
+                    // ordered frames before it, the ones ahead of the missing ordered frames are
+                    // dropped as well
+                    let base = ordering.base();
+                    let latest = frame_index == base
+                        && this.sequenced[channel].map_or(true, |(index, next)| {
+                            index != base || seq_index.serial_diff(next) >= 0
+                        });
+                    if !latest {
+                        debug!("ignore stale sequenced frame {seq_index} on channel {channel}");
+                        continue;
+                    }
+                    this.sequenced[channel] = Some((base, seq_index.wrapping_add(1)));
+                    this.span.take();
+                    return Poll::Ready(Some(Ok(frame_set)));
+                }
+                match ordering.insert(frame_index, frame_set) {
+                    Ok(()) => {}
+                    Err(Rejected::Stale) => {
+                        warn!("ignore old or duplicated ordered frame index {frame_index}");
+                    }
+                    Err(Rejected::Overflow) => {
+                        let err = format!(
+                            "ordered frame index {frame_index} on channel {channel} is too far \
+                             ahead of {}",
+                            ordering.base()
+                        );
+                        Event::add_to_local_parent(err.clone(), || []);
+                        return Poll::Ready(Some(Err(CodecError::OrderedFrame(err))));
+                    }
                 }
-                ordering.map.insert(frame_index, frame_set);
                 // we cannot read anymore
                 continue;
             }
             // the frame set which does not require ordered
             this.span.take();
+            if is_disconnect(&frame_set) {
+                // release the stuck frames ahead of the disconnect notification
+                release(this.ordering, *this.gap_policy, this.released);
+                this.released.push_back(Ok(frame_set));
+                continue;
+            }
             return Poll::Ready(Some(Ok(frame_set)));
         }
     }
@@ -141,7 +219,10 @@ mod test {
 
     use super::Ordered;
     use crate::errors::CodecError;
+    use crate::io::OrderGapPolicy;
     use crate::packet::connected::{Flags, Frame, FrameSet, Ordered as OrderedFlag};
+    use crate::packet::PackType;
+    use crate::Reliability;
 
     fn frame_sets(idx: impl IntoIterator<Item = (u8, u32)>) -> Vec<FrameSet<Frame>> {
         idx.into_iter()
@@ -175,7 +256,7 @@ mod test {
             }
         };
         tokio::pin!(frame);
-        let mut ordered = frame.map(Ok).ordered(10);
+        let mut ordered = frame.map(Ok).ordered(10, OrderGapPolicy::Discard);
         let cmp_sets = frame_sets([(0, 0), (0, 1), (0, 2), (0, 3), (0, 4)]).into_iter();
         for next in cmp_sets {
             assert_eq!(ordered.next().await.unwrap().unwrap(), next);
@@ -184,6 +265,54 @@ mod test {
         assert!(ordered.next().await.is_none());
     }
 
+    #[tokio::test]
+    async fn test_sequenced_works() {
+        // (channel, frame_index, seq_frame_index)
+        let sets = |idx: &[(u8, u32, Option<u32>)]| {
+            idx.iter()
+                .map(|&(channel, frame_index, seq_index)| {
+                    let mut frame_set = frame_sets([(channel, frame_index)]).pop().unwrap();
+                    if let Some(seq_index) = seq_index {
+                        frame_set.set.flags = Flags::new(Reliability::ReliableSequenced, false);
+                        frame_set.set.seq_frame_index = Some(seq_index.into());
+                    }
+                    frame_set
+                })
+                .collect::<Vec<_>>()
+        };
+        let input = sets(&[
+            (0, 1, None),
+            // not blocked by the missing ordered frame on channel 0
+            (1, 0, Some(1)),
+            (1, 0, Some(0)),
+            (1, 0, Some(2)),
+            (0, 0, None),
+            (0, 2, Some(0)),
+            // behind the ordered frames
+            (0, 1, Some(5)),
+        ]);
+        let frame = {
+            #[stream]
+            async move {
+                for frame_set in input {
+                    yield frame_set;
+                }
+            }
+        };
+        tokio::pin!(frame);
+        let mut ordered = frame.map(Ok).ordered(2, OrderGapPolicy::Discard);
+        for next in sets(&[
+            (1, 0, Some(1)),
+            (1, 0, Some(2)),
+            (0, 0, None),
+            (0, 1, None),
+            (0, 2, Some(0)),
+        ]) {
+            assert_eq!(ordered.next().await.unwrap().unwrap(), next);
+        }
+        assert!(ordered.next().await.is_none());
+    }
+
     #[tokio::test]
     async fn test_ordered_channel_exceed() {
         let frame = {
@@ -195,10 +324,95 @@ mod test {
             }
         };
         tokio::pin!(frame);
-        let mut ordered = frame.map(Ok).ordered(10);
+        let mut ordered = frame.map(Ok).ordered(10, OrderGapPolicy::Discard);
+        assert!(matches!(
+            ordered.next().await.unwrap().unwrap_err(),
+            CodecError::OrderedFrame(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_ordered_too_far_ahead() {
+        // the index 0 is missing, the frames after it fill the window and its overflow
+        let frame = {
+            #[stream]
+            async {
+                for frame_set in frame_sets((1..=512).map(|i| (0, i))) {
+                    yield frame_set;
+                }
+            }
+        };
+        tokio::pin!(frame);
+        let mut ordered = frame.map(Ok).ordered(1, OrderGapPolicy::Discard);
         assert!(matches!(
             ordered.next().await.unwrap().unwrap_err(),
             CodecError::OrderedFrame(_)
         ));
     }
+
+    fn disconnect() -> FrameSet<Frame> {
+        FrameSet {
+            seq_num: 0.into(),
+            set: Frame {
+                flags: Flags::parse(0b010_00000),
+                reliable_frame_index: None,
+                seq_frame_index: None,
+                ordered: None,
+                fragment: None,
+                body: Bytes::from_static(&[PackType::DisconnectNotification as u8]),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ordered_gap_on_teardown() {
+        // the index 1 on channel 0 is never filled, the peer closes after that
+        let input = || {
+            let mut sets = frame_sets([(0, 0), (0, 2), (1, 1), (0, 3)]);
+            sets.push(disconnect());
+            futures::stream::iter(sets)
+        };
+
+        let mut ordered = input().map(Ok).ordered(2, OrderGapPolicy::Deliver);
+        let expected = frame_sets([(0, 0), (0, 2), (0, 3), (1, 1)])
+            .into_iter()
+            .chain([disconnect()]);
+        for next in expected {
+            assert_eq!(ordered.next().await.unwrap().unwrap(), next);
+        }
+        assert!(ordered.next().await.is_none());
+
+        let mut ordered = input().map(Ok).ordered(2, OrderGapPolicy::Discard);
+        assert_eq!(
+            ordered.next().await.unwrap().unwrap(),
+            frame_sets([(0, 0)]).pop().unwrap()
+        );
+        assert_eq!(ordered.next().await.unwrap().unwrap(), disconnect());
+        assert!(ordered.next().await.is_none());
+
+        let mut ordered = input().map(Ok).ordered(2, OrderGapPolicy::Fail);
+        assert_eq!(
+            ordered.next().await.unwrap().unwrap(),
+            frame_sets([(0, 0)]).pop().unwrap()
+        );
+        assert!(matches!(
+            ordered.next().await.unwrap().unwrap_err(),
+            CodecError::OrderedFrame(_)
+        ));
+        assert_eq!(ordered.next().await.unwrap().unwrap(), disconnect());
+        assert!(ordered.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ordered_gap_on_router_dropped() {
+        let frame = futures::stream::iter(frame_sets([(0, 1), (0, 2)]));
+        let mut ordered = frame.map(Ok).ordered(1, OrderGapPolicy::Deliver);
+        for next in frame_sets([(0, 1), (0, 2)]) {
+            assert_eq!(ordered.next().await.unwrap().unwrap(), next);
+        }
+        assert!(ordered.next().await.is_none());
+        // nothing is buffered after the teardown
+        assert!(ordered.ordering.iter().all(|ring| ring.len() == 0));
+        assert!(ordered.next().await.is_none());
+    }
 }
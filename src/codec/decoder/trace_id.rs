@@ -0,0 +1,173 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Buf;
+use futures::{ready, Stream, StreamExt};
+use pin_project_lite::pin_project;
+
+use crate::errors::CodecError;
+use crate::link::SharedLink;
+use crate::packet::connected::{FrameBody, CAPABILITY_TRACE_ID, HEADER_ESCAPED, HEADER_WRAPPED};
+use crate::packet::PackType;
+
+pin_project! {
+    // Trace id layer, strip the trace ids of the frame bodies and record them in the link
+    pub(crate) struct TraceIdDecoder<F> {
+        #[pin]
+        frame: F,
+        link: SharedLink,
+    }
+}
+
+pub(crate) trait TraceIdDecoded: Sized {
+    fn trace_id_decoded(self, link: SharedLink) -> TraceIdDecoder<Self>;
+}
+
+impl<F> TraceIdDecoded for F
+where
+    F: Stream<Item = Result<FrameBody, CodecError>>,
+{
+    fn trace_id_decoded(self, link: SharedLink) -> TraceIdDecoder<Self> {
+        TraceIdDecoder { frame: self, link }
+    }
+}
+
+impl<F> Stream for TraceIdDecoder<F>
+where
+    F: Stream<Item = Result<FrameBody, CodecError>>,
+{
+    type Item = Result<FrameBody, CodecError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        let Some(body) = ready!(this.frame.poll_next_unpin(cx)?) else {
+            return Poll::Ready(None);
+        };
+        // the traced frame bodies are only recognized if the propagation is negotiated or offered
+        // before the negotiation, they carry the user data only
+        let FrameBody::User(mut data) = body else {
+            return Poll::Ready(Some(Ok(body)));
+        };
+        if data.first() != Some(&(PackType::Traced as u8))
+            || !this.link.capability_applies(CAPABILITY_TRACE_ID)
+        {
+            this.link.receive_trace_id(None);
+            return Poll::Ready(Some(Ok(FrameBody::User(data))));
+        }
+        // the pack type, the flag, the trace id if wrapped and at least one byte of the escaped
+        // body, the traced one may be empty
+        let (expected, context) = match data.get(1) {
+            Some(&HEADER_WRAPPED) => (10, "traced frame body"),
+            Some(&HEADER_ESCAPED) => (3, "escaped frame body"),
+            Some(&flag) => return Poll::Ready(Some(Err(CodecError::InvalidPacketType(flag)))),
+            None => (2, "traced frame body"),
+        };
+        if data.len() < expected {
+            return Poll::Ready(Some(Err(CodecError::InvalidPacketLength {
+                expected,
+                got: data.len(),
+                context,
+            })));
+        }
+        data.advance(1);
+        let trace_id = (data.get_u8() == HEADER_WRAPPED).then(|| data.get_u64());
+        this.link.receive_trace_id(trace_id);
+        Poll::Ready(Some(Ok(FrameBody::User(data))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bytes::{BufMut, Bytes, BytesMut};
+
+    use super::*;
+    use crate::link::TransferLink;
+    use crate::RoleContext;
+
+    fn traced(trace_id: u64, data: &[u8]) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u8(PackType::Traced as u8);
+        buf.put_u8(HEADER_WRAPPED);
+        buf.put_u64(trace_id);
+        buf.put_slice(data);
+        buf.freeze()
+    }
+
+    #[tokio::test]
+    async fn test_trace_id_decode_works() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let data = Bytes::from_static(&[0xfe, 1, 2, 3]);
+        let bodies = vec![
+            FrameBody::User(traced(114514, &data)),
+            FrameBody::User(data.clone()),
+        ];
+
+        // not negotiated
+        let mut not_negotiated = futures::stream::iter(bodies.clone())
+            .map(Ok)
+            .trace_id_decoded(Arc::clone(&link));
+        assert_eq!(not_negotiated.next().await.unwrap().unwrap(), bodies[0]);
+        assert_eq!(link.received_trace_id(), None);
+
+        link.enable_trace_id_propagation();
+        link.negotiate(CAPABILITY_TRACE_ID);
+        let mut decoded = futures::stream::iter(bodies)
+            .map(Ok)
+            .trace_id_decoded(Arc::clone(&link));
+        assert_eq!(
+            decoded.next().await.unwrap().unwrap(),
+            FrameBody::User(data.clone())
+        );
+        assert_eq!(link.received_trace_id(), Some(114514));
+        assert_eq!(
+            decoded.next().await.unwrap().unwrap(),
+            FrameBody::User(data)
+        );
+        assert_eq!(link.received_trace_id(), None);
+
+        // the escaped body is not parsed as an internal one
+        let disconnect = PackType::DisconnectNotification as u8;
+        let mut escaped = futures::stream::iter([
+            FrameBody::User(traced(1, &[disconnect])),
+            FrameBody::User(Bytes::from(vec![
+                PackType::Traced as u8,
+                HEADER_ESCAPED,
+                PackType::Traced as u8,
+                disconnect,
+            ])),
+        ])
+        .map(Ok)
+        .trace_id_decoded(Arc::clone(&link));
+        assert_eq!(
+            escaped.next().await.unwrap().unwrap(),
+            FrameBody::User(Bytes::from(vec![disconnect]))
+        );
+        assert_eq!(link.received_trace_id(), Some(1));
+        assert_eq!(
+            escaped.next().await.unwrap().unwrap(),
+            FrameBody::User(Bytes::from(vec![PackType::Traced as u8, disconnect]))
+        );
+        assert_eq!(link.received_trace_id(), None);
+
+        // empty
+        let mut empty = futures::stream::iter([FrameBody::User(traced(1, &[]))])
+            .map(Ok)
+            .trace_id_decoded(Arc::clone(&link));
+        assert_eq!(
+            empty.next().await.unwrap().unwrap(),
+            FrameBody::User(Bytes::new())
+        );
+        assert_eq!(link.received_trace_id(), Some(1));
+
+        // truncated
+        let mut truncated = futures::stream::iter([FrameBody::User(traced(1, &[]).slice(..9))])
+            .map(Ok)
+            .trace_id_decoded(link);
+        assert!(matches!(
+            truncated.next().await.unwrap().unwrap_err(),
+            CodecError::InvalidPacketLength { got: 9, .. }
+        ));
+    }
+}
@@ -1,13 +1,14 @@
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
 
-use bytes::BytesMut;
+use bytes::{BufMut, BytesMut};
 use futures::Sink;
 use pin_project_lite::pin_project;
 
 use crate::errors::CodecError;
 use crate::link::SharedLink;
-use crate::packet::connected::FrameBody;
+use crate::packet::connected::{FrameBody, CAPABILITY_MTU_UPDATE, HEADER_ESCAPED};
+use crate::packet::PackType;
 use crate::{Message, Reliability};
 
 pin_project! {
@@ -42,8 +43,12 @@ fn encode(body: FrameBody) -> Message {
         FrameBody::ConnectionRequest { .. } => Reliability::ReliableOrdered,
         FrameBody::ConnectionRequestAccepted { .. } => Reliability::Reliable,
         FrameBody::NewIncomingConnection { .. } => Reliability::ReliableOrdered,
+        FrameBody::ConnectionRequestFailed => Reliability::Reliable,
+        // kept for resending until the peer acknowledges it, the close waits for the ack. It is
+        // sent after all frames are acknowledged, so it needs no ordering.
         FrameBody::DisconnectNotification => Reliability::Reliable,
         FrameBody::DetectLostConnections => Reliability::Reliable,
+        FrameBody::MtuUpdate { .. } => Reliability::Reliable,
         FrameBody::User(_) => {
             panic!("you should not send user packet into BodyEncoder, please send `Message`")
         }
@@ -93,9 +98,34 @@ where
         Sink::<FrameBody>::poll_ready(self, cx)
     }
 
-    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+    fn start_send(self: Pin<&mut Self>, mut item: Message) -> Result<(), Self::Error> {
+        let this = self.project();
+        // the peer takes any body starting with the header as the mtu update once negotiated
+        if item.get_data().first() == Some(&(PackType::MtuUpdate as u8))
+            && this.link.capability_applies(CAPABILITY_MTU_UPDATE)
+        {
+            let mut buf = BytesMut::with_capacity(2 + item.get_data().len());
+            buf.put_u8(PackType::MtuUpdate as u8);
+            buf.put_u8(HEADER_ESCAPED);
+            buf.put_slice(item.get_data());
+            let mut escaped = Message::new(
+                item.get_reliability(),
+                item.get_order_channel(),
+                buf.freeze(),
+            );
+            escaped.set_priority(item.get_priority());
+            escaped.set_ack_receipt(item.get_ack_receipt());
+            item = escaped;
+        }
+        // only the reliable messages and the unreliable ones requesting receipts sent by the user
+        // have receipts
+        if (item.get_reliability().is_reliable() || item.wants_unreliable_receipt())
+            && let Some(id) = this.link.next_receipt_id()
+        {
+            item.set_receipt(id);
+        }
         // skip encode
-        self.project().frame.start_send(item)
+        this.frame.start_send(item)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -0,0 +1,206 @@
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use futures::Sink;
+use pin_project_lite::pin_project;
+
+use crate::link::SharedLink;
+use crate::packet::connected::{FrameBody, CAPABILITY_COMPRESSION, HEADER_ESCAPED, HEADER_WRAPPED};
+use crate::packet::PackType;
+use crate::Message;
+
+/// The minimum threshold of compressing, the tiny frame bodies are hardly compressible
+pub(crate) const MIN_COMPRESSION_THRESHOLD: usize = 64;
+
+pin_project! {
+    // Compress layer, compress the messages larger than the threshold before fragmentation once
+    // negotiated, and escape the raw ones starting with the header. The internal frame bodies are
+    // never compressed.
+    pub(crate) struct Compress<F> {
+        #[pin]
+        frame: F,
+        link: SharedLink,
+    }
+}
+
+pub(crate) trait Compressed: Sized {
+    fn compressed(self, link: SharedLink) -> Compress<Self>;
+}
+
+impl<F> Compressed for F
+where
+    F: Sink<Message>,
+{
+    fn compressed(self, link: SharedLink) -> Compress<Self> {
+        Compress { frame: self, link }
+    }
+}
+
+/// Compress the data with the [`PackType::Compressed`] header, return `None` if it does not get
+/// smaller
+fn compress(data: &[u8]) -> Option<Bytes> {
+    let mut encoder = ZlibEncoder::new(
+        vec![PackType::Compressed as u8, HEADER_WRAPPED],
+        Compression::fast(),
+    );
+    encoder.write_all(data).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < data.len()).then(|| Bytes::from(compressed))
+}
+
+impl<F> Sink<Message> for Compress<F>
+where
+    F: Sink<Message>,
+{
+    type Error = F::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: Message) -> Result<(), Self::Error> {
+        let this = self.project();
+        let data = if let Some(threshold) = this.link.compression_threshold()
+            && msg.get_data().len() >= threshold.max(MIN_COMPRESSION_THRESHOLD)
+            && let Some(compressed) = compress(msg.get_data())
+        {
+            compressed
+        } else if msg.get_data().first() == Some(&(PackType::Compressed as u8))
+            && this.link.capability_applies(CAPABILITY_COMPRESSION)
+        {
+            // the peer takes any body starting with the header as compressed, including the ones
+            // sent before the client learns the negotiation
+            let mut buf = BytesMut::with_capacity(2 + msg.get_data().len());
+            buf.put_u8(PackType::Compressed as u8);
+            buf.put_u8(HEADER_ESCAPED);
+            buf.put_slice(msg.get_data());
+            buf.freeze()
+        } else {
+            return this.frame.start_send(msg);
+        };
+        let mut wrapped = Message::new(msg.get_reliability(), msg.get_order_channel(), data);
+        wrapped.set_priority(msg.get_priority());
+        wrapped.set_ack_receipt(msg.get_ack_receipt());
+        this.frame.start_send(wrapped)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Message>::poll_flush(self.project().frame, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Message>::poll_close(self.project().frame, cx)
+    }
+}
+
+impl<F> Sink<FrameBody> for Compress<F>
+where
+    F: Sink<FrameBody>,
+{
+    type Error = F::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, body: FrameBody) -> Result<(), Self::Error> {
+        self.project().frame.start_send(body)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<FrameBody>::poll_flush(self.project().frame, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<FrameBody>::poll_close(self.project().frame, cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+    use futures::SinkExt;
+
+    use super::*;
+    use crate::link::TransferLink;
+    use crate::packet::connected::CAPABILITY_COMPRESSION;
+    use crate::{Reliability, RoleContext};
+
+    #[tokio::test]
+    async fn test_compress_works() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        link.enable_compression(128);
+        link.negotiate(CAPABILITY_COMPRESSION);
+        let mut dst = Vec::<Message>::new().compressed(link);
+
+        let compressible = Bytes::from(b"hello world, ".repeat(100));
+        let tiny = Bytes::from(b"hello world, ".repeat(5));
+        let mut incompressible = vec![0; 1024];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut incompressible[..]);
+        let incompressible = Bytes::from(incompressible);
+        let headed = Bytes::from_static(&[PackType::Compressed as u8, 1, 2, 3]);
+        for data in [&compressible, &tiny, &incompressible, &headed] {
+            dst.send(Message::new(Reliability::Reliable, 0, data.clone()))
+                .await
+                .unwrap();
+        }
+
+        let sent = dst.frame;
+        let on_wire = sent[0].get_data();
+        assert_eq!(on_wire[..2], [PackType::Compressed as u8, HEADER_WRAPPED]);
+        assert!(on_wire.len() < compressible.len());
+        let mut decompressed = vec![];
+        ZlibDecoder::new(&on_wire[2..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, compressible);
+
+        // below the threshold
+        assert_eq!(sent[1].get_data(), &tiny);
+        // it does not get smaller
+        assert_eq!(sent[2].get_data(), &incompressible);
+        // the raw body starting with the header is escaped
+        assert_eq!(
+            sent[3].get_data()[..2],
+            [PackType::Compressed as u8, HEADER_ESCAPED]
+        );
+        assert_eq!(sent[3].get_data()[2..], headed);
+    }
+
+    #[tokio::test]
+    async fn test_compress_not_negotiated() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        link.enable_compression(128);
+        let mut dst = Vec::<Message>::new().compressed(link);
+
+        let compressible = Bytes::from(b"hello world, ".repeat(100));
+        let headed = Bytes::from_static(&[PackType::Compressed as u8, 1, 2, 3]);
+        for data in [&compressible, &headed] {
+            dst.send(Message::new(Reliability::Reliable, 0, data.clone()))
+                .await
+                .unwrap();
+        }
+        assert_eq!(dst.frame[0].get_data(), &compressible);
+        // the peer may have negotiated it already
+        assert_eq!(
+            dst.frame[1].get_data()[..2],
+            [PackType::Compressed as u8, HEADER_ESCAPED]
+        );
+
+        // the peer does not agree
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        link.enable_compression(128);
+        link.negotiate(0);
+        let mut dst = Vec::<Message>::new().compressed(link);
+        dst.send(Message::new(Reliability::Reliable, 0, headed.clone()))
+            .await
+            .unwrap();
+        assert_eq!(dst.frame[0].get_data(), &headed);
+    }
+}
@@ -0,0 +1,149 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{ready, Sink};
+use pin_project_lite::pin_project;
+
+use crate::cipher::CipherSession;
+use crate::link::SharedLink;
+use crate::packet::connected::{FrameBody, HEADER_WRAPPED};
+use crate::packet::PackType;
+use crate::Message;
+
+pin_project! {
+    // Encrypt layer, encrypt the messages before fragmentation once the session is established.
+    // The messages are held until that, the internal frame bodies are never encrypted.
+    pub(crate) struct Encrypt<F> {
+        #[pin]
+        frame: F,
+        link: SharedLink,
+    }
+}
+
+pub(crate) trait Encrypted: Sized {
+    fn encrypted(self, link: SharedLink) -> Encrypt<Self>;
+}
+
+impl<F> Encrypted for F
+where
+    F: Sink<Message>,
+{
+    fn encrypted(self, link: SharedLink) -> Encrypt<Self> {
+        Encrypt { frame: self, link }
+    }
+}
+
+/// Encrypt the data with the [`PackType::Encrypted`] header
+fn encrypt(session: &dyn CipherSession, data: &[u8]) -> Bytes {
+    let ciphertext = session.encrypt(data);
+    let mut buf = BytesMut::with_capacity(2 + ciphertext.len());
+    buf.put_u8(PackType::Encrypted as u8);
+    buf.put_u8(HEADER_WRAPPED);
+    buf.put_slice(&ciphertext);
+    buf.freeze()
+}
+
+impl<F> Sink<Message> for Encrypt<F>
+where
+    F: Sink<Message>,
+{
+    type Error = F::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let mut this = self.project();
+        // the client may send before the online handshake completes, hold the messages until
+        // the session is established and send the handshake packets meanwhile
+        if this.link.awaits_session() {
+            ready!(Sink::<Message>::poll_flush(this.frame.as_mut(), cx))?;
+            this.link.register_session_waker(cx.waker());
+            if this.link.awaits_session() {
+                return Poll::Pending;
+            }
+        }
+        this.frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: Message) -> Result<(), Self::Error> {
+        let this = self.project();
+        if !this.link.encryption_enabled() {
+            return this.frame.start_send(msg);
+        }
+        let Some(session) = this.link.cipher_session() else {
+            // the connection failed before the session is established, never send the plaintext
+            return Ok(());
+        };
+        let mut encrypted = Message::new(
+            msg.get_reliability(),
+            msg.get_order_channel(),
+            encrypt(session, msg.get_data()),
+        );
+        encrypted.set_priority(msg.get_priority());
+        encrypted.set_ack_receipt(msg.get_ack_receipt());
+        this.frame.start_send(encrypted)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Message>::poll_flush(self.project().frame, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Message>::poll_close(self.project().frame, cx)
+    }
+}
+
+impl<F> Sink<FrameBody> for Encrypt<F>
+where
+    F: Sink<FrameBody>,
+{
+    type Error = F::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, body: FrameBody) -> Result<(), Self::Error> {
+        self.project().frame.start_send(body)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<FrameBody>::poll_flush(self.project().frame, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<FrameBody>::poll_close(self.project().frame, cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use futures::SinkExt;
+
+    use super::*;
+    use crate::cipher::{XorCipher, XorSession};
+    use crate::link::TransferLink;
+    use crate::{Reliability, RoleContext};
+
+    #[tokio::test]
+    async fn test_encrypt_works() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        link.enable_encryption(Arc::new(XorCipher(1)));
+        let mut dst = Vec::<Message>::new().encrypted(Arc::clone(&link));
+        let data = Bytes::from_static(b"hello world");
+
+        // held until the session is established
+        let mut sending = dst.send(Message::new(Reliability::Reliable, 0, data.clone()));
+        assert!(futures::poll!(&mut sending).is_pending());
+        link.start_key_exchange();
+        assert!(link.establish_session(&[2]));
+        sending.await.unwrap();
+
+        let sent = dst.frame;
+        assert_eq!(sent.len(), 1);
+        let on_wire = sent[0].get_data();
+        assert_eq!(on_wire[..2], [PackType::Encrypted as u8, HEADER_WRAPPED]);
+        assert_eq!(XorSession(1 ^ 2).decrypt(&on_wire[2..]).unwrap(), data);
+    }
+}
@@ -7,44 +7,65 @@ use futures::Sink;
 use pin_project_lite::pin_project;
 
 use crate::errors::CodecError;
+use crate::link::SharedLink;
 use crate::packet::connected::{self, Flags, Frame, Ordered};
 use crate::packet::{FRAGMENT_PART_SIZE, FRAME_SET_HEADER_SIZE};
+use crate::resend_map::MessageKey;
 use crate::utils::u24;
-use crate::{Message, Reliability};
+use crate::{Message, Priority, Reliability};
 
 pin_project! {
     pub(crate) struct Fragment<F> {
         #[pin]
         frame: F,
         mtu: u16,
-        reliable_write_index: u24,
+        link: SharedLink,
         order_write_index: Vec<u24>,
+        // the sequence of the sequenced messages after the last ordered one on each channel
+        seq_write_index: Vec<u24>,
         parted_id_write: u16,
     }
 }
 
 pub(crate) trait Fragmented: Sized {
-    fn fragmented(self, mtu: u16, max_channels: usize) -> Fragment<Self>;
+    fn fragmented(self, mtu: u16, max_channels: usize, link: SharedLink) -> Fragment<Self>;
 }
 
 impl<F> Fragmented for F
 where
-    F: Sink<Frame, Error = CodecError>,
+    F: Sink<(Frame, Priority), Error = CodecError>,
 {
-    fn fragmented(self, mtu: u16, max_channels: usize) -> Fragment<Self> {
+    fn fragmented(self, mtu: u16, max_channels: usize, link: SharedLink) -> Fragment<Self> {
         Fragment {
             frame: self,
             mtu,
-            reliable_write_index: 0.into(),
+            link,
             order_write_index: std::iter::repeat(0.into()).take(max_channels).collect(),
+            seq_write_index: std::iter::repeat(0.into()).take(max_channels).collect(),
             parted_id_write: 0,
         }
     }
 }
 
+/// Advance the write indices of the channel after a message is sent. The sequence restarts after
+/// each ordered message.
+fn advance_write_index(
+    order_write_index: &mut [u24],
+    seq_write_index: &mut [u24],
+    reliability: Reliability,
+    channel: usize,
+) {
+    if reliability.is_sequenced() {
+        seq_write_index[channel] = seq_write_index[channel].wrapping_add(1);
+    } else if reliability.is_sequenced_or_ordered() {
+        order_write_index[channel] = order_write_index[channel].wrapping_add(1);
+        seq_write_index[channel] = 0.into();
+    }
+}
+
 impl<F> Sink<Message> for Fragment<F>
 where
-    F: Sink<Frame, Error = CodecError>,
+    F: Sink<(Frame, Priority), Error = CodecError>,
 {
     type Error = CodecError;
 
@@ -56,10 +77,23 @@ where
         let mut this = self.project();
         let mut reliability = msg.get_reliability();
         let order_channel = msg.get_order_channel() as usize;
+        let priority = msg.get_priority();
+        let receipt = msg.get_receipt();
+        if msg.wants_unreliable_receipt() {
+            // the tagged frame is matched with its receipt id when packed into a frame set, it is
+            // not tagged if the receipts are disabled
+            reliability = if receipt.is_some() {
+                Reliability::UnreliableWithAckReceipt
+            } else {
+                Reliability::Unreliable
+            };
+        }
         let mut body = msg.into_data();
 
         // max_len is the maximum size of the frame body (excluding the fragment part option)
-        let max_len = *this.mtu as usize - FRAME_SET_HEADER_SIZE - reliability.size();
+        // the mtu may be lowered by the peer
+        let mtu = this.link.limit_mtu(*this.mtu);
+        let max_len = mtu as usize - FRAME_SET_HEADER_SIZE - reliability.size();
 
         if body.len() > max_len {
             // adjust reliability when packet needs splitting
@@ -71,16 +105,15 @@ where
             };
         }
 
-        // get reliable_frame_index and ordered part
+        // get reliable_frame_index, seq_frame_index and ordered part
         let mut common = || {
             let mut reliable_frame_index = None;
+            let mut seq_frame_index = None;
             let mut ordered = None;
             if reliability.is_reliable() {
-                reliable_frame_index = Some(*this.reliable_write_index);
-                *this.reliable_write_index += 1;
+                // shared with the raw frame sets
+                reliable_frame_index = Some(this.link.next_reliable_index());
             }
-            // TODO: sequencing
-
             if reliability.is_sequenced_or_ordered() {
                 if order_channel >= this.order_write_index.len() {
                     return Err(
@@ -92,29 +125,45 @@ where
                         )
                     );
                 }
+                // the sequenced ones share the frame index of the next ordered one
+                if reliability.is_sequenced() {
+                    seq_frame_index = Some(this.seq_write_index[order_channel]);
+                }
                 ordered = Some(Ordered {
                     frame_index: this.order_write_index[order_channel],
                     channel: order_channel as u8,
                 });
             }
-            Ok((reliable_frame_index, ordered))
+            Ok((reliable_frame_index, seq_frame_index, ordered))
         };
 
         if body.len() <= max_len {
             // not exceeding the mtu, no need to split.
-            let (reliable_frame_index, ordered) = common()?;
-            if reliability.is_sequenced_or_ordered() {
-                this.order_write_index[order_channel] += 1;
+            let (reliable_frame_index, seq_frame_index, ordered) = common()?;
+            advance_write_index(
+                this.order_write_index,
+                this.seq_write_index,
+                reliability,
+                order_channel,
+            );
+            if let Some(id) = receipt {
+                match reliable_frame_index {
+                    Some(index) => this.link.expect_receipt(MessageKey::Single(index), id),
+                    None if reliability == Reliability::UnreliableWithAckReceipt => {
+                        this.link.expect_unreliable_receipt(id);
+                    }
+                    None => {}
+                }
             }
             let frame = Frame {
                 flags: Flags::new(reliability, false),
                 reliable_frame_index,
-                seq_frame_index: None,
+                seq_frame_index,
                 ordered,
                 fragment: None,
                 body,
             };
-            return this.frame.start_send(frame);
+            return this.frame.start_send((frame, priority));
         }
 
         // subtract the fragment part option size
@@ -122,14 +171,17 @@ where
         let parted_size = body.len().div_ceil(per_len) as u32;
         let parted_id = *this.parted_id_write;
         *this.parted_id_write = this.parted_id_write.wrapping_add(1);
+        if let Some(id) = receipt {
+            this.link.expect_receipt(MessageKey::Parted(parted_id), id);
+        }
 
         // exceeding the mtu, split the data
         for parted_index in 0..parted_size {
-            let (reliable_frame_index, ordered) = common()?;
+            let (reliable_frame_index, seq_frame_index, ordered) = common()?;
             let frame = Frame {
                 flags: Flags::new(reliability, true),
                 reliable_frame_index,
-                seq_frame_index: None,
+                seq_frame_index,
                 ordered,
                 fragment: Some(connected::Fragment {
                     parted_size,
@@ -145,12 +197,15 @@ where
             // FIXME: poll_ready is not ensured before start_send. But it is ok because the next
             // layer has buffer(ie. next_frame.start_send will always return Ok, and never mess up
             // data)
-            this.frame.as_mut().start_send(frame)?;
+            this.frame.as_mut().start_send((frame, priority))?;
         }
 
-        if reliability.is_sequenced_or_ordered() {
-            this.order_write_index[order_channel] += 1;
-        }
+        advance_write_index(
+            this.order_write_index,
+            this.seq_write_index,
+            reliability,
+            order_channel,
+        );
 
         debug_assert!(
             body.remaining() == 0,
@@ -171,18 +226,22 @@ where
 
 #[cfg(test)]
 mod test {
+    use std::sync::Arc;
+
     use bytes::Bytes;
     use connected::Frames;
     use futures::SinkExt;
 
     use super::*;
+    use crate::link::TransferLink;
+    use crate::RoleContext;
 
     #[derive(Debug, Default)]
     struct DstSink {
         buf: Frames,
     }
 
-    impl Sink<Frame> for DstSink {
+    impl Sink<(Frame, Priority)> for DstSink {
         type Error = CodecError;
 
         fn poll_ready(
@@ -192,7 +251,10 @@ mod test {
             Poll::Ready(Ok(()))
         }
 
-        fn start_send(mut self: Pin<&mut Self>, item: Frame) -> Result<(), Self::Error> {
+        fn start_send(
+            mut self: Pin<&mut Self>,
+            (item, _): (Frame, Priority),
+        ) -> Result<(), Self::Error> {
             self.buf.push(item);
             Ok(())
         }
@@ -214,7 +276,8 @@ mod test {
 
     #[tokio::test]
     async fn test_fragmented_works() {
-        let mut dst = DstSink::default().fragmented(50, 8);
+        let mut dst =
+            DstSink::default().fragmented(50, 8, TransferLink::new_arc(RoleContext::test_server()));
         // 1
         dst.send(Message::new(
             Reliability::ReliableOrdered,
@@ -260,7 +323,7 @@ mod test {
 
         assert_eq!(dst.order_write_index[0].to_u32(), 1);
         assert_eq!(dst.order_write_index[1].to_u32(), 1);
-        assert_eq!(dst.reliable_write_index.to_u32(), 7);
+        assert_eq!(dst.link.seq_counters().next_reliable_index, 7);
 
         assert_eq!(dst.frame.buf.len(), 6);
         // adjusted
@@ -268,9 +331,95 @@ mod test {
         assert_eq!(dst.frame.buf[5].flags.reliability, Reliability::Reliable);
     }
 
+    #[tokio::test]
+    async fn test_fragmented_sequenced() {
+        let mut dst =
+            DstSink::default().fragmented(50, 2, TransferLink::new_arc(RoleContext::test_server()));
+        for (reliability, channel) in [
+            (Reliability::UnreliableSequenced, 0),
+            (Reliability::UnreliableSequenced, 0),
+            (Reliability::ReliableOrdered, 0),
+            (Reliability::ReliableSequenced, 0),
+            (Reliability::ReliableSequenced, 1),
+        ] {
+            dst.send(Message::new(
+                reliability,
+                channel,
+                Bytes::from_static(b"hello"),
+            ))
+            .await
+            .unwrap();
+        }
+
+        let indices = dst
+            .frame
+            .buf
+            .iter()
+            .map(|frame| {
+                let ordered = frame.ordered.unwrap();
+                (
+                    ordered.channel,
+                    ordered.frame_index.to_u32(),
+                    frame.seq_frame_index.map(u24::to_u32),
+                )
+            })
+            .collect::<Vec<_>>();
+        // the sequenced ones share the frame index of the next ordered one, and the sequence
+        // restarts after it
+        assert_eq!(
+            indices,
+            [
+                (0, 0, Some(0)),
+                (0, 0, Some(1)),
+                (0, 0, None),
+                (0, 1, Some(0)),
+                (1, 0, Some(0)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_write_index_wrap_around() {
+        let mut dst =
+            DstSink::default().fragmented(50, 1, TransferLink::new_arc(RoleContext::test_server()));
+        dst.link.fast_forward_reliable_index(u24::MAX);
+        dst.order_write_index[0] = u24::MAX;
+        dst.seq_write_index[0] = u24::MAX;
+        for reliability in [
+            Reliability::ReliableSequenced,
+            Reliability::ReliableOrdered,
+            Reliability::ReliableOrdered,
+        ] {
+            dst.send(Message::new(reliability, 0, Bytes::from_static(b"hello")))
+                .await
+                .unwrap();
+        }
+
+        let indices = dst
+            .frame
+            .buf
+            .iter()
+            .map(|frame| {
+                (
+                    frame.reliable_frame_index.unwrap().to_u32(),
+                    frame.ordered.unwrap().frame_index.to_u32(),
+                    frame.seq_frame_index.map(u24::to_u32),
+                )
+            })
+            .collect::<Vec<_>>();
+        let max = u24::MAX.to_u32();
+        assert_eq!(
+            indices,
+            [(max, max, Some(max)), (0, max, None), (1, 0, None)]
+        );
+        assert_eq!(dst.link.seq_counters().next_reliable_index, 2);
+        assert_eq!(dst.order_write_index[0].to_u32(), 1);
+    }
+
     #[tokio::test]
     async fn test_fragmented_fulfill_one_packet() {
-        let mut dst = DstSink::default().fragmented(50, 8);
+        let mut dst =
+            DstSink::default().fragmented(50, 8, TransferLink::new_arc(RoleContext::test_server()));
         dst.send(Message::new(
             Reliability::ReliableOrdered,
             0,
@@ -285,7 +434,8 @@ mod test {
 
     #[tokio::test]
     async fn test_fragmented_split_packet() {
-        let mut dst = DstSink::default().fragmented(50, 8);
+        let mut dst =
+            DstSink::default().fragmented(50, 8, TransferLink::new_arc(RoleContext::test_server()));
         dst.send(Message::new(
             Reliability::ReliableOrdered,
             0,
@@ -310,4 +460,53 @@ mod test {
         assert_eq!(fragment.parted_id, 0);
         assert_eq!(fragment.parted_index, 1);
     }
+
+    #[tokio::test]
+    async fn test_fragmented_mtu_lowered() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let mut dst = DstSink::default().fragmented(100, 8, Arc::clone(&link));
+        let data = Bytes::from_iter(std::iter::repeat(0xfe).take(100));
+        dst.send(Message::new(Reliability::ReliableOrdered, 0, data.clone()))
+            .await
+            .unwrap();
+        assert_eq!(dst.frame.buf.len(), 2);
+        assert!(dst.frame.buf[0].size() > 50 - FRAME_SET_HEADER_SIZE);
+
+        // the frame sets of 100 bytes are black-holed by the path
+        link.set_negotiated_mtu(100);
+        link.enable_mtu_ladder(vec![50]);
+        link.black_holed(100);
+        dst.send(Message::new(Reliability::ReliableOrdered, 0, data.clone()))
+            .await
+            .unwrap();
+        assert_eq!(dst.frame.buf.len(), 2 + 4);
+        for frame in &dst.frame.buf[2..] {
+            assert!(frame.size() <= 50 - FRAME_SET_HEADER_SIZE);
+        }
+
+        // mtu never grows
+        link.update_mtu(200);
+        dst.send(Message::new(Reliability::ReliableOrdered, 0, data))
+            .await
+            .unwrap();
+        assert_eq!(dst.frame.buf.len(), 2 + 4 + 2);
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_datagram_size_capped() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        link.cap_datagram_size(60);
+        let mut dst = DstSink::default().fragmented(100, 8, Arc::clone(&link));
+        dst.send(Message::new(
+            Reliability::ReliableOrdered,
+            0,
+            Bytes::from_iter(std::iter::repeat(0xfe).take(100)),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(dst.frame.buf.len(), 3);
+        for frame in &dst.frame.buf {
+            assert!(frame.size() <= 60 - FRAME_SET_HEADER_SIZE);
+        }
+    }
 }
@@ -1,5 +1,17 @@
 mod body;
+#[cfg(feature = "compression")]
+mod compress;
+#[cfg(feature = "encryption")]
+mod encrypt;
 mod fragment;
+mod trace_id;
 
 pub(super) use self::body::*;
+#[cfg(feature = "compression")]
+pub(crate) use self::compress::MIN_COMPRESSION_THRESHOLD;
+#[cfg(feature = "compression")]
+pub(super) use self::compress::*;
+#[cfg(feature = "encryption")]
+pub(super) use self::encrypt::*;
 pub(super) use self::fragment::*;
+pub(super) use self::trace_id::*;
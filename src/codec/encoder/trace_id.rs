@@ -0,0 +1,157 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{BufMut, BytesMut};
+use futures::Sink;
+use pin_project_lite::pin_project;
+
+use crate::link::SharedLink;
+use crate::packet::connected::{FrameBody, CAPABILITY_TRACE_ID, HEADER_ESCAPED, HEADER_WRAPPED};
+use crate::packet::PackType;
+use crate::Message;
+
+pin_project! {
+    // Trace id layer, prefix the messages with their trace ids before the compression once the
+    // propagation is negotiated, and escape the untraced ones starting with the header. The
+    // internal frame bodies never carry the trace ids.
+    pub(crate) struct TraceIdEncoder<F> {
+        #[pin]
+        frame: F,
+        link: SharedLink,
+    }
+}
+
+pub(crate) trait TraceIdEncoded: Sized {
+    fn trace_id_encoded(self, link: SharedLink) -> TraceIdEncoder<Self>;
+}
+
+impl<F> TraceIdEncoded for F
+where
+    F: Sink<Message>,
+{
+    fn trace_id_encoded(self, link: SharedLink) -> TraceIdEncoder<Self> {
+        TraceIdEncoder { frame: self, link }
+    }
+}
+
+impl<F> Sink<Message> for TraceIdEncoder<F>
+where
+    F: Sink<Message>,
+{
+    type Error = F::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: Message) -> Result<(), Self::Error> {
+        let this = self.project();
+        let mut buf = BytesMut::with_capacity(10 + msg.get_data().len());
+        buf.put_u8(PackType::Traced as u8);
+        if let Some(trace_id) = msg.get_trace_id()
+            && this.link.trace_id_negotiated()
+        {
+            buf.put_u8(HEADER_WRAPPED);
+            buf.put_u64(trace_id);
+        } else if msg.get_data().first() == Some(&(PackType::Traced as u8))
+            && this.link.capability_applies(CAPABILITY_TRACE_ID)
+        {
+            // the peer takes any body starting with the header as traced, including the ones sent
+            // before the client learns the negotiation
+            buf.put_u8(HEADER_ESCAPED);
+        } else {
+            return this.frame.start_send(msg);
+        }
+        buf.put_slice(msg.get_data());
+        let mut traced = Message::new(msg.get_reliability(), msg.get_order_channel(), buf.freeze());
+        traced.set_priority(msg.get_priority());
+        traced.set_ack_receipt(msg.get_ack_receipt());
+        this.frame.start_send(traced)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Message>::poll_flush(self.project().frame, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<Message>::poll_close(self.project().frame, cx)
+    }
+}
+
+impl<F> Sink<FrameBody> for TraceIdEncoder<F>
+where
+    F: Sink<FrameBody>,
+{
+    type Error = F::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().frame.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, body: FrameBody) -> Result<(), Self::Error> {
+        self.project().frame.start_send(body)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<FrameBody>::poll_flush(self.project().frame, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<FrameBody>::poll_close(self.project().frame, cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::SinkExt;
+
+    use super::*;
+    use crate::link::TransferLink;
+    use crate::{Reliability, RoleContext};
+
+    #[tokio::test]
+    async fn test_trace_id_encode_works() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        link.enable_trace_id_propagation();
+        let mut dst = Vec::<Message>::new().trace_id_encoded(Arc::clone(&link));
+        let data = Bytes::from_static(b"hello");
+        let headed = Bytes::from_static(&[PackType::Traced as u8, 1, 2, 3]);
+
+        // not negotiated, the peer may have negotiated it already
+        dst.send(Message::new(Reliability::Reliable, 0, data.clone()).with_trace_id(114514))
+            .await
+            .unwrap();
+        dst.send(Message::new(Reliability::Reliable, 0, headed.clone()).with_trace_id(114514))
+            .await
+            .unwrap();
+        link.negotiate(CAPABILITY_TRACE_ID);
+        dst.send(Message::new(Reliability::Reliable, 0, data.clone()).with_trace_id(114514))
+            .await
+            .unwrap();
+        dst.send(Message::new(Reliability::Reliable, 0, data.clone()))
+            .await
+            .unwrap();
+        dst.send(Message::new(Reliability::Reliable, 0, headed.clone()))
+            .await
+            .unwrap();
+
+        let sent = dst.frame;
+        assert_eq!(sent[0].get_data(), &data);
+        // the body starting with the header is escaped before the trace ids are propagated
+        let on_wire = sent[1].get_data();
+        assert_eq!(on_wire[..2], [PackType::Traced as u8, HEADER_ESCAPED]);
+        assert_eq!(on_wire[2..], headed);
+        let on_wire = sent[2].get_data();
+        assert_eq!(on_wire[..2], [PackType::Traced as u8, HEADER_WRAPPED]);
+        assert_eq!(on_wire[2..10], 114514_u64.to_be_bytes());
+        assert_eq!(on_wire[10..], data);
+        assert_eq!(sent[3].get_data(), &data);
+        // the untraced body starting with the header is escaped
+        let on_wire = sent[4].get_data();
+        assert_eq!(on_wire[..2], [PackType::Traced as u8, HEADER_ESCAPED]);
+        assert_eq!(on_wire[2..], headed);
+    }
+}
@@ -7,28 +7,45 @@ mod decoder;
 /// Frames pipeline encoder
 mod encoder;
 
-/// Tokio codec helper
-#[cfg(feature = "tokio-udp")]
-pub(crate) mod tokio;
+/// Chaos injection on the real UDP path
+#[cfg(feature = "chaos")]
+pub(crate) mod chaos;
 
-use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::{fmt, io};
 
 use bytes::BytesMut;
 use futures::{Sink, Stream, StreamExt};
 use log::{debug, trace};
 
-use self::decoder::{BodyDecoded, DeFragmented, Deduplicated, Ordered, TracePending};
-use self::encoder::{BodyEncoded, Fragmented};
+#[cfg(feature = "compression")]
+use self::decoder::Decompressed;
+#[cfg(feature = "encryption")]
+use self::decoder::Decrypted;
+pub(crate) use self::decoder::ReassemblyQuota;
+use self::decoder::{
+    BodyDecoded, DeFragmented, Deduplicated, GamePacketFiltered, Ordered, TraceIdDecoded,
+    TracePending,
+};
+#[cfg(feature = "compression")]
+use self::encoder::Compressed;
+#[cfg(feature = "encryption")]
+use self::encoder::Encrypted;
+#[cfg(feature = "compression")]
+pub(crate) use self::encoder::MIN_COMPRESSION_THRESHOLD;
+use self::encoder::{BodyEncoded, Fragmented, TraceIdEncoded};
 use crate::errors::CodecError;
-use crate::link::SharedLink;
+use crate::io::OrderGapPolicy;
+use crate::link::{Anomaly, SharedLink};
 use crate::packet::connected::{Frame, FrameBody, FrameSet, FramesMut};
+use crate::runtime::AsyncUdpSocket;
 use crate::utils::Logged;
-use crate::{Message, RoleContext};
+use crate::{Message, Priority, RoleContext};
 
 /// Codec config
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct Config {
     /// Limit the max size of a parted frames set, 0 means no limit
     /// It will abort the split frame if the `parted_size` reaches limit.
@@ -40,6 +57,31 @@ pub(crate) struct Config {
     pub(crate) max_parted_count: usize,
     /// Maximum ordered channel, the value should be less than 256
     pub(crate) max_channels: usize,
+    /// The quota of parted frames sets being reassembled shared across connections, `None`
+    /// means no limit
+    pub(crate) reassembly_quota: Option<ReassemblyQuota>,
+    /// The allowed game packet ids (the first byte of the user packets) sorted, `None` means
+    /// all are allowed
+    pub(crate) game_packet_ids: Option<Vec<u8>>,
+    /// The handling of the ordered frames stuck behind a missing index on teardown
+    pub(crate) order_gap_policy: OrderGapPolicy,
+    /// The count of the reliable frame indices tracked by the deduplication past the first
+    /// missing one
+    pub(crate) dedup_window: usize,
+    /// The callback observing the errors of decoding the frames, `None` means they are only
+    /// logged
+    pub(crate) on_error: Option<OnCodecError>,
+}
+
+/// The callback observing the errors of decoding the frames of the connections along with the
+/// peer address
+#[derive(Clone)]
+pub(crate) struct OnCodecError(pub(crate) Arc<dyn Fn(&CodecError, SocketAddr) + Send + Sync>);
+
+impl fmt::Debug for OnCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnCodecError")
+    }
 }
 
 impl Default for Config {
@@ -49,6 +91,11 @@ impl Default for Config {
             max_parted_size: 256,
             max_parted_count: 256,
             max_channels: 1,
+            reassembly_quota: None,
+            game_packet_ids: None,
+            order_gap_policy: OrderGapPolicy::Discard,
+            dedup_window: 65536,
+            on_error: None,
         }
     }
 }
@@ -70,6 +117,36 @@ pub(crate) trait AsyncSocket: Unpin {
     ) -> Poll<io::Result<usize>>;
 }
 
+impl<S: AsyncUdpSocket> AsyncSocket for Arc<S> {
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut BytesMut,
+    ) -> Poll<io::Result<SocketAddr>> {
+        S::poll_recv_from(self, cx, buf)
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        S::poll_send_to(self, cx, buf, target)
+    }
+}
+
+/// The socket retained by each connection, it's used to query the bound address.
+pub(crate) trait BoundSocket: Send + Sync {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl<S: AsyncUdpSocket> BoundSocket for S {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        S::local_addr(self)
+    }
+}
+
 /// Frames pipeline decoder
 /// It will convert the stream of raw frames into defragmented, deduplicated and ordered frames.
 pub(crate) trait Decoded {
@@ -91,18 +168,46 @@ where
         link: SharedLink,
         role: RoleContext,
     ) -> impl Stream<Item = FrameBody> {
-        self.map(Ok)
+        let anomaly_link = Arc::clone(&link);
+        let on_error = config.on_error.clone();
+        let filter_link = Arc::clone(&link);
+        let trace_id_link = Arc::clone(&link);
+        let body_link = Arc::clone(&link);
+        #[cfg(feature = "encryption")]
+        let decrypt_link = Arc::clone(&link);
+        #[cfg(feature = "compression")]
+        let decompress_link = Arc::clone(&link);
+        let decoded = self
+            .map(Ok)
             .trace_pending()
-            .deduplicated()
-            .defragmented(config.max_parted_size, config.max_parted_count, link)
-            .ordered(config.max_channels)
-            .body_decoded()
+            .deduplicated(config.dedup_window)
+            .defragmented(
+                config.max_parted_size,
+                config.max_parted_count,
+                config.reassembly_quota,
+                link,
+            )
+            .ordered(config.max_channels, config.order_gap_policy)
+            .body_decoded(body_link);
+        #[cfg(feature = "encryption")]
+        let decoded = decoded.decrypted(decrypt_link);
+        #[cfg(feature = "compression")]
+        let decoded = decoded.decompressed(decompress_link);
+        decoded
+            .trace_id_decoded(trace_id_link)
+            .game_packet_filtered(config.game_packet_ids, filter_link)
             .logged_all(
                 move |pack| {
                     trace!("[{role}] received packet: {:?}", pack);
                 },
                 move |err| {
+                    anomaly_link.record_anomaly(Anomaly::DecodeError);
                     debug!("[{role}] got codec error: {err} when pipelining packets");
+                    if let Some(on_error) = &on_error
+                        && let Some(addr) = anomaly_link.peer_addr()
+                    {
+                        (on_error.0)(err, addr);
+                    }
                 },
             )
     }
@@ -121,7 +226,7 @@ pub(crate) trait Encoded {
 
 impl<F> Encoded for F
 where
-    F: Sink<Frame, Error = CodecError>,
+    F: Sink<(Frame, Priority), Error = CodecError>,
 {
     fn frame_encoded(
         self,
@@ -129,7 +234,14 @@ where
         config: Config,
         link: SharedLink,
     ) -> impl Sink<Message, Error = CodecError> + Sink<FrameBody, Error = CodecError> {
-        self.fragmented(mtu, config.max_channels).body_encoded(link)
+        let encoded = self
+            .fragmented(mtu, config.max_channels, Arc::clone(&link))
+            .body_encoded(Arc::clone(&link));
+        #[cfg(feature = "encryption")]
+        let encoded = encoded.encrypted(Arc::clone(&link));
+        #[cfg(feature = "compression")]
+        let encoded = encoded.compressed(Arc::clone(&link));
+        encoded.trace_id_encoded(link)
     }
 }
 
@@ -265,7 +377,7 @@ pub mod micro_bench {
         async fn bench_decoded_checked(self) {
             use bytes::Buf as _;
 
-            let config = self.config;
+            let config = self.config.clone();
             let data = self.data.clone();
             let link = TransferLink::new_arc(RoleContext::test_server());
 
@@ -284,7 +396,7 @@ pub mod micro_bench {
 
         #[allow(clippy::semicolon_if_nothing_returned)]
         pub async fn bench_decoded(self) {
-            let config = self.config;
+            let config = self.config.clone();
             let link = TransferLink::new_arc(RoleContext::test_server());
 
             let stream = self
@@ -322,3 +434,125 @@ pub mod micro_bench {
         bench.bench_decoded_checked().await;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::{Bytes, BytesMut};
+    use futures::{FutureExt, StreamExt};
+
+    use super::{Config, Decoded};
+    use crate::link::TransferLink;
+    use crate::packet::connected::{
+        Flags, Fragment, Frame, FrameBody, FrameSet, FramesMut, Ordered,
+    };
+    use crate::{Reliability, RoleContext};
+
+    fn ordered_frame(
+        reliable_index: u32,
+        order_index: u32,
+        fragment: Option<Fragment>,
+        body: &[u8],
+    ) -> FrameSet<FramesMut> {
+        FrameSet {
+            seq_num: reliable_index.into(),
+            set: vec![Frame {
+                flags: Flags::new(Reliability::ReliableOrdered, fragment.is_some()),
+                reliable_frame_index: Some(reliable_index.into()),
+                seq_frame_index: None,
+                ordered: Some(Ordered {
+                    frame_index: order_index.into(),
+                    channel: 0,
+                }),
+                fragment,
+                body: BytesMut::from(body),
+            }],
+        }
+    }
+
+    fn sequenced_frame(
+        reliable_index: u32,
+        seq_index: u32,
+        fragment: Option<Fragment>,
+        body: &[u8],
+    ) -> FrameSet<FramesMut> {
+        let mut frame_set = ordered_frame(reliable_index, 0, fragment, body);
+        frame_set.set[0].flags = Flags::new(Reliability::ReliableSequenced, fragment.is_some());
+        frame_set.set[0].seq_frame_index = Some(seq_index.into());
+        frame_set
+    }
+
+    fn part(parted_index: u32) -> Option<Fragment> {
+        Some(Fragment {
+            parted_size: 3,
+            parted_id: 0,
+            parted_index,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_ordered_resumes_after_lost_fragment() {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let mut decoded = rx.frame_decoded(Config::default(), link, RoleContext::test_server());
+
+        // the first ordered message is split into 3 parts, the 2nd part is lost
+        tx.unbounded_send(ordered_frame(0, 0, part(0), b"\xfe1"))
+            .unwrap();
+        tx.unbounded_send(ordered_frame(2, 0, part(2), b"3"))
+            .unwrap();
+        tx.unbounded_send(ordered_frame(3, 1, None, b"\xfe4"))
+            .unwrap();
+        tx.unbounded_send(ordered_frame(4, 2, None, b"\xfe5"))
+            .unwrap();
+        // the following ordered messages are held until the reassembly completes
+        assert!(decoded.next().now_or_never().is_none());
+
+        // the lost part is retransmitted in a new frame set
+        let mut resent = ordered_frame(1, 0, part(1), b"2");
+        resent.seq_num = 5.into();
+        tx.unbounded_send(resent).unwrap();
+        drop(tx);
+
+        let bodies: Vec<_> = decoded.collect().await;
+        assert_eq!(
+            bodies,
+            vec![
+                FrameBody::User(Bytes::from_static(b"\xfe123")),
+                FrameBody::User(Bytes::from_static(b"\xfe4")),
+                FrameBody::User(Bytes::from_static(b"\xfe5")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sequenced_drops_stale_reassembled() {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let mut decoded = rx.frame_decoded(Config::default(), link, RoleContext::test_server());
+
+        // the first sequenced message is split into 3 parts, the 2nd part arrives late
+        tx.unbounded_send(sequenced_frame(0, 0, part(0), b"\xfe1"))
+            .unwrap();
+        tx.unbounded_send(sequenced_frame(2, 0, part(2), b"3"))
+            .unwrap();
+        // the newer sequenced message is delivered at once
+        tx.unbounded_send(sequenced_frame(3, 1, None, b"\xfe4"))
+            .unwrap();
+        assert_eq!(
+            decoded.next().await.unwrap(),
+            FrameBody::User(Bytes::from_static(b"\xfe4"))
+        );
+
+        // the reassembled message is superseded, so are the older ones arriving out of order
+        tx.unbounded_send(sequenced_frame(1, 0, part(1), b"2"))
+            .unwrap();
+        tx.unbounded_send(sequenced_frame(5, 3, None, b"\xfe6"))
+            .unwrap();
+        tx.unbounded_send(sequenced_frame(4, 2, None, b"\xfe5"))
+            .unwrap();
+        drop(tx);
+
+        let bodies: Vec<_> = decoded.collect().await;
+        assert_eq!(bodies, vec![FrameBody::User(Bytes::from_static(b"\xfe6"))]);
+    }
+}
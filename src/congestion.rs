@@ -0,0 +1,104 @@
+use crate::utils::u24;
+
+/// The congestion window (in frame sets) before any loss
+pub(crate) const INITIAL_CWND: usize = 16;
+/// The lower bound of the congestion window
+pub(crate) const MIN_CWND: usize = 2;
+
+/// NewReno congestion controller counting in the reliable frame sets awaiting acknowledgement.
+///
+/// The window grows by one frame set per acknowledgement in the slow start, and by one frame
+/// set per window in the congestion avoidance. It halves on the loss (the NACK or the stale),
+/// at most once for the losses of the frame sets sent before the last halving (the recovery).
+#[derive(Debug, Clone)]
+pub(crate) struct NewReno {
+    cwnd: usize,
+    ssthresh: usize,
+    // the frame sets acknowledged since the window grew last time in the congestion avoidance
+    acked: usize,
+    // the losses of the frame sets before it belong to the current recovery
+    recovery: Option<u24>,
+}
+
+impl Default for NewReno {
+    fn default() -> Self {
+        Self {
+            cwnd: INITIAL_CWND,
+            ssthresh: usize::MAX,
+            acked: 0,
+            recovery: None,
+        }
+    }
+}
+
+impl NewReno {
+    /// The count of the frame sets allowed to be awaiting acknowledgement
+    pub(crate) fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    /// A frame set is acknowledged
+    pub(crate) fn on_ack(&mut self) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += 1;
+            return;
+        }
+        self.acked += 1;
+        if self.acked >= self.cwnd {
+            self.acked = 0;
+            self.cwnd += 1;
+        }
+    }
+
+    /// The frame set `seq_num` is lost, `next_seq_num` is the one of the next frame set to send
+    pub(crate) fn on_loss(&mut self, seq_num: u24, next_seq_num: u24) {
+        if let Some(recovery) = self.recovery
+            && seq_num.serial_diff(recovery) < 0
+        {
+            return;
+        }
+        self.ssthresh = (self.cwnd / 2).max(MIN_CWND);
+        self.cwnd = self.ssthresh;
+        self.acked = 0;
+        self.recovery = Some(next_seq_num);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NewReno, INITIAL_CWND, MIN_CWND};
+
+    #[test]
+    fn test_new_reno_window() {
+        let mut reno = NewReno::default();
+        assert_eq!(reno.cwnd(), INITIAL_CWND);
+
+        // slow start
+        for _ in 0..INITIAL_CWND {
+            reno.on_ack();
+        }
+        assert_eq!(reno.cwnd(), INITIAL_CWND * 2);
+
+        // halves once in the recovery
+        reno.on_loss(10.into(), 40.into());
+        assert_eq!(reno.cwnd(), INITIAL_CWND);
+        reno.on_loss(20.into(), 42.into());
+        assert_eq!(reno.cwnd(), INITIAL_CWND);
+
+        // congestion avoidance, one frame set per window
+        for _ in 0..INITIAL_CWND - 1 {
+            reno.on_ack();
+        }
+        assert_eq!(reno.cwnd(), INITIAL_CWND);
+        reno.on_ack();
+        assert_eq!(reno.cwnd(), INITIAL_CWND + 1);
+
+        // the loss after the recovery halves again, down to the lower bound
+        reno.on_loss(40.into(), 60.into());
+        assert_eq!(reno.cwnd(), (INITIAL_CWND + 1) / 2);
+        for seq_num in 0..10 {
+            reno.on_loss((60 + seq_num * 10).into(), (70 + seq_num * 10).into());
+        }
+        assert_eq!(reno.cwnd(), MIN_CWND);
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use std::{fmt, io};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::task::AtomicWaker;
+use futures::{future, Stream, StreamExt};
+use log::debug;
+
+use crate::client::{self, ConnectTo};
+use crate::errors::Error;
+use crate::io::{AnomalyInfo, Ping, RawFrameSet, IO};
+use crate::runtime::{AsyncUdpSocket, Runtime};
+use crate::server::{
+    self, Advertise, BanList, ConnEvents, MakeIncoming, PendingHandshakes, Shutdown,
+};
+
+/// The datagrams are received into the buffer of this size, no datagram exceeds it
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+type Datagram = (Bytes, SocketAddr);
+
+/// The endpoint sharing one socket between the server and the client roles, e.g. for the
+/// peer-to-peer applications. The datagrams from the addresses connected by [`Endpoint::connect`]
+/// are routed to those connections, and the others are served by the incoming.
+///
+/// ```no_run
+/// # async fn endpoint() -> Result<(), raknet_rs::errors::Error> {
+/// use raknet_rs::endpoint::IntoEndpoint;
+/// use raknet_rs::{client, server};
+///
+/// let socket = tokio::net::UdpSocket::bind("0.0.0.0:19132").await?;
+/// let mut endpoint = socket.into_endpoint(server::Config::new());
+/// let outgoing = endpoint
+///     .connect("127.0.0.1:19133".parse().unwrap(), client::Config::new())
+///     .await?;
+/// let incoming = endpoint.accept().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Endpoint<S, I> {
+    socket: Arc<S>,
+    routes: Arc<Routes>,
+    incoming: I,
+}
+
+impl<S, I> fmt::Debug for Endpoint<S, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Endpoint").finish_non_exhaustive()
+    }
+}
+
+pub trait IntoEndpoint: AsyncUdpSocket + Sized {
+    /// Make the endpoint serving the incoming connections by the config, the outgoing ones are
+    /// made by [`Endpoint::connect`]
+    fn into_endpoint(
+        self,
+        config: server::Config,
+    ) -> Endpoint<
+        Self,
+        impl Stream<Item = impl IO + Ping + RawFrameSet>
+            + AnomalyInfo
+            + PendingHandshakes
+            + BanList
+            + Advertise
+            + Shutdown
+            + ConnEvents,
+    >;
+}
+
+impl<S: AsyncUdpSocket> IntoEndpoint for S {
+    fn into_endpoint(
+        self,
+        config: server::Config,
+    ) -> Endpoint<
+        Self,
+        impl Stream<Item = impl IO + Ping + RawFrameSet>
+            + AnomalyInfo
+            + PendingHandshakes
+            + BanList
+            + Advertise
+            + Shutdown
+            + ConnEvents,
+    > {
+        let socket = Arc::new(self);
+        let (server_tx, server_rx) = mpsc::unbounded();
+        let closed = Arc::new(AtomicWaker::new());
+        let routes = Arc::new(Routes {
+            clients: parking_lot::Mutex::new(HashMap::new()),
+            server: server_tx,
+            closed: Arc::clone(&closed),
+        });
+        S::Runtime::spawn(dispatch(
+            Arc::clone(&socket),
+            Arc::downgrade(&routes),
+            closed,
+        ));
+        let incoming = DemuxSocket {
+            socket: Arc::clone(&socket),
+            routes: Arc::clone(&routes),
+            rx: parking_lot::Mutex::new(server_rx),
+            peer: None,
+        }
+        .make_incoming(config);
+        Endpoint {
+            socket,
+            routes,
+            incoming,
+        }
+    }
+}
+
+impl<S, I> Endpoint<S, I>
+where
+    S: AsyncUdpSocket,
+    I: Stream + Unpin,
+{
+    /// Accept the next incoming connection, `None` if the incoming ends
+    pub async fn accept(&mut self) -> Option<I::Item> {
+        self.incoming.next().await
+    }
+
+    /// The incoming serving the connections, e.g. to ban the ips or shut down
+    pub fn incoming(&mut self) -> &mut I {
+        &mut self.incoming
+    }
+
+    /// Connect to the address over the socket of the endpoint. The datagrams from the address
+    /// are routed to this connection until it is dropped, so an address is either connected or
+    /// served by the incoming.
+    ///
+    /// # Errors
+    /// Returns [`io::ErrorKind::AddrInUse`] if the address is connected already, or the error of
+    /// the handshake.
+    pub fn connect(
+        &self,
+        addr: SocketAddr,
+        config: client::Config,
+    ) -> impl Future<Output = Result<impl IO + Ping + RawFrameSet, Error>> {
+        let socket = self.route(addr);
+        async move { socket?.connect_to(addr, config).await }
+    }
+
+    /// The address the socket is bound to
+    ///
+    /// # Errors
+    /// Returns the error of the underlying socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl<S, I> Endpoint<S, I> {
+    /// Route the datagrams from the address to the returned socket
+    fn route(&self, addr: SocketAddr) -> Result<DemuxSocket<S>, Error> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut clients = self.routes.clients.lock();
+        if clients.contains_key(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "the address is connected already",
+            )
+            .into());
+        }
+        clients.insert(addr, tx);
+        Ok(DemuxSocket {
+            socket: Arc::clone(&self.socket),
+            routes: Arc::clone(&self.routes),
+            rx: parking_lot::Mutex::new(rx),
+            peer: Some(addr),
+        })
+    }
+}
+
+/// The routes of the received datagrams, the dispatcher stops once it is dropped
+struct Routes {
+    clients: parking_lot::Mutex<HashMap<SocketAddr, UnboundedSender<Datagram>>>,
+    server: UnboundedSender<Datagram>,
+    closed: Arc<AtomicWaker>,
+}
+
+impl Routes {
+    fn deliver(&self, datagram: Bytes, from: SocketAddr) {
+        let clients = self.clients.lock();
+        let tx = clients.get(&from).unwrap_or(&self.server);
+        // the receiver is dropped along with its route
+        let _ = tx.unbounded_send((datagram, from));
+    }
+}
+
+impl Drop for Routes {
+    fn drop(&mut self) {
+        self.closed.wake();
+    }
+}
+
+/// Receive the datagrams from the socket and route them until the routes are dropped
+async fn dispatch<S: AsyncUdpSocket>(
+    socket: Arc<S>,
+    routes: Weak<Routes>,
+    closed: Arc<AtomicWaker>,
+) {
+    let mut buf = BytesMut::with_capacity(MAX_DATAGRAM_SIZE);
+    loop {
+        buf.clear();
+        let res = future::poll_fn(|cx| {
+            closed.register(cx.waker());
+            if routes.strong_count() == 0 {
+                return Poll::Ready(None);
+            }
+            socket.poll_recv_from(cx, &mut buf).map(Some)
+        })
+        .await;
+        let from = match res {
+            Some(Ok(from)) => from,
+            Some(Err(err)) => {
+                debug!("failed to receive data: {err}");
+                continue;
+            }
+            None => return,
+        };
+        let Some(routes) = routes.upgrade() else {
+            return;
+        };
+        routes.deliver(Bytes::copy_from_slice(&buf), from);
+    }
+}
+
+/// The socket of one role of the endpoint, it receives the datagrams routed to it and sends by
+/// the shared socket
+struct DemuxSocket<S> {
+    socket: Arc<S>,
+    routes: Arc<Routes>,
+    rx: parking_lot::Mutex<UnboundedReceiver<Datagram>>,
+    // the address connected by the client role, `None` for the server role
+    peer: Option<SocketAddr>,
+}
+
+impl<S: AsyncUdpSocket> AsyncUdpSocket for DemuxSocket<S> {
+    type Runtime = S::Runtime;
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut BytesMut,
+    ) -> Poll<io::Result<SocketAddr>> {
+        // the sender lives in the routes as long as this socket
+        let Poll::Ready(Some((datagram, from))) = self.rx.lock().poll_next_unpin(cx) else {
+            return Poll::Pending;
+        };
+        let len = datagram.len().min(buf.capacity() - buf.len());
+        buf.put_slice(&datagram[..len]);
+        Poll::Ready(Ok(from))
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        self.socket.poll_send_to(cx, buf, target)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        self.socket.set_dscp(dscp)
+    }
+}
+
+impl<S> Drop for DemuxSocket<S> {
+    fn drop(&mut self) {
+        if let Some(peer) = self.peer {
+            self.routes.clients.lock().remove(&peer);
+        }
+    }
+}
@@ -1,13 +1,19 @@
+use std::time::Duration;
+
 #[derive(thiserror::Error, Debug)]
 pub enum CodecError {
     #[error("io error {0}")]
     IO(#[from] std::io::Error),
     #[error("invalid ip version {0}")]
     InvalidIPVer(u8),
-    #[error("expect IPv6 family 0x17, got {0}")]
+    #[error("expect IPv6 family 0x17 or 0x0a, got {0}")]
     InvalidIPV6Family(u16),
-    #[error("invalid packet length when decode {0}")]
-    InvalidPacketLength(&'static str),
+    #[error("invalid packet length when decode {context}, expect {expected} bytes, got {got}")]
+    InvalidPacketLength {
+        expected: usize,
+        got: usize,
+        context: &'static str,
+    },
     #[error("invalid record type {0}")]
     InvalidRecordType(u8),
     #[error("invalid packet type {0}, maybe it is a user packet")]
@@ -20,6 +26,23 @@ pub enum CodecError {
     AckCountExceed,
     #[error("magic number not matched, pos {0}, byte {1}")]
     MagicNotMatched(usize, u8),
+    #[error("invalid motd, field {0}")]
+    InvalidMotd(&'static str),
+    #[cfg(feature = "compression")]
+    #[error("decompress error, reason: {0}")]
+    Decompress(String),
+    #[cfg(feature = "encryption")]
+    #[error("decrypt error, reason: {0}")]
+    Decrypt(String),
+}
+
+/// The phase of the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakePhase {
+    /// Exchanging open connection requests and replies
+    Offline,
+    /// Exchanging connection request and accepted
+    Online,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -30,4 +53,30 @@ pub enum Error {
     Codec(#[from] CodecError),
     #[error("connection closed")]
     ConnectionClosed,
+    #[error("handshake timeout in {phase:?} phase")]
+    HandshakeTimeout { phase: HandshakePhase },
+    #[error("incompatible protocol, the server supports protocol version {server_protocol}")]
+    IncompatibleProtocol { server_protocol: u8 },
+    #[error("the ip recently connected to the server, retry after {retry_after:?}")]
+    RecentlyConnected { retry_after: Option<Duration> },
+    #[error("connection stalled without any progress from the peer")]
+    ConnectionStalled,
+    #[error("nothing is received from the peer within the idle timeout")]
+    IdleTimeout,
+    #[error("the peer is unreachable, the frames exceed the max retransmissions")]
+    PeerUnreachable,
+    #[error("the server rejected the connection request")]
+    ConnectionRequestFailed,
+    #[error("the server reached the maximum connections")]
+    NoFreeIncomingConnections,
+    #[error("the ip is banned by the server")]
+    ConnectionBanned,
+    #[error("the address is already connected to the server")]
+    AlreadyConnected,
+    #[error("no pong is received from the server within the timeout")]
+    PingTimeout,
+    #[error("the advertisement of the server is rejected")]
+    AdvertisementRejected,
+    #[error("order channel {channel} out of range, max channels {max_channels}")]
+    InvalidOrderChannel { channel: u8, max_channels: usize },
 }
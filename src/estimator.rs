@@ -5,8 +5,10 @@ use std::time::Duration;
 
 /// The granularity of the timer
 const TIMER_GRANULARITY: Duration = Duration::from_millis(1);
+/// The RTT assumed before any sample, as recommended in RFC9002#6.2.2
+pub(crate) const INITIAL_RTT: Duration = Duration::from_millis(333);
 /// Maximum ACK delay (i.e from the time the frame set is received to the time the ack is sent).
-const MAX_ACK_DELAY: Duration = Duration::from_millis(25);
+pub(crate) const MAX_ACK_DELAY: Duration = Duration::from_millis(25);
 
 pub(crate) trait RttEstimator {
     /// The current best RTT estimation.
@@ -33,7 +35,64 @@ pub(crate) trait AckDelayEstimator {
     fn update(&mut self, inflight: u64);
 }
 
-/// RTT estimation based on RFC6298
+/// Loss rate estimation based on the selective acknowledgements.
+/// A frame set is deemed lost when a frame set sent [`LOSS_THRESHOLD`] later has been acknowledged.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct LossEstimator {
+    acked: u64,
+    lost: u64,
+    // the loss rate smoothed over the recent frame sets
+    recent: f64,
+}
+
+/// The weight of a new sample in the recent loss rate
+const RECENT_LOSS_WEIGHT: f64 = 1.0 / 16.0;
+
+/// The reordering threshold (in sequence numbers) before a frame set is deemed lost
+pub(crate) const LOSS_THRESHOLD: u32 = 3;
+
+impl LossEstimator {
+    /// A frame set is acknowledged
+    pub(crate) fn on_ack(&mut self) {
+        self.acked += 1;
+        self.sample(0.0);
+    }
+
+    /// A frame set is deemed lost
+    pub(crate) fn on_loss(&mut self) {
+        self.lost += 1;
+        self.sample(1.0);
+    }
+
+    /// A frame set deemed lost is acknowledged later
+    pub(crate) fn on_spurious_loss(&mut self) {
+        self.lost -= 1;
+        self.acked += 1;
+        self.sample(0.0);
+    }
+
+    fn sample(&mut self, loss: f64) {
+        self.recent += (loss - self.recent) * RECENT_LOSS_WEIGHT;
+    }
+
+    /// The loss rate smoothed over the recent frame sets in [0, 1], it recovers after the loss
+    /// stops
+    pub(crate) fn recent_loss_rate(&self) -> f64 {
+        self.recent
+    }
+
+    /// The estimated loss rate in [0, 1]
+    pub(crate) fn loss_rate(&self) -> f64 {
+        let total = self.acked + self.lost;
+        if total == 0 {
+            return 0.0;
+        }
+        self.lost as f64 / total as f64
+    }
+}
+
+/// RTT estimation based on RFC6298, i.e. the Jacobson's algorithm. The caller should not sample
+/// the RTT of the retransmitted packets (Karn's algorithm).
 #[derive(Copy, Clone)]
 pub struct RFC6298Impl {
     /// The most recent RTT measurement made when receiving an ack for a previously unacked packet
@@ -47,7 +106,7 @@ pub struct RFC6298Impl {
 }
 
 impl RFC6298Impl {
-    fn new(initial_rtt: Duration) -> Self {
+    pub(crate) fn new(initial_rtt: Duration) -> Self {
         Self {
             latest: initial_rtt,
             smoothed: None,
@@ -74,6 +133,11 @@ impl RFC6298Impl {
         self.min
     }
 
+    /// The RTT variance, `None` if no sample is taken yet.
+    pub fn jitter(&self) -> Option<Duration> {
+        self.smoothed.map(|_| self.var)
+    }
+
     // PTO computed as described in RFC9002#6.2.1
     pub(crate) fn pto_base(&self) -> Duration {
         self.get() + cmp::max(4 * self.var, TIMER_GRANULARITY)
@@ -104,3 +168,34 @@ impl RFC6298Impl {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_jitter_reflects_variance() {
+        let mut steady = RFC6298Impl::new(INITIAL_RTT);
+        assert!(steady.jitter().is_none());
+        for _ in 0..32 {
+            steady.update(Duration::ZERO, Duration::from_millis(50));
+        }
+        assert_eq!(steady.get(), Duration::from_millis(50));
+        assert!(steady.jitter().unwrap() < Duration::from_millis(1));
+
+        let mut varied = RFC6298Impl::new(INITIAL_RTT);
+        for i in 0..32 {
+            let rtt = if i % 2 == 0 { 30 } else { 70 };
+            varied.update(Duration::ZERO, Duration::from_millis(rtt));
+        }
+        let jitter = varied.jitter().unwrap();
+        assert!(jitter > Duration::from_millis(15), "{jitter:?}");
+        assert!(jitter < Duration::from_millis(40), "{jitter:?}");
+
+        // the jitter decays once the samples settle down
+        for _ in 0..32 {
+            varied.update(Duration::ZERO, Duration::from_millis(50));
+        }
+        assert!(varied.jitter().unwrap() < jitter / 4);
+    }
+}
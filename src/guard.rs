@@ -7,13 +7,13 @@ use futures::Sink;
 use log::trace;
 use pin_project_lite::pin_project;
 
-use crate::errors::CodecError;
+use crate::errors::{CodecError, Error};
 use crate::link::SharedLink;
-use crate::packet::connected::{self, Frame, FrameSet, FramesRef};
+use crate::packet::connected::{self, Frame, FrameSet, Frames, FramesRef};
 use crate::packet::{Packet, FRAME_SET_HEADER_SIZE};
 use crate::resend_map::ResendMap;
-use crate::utils::u24;
-use crate::{PeerContext, RoleContext};
+use crate::utils::{metric, u24};
+use crate::{PeerContext, Priority, Reliability, RoleContext};
 
 pin_project! {
     // OutgoingGuard equips with Acknowledgement handler and packets buffer and provides
@@ -23,10 +23,17 @@ pin_project! {
         frame: F,
         link: SharedLink,
         seq_num_write_index: u24,
-        buf: VecDeque<Frame>,
+        buf: SendBuf,
+        // an immediate message is buffered, flush it without waiting for `poll_flush`
+        flush_immediate: bool,
         peer: PeerContext,
         role: RoleContext,
         cap: usize,
+        max_inflight: Option<usize>,
+        // the ordered channel being flushed alone
+        flushing_channel: Option<u8>,
+        // hold the buffered frames fitting in a single frame set until the flush
+        coalesce: bool,
         resend: ResendMap,
     }
 }
@@ -36,6 +43,8 @@ pub(crate) trait HandleOutgoing: Sized {
         self,
         link: SharedLink,
         cap: usize,
+        max_inflight: Option<usize>,
+        resend: ResendMap,
         peer: PeerContext,
         role: RoleContext,
     ) -> OutgoingGuard<Self>;
@@ -49,50 +58,86 @@ where
         self,
         link: SharedLink,
         cap: usize,
+        max_inflight: Option<usize>,
+        resend: ResendMap,
         peer: PeerContext,
         role: RoleContext,
     ) -> OutgoingGuard<Self> {
         assert!(cap > 0, "cap must larger than 0");
+        #[cfg(feature = "metrics")]
+        let resend = resend.with_metrics(link.metrics().cloned());
         OutgoingGuard {
             frame: self,
             link,
             seq_num_write_index: 0.into(),
-            buf: VecDeque::with_capacity(cap),
+            buf: SendBuf::with_capacity(cap),
+            flush_immediate: false,
             peer,
             role,
             cap,
-            resend: ResendMap::new(role),
+            max_inflight,
+            flushing_channel: None,
+            coalesce: false,
+            resend,
         }
     }
 }
 
+impl<F> OutgoingGuard<F> {
+    /// Coalesce the frames buffered between the flushes into the frame sets filled up to the mtu
+    pub(crate) fn with_coalesce(mut self, coalesce: bool) -> Self {
+        self.coalesce = coalesce;
+        self
+    }
+}
+
 impl<F> OutgoingGuard<F>
 where
     F: for<'a> Sink<(Packet<FramesRef<'a>>, SocketAddr), Error = CodecError>,
 {
-    /// Try to empty the outgoing buffer
-    fn try_empty(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), CodecError>> {
+    /// Try to empty the outgoing buffer, only the frames of the ordered channel are sent if it is
+    /// specified. The buffered frames fitting in a single frame set are held if it is
+    /// `coalescing`.
+    fn try_empty(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        channel: Option<u8>,
+        coalescing: bool,
+    ) -> Poll<Result<(), CodecError>> {
         let mut this = self.project();
 
         // empty incoming buffer
-        this.link.process_ack(this.resend);
-        this.link.process_resend(this.resend, this.buf);
+        this.link
+            .process_ack(this.resend, *this.seq_num_write_index);
+        this.link
+            .process_resend(this.resend, this.buf.resend_lane());
 
         // poll stale frames into buffer
-        this.resend.process_stales(this.buf);
+        this.resend.process_stales(this.buf.resend_lane());
+        if this.resend.is_unreachable() {
+            this.link.fail(Error::PeerUnreachable);
+        }
+        if let Some(size) = this.resend.take_black_hole() {
+            this.link.black_holed(size);
+        }
+        this.link.publish_unacked(this.resend);
+        // the stale frames are moved into the buffer, they are still awaiting acknowledgement
+        this.link
+            .check_watchdog(!this.resend.is_empty() || !this.buf.is_empty());
 
         ready!(this.frame.as_mut().poll_ready(cx))?;
         let mut sent = false;
+        // the mtu may be lowered by the peer
+        let mtu = this.link.limit_mtu(this.peer.mtu);
 
-        // TODO: Weighted Round-Robin
-
-        while !this.link.flush_empty() || !this.buf.is_empty() {
+        while has_pending(this.link, this.buf, channel) {
+            let mut progressed = false;
             // 1st. empty the nack
             if sent {
                 ready!(this.frame.as_mut().poll_ready(cx))?;
                 sent = false;
             }
-            if let Some(nack) = this.link.process_outgoing_nack(this.peer.mtu) {
+            if let Some(nack) = this.link.process_outgoing_nack(mtu) {
                 trace!(
                     "[{}] send ack {nack:?}, total count: {}",
                     this.role,
@@ -103,6 +148,7 @@ where
                     this.peer.addr,
                 ))?;
                 sent = true;
+                progressed = true;
             }
 
             // 2nd. empty the ack
@@ -110,7 +156,7 @@ where
                 ready!(this.frame.as_mut().poll_ready(cx))?;
                 sent = false;
             }
-            if let Some(ack) = this.link.process_outgoing_ack(this.peer.mtu) {
+            if let Some(ack) = this.link.process_outgoing_ack(mtu) {
                 trace!(
                     "[{}] send ack {ack:?}, total count: {}",
                     this.role,
@@ -121,6 +167,7 @@ where
                     this.peer.addr,
                 ))?;
                 sent = true;
+                progressed = true;
             }
 
             // 3rd. empty the unconnected packets
@@ -139,6 +186,7 @@ where
                     .as_mut()
                     .start_send((Packet::Unconnected(packet), this.peer.addr))?;
                 sent = true;
+                progressed = true;
             }
 
             // 4th. empty the frame set
@@ -147,30 +195,22 @@ where
                 sent = false;
             }
 
-            let mut frames = vec![];
-            let mut reliable = false;
-
-            // TODO: implement sliding window congestion control to select a proper transmission
-            // bandwidth
-            let mut remain_mtu = this.peer.mtu as usize - FRAME_SET_HEADER_SIZE;
-            while let Some(frame) = this.buf.back() {
-                if remain_mtu >= frame.size() {
-                    if frame.flags.reliability.is_reliable() {
-                        reliable = true;
-                    }
-                    remain_mtu -= frame.size();
-                    trace!(
-                        "[{}] send frame, seq_num: {}, reliable: {}, first byte: 0x{:02x}",
-                        this.role,
-                        *this.seq_num_write_index,
-                        reliable,
-                        frame.body[0],
-                    );
-                    frames.push(this.buf.pop_back().unwrap());
-                    continue;
+            // the raw frame set bypassing the fragmenter is sent as is, one each time
+            let raw = channel
+                .is_none()
+                .then(|| this.link.process_raw_frames())
+                .flatten();
+            // the tagged frames of the raw frame set have no receipt ids
+            let packed = raw.is_none();
+            let held = coalescing && holds(this.buf, *this.cap, mtu);
+            // the buffered frames are held back while the congestion window is full
+            let (mut frames, reliable) = match raw {
+                Some(raw) => raw,
+                None if this.resend.has_window() && !held => {
+                    pack_frames(this.buf, channel, mtu, this.role, *this.seq_num_write_index)
                 }
-                break;
-            }
+                None => (vec![], false),
+            };
             if !frames.is_empty() {
                 let frame_set = FrameSet {
                     seq_num: *this.seq_num_write_index,
@@ -181,48 +221,287 @@ where
                     this.peer.addr,
                 ))?;
                 sent = true;
+                progressed = true;
+                metric!(this.link.metrics(), frames_sent, frames.len());
+                metric!(
+                    this.link.metrics(),
+                    bytes_sent,
+                    FRAME_SET_HEADER_SIZE + frames.iter().map(Frame::size).sum::<usize>()
+                );
+                this.resend.on_sent();
+                // every frame set is acknowledged by the peer, but only the reliable frames are
+                // kept for resending, the unreliable frames are never retransmitted
+                let tagged = frames.iter().filter(|frame| is_tagged(frame)).count();
+                if packed && tagged > 0 {
+                    let ids = this.link.take_unreliable_receipts(tagged);
+                    this.resend.record_receipts(*this.seq_num_write_index, ids);
+                }
                 if reliable {
-                    // keep for resending
+                    frames.retain(|frame| frame.flags.reliability.is_reliable());
                     this.resend.record(*this.seq_num_write_index, frames);
                 }
-                *this.seq_num_write_index += 1;
+                *this.seq_num_write_index = this.seq_num_write_index.wrapping_add(1);
+            }
+            if !progressed {
+                break;
             }
         }
+        this.link.publish_unacked(this.resend);
+        this.link.publish_next_seq_num(*this.seq_num_write_index);
+        this.link.update_rto(this.resend.base_rto());
+        this.link.update_rtt(this.resend.rtt());
+        this.link.update_transfer_stats(this.resend.stats());
+        // the held acknowledgements are sent by the next flush after they are due
+        this.link.poll_held_acks(cx);
 
+        let pending = if coalescing && holds(this.buf, *this.cap, mtu) {
+            !this.link.flush_empty()
+        } else {
+            has_pending(this.link, this.buf, channel)
+        };
+        if pending {
+            // the congestion window is full, wake up when receiving acknowledgements or resending
+            this.link.turn_on_waking();
+            if this.resend.poll_wait(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+            return Poll::Pending;
+        }
         Poll::Ready(Ok(()))
     }
+
+    /// Wait until the buffered frames and the frames awaiting acknowledgement are below the cap.
+    /// It is passed once nothing is awaiting acknowledgement, so that a message split into more
+    /// frames than the cap never blocks forever.
+    fn poll_send_buf(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), CodecError>> {
+        if self.buf.len() + self.resend.unacked_frames() < self.cap {
+            return Poll::Ready(Ok(()));
+        }
+        // wake up when receiving acknowledgements or resending
+        self.link.turn_on_waking();
+        loop {
+            // the acknowledgements may arrive before turning on the waking
+            ready!(self.as_mut().try_empty(cx, None, false))?;
+            if self.buf.len() + self.resend.unacked_frames() < self.cap || self.resend.is_empty() {
+                break;
+            }
+            ready!(self.resend.poll_wait(cx));
+        }
+        self.link.turn_off_waking();
+        Poll::Ready(Ok(()))
+    }
+
+    /// Wait until the inflight reliable messages are below the limit
+    fn poll_inflight(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), CodecError>> {
+        let Some(max_inflight) = self.max_inflight else {
+            return Poll::Ready(Ok(()));
+        };
+        if self.resend.inflight_messages() < max_inflight {
+            return Poll::Ready(Ok(()));
+        }
+        // wake up when receiving acknowledgements or resending
+        self.link.turn_on_waking();
+        loop {
+            // the acknowledgements may arrive before turning on the waking
+            ready!(self.as_mut().try_empty(cx, None, false))?;
+            if self.resend.inflight_messages() < max_inflight || self.resend.is_empty() {
+                break;
+            }
+            ready!(self.resend.poll_wait(cx));
+        }
+        self.link.turn_off_waking();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The ordered channel of the frame
+fn frame_channel(frame: &Frame) -> Option<u8> {
+    frame.ordered.as_ref().map(|ordered| ordered.channel)
+}
+
+/// Whether the frame is an unreliable one tagged for a receipt
+fn is_tagged(frame: &Frame) -> bool {
+    frame.flags.reliability == Reliability::UnreliableWithAckReceipt
 }
 
-impl<F> Sink<Frame> for OutgoingGuard<F>
+/// Whether the buffered frame must be sent before the frame, i.e. they are of the same ordered
+/// channel, or they are both tagged unreliable frames matched with the receipt ids in order
+fn precedes(buffered: &Frame, frame: &Frame) -> bool {
+    match frame_channel(frame) {
+        Some(channel) => frame_channel(buffered) == Some(channel),
+        None => is_tagged(frame) && is_tagged(buffered),
+    }
+}
+
+/// The count of the lanes, one for each [`Priority`]
+const LANES: usize = 4;
+
+/// The outgoing buffer with a lane for each priority, the lanes of the higher priorities are
+/// drained first and the oldest frame of each lane is at the back. The resent frames join the
+/// high lane ahead of the new ones.
+#[derive(Debug)]
+struct SendBuf {
+    lanes: [VecDeque<Frame>; LANES],
+}
+
+impl SendBuf {
+    fn with_capacity(cap: usize) -> Self {
+        let mut lanes: [VecDeque<Frame>; LANES] = Default::default();
+        lanes[Priority::Medium as usize].reserve(cap);
+        Self { lanes }
+    }
+
+    fn len(&self) -> usize {
+        self.lanes.iter().map(VecDeque::len).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lanes.iter().all(VecDeque::is_empty)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Frame> {
+        self.lanes.iter().flatten()
+    }
+
+    fn resend_lane(&mut self) -> &mut VecDeque<Frame> {
+        &mut self.lanes[Priority::High as usize]
+    }
+
+    /// Buffer the frame in the lane of the priority. A sequenced or ordered frame is demoted to
+    /// the lowest lane buffering the frames of its channel, so that it never overtakes them. So
+    /// is a tagged unreliable frame among the tagged ones.
+    fn push(&mut self, frame: Frame, priority: Priority) {
+        let mut lane = priority as usize;
+        if (frame_channel(&frame).is_some() || is_tagged(&frame))
+            && let Some(lower) = (lane + 1..LANES).rev().find(|&lower| {
+                self.lanes[lower]
+                    .iter()
+                    .any(|buffered| precedes(buffered, &frame))
+            })
+        {
+            lane = lower;
+        }
+        self.lanes[lane].push_front(frame);
+    }
+}
+
+/// Return whether the buffered frames are held for coalescing, i.e. they fit in a single frame set
+/// and the buffer is not full
+fn holds(buf: &SendBuf, cap: usize, mtu: u16) -> bool {
+    buf.len() < cap
+        && buf.iter().map(Frame::size).sum::<usize>() <= mtu as usize - FRAME_SET_HEADER_SIZE
+}
+
+/// Return whether there are packets to send, only the frames of the ordered channel are counted
+/// if it is specified
+fn has_pending(link: &SharedLink, buf: &SendBuf, channel: Option<u8>) -> bool {
+    if channel.is_none() {
+        return !link.flush_empty() || !buf.is_empty();
+    }
+    !link.control_flush_empty() || buf.iter().any(|frame| frame_channel(frame) == channel)
+}
+
+/// Pack the frames (of the ordered channel if it is specified) from the buffer within the mtu in
+/// the order of the priorities, return the frames and whether there are reliable frames
+fn pack_frames(
+    buf: &mut SendBuf,
+    channel: Option<u8>,
+    mtu: u16,
+    role: &RoleContext,
+    seq_num: u24,
+) -> (Frames, bool) {
+    let mut frames = vec![];
+    let mut reliable = false;
+    let mut remain_mtu = (mtu as usize).saturating_sub(FRAME_SET_HEADER_SIZE);
+    'lanes: for lane in &mut buf.lanes {
+        let mut idx = lane.len();
+        while idx > 0 {
+            idx -= 1;
+            let frame = &lane[idx];
+            if channel.is_some() && frame_channel(frame) != channel {
+                continue;
+            }
+            // the frames fragmented before the mtu is lowered are sent alone
+            if remain_mtu >= frame.size() || frames.is_empty() {
+                if frame.flags.reliability.is_reliable() {
+                    reliable = true;
+                }
+                remain_mtu = remain_mtu.saturating_sub(frame.size());
+                trace!(
+                    "[{}] send frame, seq_num: {}, reliable: {}, first byte: 0x{:02x}",
+                    role,
+                    seq_num,
+                    reliable,
+                    frame.body[0],
+                );
+                frames.push(lane.remove(idx).unwrap());
+                continue;
+            }
+            break 'lanes;
+        }
+    }
+    (frames, reliable)
+}
+
+impl<F> Sink<(Frame, Priority)> for OutgoingGuard<F>
 where
     F: for<'a> Sink<(Packet<FramesRef<'a>>, SocketAddr), Error = CodecError>,
 {
     type Error = CodecError;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        let upstream = self.as_mut().try_empty(cx)?;
+        // the immediate frames are never held
+        let coalescing = self.coalesce && !self.flush_immediate;
+        let upstream = self.as_mut().try_empty(cx, None, coalescing)?;
 
         if self.buf.len() >= self.cap {
             debug_assert!(
                 upstream == Poll::Pending,
                 "OutgoingGuard::try_empty returns Ready but buffer still remains!"
             );
-            Poll::Pending
-        } else {
-            Poll::Ready(Ok(()))
+            return Poll::Pending;
+        }
+        if self.flush_immediate {
+            // the immediate frames are sent by the `try_empty` above
+            ready!(self.as_mut().project().frame.poll_flush(cx))?;
+            *self.as_mut().project().flush_immediate = false;
         }
+        // the frames awaiting acknowledgement are held by the resend map, they are counted as well
+        ready!(self.as_mut().poll_send_buf(cx))?;
+        self.poll_inflight(cx)
     }
 
-    fn start_send(self: Pin<&mut Self>, frame: Frame) -> Result<(), Self::Error> {
+    fn start_send(
+        self: Pin<&mut Self>,
+        (frame, priority): (Frame, Priority),
+    ) -> Result<(), Self::Error> {
         let this = self.project();
-        this.buf.push_front(frame);
+        if this.max_inflight.is_some() || this.link.receipts_enabled() {
+            this.resend.track_message(&frame);
+        }
+        if priority == Priority::Immediate {
+            *this.flush_immediate = true;
+        }
+        this.buf.push(frame, priority);
         // Always success
         Ok(())
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        ready!(self.as_mut().try_empty(cx))?;
-        debug_assert!(self.buf.is_empty() && self.link.flush_empty());
+        let this = self.as_mut().project();
+        if this.flushing_channel.is_none() {
+            *this.flushing_channel = this.link.take_flushing_channel();
+        }
+        let channel = *this.flushing_channel;
+        ready!(self.as_mut().try_empty(cx, channel, false))?;
+        *self.as_mut().project().flushing_channel = None;
+        debug_assert!(channel.is_some() || (self.buf.is_empty() && self.link.flush_empty()));
         self.project().frame.poll_flush(cx)
     }
 
@@ -231,8 +510,10 @@ where
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         // maybe go to sleep, turn on the waking
         self.link.turn_on_waking();
+        // the connection is going away, the received frame sets are acknowledged at once
+        self.link.disable_ack_delay();
         loop {
-            ready!(self.as_mut().try_empty(cx))?;
+            ready!(self.as_mut().try_empty(cx, None, false))?;
             debug_assert!(self.buf.is_empty() && self.link.flush_empty());
             ready!(self.as_mut().project().frame.poll_flush(cx))?;
             if self.resend.is_empty() {
@@ -250,4 +531,561 @@ where
     }
 }
 
-// TODO: test
+#[cfg(test)]
+impl<F> OutgoingGuard<F> {
+    /// Fast-forward the sequence number of the next frame set, test only
+    pub(crate) fn fast_forward_seq_num(&mut self, seq_num: u24) {
+        self.seq_num_write_index = seq_num;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use bytes::Bytes;
+    use futures::Sink;
+
+    use super::{pack_frames, HandleOutgoing, OutgoingGuard, SendBuf};
+    use crate::congestion::INITIAL_CWND;
+    use crate::errors::CodecError;
+    use crate::link::TransferLink;
+    use crate::packet::connected::{self, AckOrNack, Flags, Fragment, Frame, FramesRef};
+    use crate::packet::{Packet, FRAME_SET_HEADER_SIZE};
+    use crate::resend_map::ResendMap;
+    use crate::utils::tests::TestWaker;
+    use crate::utils::u24;
+    use crate::{PeerContext, Priority, Reliability, RoleContext};
+
+    #[derive(Default)]
+    struct TestSink {
+        sent: Vec<u32>,
+        // the count of frames in each frame set
+        frames: Vec<usize>,
+        flushes: usize,
+    }
+
+    impl<'a> Sink<(Packet<FramesRef<'a>>, SocketAddr)> for TestSink {
+        type Error = CodecError;
+
+        fn poll_ready(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(
+            mut self: Pin<&mut Self>,
+            (pack, _): (Packet<FramesRef<'a>>, SocketAddr),
+        ) -> Result<(), Self::Error> {
+            if let Packet::Connected(connected::Packet::FrameSet(frame_set)) = pack {
+                self.sent.push(frame_set.seq_num.to_u32());
+                self.frames.push(frame_set.set.len());
+            }
+            Ok(())
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            self.flushes += 1;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn ordered_frame(index: u32, channel: u8) -> Frame {
+        Frame {
+            flags: Flags::new(Reliability::ReliableOrdered, false),
+            reliable_frame_index: Some(index.into()),
+            seq_frame_index: None,
+            ordered: Some(connected::Ordered {
+                frame_index: index.into(),
+                channel,
+            }),
+            fragment: None,
+            body: Bytes::from_static(b"1"),
+        }
+    }
+
+    fn reliable_frame(index: u32, fragment: Option<Fragment>) -> Frame {
+        Frame {
+            flags: Flags::new(Reliability::Reliable, fragment.is_some()),
+            reliable_frame_index: Some(index.into()),
+            seq_frame_index: None,
+            ordered: None,
+            fragment,
+            body: Bytes::from_static(b"1"),
+        }
+    }
+
+    #[test]
+    fn test_max_inflight_messages() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let mut guard = TestSink::default().handle_outgoing(
+            link.clone(),
+            1024,
+            Some(2),
+            ResendMap::new(RoleContext::test_server()),
+            PeerContext {
+                addr: "0.0.0.0:0".parse().unwrap(),
+                mtu: 1400,
+                guid: None,
+            },
+            RoleContext::test_server(),
+        );
+        let mut guard = Pin::new(&mut guard);
+        let (waker, woken) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+
+        // a parted message with two frames is counted once
+        assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+        for parted_index in 0..2 {
+            let fragment = Fragment {
+                parted_size: 2,
+                parted_id: 0,
+                parted_index,
+            };
+            guard
+                .as_mut()
+                .start_send((
+                    reliable_frame(parted_index, Some(fragment)),
+                    Priority::Medium,
+                ))
+                .unwrap();
+        }
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+        guard
+            .as_mut()
+            .start_send((reliable_frame(2, None), Priority::Medium))
+            .unwrap();
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        assert_eq!(guard.frame.sent, vec![0, 1]);
+
+        // blocks until an earlier message is acknowledged
+        assert!(guard.as_mut().poll_ready(&mut cx).is_pending());
+        link.incoming_ack(AckOrNack::extend_from([0.into()].into_iter(), 1400).unwrap());
+        assert!(woken.woken.load(std::sync::atomic::Ordering::Relaxed));
+        assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn test_send_buf_cap_backpressure() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let mut guard = TestSink::default().handle_outgoing(
+            link.clone(),
+            4,
+            None,
+            ResendMap::new(RoleContext::test_server()).with_congestion_control(true),
+            PeerContext {
+                addr: "0.0.0.0:0".parse().unwrap(),
+                mtu: 1400,
+                guid: None,
+            },
+            RoleContext::test_server(),
+        );
+        let mut guard = Pin::new(&mut guard);
+        let (waker, woken) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+
+        for index in 0..4 {
+            assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+            guard
+                .as_mut()
+                .start_send((reliable_frame(index, None), Priority::Medium))
+                .unwrap();
+            assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        }
+        assert_eq!(guard.resend.unacked_frames(), 4);
+
+        // the sent frames awaiting acknowledgement fill the cap, the flush still goes through
+        assert!(guard.as_mut().poll_ready(&mut cx).is_pending());
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+
+        // the acknowledgement releases the backpressure
+        link.incoming_ack(AckOrNack::extend_from([0.into()].into_iter(), 1400).unwrap());
+        assert!(woken.woken.load(std::sync::atomic::Ordering::Relaxed));
+        assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+        assert_eq!(guard.resend.unacked_frames(), 3);
+
+        // the nacked frames are resent along with the new one, they are still counted
+        link.incoming_nack(AckOrNack::extend_from([1.into()].into_iter(), 1400).unwrap());
+        guard
+            .as_mut()
+            .start_send((reliable_frame(4, None), Priority::Medium))
+            .unwrap();
+        assert!(guard.as_mut().poll_ready(&mut cx).is_pending());
+        assert_eq!(guard.resend.unacked_frames() + guard.buf.len(), 4);
+    }
+
+    #[test]
+    fn test_congestion_window() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let mut guard = TestSink::default().handle_outgoing(
+            link.clone(),
+            1024,
+            None,
+            ResendMap::new(RoleContext::test_server()).with_congestion_control(true),
+            PeerContext {
+                // the mtu only fits one frame in each frame set
+                addr: "0.0.0.0:0".parse().unwrap(),
+                mtu: (FRAME_SET_HEADER_SIZE + reliable_frame(0, None).size()) as u16,
+                guid: None,
+            },
+            RoleContext::test_server(),
+        );
+        let mut guard = Pin::new(&mut guard);
+        let (waker, woken) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+
+        let count = INITIAL_CWND as u32 + 4;
+        for index in 0..count {
+            assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+            guard
+                .as_mut()
+                .start_send((reliable_frame(index, None), Priority::Medium))
+                .unwrap();
+        }
+        // the frame sets beyond the window are held back
+        assert!(guard.as_mut().poll_flush(&mut cx).is_pending());
+        assert_eq!(guard.frame.sent.len(), INITIAL_CWND);
+        assert_eq!(guard.buf.len(), 4);
+
+        // the acknowledgements open the window, it grows in the slow start
+        link.incoming_ack(AckOrNack::extend_from([0.into(), 1.into()].into_iter(), 1400).unwrap());
+        assert!(woken.woken.load(std::sync::atomic::Ordering::Relaxed));
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        assert_eq!(guard.frame.sent.len(), count as usize);
+        assert_eq!(guard.resend.cwnd(), Some(INITIAL_CWND + 2));
+
+        // the window halves on the nack, the resending frames are held back as well
+        link.incoming_nack(AckOrNack::extend_from([2.into(), 3.into()].into_iter(), 1400).unwrap());
+        assert!(guard.as_mut().poll_flush(&mut cx).is_pending());
+        assert_eq!(guard.resend.cwnd(), Some((INITIAL_CWND + 2) / 2));
+        assert_eq!(guard.buf.len(), 2);
+    }
+
+    #[test]
+    fn test_priority_lanes() {
+        let mut buf = SendBuf::with_capacity(8);
+        buf.push(reliable_frame(0, None), Priority::Low);
+        buf.push(reliable_frame(1, None), Priority::Medium);
+        buf.push(ordered_frame(2, 0), Priority::Low);
+        buf.push(reliable_frame(3, None), Priority::High);
+        // never overtakes the buffered frames of the same channel
+        buf.push(ordered_frame(4, 0), Priority::Immediate);
+        buf.push(ordered_frame(5, 1), Priority::Immediate);
+        assert_eq!(buf.len(), 6);
+
+        let (frames, reliable) =
+            pack_frames(&mut buf, None, 1400, &RoleContext::test_server(), 0.into());
+        assert!(reliable);
+        assert!(buf.is_empty());
+        let indices: Vec<u32> = frames
+            .iter()
+            .map(|frame| frame.reliable_frame_index.unwrap().to_u32())
+            .collect();
+        assert_eq!(indices, vec![5, 3, 1, 0, 2, 4]);
+    }
+
+    #[test]
+    fn test_immediate_flush() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let mut guard = TestSink::default().handle_outgoing(
+            link,
+            1024,
+            None,
+            ResendMap::new(RoleContext::test_server()),
+            PeerContext {
+                addr: "0.0.0.0:0".parse().unwrap(),
+                mtu: 1400,
+                guid: None,
+            },
+            RoleContext::test_server(),
+        );
+        let mut guard = Pin::new(&mut guard);
+        let (waker, _) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+        guard
+            .as_mut()
+            .start_send((reliable_frame(0, None), Priority::Medium))
+            .unwrap();
+        assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+        assert_eq!(guard.frame.flushes, 0);
+
+        // flushed by the next poll without waiting for poll_flush
+        guard
+            .as_mut()
+            .start_send((reliable_frame(1, None), Priority::Immediate))
+            .unwrap();
+        assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+        assert_eq!(guard.frame.flushes, 1);
+        assert_eq!(guard.frame.sent, vec![0, 1]);
+        assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+        assert_eq!(guard.frame.flushes, 1);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let mut guard = TestSink::default()
+            .handle_outgoing(
+                link,
+                1024,
+                None,
+                ResendMap::new(RoleContext::test_server()),
+                PeerContext {
+                    addr: "0.0.0.0:0".parse().unwrap(),
+                    mtu: 100,
+                    guid: None,
+                },
+                RoleContext::test_server(),
+            )
+            .with_coalesce(true);
+        let mut guard = Pin::new(&mut guard);
+        let (waker, _) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+        let mut feed = |guard: &mut Pin<&mut OutgoingGuard<TestSink>>, index, priority| {
+            assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+            guard
+                .as_mut()
+                .start_send((reliable_frame(index, None), priority))
+                .unwrap();
+        };
+
+        // the tiny frames within one flush are sent in one frame set
+        for index in 0..3 {
+            feed(&mut guard, index, Priority::Medium);
+        }
+        assert!(guard.frame.sent.is_empty());
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        assert_eq!(guard.frame.sent, vec![0]);
+        assert_eq!(guard.frame.frames, vec![3]);
+
+        // the immediate frame is not held
+        feed(&mut guard, 3, Priority::Medium);
+        feed(&mut guard, 4, Priority::Immediate);
+        feed(&mut guard, 5, Priority::Medium);
+        assert_eq!(guard.frame.frames, vec![3, 2]);
+
+        // the filled frame set is sent without waiting for the flush, 13 frames of 7 bytes fit in
+        // the mtu of 100
+        for index in 6..25 {
+            feed(&mut guard, index, Priority::Medium);
+        }
+        assert_eq!(guard.frame.frames, vec![3, 2, 13]);
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        assert_eq!(guard.frame.frames, vec![3, 2, 13, 7]);
+    }
+
+    #[test]
+    fn test_flush_channel() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let mut guard = TestSink::default().handle_outgoing(
+            link.clone(),
+            1024,
+            None,
+            ResendMap::new(RoleContext::test_server()),
+            PeerContext {
+                addr: "0.0.0.0:0".parse().unwrap(),
+                mtu: 1400,
+                guid: None,
+            },
+            RoleContext::test_server(),
+        );
+        let mut guard = Pin::new(&mut guard);
+        let (waker, _) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+        guard
+            .as_mut()
+            .start_send((ordered_frame(0, 0), Priority::Medium))
+            .unwrap();
+        guard
+            .as_mut()
+            .start_send((ordered_frame(1, 1), Priority::Medium))
+            .unwrap();
+        guard
+            .as_mut()
+            .start_send((ordered_frame(2, 0), Priority::Medium))
+            .unwrap();
+
+        // only the frame of channel 1 is sent
+        link.flush_channel(1);
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        assert_eq!(guard.frame.sent, vec![0]);
+        assert_eq!(guard.buf.len(), 2);
+        assert!(guard
+            .buf
+            .iter()
+            .all(|frame| super::frame_channel(frame) == Some(0)));
+
+        // the channel 0 is flushed as usual
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        assert_eq!(guard.frame.sent, vec![0, 1]);
+        assert!(guard.buf.is_empty());
+    }
+
+    #[test]
+    fn test_seq_num_wrap_around() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let mut guard = TestSink::default().handle_outgoing(
+            link.clone(),
+            1024,
+            None,
+            ResendMap::new(RoleContext::test_server()),
+            PeerContext {
+                addr: "0.0.0.0:0".parse().unwrap(),
+                mtu: 1400,
+                guid: None,
+            },
+            RoleContext::test_server(),
+        );
+        guard.fast_forward_seq_num(u24::MAX - 1);
+        let mut guard = Pin::new(&mut guard);
+        let (waker, _) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+
+        for index in 0..4 {
+            assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+            guard
+                .as_mut()
+                .start_send((reliable_frame(index, None), Priority::Medium))
+                .unwrap();
+            assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        }
+        let max = u24::MAX.to_u32();
+        assert_eq!(guard.frame.sent, vec![max - 1, max, 0, 1]);
+        assert_eq!(link.seq_counters().next_seq_num, 2);
+
+        // acknowledge the frame sets across the wrap
+        let acked = [0, 1, max - 1, max].into_iter().map(u24::from);
+        link.incoming_ack(AckOrNack::extend_from(acked, 1400).unwrap());
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        assert!(guard.resend.is_empty());
+        assert_eq!(link.anomalies().out_of_window_acks, 0);
+
+        // the frame set after the wrap has never been sent
+        link.incoming_ack(AckOrNack::extend_from([2.into()].into_iter(), 1400).unwrap());
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        assert_eq!(link.anomalies().out_of_window_acks, 1);
+    }
+
+    #[test]
+    fn test_fast_retransmit_threshold() {
+        for (threshold, resent) in [(0, false), (2, true), (3, false)] {
+            let link = TransferLink::new_arc(RoleContext::test_server());
+            let mut guard = TestSink::default().handle_outgoing(
+                link.clone(),
+                1024,
+                None,
+                ResendMap::new(RoleContext::test_server())
+                    .with_fast_retransmit_threshold(threshold),
+                PeerContext {
+                    addr: "0.0.0.0:0".parse().unwrap(),
+                    mtu: 1400,
+                    guid: None,
+                },
+                RoleContext::test_server(),
+            );
+            let mut guard = Pin::new(&mut guard);
+            let (waker, _) = TestWaker::pair();
+            let mut cx = Context::from_waker(&waker);
+
+            for index in 0..3 {
+                assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+                guard
+                    .as_mut()
+                    .start_send((reliable_frame(index, None), Priority::Medium))
+                    .unwrap();
+                assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+            }
+            assert_eq!(guard.frame.sent, vec![0, 1, 2]);
+
+            // the frame set 2 is acknowledged, 0 is 2 frame sets behind it
+            link.incoming_ack(AckOrNack::extend_from([2.into()].into_iter(), 1400).unwrap());
+            assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+            // resent in a new frame set before the RTO
+            let expected: &[u32] = if resent { &[0, 1, 2, 3] } else { &[0, 1, 2] };
+            assert_eq!(guard.frame.sent, expected);
+            assert_eq!(guard.resend.seq_nums().count(), 2);
+        }
+    }
+
+    #[test]
+    fn test_unreliable_frames_not_resent() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let mut guard = TestSink::default().handle_outgoing(
+            link.clone(),
+            1024,
+            None,
+            ResendMap::new(RoleContext::test_server()),
+            PeerContext {
+                addr: "0.0.0.0:0".parse().unwrap(),
+                mtu: 1400,
+                guid: None,
+            },
+            RoleContext::test_server(),
+        );
+        let mut guard = Pin::new(&mut guard);
+        let (waker, _) = TestWaker::pair();
+        let mut cx = Context::from_waker(&waker);
+        let unreliable = || Frame {
+            flags: Flags::new(Reliability::Unreliable, false),
+            reliable_frame_index: None,
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            body: Bytes::from_static(b"1"),
+        };
+
+        // the frame set 0 only contains an unreliable frame
+        assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+        guard
+            .as_mut()
+            .start_send((unreliable(), Priority::Medium))
+            .unwrap();
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        // the frame set 1 mixes the reliable and unreliable frames
+        assert!(guard.as_mut().poll_ready(&mut cx).is_ready());
+        guard
+            .as_mut()
+            .start_send((reliable_frame(0, None), Priority::Medium))
+            .unwrap();
+        guard
+            .as_mut()
+            .start_send((unreliable(), Priority::Medium))
+            .unwrap();
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        assert_eq!(guard.frame.sent, vec![0, 1]);
+        assert_eq!(guard.frame.frames, vec![1, 2]);
+        assert_eq!(guard.resend.seq_nums().collect::<Vec<_>>(), vec![1.into()]);
+
+        // both frame sets are lost, only the reliable frame is resent
+        link.incoming_nack(AckOrNack::extend_from([0.into(), 1.into()].into_iter(), 1400).unwrap());
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        assert_eq!(guard.frame.sent, vec![0, 1, 2]);
+        assert_eq!(guard.frame.frames, vec![1, 2, 1]);
+
+        // the frame set only containing unreliable frames is still acknowledged by the peer
+        link.incoming_ack(AckOrNack::extend_from([0.into(), 2.into()].into_iter(), 1400).unwrap());
+        assert!(guard.as_mut().poll_flush(&mut cx).is_ready());
+        assert!(guard.resend.is_empty());
+        assert_eq!(link.anomalies().out_of_window_acks, 0);
+        assert_eq!(guard.frame.sent, vec![0, 1, 2]);
+    }
+}
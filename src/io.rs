@@ -1,31 +1,227 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
 
-use bytes::Bytes;
-use futures::{Future, Sink, SinkExt, Stream};
+use bytes::{Bytes, BytesMut};
+use futures::stream::Peekable;
+use futures::{Future, Sink, Stream, StreamExt};
+use log::debug;
 use minitrace::collector::TraceId;
 use pin_project_lite::pin_project;
 
-use crate::errors::Error;
-use crate::packet::connected::FrameBody;
-use crate::utils::timestamp;
+use crate::codec::BoundSocket;
+use crate::errors::{CodecError, Error};
+use crate::link::SharedLink;
+use crate::packet::connected::{self, FrameBody, FrameMut};
+use crate::packet::Packet;
+use crate::utils::{timestamp, u24};
 use crate::{Message, Reliability};
 
+/// The snapshot of the link quality of a connection, it is cheap to take periodically
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// The smoothed RTT, `None` if no sample is taken yet
+    pub rtt: Option<Duration>,
+    /// The RTT variance, `None` if no sample is taken yet
+    pub rtt_var: Option<Duration>,
+    /// The effective base RTO of resending
+    pub rto: Duration,
+    /// The frame sets sent, including the ones carrying the retransmitted frames
+    pub packets_sent: u64,
+    /// The frame sets carrying the reliable frames acknowledged by the peer
+    pub packets_acked: u64,
+    /// The frame sets carrying the retransmitted frames
+    pub packets_resent: u64,
+    /// The frame sets negatively acknowledged by the peer
+    pub nacks_received: u64,
+}
+
 /// Trace info extension for io
 pub trait TraceInfo {
     fn last_trace_id(&self) -> Option<TraceId>;
 }
 
+/// Counters of abnormal behaviors of the peers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Anomalies {
+    /// Received frames that cannot be decoded
+    pub decode_errors: usize,
+    /// Received packets that should not appear at the current state
+    pub unexpected_packets: usize,
+    /// Received acknowledgements for the frames that have never been sent
+    pub out_of_window_acks: usize,
+    /// Received acknowledgements dropped due to the flood of them
+    pub ack_overflows: usize,
+    /// Received user packets dropped due to the game packet id is not allowed
+    pub unknown_game_packets: usize,
+    /// Stalls detected by the watchdog, i.e. nothing is received from the peer while awaiting
+    /// the acknowledgement
+    pub stalls: usize,
+}
+
+/// Anomaly info extension, implemented by each connection and aggregated on the incoming
+pub trait AnomalyInfo {
+    fn anomalies(&self) -> Anomalies;
+
+    fn reset_anomalies(&self);
+}
+
+/// The snapshot of the sequence counters of a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SeqCounters {
+    /// The sequence number of the next outbound frame set
+    pub next_seq_num: u32,
+    /// The highest sequence number of the received frame sets, `None` if nothing is received
+    pub highest_received_seq_num: Option<u32>,
+    /// The reliable frame index of the next outbound reliable frame
+    pub next_reliable_index: u32,
+}
+
+/// The outcome of closing a connection with a timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseOutcome {
+    /// The peer acknowledged all frames including the disconnect notification
+    Acknowledged,
+    /// The peer did not acknowledge in time, it may not know the connection is closed
+    TimedOut,
+}
+
+/// The reason of a closed connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The connection is closed or dropped locally
+    Closed,
+    /// The peer sent the disconnect notification
+    PeerClosed,
+    /// The connection failed, e.g. the handshake timed out
+    Failed,
+}
+
+/// How the frames buffered behind a missing ordered frame index are handled when the connection
+/// is closed by the peer or dropped by the router, the missing one will never arrive then
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrderGapPolicy {
+    /// Discard the buffered frames silently
+    #[default]
+    Discard,
+    /// Deliver the buffered frames in order, skipping the missing ones
+    Deliver,
+    /// Discard the buffered frames and report a codec error, counted in
+    /// [`Anomalies::decode_errors`]
+    Fail,
+}
+
+/// The id of the delivery receipt of a sent message
+pub type ReceiptId = u64;
+
 /// The basic operation for each connection
+///
+/// The received messages carried by a single frame are views into the buffer of their datagram
+/// without copying, holding them keeps that buffer alive. The fragmented messages are reassembled
+/// into a contiguous buffer.
 pub trait IO:
-    Stream<Item = Bytes> + Sink<Bytes, Error = crate::errors::Error> + TraceInfo + Send
+    Stream<Item = Bytes> + Sink<Bytes, Error = crate::errors::Error> + TraceInfo + AnomalyInfo + Send
 {
+    /// Set the default reliability of the sent messages, the default reliability configured for
+    /// the default order channel takes precedence over it
     fn set_default_reliability(self: Pin<&mut Self>, reliability: Reliability);
     fn get_default_reliability(&self) -> Reliability;
 
     fn set_default_order_channel(self: Pin<&mut Self>, order_channel: u8);
     fn get_default_order_channel(&self) -> u8;
 
+    /// The instant when the online handshake completed, `None` if it is still in progress
+    fn connected_at(&self) -> Option<Instant>;
+
+    /// The elapsed time since the online handshake completed, `None` if it is still in progress
+    fn uptime(&self) -> Option<Duration>;
+
+    /// The guid of the peer read from the offline handshake, i.e. the guid of the client on the
+    /// server side and the guid of the server on the client side. `None` if it is unknown.
+    fn peer_guid(&self) -> Option<u64>;
+
+    /// The guid of this side, i.e. the `client_guid` or the `server_guid` of the config
+    fn local_guid(&self) -> u64;
+
+    /// The address of the peer, i.e. the address of the client on the server side and the
+    /// address of the server won the handshake on the client side. `None` if it is unknown.
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    /// The mtu negotiated in the offline handshake, lowered if the peer updated it during the
+    /// connection. The application payloads beyond it minus the frame headers are fragmented.
+    fn peer_mtu(&self) -> u16;
+
+    /// The local address of the socket bound by this connection, e.g. the ephemeral port
+    /// assigned to the client socket bound to port 0. The server connections share the socket
+    /// of the incoming.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// The effective base RTO of resending. It follows the RTT estimation within `min_rto` and
+    /// `max_rto`, scales up under the connection-wide loss if `rto_loss_scale` is configured and
+    /// recovers after that. It is updated when the sink is polled.
+    fn rto(&self) -> Duration;
+
+    /// The smoothed RTT of the connection, sampled from the acknowledgements of the frame sets
+    /// sent once and the pongs. It is updated when the sink is polled, `None` if no sample is
+    /// taken yet.
+    fn rtt(&self) -> Option<Duration>;
+
+    /// The RTT variance of the connection, i.e. how much the RTT deviates from the smoothed one.
+    /// It is updated along with [`IO::rtt`], `None` if no sample is taken yet.
+    fn jitter(&self) -> Option<Duration>;
+
+    /// The snapshot of the link quality, i.e. the RTT estimation and the transfer counters. It is
+    /// updated when the sink is polled, and reading it never blocks the sink.
+    fn stats(&self) -> ConnectionStats;
+
+    /// Wait until the online handshake completes and the connection is ready to send application
+    /// data. It drives the handshake packets while waiting, the received data is kept for the
+    /// stream. It fails with the cause if the handshake fails.
+    fn ready(self: Pin<&mut Self>) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Close the connection gracefully like [`SinkExt::close`], but give up after the timeout.
+    /// The disconnect notification is resent until the peer acknowledges it, so the timeout is
+    /// the window (e.g. 2MSL) of waiting for the ack. The outcome tells whether the peer
+    /// acknowledged the disconnect notification in time.
+    ///
+    /// [`SinkExt::close`]: futures::SinkExt::close
+    fn close_with_timeout(
+        self: Pin<&mut Self>,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<CloseOutcome, Error>> + Send;
+
+    /// The reason of closing the connection, e.g. [`CloseReason::PeerClosed`] after the stream
+    /// ends by the disconnect notification of the peer. `None` if it is neither closed nor failed
+    /// yet.
+    fn close_reason(&self) -> Option<CloseReason>;
+
+    /// The snapshot of the sequence counters. The outbound ones are updated when the sink is
+    /// polled.
+    fn seq_counters(&self) -> SeqCounters;
+
+    /// Flush the queued frames of the ordered channel (the sequenced or ordered messages sent on
+    /// it) along with the acknowledgements, the frames of the other channels stay queued.
+    fn flush_channel(
+        self: Pin<&mut Self>,
+        channel: u8,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// The snapshot of the sequence numbers of the frame sets awaiting acknowledgement in
+    /// ascending order. It is updated when the sink is polled.
+    fn unacked_seqs(&self) -> Vec<u32>;
+
+    /// Subscribe the delivery receipts, enabling them if they are disabled. Each reliable message
+    /// and each unreliable one requesting it by [`Message::set_ack_receipt`] sent afterwards is
+    /// assigned an id counting from 0, shared with the `delivery_receipts` of the client config.
+    /// The id is yielded once the message is acknowledged, which is processed when the sink is
+    /// polled. The receipts of the unreliable messages are best-effort, they never arrive if the
+    /// message or the acknowledgement is lost. Subscribing again ends the previous stream.
+    fn receipts(&self) -> impl Stream<Item = ReceiptId> + Send + 'static;
+
     /// Split into a Stream and a Sink
     fn split(
         self,
@@ -33,30 +229,174 @@ pub trait IO:
         impl Stream<Item = Bytes> + TraceInfo + Send,
         impl Sink<Message, Error = Error> + Send,
     );
+
+    /// Split into the typed views sharing the connection, e.g. one for the unreliable snapshots
+    /// and one for the reliable RPCs, see [`Views`]
+    fn views(
+        self,
+    ) -> Views<impl Stream<Item = Bytes> + Send, impl Sink<Message, Error = Error> + Send>;
 }
 
 pin_project! {
-    pub(crate) struct SeparatedIO<I, O> {
+    pub(crate) struct SeparatedIO<I: Stream, O> {
         #[pin]
-        src: I,
+        src: Peekable<Buffered<I>>,
         #[pin]
         dst: O,
         default_reliability: Reliability,
         default_order_channel: u8,
+        // the default reliabilities of the order channels, override the `default_reliability`
+        channel_reliabilities: HashMap<u8, Reliability>,
+        link: SharedLink,
+        socket: Arc<dyn BoundSocket>,
+        auto_flush: Option<AutoFlush>,
+        // give up waiting for the acknowledgement of the disconnect notification after it
+        close_timeout: Option<Duration>,
+        close_deadline: Option<Instant>,
     }
 }
 
+/// The flush timer owned by the IO, it is registered in the reactor
+struct AutoFlush {
+    interval: Duration,
+    flush_at: Instant,
+    // the random guid of the timer, apart from the timers of the link cancelled on receiving acks
+    guid: u64,
+    // the waker of the timer registered for `flush_at`
+    waker: Option<Waker>,
+}
+
 impl<I, O> SeparatedIO<I, O>
 where
     I: Stream<Item = Bytes> + TraceInfo + Send,
     O: Sink<Message, Error = Error> + Send,
 {
-    pub(crate) fn new(src: I, dst: O) -> Self {
+    pub(crate) fn new(src: I, dst: O, link: SharedLink, socket: Arc<dyn BoundSocket>) -> Self {
         SeparatedIO {
-            src,
+            src: Buffered {
+                src,
+                received: VecDeque::new(),
+            }
+            .peekable(),
             dst,
             default_reliability: Reliability::ReliableOrdered,
             default_order_channel: 0,
+            channel_reliabilities: HashMap::new(),
+            link,
+            socket,
+            auto_flush: None,
+            close_timeout: None,
+            close_deadline: None,
+        }
+    }
+
+    /// Set the timeout of closing the connection
+    pub(crate) fn with_close_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+
+    /// Flush the buffered frames and the pending acknowledgements every interval while the IO is
+    /// polled
+    pub(crate) fn with_auto_flush(mut self, interval: Option<Duration>) -> Self {
+        self.auto_flush = interval.map(|interval| AutoFlush {
+            interval,
+            flush_at: Instant::now() + interval,
+            guid: rand::random(),
+            waker: None,
+        });
+        self
+    }
+
+    /// Set the default reliabilities of the order channels
+    pub(crate) fn with_channel_reliabilities(
+        mut self,
+        channel_reliabilities: HashMap<u8, Reliability>,
+    ) -> Self {
+        self.channel_reliabilities = channel_reliabilities;
+        self
+    }
+
+    /// Queue the first message of the fast open on the link, it is sent by the default
+    /// reliability ahead of the other messages
+    pub(crate) fn with_first_msg(self, first_msg: Option<Bytes>) -> Self {
+        if let Some(data) = first_msg {
+            self.link.queue_first_msg(self.message(data));
+        }
+        self
+    }
+}
+
+pin_project! {
+    /// The incoming stream yielding the items received in advance first, i.e. the messages
+    /// received while pinging
+    struct Buffered<I: Stream> {
+        #[pin]
+        src: I,
+        received: VecDeque<I::Item>,
+    }
+}
+
+impl<I: Stream> Stream for Buffered<I> {
+    type Item = I::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if let Some(item) = this.received.pop_front() {
+            return Poll::Ready(Some(item));
+        }
+        this.src.poll_next(cx)
+    }
+}
+
+impl<I> TraceInfo for Buffered<I>
+where
+    I: Stream + TraceInfo,
+{
+    fn last_trace_id(&self) -> Option<TraceId> {
+        self.src.last_trace_id()
+    }
+}
+
+impl<I, O> SeparatedIO<I, O>
+where
+    I: Stream,
+    O: Sink<Message, Error = Error>,
+{
+    /// Make the message of the data by the default order channel and its reliability
+    fn message(&self, data: Bytes) -> Message {
+        let reliability = self
+            .channel_reliabilities
+            .get(&self.default_order_channel)
+            .copied()
+            .unwrap_or(self.default_reliability);
+        Message::new(reliability, self.default_order_channel, data)
+    }
+
+    /// Flush the sink once the auto flush timer is due, and register the timer waking up the task
+    /// for the next one
+    fn poll_auto_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) {
+        let this = self.project();
+        let Some(auto_flush) = this.auto_flush.as_mut() else {
+            return;
+        };
+        if Instant::now() >= auto_flush.flush_at {
+            // the errors are reported by the end of the stream or the next sending
+            let _ = this.dst.poll_flush(cx);
+            auto_flush.flush_at = Instant::now() + auto_flush.interval;
+            auto_flush.waker = None;
+        }
+        if auto_flush
+            .waker
+            .as_ref()
+            .map_or(true, |waker| !waker.will_wake(cx.waker()))
+        {
+            this.link.role().reactor().insert_timer(
+                auto_flush.guid,
+                auto_flush.flush_at,
+                cx.waker(),
+            );
+            auto_flush.waker = Some(cx.waker().clone());
         }
     }
 }
@@ -64,26 +404,36 @@ where
 impl<I, O> Stream for SeparatedIO<I, O>
 where
     I: Stream<Item = Bytes>,
+    O: Sink<Message, Error = Error>,
 {
     type Item = Bytes;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.as_mut().poll_auto_flush(cx);
         self.project().src.poll_next(cx)
     }
 }
 
 impl<I, O> Sink<Bytes> for SeparatedIO<I, O>
 where
+    I: Stream,
     O: Sink<Message, Error = Error>,
 {
     type Error = Error;
 
-    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.project().dst.poll_ready(cx)
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.as_mut().poll_auto_flush(cx);
+        let this = self.project();
+        // the messages are held until the session is established, drive the online handshake
+        // meanwhile, the received data is kept for the stream
+        if this.link.awaits_session() {
+            let _ = this.src.poll_peek(cx);
+        }
+        this.dst.poll_ready(cx)
     }
 
     fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
-        let msg = Message::new(self.default_reliability, self.default_order_channel, item);
+        let msg = self.message(item);
         self.project().dst.start_send(msg)
     }
 
@@ -92,19 +442,58 @@ where
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.project().dst.poll_close(cx)
+        let this = self.project();
+        if let Poll::Ready(res) = this.dst.poll_close(cx) {
+            return Poll::Ready(res);
+        }
+        let Some(timeout) = *this.close_timeout else {
+            return Poll::Pending;
+        };
+        let deadline = *this
+            .close_deadline
+            .get_or_insert_with(|| Instant::now() + timeout);
+        let role = this.link.role();
+        if role
+            .reactor()
+            .poll_deadline(role.guid(), deadline, cx)
+            .is_ready()
+        {
+            // the peer may be gone, it is closed without the acknowledgement
+            this.link.mark_closed();
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Pending
     }
 }
 
 impl<I, O> TraceInfo for SeparatedIO<I, O>
 where
-    I: TraceInfo,
+    I: Stream + TraceInfo,
 {
     fn last_trace_id(&self) -> Option<TraceId> {
         self.src.last_trace_id()
     }
 }
 
+impl<I> TraceInfo for Peekable<I>
+where
+    I: Stream + TraceInfo,
+{
+    fn last_trace_id(&self) -> Option<TraceId> {
+        self.get_ref().last_trace_id()
+    }
+}
+
+impl<I: Stream, O> AnomalyInfo for SeparatedIO<I, O> {
+    fn anomalies(&self) -> Anomalies {
+        self.link.anomalies()
+    }
+
+    fn reset_anomalies(&self) {
+        self.link.reset_anomalies();
+    }
+}
+
 impl<I, O> crate::io::IO for SeparatedIO<I, O>
 where
     O: Sink<Message, Error = Error> + Send,
@@ -126,6 +515,138 @@ where
         self.default_order_channel
     }
 
+    fn connected_at(&self) -> Option<Instant> {
+        self.link.connected_at()
+    }
+
+    fn uptime(&self) -> Option<Duration> {
+        self.link.connected_at().map(|at| at.elapsed())
+    }
+
+    fn peer_guid(&self) -> Option<u64> {
+        self.link.peer_guid()
+    }
+
+    fn local_guid(&self) -> u64 {
+        self.link.role().guid()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.link.peer_addr()
+    }
+
+    fn peer_mtu(&self) -> u16 {
+        self.link.peer_mtu()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn rto(&self) -> Duration {
+        self.link.rto()
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        self.link.rtt()
+    }
+
+    fn jitter(&self) -> Option<Duration> {
+        self.link.jitter()
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        self.link.stats()
+    }
+
+    async fn ready(self: Pin<&mut Self>) -> Result<(), Error> {
+        let this = self.project();
+        let link = this.link;
+        let mut src = this.src;
+        let mut dst = this.dst;
+        futures::future::poll_fn(|cx| {
+            if link.connected_at().is_some() {
+                return Poll::Ready(Ok(()));
+            }
+            let peeked = src.as_mut().poll_peek(cx).map(|item| item.is_some());
+            // send the handshake packets, including the replies to the packets just received
+            if let Poll::Ready(Err(err)) = dst.as_mut().poll_flush(cx) {
+                return Poll::Ready(Err(err));
+            }
+            match peeked {
+                // the data is only delivered after the handshake completes
+                Poll::Ready(true) => return Poll::Ready(Ok(())),
+                Poll::Ready(false) => {
+                    // report the cause of the failure if there is one
+                    if let Poll::Ready(Err(err)) = dst.as_mut().poll_ready(cx) {
+                        return Poll::Ready(Err(err));
+                    }
+                    return Poll::Ready(Err(Error::ConnectionClosed));
+                }
+                Poll::Pending => {}
+            }
+            // the handshake may complete while polling the incoming packets
+            if link.connected_at().is_some() {
+                return Poll::Ready(Ok(()));
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    async fn close_with_timeout(
+        self: Pin<&mut Self>,
+        timeout: Duration,
+    ) -> Result<CloseOutcome, Error> {
+        let this = self.project();
+        let link = this.link;
+        let mut dst = this.dst;
+        let deadline = Instant::now() + timeout;
+        futures::future::poll_fn(|cx| {
+            if let Poll::Ready(res) = dst.as_mut().poll_close(cx) {
+                return Poll::Ready(res.map(|()| CloseOutcome::Acknowledged));
+            }
+            let role = link.role();
+            if role
+                .reactor()
+                .poll_deadline(role.guid(), deadline, cx)
+                .is_ready()
+            {
+                link.mark_closed();
+                return Poll::Ready(Ok(CloseOutcome::TimedOut));
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.link.closed_reason()
+    }
+
+    fn seq_counters(&self) -> SeqCounters {
+        self.link.seq_counters()
+    }
+
+    async fn flush_channel(self: Pin<&mut Self>, channel: u8) -> Result<(), Error> {
+        let this = self.project();
+        this.link.flush_channel(channel);
+        let mut dst = this.dst;
+        futures::future::poll_fn(|cx| dst.as_mut().poll_flush(cx)).await
+    }
+
+    fn unacked_seqs(&self) -> Vec<u32> {
+        self.link
+            .unacked_seqs()
+            .into_iter()
+            .map(u24::to_u32)
+            .collect()
+    }
+
+    fn receipts(&self) -> impl Stream<Item = ReceiptId> + Send + 'static {
+        self.link.subscribe_receipts()
+    }
+
     fn split(
         self,
     ) -> (
@@ -134,11 +655,213 @@ where
     ) {
         (self.src, self.dst)
     }
+
+    fn views(
+        self,
+    ) -> Views<impl Stream<Item = Bytes> + Send, impl Sink<Message, Error = Error> + Send> {
+        Views {
+            demux: Arc::new(parking_lot::Mutex::new(Demux {
+                src: Box::pin(self.src),
+                dst: Box::pin(self.dst),
+                link: self.link,
+                routes: HashMap::new(),
+                ended: false,
+            })),
+        }
+    }
+}
+
+/// The route of the received messages to the views
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Route {
+    Datagram,
+    Stream(u8),
+}
+
+/// The received messages waiting for the views of a route
+#[derive(Default)]
+struct RouteQueue {
+    received: VecDeque<Bytes>,
+    // the view pending on the messages
+    waker: Option<Waker>,
+    views: usize,
+}
+
+/// The connection shared by the views
+struct Demux<I, O> {
+    src: Pin<Box<I>>,
+    dst: Pin<Box<O>>,
+    link: SharedLink,
+    routes: HashMap<Route, RouteQueue>,
+    ended: bool,
+}
+
+impl<I, O> Demux<I, O> {
+    fn wake_all(&mut self) {
+        for queue in self.routes.values_mut() {
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// The typed views sharing a connection, created by [`IO::views`]. Each view is a `Stream` and
+/// a `Sink` of one kind of messages, so the reliabilities are not set per message.
+///
+/// The received reliable ordered messages are routed to the [`Views::stream`] of their order
+/// channels, and the others to the [`Views::datagrams`]. The messages of a route without a view
+/// are dropped, so make the views before receiving. Closing any view closes the connection.
+pub struct Views<I, O> {
+    demux: Arc<parking_lot::Mutex<Demux<I, O>>>,
+}
+
+impl<I, O> fmt::Debug for Views<I, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Views").finish_non_exhaustive()
+    }
+}
+
+impl<I, O> Views<I, O> {
+    /// The view sending the messages unreliably, and receiving the messages not reliable ordered
+    pub fn datagrams(&self) -> View<I, O> {
+        self.view(Route::Datagram, Reliability::Unreliable, 0)
+    }
+
+    /// The view sending and receiving the reliable ordered messages on the order channel, it
+    /// must be less than the configured maximum channels
+    pub fn stream(&self, channel: u8) -> View<I, O> {
+        self.view(
+            Route::Stream(channel),
+            Reliability::ReliableOrdered,
+            channel,
+        )
+    }
+
+    fn view(&self, route: Route, reliability: Reliability, order_channel: u8) -> View<I, O> {
+        self.demux.lock().routes.entry(route).or_default().views += 1;
+        View {
+            demux: Arc::clone(&self.demux),
+            route,
+            reliability,
+            order_channel,
+        }
+    }
+}
+
+/// A typed view of the connection, created by [`Views::datagrams`] or [`Views::stream`]
+pub struct View<I, O> {
+    demux: Arc<parking_lot::Mutex<Demux<I, O>>>,
+    route: Route,
+    reliability: Reliability,
+    order_channel: u8,
+}
+
+impl<I, O> fmt::Debug for View<I, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("View")
+            .field("route", &self.route)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<I, O> Stream for View<I, O>
+where
+    I: Stream<Item = Bytes>,
+{
+    type Item = Bytes;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut demux = self.demux.lock();
+        let demux = &mut *demux;
+        loop {
+            if let Some(data) = demux
+                .routes
+                .get_mut(&self.route)
+                .and_then(|queue| queue.received.pop_front())
+            {
+                return Poll::Ready(Some(data));
+            }
+            if demux.ended {
+                return Poll::Ready(None);
+            }
+            match demux.src.as_mut().poll_next(cx) {
+                Poll::Ready(Some(data)) => {
+                    let route = demux
+                        .link
+                        .received_order_channel()
+                        .map_or(Route::Datagram, Route::Stream);
+                    if route == self.route {
+                        return Poll::Ready(Some(data));
+                    }
+                    let Some(queue) = demux.routes.get_mut(&route) else {
+                        debug!("drop the message received on {route:?} without a view");
+                        continue;
+                    };
+                    queue.received.push_back(data);
+                    if let Some(waker) = queue.waker.take() {
+                        waker.wake();
+                    }
+                }
+                Poll::Ready(None) => {
+                    demux.ended = true;
+                    demux.wake_all();
+                }
+                Poll::Pending => {
+                    if let Some(queue) = demux.routes.get_mut(&self.route) {
+                        queue.waker = Some(cx.waker().clone());
+                    }
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl<I, O> Sink<Bytes> for View<I, O>
+where
+    O: Sink<Message, Error = Error>,
+{
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.demux.lock().dst.as_mut().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        let msg = Message::new(self.reliability, self.order_channel, item);
+        self.demux.lock().dst.as_mut().start_send(msg)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.demux.lock().dst.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.demux.lock().dst.as_mut().poll_close(cx)
+    }
 }
 
-/// Ping extension for client, experimental
+impl<I, O> Drop for View<I, O> {
+    fn drop(&mut self) {
+        let mut demux = self.demux.lock();
+        if let Some(queue) = demux.routes.get_mut(&self.route) {
+            queue.views -= 1;
+            if queue.views == 0 {
+                demux.routes.remove(&self.route);
+            }
+        }
+        // this view may be the one polling the connection, hand it over to the others
+        demux.wake_all();
+    }
+}
+
+/// Ping extension, measure the round trip time by `ConnectedPing` and `ConnectedPong`
 pub trait Ping {
-    fn ping(self: Pin<&mut Self>) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Ping the peer and wait for the pong, return the round trip time in milliseconds
+    /// precision. The ping is resent each RTO until a pong arrives, the messages received
+    /// meanwhile are kept for the following polls.
+    fn ping(self: Pin<&mut Self>) -> impl Future<Output = Result<Duration, Error>> + Send;
 }
 
 impl<I, O> Ping for SeparatedIO<I, O>
@@ -146,12 +869,188 @@ where
     O: Sink<Message, Error = Error> + Sink<FrameBody, Error = Error> + Send,
     I: Stream<Item = Bytes> + TraceInfo + Send,
 {
-    async fn ping(self: Pin<&mut Self>) -> Result<(), Error> {
-        self.project()
-            .dst
-            .send(FrameBody::ConnectedPing {
-                client_timestamp: timestamp(),
-            })
-            .await
+    async fn ping(self: Pin<&mut Self>) -> Result<Duration, Error> {
+        let this = self.project();
+        let link = this.link;
+        let mut src = this.src;
+        let mut dst = this.dst;
+        let since = timestamp();
+        // send the first ping immediately
+        let mut resend_at = Instant::now();
+        futures::future::poll_fn(|cx| loop {
+            if let Some(rtt) = link.pong_since(since) {
+                return Poll::Ready(Ok(rtt));
+            }
+            if Instant::now() >= resend_at {
+                ready!(Sink::<FrameBody>::poll_ready(dst.as_mut(), cx))?;
+                dst.as_mut().start_send(FrameBody::ConnectedPing {
+                    client_timestamp: timestamp(),
+                })?;
+                resend_at = Instant::now() + link.rto();
+            }
+            // send the ping, and the acknowledgements of the messages received meanwhile
+            if let Poll::Ready(Err(err)) = Sink::<FrameBody>::poll_flush(dst.as_mut(), cx) {
+                return Poll::Ready(Err(err));
+            }
+            // the pong is handled while polling the incoming packets
+            match src.as_mut().poll_next(cx) {
+                Poll::Ready(Some(data)) => {
+                    src.as_mut()
+                        .get_pin_mut()
+                        .project()
+                        .received
+                        .push_back(data);
+                    continue;
+                }
+                Poll::Ready(None) => {
+                    // report the cause of the failure if there is one
+                    if let Poll::Ready(Err(err)) = Sink::<FrameBody>::poll_ready(dst.as_mut(), cx) {
+                        return Poll::Ready(Err(err));
+                    }
+                    return Poll::Ready(Err(Error::ConnectionClosed));
+                }
+                Poll::Pending => {}
+            }
+            if let Some(rtt) = link.pong_since(since) {
+                return Poll::Ready(Ok(rtt));
+            }
+            let role = link.role();
+            if role
+                .reactor()
+                .poll_deadline(role.guid(), resend_at, cx)
+                .is_pending()
+            {
+                return Poll::Pending;
+            }
+        })
+        .await
+    }
+}
+
+/// Raw frame set extension, advanced.
+///
+/// Send a pre-encoded frame set (e.g. forwarded by a proxy) without re-encoding it. The frames
+/// bypass the fragmenter and the ordering layer, only the sequence number and the reliable
+/// indices are reassigned by this connection, and the frame set is kept for resending if it
+/// contains reliable frames. Be careful:
+/// - The sequenced and ordered indices of the frames are sent as is, they may collide with the ones
+///   assigned by this connection, so the peer may drop them as out of date or wait for them
+///   infinitely. Do not mix it with the ordinary sending unless you know what you are doing.
+/// - The frame set is not split, it should not exceed the MTU of this connection.
+pub trait RawFrameSet {
+    fn send_raw_frameset(
+        self: Pin<&mut Self>,
+        frame_set: Bytes,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+impl<I, O> RawFrameSet for SeparatedIO<I, O>
+where
+    O: Sink<Message, Error = Error> + Send,
+    I: Stream<Item = Bytes> + TraceInfo + Send,
+{
+    async fn send_raw_frameset(self: Pin<&mut Self>, frame_set: Bytes) -> Result<(), Error> {
+        let mut buf = BytesMut::from(&frame_set[..]);
+        let frames = match Packet::read(&mut buf)? {
+            Some(Packet::Connected(connected::Packet::FrameSet(set))) => set.set,
+            _ => {
+                let pack_type = frame_set.first().copied().unwrap_or_default();
+                return Err(CodecError::InvalidPacketType(pack_type).into());
+            }
+        };
+        let this = self.project();
+        this.link.send_raw_frames(FrameMut::freeze_all(frames));
+        let mut dst = this.dst;
+        futures::future::poll_fn(|cx| Sink::<Message>::poll_flush(dst.as_mut(), cx)).await
+    }
+}
+
+/// Map send extension, transform each outbound payload before it is fragmented. It works on the
+/// connection (a `Sink<Bytes>`) as well as the sink split from it (a `Sink<Message>`).
+pub trait MapSend: Sized {
+    fn map_send<F>(self, f: F) -> SendMapper<Self, F>
+    where
+        F: Fn(Bytes) -> Bytes,
+    {
+        SendMapper { inner: self, f }
+    }
+}
+
+impl<S> MapSend for S {}
+
+pin_project! {
+    /// The sink with the outbound payloads transformed, created by [`MapSend::map_send`]
+    pub struct SendMapper<S, F> {
+        #[pin]
+        inner: S,
+        f: F,
+    }
+}
+
+impl<S, F> Sink<Bytes> for SendMapper<S, F>
+where
+    S: Sink<Bytes>,
+    F: Fn(Bytes) -> Bytes,
+{
+    type Error = S::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        let this = self.project();
+        this.inner.start_send((this.f)(item))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<S, F> Sink<Message> for SendMapper<S, F>
+where
+    S: Sink<Message>,
+    F: Fn(Bytes) -> Bytes,
+{
+    type Error = S::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, mut msg: Message) -> Result<(), Self::Error> {
+        let this = self.project();
+        msg.data = (this.f)(msg.data);
+        this.inner.start_send(msg)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<S, F> Stream for SendMapper<S, F>
+where
+    S: Stream,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().inner.poll_next(cx)
+    }
+}
+
+impl<S: TraceInfo, F> TraceInfo for SendMapper<S, F> {
+    fn last_trace_id(&self) -> Option<TraceId> {
+        self.inner.last_trace_id()
     }
 }
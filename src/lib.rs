@@ -46,7 +46,7 @@
 mod codec;
 
 /// Errors
-mod errors;
+pub mod errors;
 
 /// Protocol packet
 mod packet;
@@ -66,9 +66,18 @@ mod link;
 /// Estimators
 mod estimator;
 
+/// Congestion control
+mod congestion;
+
 /// Resend map
 mod resend_map;
 
+/// Stall watchdog
+mod watchdog;
+
+/// Minecraft MOTD
+pub mod motd;
+
 /// Raknet server
 pub mod server;
 
@@ -78,38 +87,85 @@ pub mod client;
 /// The basic operation API
 pub mod io;
 
+/// Async runtimes
+pub mod runtime;
+
+/// Endpoint sharing one socket between the server and the client
+pub mod endpoint;
+
+/// Pluggable encryption of the user data
+#[cfg(feature = "encryption")]
+pub mod cipher;
+
 #[cfg(feature = "micro-bench")]
 pub mod micro_bench {
     pub mod codec {
         pub use crate::codec::micro_bench::*;
     }
+
+    pub mod reorder {
+        pub use crate::utils::micro_bench::*;
+    }
+
+    pub mod frames {
+        pub use crate::packet::connected::micro_bench::*;
+    }
+
+    pub mod reactor {
+        pub use crate::utils::reactor_micro_bench::*;
+    }
 }
 
 /// Unit tests
 #[cfg(test)]
 mod tests;
 
+/// Deterministic simulation
+#[cfg(test)]
+mod sim;
+
 use std::net::SocketAddr;
 
 use bytes::Bytes;
+#[cfg(feature = "chaos")]
+pub use codec::chaos::Chaos;
+pub use packet::{raknet_read_addr, raknet_write_addr};
+pub use utils::Reactor;
 
 #[derive(Debug, Clone, Copy)]
 enum RoleContext {
-    Client { guid: u64 },
-    Server { guid: u64 },
+    Client {
+        guid: u64,
+        // the reactor of the timers keyed by the guid
+        reactor: &'static Reactor,
+    },
+    Server {
+        guid: u64,
+        reactor: &'static Reactor,
+    },
 }
 
 impl RoleContext {
     #[cfg(any(test, feature = "micro-bench"))]
     fn test_server() -> Self {
         // There is always a server
-        RoleContext::Server { guid: 0 }
+        RoleContext::Server {
+            guid: 0,
+            reactor: Reactor::get(),
+        }
     }
 
     fn guid(&self) -> u64 {
         match self {
-            RoleContext::Client { guid } => *guid,
-            RoleContext::Server { guid } => *guid,
+            RoleContext::Client { guid, .. } => *guid,
+            RoleContext::Server { guid, .. } => *guid,
+        }
+    }
+
+    fn reactor(&self) -> &'static Reactor {
+        match self {
+            RoleContext::Client { reactor, .. } => reactor,
+            RoleContext::Server { reactor, .. } => reactor,
         }
     }
 }
@@ -117,8 +173,8 @@ impl RoleContext {
 impl std::fmt::Display for RoleContext {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RoleContext::Client { guid } => write!(f, "client({guid})"),
-            RoleContext::Server { guid } => write!(f, "server({guid})"),
+            RoleContext::Client { guid, .. } => write!(f, "client({guid})"),
+            RoleContext::Server { guid, .. } => write!(f, "server({guid})"),
         }
     }
 }
@@ -127,6 +183,8 @@ impl std::fmt::Display for RoleContext {
 struct PeerContext {
     addr: SocketAddr,
     mtu: u16,
+    // the guid announced by the peer in the offline handshake, if it is known
+    guid: Option<u64>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Copy)]
@@ -193,7 +251,7 @@ impl Reliability {
         )
     }
 
-    /// TODO: implement sequenced
+    /// Sequenced drops the packets older than the last received one at their `order_channels`.
     pub(crate) fn is_sequenced(&self) -> bool {
         matches!(
             self,
@@ -201,6 +259,17 @@ impl Reliability {
         )
     }
 
+    /// The reliability written into the frame flags. The receipts are local to the sender, so the
+    /// `WithAckReceipt` ones are sent as their base reliabilities like the original RakNet.
+    pub(crate) fn on_wire(self) -> Reliability {
+        match self {
+            Reliability::UnreliableWithAckReceipt => Reliability::Unreliable,
+            Reliability::ReliableWithAckReceipt => Reliability::Reliable,
+            Reliability::ReliableOrderedWithAckReceipt => Reliability::ReliableOrdered,
+            _ => self,
+        }
+    }
+
     /// The header size (without fragment part) implied from reliability
     pub(crate) fn size(&self) -> usize {
         // flag(1B) + length(2B)
@@ -218,21 +287,74 @@ impl Reliability {
     }
 }
 
+/// The priority of the outgoing messages. The buffered messages of the higher priorities are sent
+/// first when the congestion window or the batching holds them back, while a sequenced or ordered
+/// message never overtakes the buffered ones of the same order channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Sent ahead of all the buffered messages, and flushed when the sink is polled next without
+    /// waiting for `poll_flush`
+    Immediate,
+    /// Sent ahead of the medium and low priorities
+    High,
+    /// The default priority
+    #[default]
+    Medium,
+    /// Sent after all the others, e.g. the bulk data
+    Low,
+}
+
 /// Raknet message
 #[derive(Debug, Clone)]
 pub struct Message {
     reliability: Reliability,
     order_channel: u8,
+    priority: Priority,
     data: Bytes,
+    // deliver a receipt even though the message is unreliable
+    ack_receipt: bool,
+    // the id of the delivery receipt, assigned when the message is sent
+    receipt: Option<u64>,
+    // the trace id propagated to the peer
+    trace_id: Option<u64>,
 }
 
 impl Message {
+    /// Create a message, the order channel (less than the configured maximum channels, at most
+    /// 32) is only used by the sequenced or ordered reliabilities. The sending fails with
+    /// [`errors::CodecError::OrderedFrame`] if it is out of range, use [`Message::try_new`] to
+    /// validate it in advance.
     pub fn new(reliability: Reliability, order_channel: u8, data: Bytes) -> Self {
         Self {
             reliability,
             order_channel,
+            priority: Priority::default(),
             data,
+            ack_receipt: false,
+            receipt: None,
+            trace_id: None,
+        }
+    }
+
+    /// Create a message with the order channel validated against `max_channels` (the configured
+    /// maximum ordered channels). The order channel is only used by the sequenced or ordered
+    /// reliabilities, so it is not validated for the others.
+    ///
+    /// # Errors
+    /// Returns [`errors::Error::InvalidOrderChannel`] if the order channel is out of range.
+    pub fn try_new(
+        reliability: Reliability,
+        order_channel: u8,
+        data: Bytes,
+        max_channels: usize,
+    ) -> Result<Self, errors::Error> {
+        if reliability.is_sequenced_or_ordered() && order_channel as usize >= max_channels {
+            return Err(errors::Error::InvalidOrderChannel {
+                channel: order_channel,
+                max_channels,
+            });
         }
+        Ok(Self::new(reliability, order_channel, data))
     }
 
     pub fn set_reliability(&mut self, reliability: Reliability) {
@@ -243,6 +365,28 @@ impl Message {
         self.order_channel = channel;
     }
 
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Request a receipt for the [`Reliability::Unreliable`] message, it is delivered to
+    /// [`io::IO::receipts`] once the frame set carrying the message is acknowledged. The message
+    /// is never retransmitted, so the receipts are best-effort: they never arrive if the message
+    /// or the acknowledgement is lost. The message exceeding the mtu is split and sent reliably
+    /// like the other unreliable ones. The reliable messages have receipts anyway.
+    pub fn set_ack_receipt(&mut self, ack_receipt: bool) {
+        self.ack_receipt = ack_receipt;
+    }
+
+    /// Attach the trace id propagated to the peer, the peer reports it by
+    /// [`io::TraceInfo::last_trace_id`] once it receives the message, even if the message is
+    /// fragmented. It takes effect only if both sides enable the `propagate_trace_id` in their
+    /// configs, otherwise it is dropped silently.
+    pub fn with_trace_id(mut self, trace_id: u64) -> Self {
+        self.trace_id = Some(trace_id);
+        self
+    }
+
     pub fn get_reliability(&self) -> Reliability {
         self.reliability
     }
@@ -251,6 +395,18 @@ impl Message {
         self.order_channel
     }
 
+    pub fn get_priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn get_ack_receipt(&self) -> bool {
+        self.ack_receipt
+    }
+
+    pub fn get_trace_id(&self) -> Option<u64> {
+        self.trace_id
+    }
+
     pub fn get_data(&self) -> &Bytes {
         &self.data
     }
@@ -258,4 +414,21 @@ impl Message {
     pub fn into_data(self) -> Bytes {
         self.data
     }
+
+    pub(crate) fn set_receipt(&mut self, id: u64) {
+        self.receipt = Some(id);
+    }
+
+    pub(crate) fn get_receipt(&self) -> Option<u64> {
+        self.receipt
+    }
+
+    /// Whether the message is sent unreliably but tagged for a receipt
+    pub(crate) fn wants_unreliable_receipt(&self) -> bool {
+        match self.reliability {
+            Reliability::UnreliableWithAckReceipt => true,
+            Reliability::Unreliable => self.ack_receipt,
+            _ => false,
+        }
+    }
 }
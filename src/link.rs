@@ -1,22 +1,156 @@
 use std::cmp::Reverse;
-use std::collections::{BinaryHeap, VecDeque};
-use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+#[cfg(feature = "encryption")]
+use std::task::Waker;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use async_channel::Sender;
+use bytes::Bytes;
 use concurrent_queue::ConcurrentQueue;
+#[cfg(feature = "encryption")]
+use futures::task::AtomicWaker;
 use futures::Stream;
 use log::{debug, trace, warn};
 
-use crate::packet::connected::{self, AckOrNack, Frame, FrameBody, FrameSet, FramesMut};
+#[cfg(feature = "encryption")]
+use crate::cipher::{Cipher, CipherSession, KeyExchange};
+use crate::errors::Error;
+use crate::estimator::RFC6298Impl;
+use crate::io::{Anomalies, CloseReason, ConnectionStats, SeqCounters};
+use crate::packet::connected::{
+    self, AckOrNack, Frame, FrameBody, FrameSet, Frames, FramesMut, Record,
+};
 use crate::packet::unconnected;
-use crate::resend_map::ResendMap;
-use crate::utils::{u24, Reactor};
-use crate::RoleContext;
+use crate::resend_map::{MessageKey, ResendMap, TransferStats, RTO};
+#[cfg(feature = "metrics")]
+use crate::utils::Metrics;
+use crate::utils::{metric, timestamp, u24};
+use crate::watchdog::{Keepalive, Liveness, Verdict, Watchdog};
+use crate::{Message, RoleContext};
 
 /// Shared link between stream and sink
 pub(crate) type SharedLink = Arc<TransferLink>;
 
+/// The kinds of anomaly detected on a connection
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Anomaly {
+    DecodeError,
+    UnexpectedPacket,
+    OutOfWindowAck,
+    AckOverflow,
+    UnknownGamePacket,
+    Stall,
+}
+
+/// Anomaly counters, updated at each detection site
+#[derive(Debug, Default)]
+pub(crate) struct AnomalyCounters {
+    decode_errors: AtomicUsize,
+    unexpected_packets: AtomicUsize,
+    out_of_window_acks: AtomicUsize,
+    ack_overflows: AtomicUsize,
+    unknown_game_packets: AtomicUsize,
+    stalls: AtomicUsize,
+}
+
+impl AnomalyCounters {
+    fn counter(&self, anomaly: Anomaly) -> &AtomicUsize {
+        match anomaly {
+            Anomaly::DecodeError => &self.decode_errors,
+            Anomaly::UnexpectedPacket => &self.unexpected_packets,
+            Anomaly::OutOfWindowAck => &self.out_of_window_acks,
+            Anomaly::AckOverflow => &self.ack_overflows,
+            Anomaly::UnknownGamePacket => &self.unknown_game_packets,
+            Anomaly::Stall => &self.stalls,
+        }
+    }
+
+    pub(crate) fn record(&self, anomaly: Anomaly) {
+        self.counter(anomaly).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Anomalies {
+        Anomalies {
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            unexpected_packets: self.unexpected_packets.load(Ordering::Relaxed),
+            out_of_window_acks: self.out_of_window_acks.load(Ordering::Relaxed),
+            ack_overflows: self.ack_overflows.load(Ordering::Relaxed),
+            unknown_game_packets: self.unknown_game_packets.load(Ordering::Relaxed),
+            stalls: self.stalls.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.decode_errors.store(0, Ordering::Relaxed);
+        self.unexpected_packets.store(0, Ordering::Relaxed);
+        self.out_of_window_acks.store(0, Ordering::Relaxed);
+        self.ack_overflows.store(0, Ordering::Relaxed);
+        self.unknown_game_packets.store(0, Ordering::Relaxed);
+        self.stalls.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Delivery receipts of the reliable messages and the tagged unreliable messages sent by the user
+#[derive(Debug)]
+struct Receipts {
+    // the channel configured by `delivery_receipts`
+    sender: Option<flume::Sender<u64>>,
+    // the stream subscribed by `IO::receipts`
+    subscriber: parking_lot::Mutex<Option<Sender<u64>>>,
+    next_id: AtomicU64,
+    // the receipt id of each message awaiting acknowledgement
+    pending: parking_lot::Mutex<HashMap<MessageKey, u64>>,
+    // the receipt ids of the tagged unreliable frames being buffered, in the order of sending
+    unreliable: parking_lot::Mutex<VecDeque<u64>>,
+}
+
+impl Receipts {
+    fn new(sender: Option<flume::Sender<u64>>) -> Self {
+        Self {
+            sender,
+            subscriber: parking_lot::Mutex::new(None),
+            next_id: AtomicU64::new(0),
+            pending: parking_lot::Mutex::new(HashMap::new()),
+            unreliable: parking_lot::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn deliver(&self, id: u64, role: &RoleContext) {
+        if let Some(sender) = &self.sender
+            && let Err(flume::TrySendError::Full(id)) = sender.try_send(id)
+        {
+            warn!("[{role}] drop the delivery receipt {id}, channel is full");
+        }
+        let mut subscriber = self.subscriber.lock();
+        if let Some(tx) = subscriber.as_ref()
+            && tx.try_send(id).is_err()
+        {
+            // the stream is dropped
+            *subscriber = None;
+        }
+    }
+}
+
+/// The coalescing of the outgoing acknowledgements
+struct AckDelay {
+    delay: Duration,
+    threshold: usize,
+    // the instant of receiving the earliest frame set not acknowledged yet
+    since: Option<Instant>,
+}
+
+/// The missing frame sets held for the reordering before being nacked
+struct ReorderWindow {
+    frame_sets: u32,
+    delay: Duration,
+    // the missing sequence numbers in the ascending order and the instants detecting them
+    missing: VecDeque<(u24, Instant)>,
+}
+
 /// Transfer data and task between stream and sink.
 /// It is thread-safe under immutable reference
 pub(crate) struct TransferLink {
@@ -25,24 +159,148 @@ pub(crate) struct TransferLink {
     forward_waking: AtomicBool,
 
     outgoing_ack: parking_lot::Mutex<BinaryHeap<Reverse<u24>>>,
+    /// the outgoing acknowledgements are held until they are due if it is enabled
+    ack_delay: parking_lot::Mutex<Option<AckDelay>>,
     // TODO: nack channel should always be in order according to [`DeFragment::poll_next`], replace
     // it with ConcurrentQueue if we cannot find a way to break the order
     outgoing_nack: parking_lot::Mutex<BinaryHeap<Reverse<u24>>>,
+    /// the missing frame sets are nacked after the reorder window if it is enabled
+    reorder_window: parking_lot::Mutex<Option<ReorderWindow>>,
 
     unconnected: ConcurrentQueue<unconnected::Packet>,
     frame_body: ConcurrentQueue<FrameBody>,
+    // pre-encoded frames bypassing the fragmenter, each one is sent in a single frame set
+    raw_frames: ConcurrentQueue<Frames>,
+    // the ordered channel to be flushed alone on the next flush
+    flushing_channel: parking_lot::Mutex<Option<u8>>,
+
+    connected_at: OnceLock<Instant>,
+    // the guid of the peer, known from the offline handshake
+    peer_guid: OnceLock<u64>,
+    // the address of the peer, the client knows it once an address wins the handshake
+    peer_addr: OnceLock<SocketAddr>,
+    // the mtu negotiated in the offline handshake, 0 means it is unknown
+    negotiated_mtu: AtomicU16,
+    // the lowered mtu during the connection, 0 means it is not lowered
+    mtu: AtomicU16,
+    // inform the peer of the lowered mtu if this side enables it and the peer agrees
+    mtu_update: AtomicBool,
+    mtu_update_negotiated: AtomicBool,
+    // the lower mtus stepped down through when the path black-holes the large datagrams, it is
+    // set if the path mtu discovery is enabled
+    mtu_ladder: OnceLock<Vec<u16>>,
+    // the cap of the outgoing datagram size, 0 means no cap
+    max_datagram_size: AtomicU16,
+    // the snapshot of the unacknowledged sequence numbers, published by the outgoing guard
+    unacked: parking_lot::Mutex<Vec<u24>>,
+    // the effective base RTO in microseconds, published by the outgoing guard
+    rto: AtomicU64,
+    // the smoothed RTT and its variance in microseconds, published by the outgoing guard,
+    // `NO_RTT_SAMPLE` means no sample is taken yet
+    rtt: AtomicU64,
+    rtt_var: AtomicU64,
+    // the RTT samples measured by the pongs, consumed by the outgoing guard
+    pong_rtt: ConcurrentQueue<Duration>,
+    // the timestamp of the ping answered by the last pong, and the RTT measured by it
+    last_pong: parking_lot::Mutex<Option<(i64, Duration)>>,
+    // the transfer counters published by the outgoing guard
+    transfer: TransferCounters,
+    // the sequence counters published by the outgoing guard, the fragmenter and the router
+    seq_counters: parking_lot::Mutex<SeqCounters>,
+
+    anomalies: AnomalyCounters,
+    // aggregated anomalies of all connections, i.e. the anomalies of the incoming
+    parent_anomalies: Option<Arc<AnomalyCounters>>,
+    // the error causes the connection to stop, it will be reported by the sink
+    error: parking_lot::Mutex<Option<Error>>,
+    failed: AtomicBool,
+    // whether the peer sent the disconnect notification
+    peer_closed: AtomicBool,
+    // the connection is closed locally
+    closed: AtomicBool,
+    // detect the stall of the connection if it is enabled
+    watchdog: parking_lot::Mutex<Option<Watchdog>>,
+    // close the idle connection and ping the peer if it is enabled
+    keepalive: parking_lot::Mutex<Option<Keepalive>>,
+    // push the delivery receipts if it is enabled
+    receipts: OnceLock<Receipts>,
+
+    // the local threshold of compressing frame bodies, and whether the compression is negotiated
+    #[cfg(feature = "compression")]
+    compression_threshold: OnceLock<usize>,
+    #[cfg(feature = "compression")]
+    compression: AtomicBool,
+    // propagate the trace ids of the messages if this side enables it and the peer agrees, and
+    // the one carried by the last received message
+    propagate_trace_id: AtomicBool,
+    trace_id_negotiated: AtomicBool,
+    received_trace_id: parking_lot::Mutex<Option<u64>>,
+    // the order channel of the last received message if it is reliable ordered
+    received_order_channel: parking_lot::Mutex<Option<u8>>,
+    // the capability flags agreed with the peer once negotiated
+    agreed: OnceLock<u8>,
+    // the first message of the fast open, sent ahead of the other messages
+    first_msg: parking_lot::Mutex<Option<Message>>,
+    // the cipher of this side, the pending key exchange and the session established with the
+    // peer by it
+    #[cfg(feature = "encryption")]
+    cipher: OnceLock<Arc<dyn Cipher>>,
+    #[cfg(feature = "encryption")]
+    key_exchange: parking_lot::Mutex<Option<Box<dyn KeyExchange>>>,
+    #[cfg(feature = "encryption")]
+    cipher_session: OnceLock<Box<dyn CipherSession>>,
+    // wake the user messages held until the session is established
+    #[cfg(feature = "encryption")]
+    session_waker: AtomicWaker,
+    // the counters recorded into the `metrics` crate if it is enabled
+    #[cfg(feature = "metrics")]
+    metrics: OnceLock<Metrics>,
 
     role: RoleContext,
 }
 
+const NO_RTT_SAMPLE: u64 = u64::MAX;
+
+fn load_rtt(micros: &AtomicU64) -> Option<Duration> {
+    let micros = micros.load(Ordering::Relaxed);
+    (micros != NO_RTT_SAMPLE).then(|| Duration::from_micros(micros))
+}
+
+/// The atomic copy of [`TransferStats`], read without blocking the outgoing guard
+#[derive(Default)]
+struct TransferCounters {
+    sent: AtomicU64,
+    acked: AtomicU64,
+    resent: AtomicU64,
+    nacked: AtomicU64,
+}
+
+impl TransferCounters {
+    fn store(&self, stats: TransferStats) {
+        self.sent.store(stats.sent, Ordering::Relaxed);
+        self.acked.store(stats.acked, Ordering::Relaxed);
+        self.resent.store(stats.resent, Ordering::Relaxed);
+        self.nacked.store(stats.nacked, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> TransferStats {
+        TransferStats {
+            sent: self.sent.load(Ordering::Relaxed),
+            acked: self.acked.load(Ordering::Relaxed),
+            resent: self.resent.load(Ordering::Relaxed),
+            nacked: self.nacked.load(Ordering::Relaxed),
+        }
+    }
+}
+
 /// Pop priority queue while holding the lock
 struct BatchRecv<'a, T> {
-    guard: parking_lot::MutexGuard<'a, BinaryHeap<Reverse<T>>>,
+    heap: &'a mut BinaryHeap<Reverse<T>>,
 }
 
 impl<'a, T> BatchRecv<'a, T> {
-    fn new(guard: parking_lot::MutexGuard<'a, BinaryHeap<Reverse<T>>>) -> Self {
-        Self { guard }
+    fn new(heap: &'a mut BinaryHeap<Reverse<T>>) -> Self {
+        Self { heap }
     }
 }
 
@@ -50,44 +308,107 @@ impl<'a, T: Ord> Iterator for BatchRecv<'a, T> {
     type Item = T;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.guard.pop().map(|v| v.0)
+        let Reverse(v) = self.heap.pop()?;
+        // the duplicated ones are popped together, so that the records are minimal
+        while self.heap.peek().is_some_and(|Reverse(next)| *next == v) {
+            self.heap.pop();
+        }
+        Some(v)
     }
 }
 
 impl TransferLink {
     pub(crate) fn new_arc(role: RoleContext) -> SharedLink {
+        Self::new_arc_with_parent(role, None)
+    }
+
+    /// Create a link whose anomalies are also aggregated into the `parent_anomalies`
+    pub(crate) fn new_arc_with_parent(
+        role: RoleContext,
+        parent_anomalies: Option<Arc<AnomalyCounters>>,
+    ) -> SharedLink {
         // avoiding ack flood, the overwhelming ack will be dropped and new ack will be displaced
         const MAX_ACK_BUFFER: usize = 1024;
+        // only the recent samples are kept if the outgoing guard is not polled
+        const MAX_PONG_RTT_BUFFER: usize = 16;
 
         Arc::new(Self {
             incoming_ack: ConcurrentQueue::bounded(MAX_ACK_BUFFER),
             incoming_nack: ConcurrentQueue::bounded(MAX_ACK_BUFFER),
             forward_waking: AtomicBool::new(false),
             outgoing_ack: parking_lot::Mutex::new(BinaryHeap::with_capacity(MAX_ACK_BUFFER)),
+            ack_delay: parking_lot::Mutex::new(None),
             outgoing_nack: parking_lot::Mutex::new(BinaryHeap::with_capacity(MAX_ACK_BUFFER)),
+            reorder_window: parking_lot::Mutex::new(None),
             unconnected: ConcurrentQueue::unbounded(),
             frame_body: ConcurrentQueue::unbounded(),
+            raw_frames: ConcurrentQueue::unbounded(),
+            flushing_channel: parking_lot::Mutex::new(None),
+            connected_at: OnceLock::new(),
+            peer_guid: OnceLock::new(),
+            peer_addr: OnceLock::new(),
+            negotiated_mtu: AtomicU16::new(0),
+            mtu: AtomicU16::new(0),
+            mtu_update: AtomicBool::new(false),
+            mtu_update_negotiated: AtomicBool::new(false),
+            mtu_ladder: OnceLock::new(),
+            max_datagram_size: AtomicU16::new(0),
+            unacked: parking_lot::Mutex::new(vec![]),
+            rto: AtomicU64::new(RTO.as_micros() as u64),
+            rtt: AtomicU64::new(NO_RTT_SAMPLE),
+            rtt_var: AtomicU64::new(NO_RTT_SAMPLE),
+            pong_rtt: ConcurrentQueue::bounded(MAX_PONG_RTT_BUFFER),
+            last_pong: parking_lot::Mutex::new(None),
+            transfer: TransferCounters::default(),
+            seq_counters: parking_lot::Mutex::new(SeqCounters::default()),
+            anomalies: AnomalyCounters::default(),
+            parent_anomalies,
+            error: parking_lot::Mutex::new(None),
+            failed: AtomicBool::new(false),
+            peer_closed: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            watchdog: parking_lot::Mutex::new(None),
+            keepalive: parking_lot::Mutex::new(None),
+            receipts: OnceLock::new(),
+            #[cfg(feature = "compression")]
+            compression_threshold: OnceLock::new(),
+            #[cfg(feature = "compression")]
+            compression: AtomicBool::new(false),
+            propagate_trace_id: AtomicBool::new(false),
+            trace_id_negotiated: AtomicBool::new(false),
+            agreed: OnceLock::new(),
+            first_msg: parking_lot::Mutex::new(None),
+            received_trace_id: parking_lot::Mutex::new(None),
+            received_order_channel: parking_lot::Mutex::new(None),
+            #[cfg(feature = "encryption")]
+            cipher: OnceLock::new(),
+            #[cfg(feature = "encryption")]
+            key_exchange: parking_lot::Mutex::new(None),
+            #[cfg(feature = "encryption")]
+            cipher_session: OnceLock::new(),
+            #[cfg(feature = "encryption")]
+            session_waker: AtomicWaker::new(),
+            #[cfg(feature = "metrics")]
+            metrics: OnceLock::new(),
             role,
         })
     }
 
     pub(crate) fn turn_on_waking(&self) {
-        self.forward_waking
-            .store(true, std::sync::atomic::Ordering::Relaxed);
+        self.forward_waking.store(true, Ordering::Relaxed);
     }
 
     fn should_waking(&self) -> bool {
-        self.forward_waking
-            .load(std::sync::atomic::Ordering::Relaxed)
+        self.forward_waking.load(Ordering::Relaxed)
     }
 
     pub(crate) fn turn_off_waking(&self) {
-        self.forward_waking
-            .store(false, std::sync::atomic::Ordering::Relaxed);
+        self.forward_waking.store(false, Ordering::Relaxed);
     }
 
     pub(crate) fn incoming_ack(&self, records: AckOrNack) {
         if let Some(dropped) = self.incoming_ack.force_push(records).unwrap() {
+            self.record_anomaly(Anomaly::AckOverflow);
             warn!(
                 "[{}] discard received ack {dropped:?}, total count: {}",
                 self.role,
@@ -96,7 +417,7 @@ impl TransferLink {
         }
         // wake up after sends ack
         if self.should_waking() {
-            for waker in Reactor::get().cancel_all_timers(self.role.guid()) {
+            for waker in self.role.reactor().cancel_all_timers(self.role.guid()) {
                 // safe to panic
                 waker.wake();
                 debug!("[{}] wake up a certain waker after receives ack", self.role,);
@@ -106,6 +427,7 @@ impl TransferLink {
 
     pub(crate) fn incoming_nack(&self, records: AckOrNack) {
         if let Some(dropped) = self.incoming_nack.force_push(records).unwrap() {
+            self.record_anomaly(Anomaly::AckOverflow);
             warn!(
                 "[{}] discard received nack {dropped:?}, total count: {}",
                 self.role,
@@ -115,17 +437,73 @@ impl TransferLink {
     }
 
     pub(crate) fn outgoing_ack(&self, seq_num: u24) {
-        self.outgoing_ack.lock().push(Reverse(seq_num));
+        let mut acks = self.outgoing_ack.lock();
+        acks.push(Reverse(seq_num));
+        if let Some(ack_delay) = self.ack_delay.lock().as_mut() {
+            ack_delay.since.get_or_insert_with(Instant::now);
+        }
     }
 
     pub(crate) fn outgoing_nack(&self, seq_num: u24) {
         self.outgoing_nack.lock().push(Reverse(seq_num));
     }
 
+    /// Nack the missing frame sets, they are held in the reorder window first if it is enabled
     pub(crate) fn outgoing_nack_batch(&self, t: impl IntoIterator<Item = u24>) {
+        if let Some(reorder) = self.reorder_window.lock().as_mut() {
+            let now = Instant::now();
+            reorder
+                .missing
+                .extend(t.into_iter().map(|seq_num| (seq_num, now)));
+            return;
+        }
         self.outgoing_nack.lock().extend(t.into_iter().map(Reverse));
     }
 
+    /// Forget the missing frame set if it arrives late, and nack the missing ones that more than
+    /// the window of frame sets are received after. The late one never exceeds the window of the
+    /// missing ones still held, they are checked by the highest one received before.
+    fn reorder_received(&self, seq_num: u24) {
+        let mut reorder = self.reorder_window.lock();
+        let Some(reorder) = reorder.as_mut() else {
+            return;
+        };
+        reorder.missing.retain(|&(missing, _)| missing != seq_num);
+        let mut nack = self.outgoing_nack.lock();
+        while let Some(&(missing, _)) = reorder.missing.front()
+            && seq_num.serial_diff(missing) > reorder.frame_sets as i32
+        {
+            reorder.missing.pop_front();
+            nack.push(Reverse(missing));
+        }
+    }
+
+    /// Nack the missing frame sets held longer than the delay of the reorder window
+    fn release_reordered(&self) {
+        let mut reorder = self.reorder_window.lock();
+        let Some(reorder) = reorder.as_mut() else {
+            return;
+        };
+        let now = Instant::now();
+        let mut nack = self.outgoing_nack.lock();
+        while let Some(&(missing, detected_at)) = reorder.missing.front()
+            && detected_at + reorder.delay <= now
+        {
+            reorder.missing.pop_front();
+            nack.push(Reverse(missing));
+        }
+    }
+
+    /// Hold the nacks of the missing frame sets until the count of the frame sets received after
+    /// them exceeds `frame_sets` or the delay elapses, whichever comes first
+    pub(crate) fn enable_reorder_window(&self, frame_sets: u32, delay: Duration) {
+        *self.reorder_window.lock() = Some(ReorderWindow {
+            frame_sets,
+            delay,
+            missing: VecDeque::new(),
+        });
+    }
+
     pub(crate) fn send_unconnected(&self, packet: unconnected::Packet) {
         self.unconnected.push(packet).unwrap();
     }
@@ -134,16 +512,50 @@ impl TransferLink {
         self.frame_body.push(body).unwrap();
     }
 
-    // Clear all acknowledged frames
-    pub(crate) fn process_ack(&self, resend: &mut ResendMap) {
+    /// Send the raw frames as is, except that their reliable frame indices are reassigned from
+    /// the ones of this connection
+    pub(crate) fn send_raw_frames(&self, mut frames: Frames) {
+        for frame in &mut frames {
+            if frame.reliable_frame_index.is_some() {
+                frame.reliable_frame_index = Some(self.next_reliable_index());
+            }
+        }
+        self.raw_frames.push(frames).unwrap();
+    }
+
+    // Clear all acknowledged frames, `next_seq_num` is the next sequence number to be sent
+    pub(crate) fn process_ack(&self, resend: &mut ResendMap, next_seq_num: u24) {
         for ack in self.incoming_ack.try_iter() {
             trace!(
                 "[{}] receive ack {ack:?}, total count: {}",
                 self.role,
                 ack.total_cnt()
             );
+            // the peer acknowledges frames that have never been sent
+            for record in &ack.records {
+                let (Record::Range(_, end) | Record::Single(end)) = record;
+                if end.serial_diff(next_seq_num) >= 0 {
+                    self.record_anomaly(Anomaly::OutOfWindowAck);
+                }
+            }
             resend.on_ack(ack);
         }
+        for rtt in self.pong_rtt.try_iter() {
+            // the pong is replied immediately
+            resend.sample_rtt(Duration::ZERO, rtt);
+        }
+        let acked = resend.take_acked_messages();
+        if let Some(receipts) = self.receipts.get() {
+            if !acked.is_empty() {
+                let mut pending = receipts.pending.lock();
+                for id in acked.iter().filter_map(|key| pending.remove(key)) {
+                    receipts.deliver(id, &self.role);
+                }
+            }
+            for id in resend.take_acked_receipts() {
+                receipts.deliver(id, &self.role);
+            }
+        }
     }
 
     /// Push all missing frames into buffer
@@ -158,16 +570,81 @@ impl TransferLink {
                 self.role,
                 nack.total_cnt()
             );
+            metric!(self.metrics(), nacks_recv, 1);
             resend.on_nack_into(nack, buffer);
         }
+        resend.fast_retransmit_into(buffer);
     }
 
+    /// Coalesce the received sequence numbers into an ack, return `None` while they are held
     pub(crate) fn process_outgoing_ack(&self, mtu: u16) -> Option<AckOrNack> {
-        AckOrNack::extend_from(BatchRecv::new(self.outgoing_ack.lock()), mtu)
+        let mut acks = self.outgoing_ack.lock();
+        if self.ack_held_until(acks.len()).is_some() {
+            return None;
+        }
+        let ack = AckOrNack::extend_from(BatchRecv::new(&mut acks), mtu);
+        if ack.is_some() {
+            metric!(self.metrics(), acks_sent, 1);
+        }
+        if acks.is_empty()
+            && let Some(ack_delay) = self.ack_delay.lock().as_mut()
+        {
+            ack_delay.since = None;
+        }
+        ack
     }
 
     pub(crate) fn process_outgoing_nack(&self, mtu: u16) -> Option<AckOrNack> {
-        AckOrNack::extend_from(BatchRecv::new(self.outgoing_nack.lock()), mtu)
+        self.release_reordered();
+        AckOrNack::extend_from(BatchRecv::new(&mut self.outgoing_nack.lock()), mtu)
+    }
+
+    /// Hold the outgoing acknowledgements for the delay or until the count of the received frame
+    /// sets reaches the threshold, whichever comes first
+    pub(crate) fn enable_ack_delay(&self, delay: Duration, threshold: usize) {
+        *self.ack_delay.lock() = Some(AckDelay {
+            delay,
+            threshold,
+            since: None,
+        });
+    }
+
+    /// Send the outgoing acknowledgements without holding them from now on
+    pub(crate) fn disable_ack_delay(&self) {
+        *self.ack_delay.lock() = None;
+    }
+
+    /// Return the instant the held acknowledgements are due, `None` if they are due now
+    fn ack_held_until(&self, pending: usize) -> Option<Instant> {
+        let ack_delay = self.ack_delay.lock();
+        let ack_delay = ack_delay.as_ref()?;
+        if pending >= ack_delay.threshold {
+            return None;
+        }
+        // the peer resends the frame set after its rto, which is close to the one of this side,
+        // so the delay is kept well below it
+        let due = ack_delay.since? + ack_delay.delay.min(self.rto() / 2);
+        (due > Instant::now()).then_some(due)
+    }
+
+    /// Wake up the task when the held acknowledgements or the held nacks are due
+    pub(crate) fn poll_held_acks(&self, cx: &Context<'_>) {
+        let acks = self.outgoing_ack.lock();
+        let ack_due = if acks.is_empty() {
+            None
+        } else {
+            self.ack_held_until(acks.len())
+        };
+        drop(acks);
+        let nack_due = self.reorder_window.lock().as_ref().and_then(|reorder| {
+            reorder
+                .missing
+                .front()
+                .map(|&(_, detected_at)| detected_at + reorder.delay)
+        });
+        if let Some(due) = ack_due.into_iter().chain(nack_due).min() {
+            let _ = self.role.reactor().poll_deadline(self.role.guid(), due, cx);
+        }
     }
 
     pub(crate) fn process_unconnected(&self) -> impl Iterator<Item = unconnected::Packet> + '_ {
@@ -178,17 +655,674 @@ impl TransferLink {
         self.frame_body.try_iter()
     }
 
+    /// Pop a raw frame set, return its frames and whether it contains reliable frames
+    pub(crate) fn process_raw_frames(&self) -> Option<(Frames, bool)> {
+        let frames = self.raw_frames.pop().ok()?;
+        trace!(
+            "[{}] send raw frame set, frames count: {}",
+            self.role,
+            frames.len()
+        );
+        let reliable = frames
+            .iter()
+            .any(|frame| frame.flags.reliability.is_reliable());
+        Some((frames, reliable))
+    }
+
     // Return whether the flush buffer is empty
     pub(crate) fn flush_empty(&self) -> bool {
-        self.outgoing_ack.lock().is_empty()
+        self.control_flush_empty() && self.raw_frames.is_empty()
+    }
+
+    /// Return whether the acknowledgements and the unconnected packets are all sent
+    pub(crate) fn control_flush_empty(&self) -> bool {
+        let acks = self.outgoing_ack.lock();
+        // the held acknowledgements are not due yet
+        (acks.is_empty() || self.ack_held_until(acks.len()).is_some())
             && self.outgoing_nack.lock().is_empty()
             && self.unconnected.is_empty()
     }
 
+    /// Flush the frames of the ordered channel alone on the next flush
+    pub(crate) fn flush_channel(&self, channel: u8) {
+        *self.flushing_channel.lock() = Some(channel);
+    }
+
+    pub(crate) fn take_flushing_channel(&self) -> Option<u8> {
+        self.flushing_channel.lock().take()
+    }
+
     /// Return whether the frame body buffer is empty
     pub(crate) fn frame_body_empty(&self) -> bool {
         self.frame_body.is_empty()
     }
+
+    /// Record the instant when the online handshake completed, only the first call takes effect
+    pub(crate) fn mark_connected(&self) {
+        let _ = self.connected_at.set(Instant::now());
+    }
+
+    /// Queue the first message of the fast open, it is sent ahead of the other messages
+    pub(crate) fn queue_first_msg(&self, msg: Message) {
+        *self.first_msg.lock() = Some(msg);
+    }
+
+    /// Whether the first message of the fast open is waiting to be sent
+    pub(crate) fn has_first_msg(&self) -> bool {
+        self.first_msg.lock().is_some()
+    }
+
+    /// Take the first message of the fast open
+    pub(crate) fn take_first_msg(&self) -> Option<Message> {
+        self.first_msg.lock().take()
+    }
+
+    /// Lower the outgoing mtu to the given value, and inform the peer to do the same if it agrees
+    pub(crate) fn lower_mtu(&self, mtu: u16) {
+        self.update_mtu(mtu);
+        if self.mtu_update_negotiated() {
+            self.send_frame_body(FrameBody::MtuUpdate { mtu });
+        }
+    }
+
+    /// Accept the mtu updates of the peer, and send the ones of this side if the peer agrees
+    pub(crate) fn enable_mtu_update(&self) {
+        self.mtu_update.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the mtu updates are negotiated, the original raknet uses their id for the user
+    /// data
+    pub(crate) fn mtu_update_negotiated(&self) -> bool {
+        self.mtu_update_negotiated.load(Ordering::Relaxed)
+    }
+
+    /// Step down through the ladder once the path black-holes the large datagrams, only the first
+    /// call takes effect
+    pub(crate) fn enable_mtu_ladder(&self, mut ladder: Vec<u16>) {
+        ladder.sort_unstable_by(|a, b| b.cmp(a));
+        ladder.dedup();
+        let _ = self.mtu_ladder.set(ladder);
+        self.enable_mtu_update();
+    }
+
+    /// The frame set of the size is suspected to be black-holed by the path, lower the mtu to the
+    /// next step of the ladder below it. The frame sets packed before the mtu is lowered are
+    /// ignored.
+    pub(crate) fn black_holed(&self, size: usize) {
+        let Some(ladder) = self.mtu_ladder.get() else {
+            return;
+        };
+        if size > usize::from(self.peer_mtu()) {
+            return;
+        }
+        if let Some(&step) = ladder.iter().find(|&&step| usize::from(step) < size) {
+            debug!("[{}] frame sets of {size} bytes are black-holed", self.role);
+            self.lower_mtu(step);
+        }
+    }
+
+    /// Update the outgoing mtu
+    pub(crate) fn update_mtu(&self, mtu: u16) {
+        debug!("[{}] update outgoing mtu to {mtu}", self.role);
+        self.mtu.store(mtu, Ordering::Relaxed);
+    }
+
+    /// Record the mtu negotiated in the offline handshake
+    pub(crate) fn set_negotiated_mtu(&self, mtu: u16) {
+        self.negotiated_mtu.store(mtu, Ordering::Relaxed);
+    }
+
+    /// Return the mtu negotiated with the peer, lowered by the mtu updates during the connection
+    pub(crate) fn peer_mtu(&self) -> u16 {
+        let mtu = self.negotiated_mtu.load(Ordering::Relaxed);
+        match self.mtu.load(Ordering::Relaxed) {
+            0 => mtu,
+            updated => updated.min(mtu),
+        }
+    }
+
+    /// Cap the outgoing datagram size below the mtu without informing the peer
+    pub(crate) fn cap_datagram_size(&self, size: u16) {
+        debug!("[{}] cap outgoing datagram size to {size}", self.role);
+        self.max_datagram_size.store(size, Ordering::Relaxed);
+    }
+
+    /// Limit the given mtu by the updated one and the datagram size cap
+    pub(crate) fn limit_mtu(&self, mtu: u16) -> u16 {
+        let mtu = match self.mtu.load(Ordering::Relaxed) {
+            0 => mtu,
+            updated => updated.min(mtu),
+        };
+        match self.max_datagram_size.load(Ordering::Relaxed) {
+            0 => mtu,
+            cap => cap.min(mtu),
+        }
+    }
+
+    /// Stop the connection with an error, only the first error takes effect
+    pub(crate) fn fail(&self, err: Error) {
+        if self.failed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        warn!("[{}] connection failed: {err}", self.role);
+        *self.error.lock() = Some(err);
+        #[cfg(feature = "encryption")]
+        self.session_waker.wake();
+    }
+
+    /// Return whether the connection is failed
+    pub(crate) fn is_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Enable the delivery receipts, the receipt id of each acknowledged reliable message and
+    /// tagged unreliable message sent by the user is pushed into the sender
+    pub(crate) fn enable_receipts(&self, sender: flume::Sender<u64>) {
+        let _ = self.receipts.set(Receipts::new(Some(sender)));
+    }
+
+    /// Subscribe the delivery receipts, enabling them if they are disabled. Only the messages
+    /// sent afterwards are assigned receipt ids. Subscribing again ends the previous stream.
+    pub(crate) fn subscribe_receipts(&self) -> async_channel::Receiver<u64> {
+        let (tx, rx) = async_channel::unbounded();
+        *self
+            .receipts
+            .get_or_init(|| Receipts::new(None))
+            .subscriber
+            .lock() = Some(tx);
+        rx
+    }
+
+    pub(crate) fn receipts_enabled(&self) -> bool {
+        self.receipts.get().is_some()
+    }
+
+    /// Assign the next receipt id, return `None` if the delivery receipts are disabled
+    pub(crate) fn next_receipt_id(&self) -> Option<u64> {
+        self.receipts
+            .get()
+            .map(|receipts| receipts.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Await the acknowledgement of the message to push its receipt
+    pub(crate) fn expect_receipt(&self, key: MessageKey, id: u64) {
+        if let Some(receipts) = self.receipts.get() {
+            receipts.pending.lock().insert(key, id);
+        }
+    }
+
+    /// Await the acknowledgement of the frame set carrying the next tagged unreliable frame sent
+    /// to push its receipt
+    pub(crate) fn expect_unreliable_receipt(&self, id: u64) {
+        if let Some(receipts) = self.receipts.get() {
+            receipts.unreliable.lock().push_back(id);
+        }
+    }
+
+    /// Take the receipt ids of the tagged unreliable frames packed into a frame set, in the order
+    /// of sending
+    pub(crate) fn take_unreliable_receipts(&self, count: usize) -> Vec<u64> {
+        let Some(receipts) = self.receipts.get() else {
+            return vec![];
+        };
+        let mut unreliable = receipts.unreliable.lock();
+        let count = count.min(unreliable.len());
+        unreliable.drain(..count).collect()
+    }
+
+    /// Enable the watchdog detecting the stall of the connection
+    pub(crate) fn enable_watchdog(&self, stall_timeout: Duration, close_grace: Option<Duration>) {
+        *self.watchdog.lock() = Some(Watchdog::new(stall_timeout, close_grace, Instant::now()));
+    }
+
+    /// Feed the watchdog and the keepalive when receiving anything from the peer
+    fn feed_watchdog(&self) {
+        if let Some(watchdog) = self.watchdog.lock().as_mut() {
+            watchdog.progress(Instant::now());
+        }
+        if let Some(keepalive) = self.keepalive.lock().as_mut() {
+            keepalive.received(Instant::now());
+        }
+    }
+
+    /// Check the stall of the connection, `awaiting` is whether any frame set is awaiting
+    /// acknowledgement. The stalled connection fails after the grace period.
+    pub(crate) fn check_watchdog(&self, awaiting: bool) {
+        let verdict = match self.watchdog.lock().as_mut() {
+            Some(watchdog) => watchdog.check(Instant::now(), awaiting),
+            None => return,
+        };
+        match verdict {
+            Verdict::Stalled => {
+                warn!(
+                    "[{}] connection stalled, nothing is received from the peer",
+                    self.role
+                );
+                self.record_anomaly(Anomaly::Stall);
+            }
+            Verdict::Expired => self.fail(Error::ConnectionStalled),
+            Verdict::Healthy | Verdict::Stalling => {}
+        }
+    }
+
+    /// Enable the keepalive closing the connection idle for the timeout
+    pub(crate) fn enable_keepalive(&self, idle_timeout: Duration) {
+        *self.keepalive.lock() = Some(Keepalive::new(idle_timeout, Instant::now()));
+    }
+
+    /// Poll the keepalive of the connection, the pings are sent while nothing is received from
+    /// the peer. Return `Ready` after the connection fails with `IdleTimeout`, it is always
+    /// `Pending` if the keepalive is disabled.
+    pub(crate) fn poll_keepalive(&self, cx: &Context<'_>) -> Poll<()> {
+        loop {
+            let liveness = match self.keepalive.lock().as_mut() {
+                Some(keepalive) => keepalive.check(Instant::now()),
+                None => return Poll::Pending,
+            };
+            let Liveness::Alive { ping, wake_at } = liveness else {
+                self.fail(Error::IdleTimeout);
+                return Poll::Ready(());
+            };
+            if ping {
+                // sent on the next flush along with the acknowledgements
+                self.send_frame_body(FrameBody::ConnectedPing {
+                    client_timestamp: timestamp(),
+                });
+            }
+            if self
+                .role
+                .reactor()
+                .poll_deadline(self.role.guid(), wake_at, cx)
+                .is_pending()
+            {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    /// Mark the peer sent the disconnect notification
+    pub(crate) fn mark_peer_closed(&self) {
+        self.peer_closed.store(true, Ordering::Relaxed);
+    }
+
+    /// Mark the connection is closed locally, after sending the disconnect notification
+    pub(crate) fn mark_closed(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+
+    /// The reason of closing the connection, `None` if it is neither closed nor failed yet
+    pub(crate) fn closed_reason(&self) -> Option<CloseReason> {
+        let closed = self.is_failed()
+            || self.peer_closed.load(Ordering::Relaxed)
+            || self.closed.load(Ordering::Relaxed);
+        closed.then(|| self.close_reason())
+    }
+
+    /// The reason of closing the connection
+    pub(crate) fn close_reason(&self) -> CloseReason {
+        if self.is_failed() {
+            CloseReason::Failed
+        } else if self.peer_closed.load(Ordering::Relaxed) {
+            CloseReason::PeerClosed
+        } else {
+            CloseReason::Closed
+        }
+    }
+
+    /// Take the error causes the connection to stop, it returns `None` after the first take
+    pub(crate) fn take_error(&self) -> Option<Error> {
+        self.error.lock().take()
+    }
+
+    /// Record an anomaly of this connection
+    pub(crate) fn record_anomaly(&self, anomaly: Anomaly) {
+        self.anomalies.record(anomaly);
+        if let Some(parent) = &self.parent_anomalies {
+            parent.record(anomaly);
+        }
+    }
+
+    pub(crate) fn anomalies(&self) -> Anomalies {
+        self.anomalies.snapshot()
+    }
+
+    pub(crate) fn reset_anomalies(&self) {
+        self.anomalies.reset();
+    }
+
+    /// Publish the unacknowledged sequence numbers if the resend map changed. It is skipped if
+    /// the snapshot is being read to avoid blocking the send path.
+    pub(crate) fn publish_next_seq_num(&self, seq_num: u24) {
+        self.seq_counters.lock().next_seq_num = seq_num.to_u32();
+    }
+
+    pub(crate) fn publish_received_seq_num(&self, seq_num: u24) {
+        let mut counters = self.seq_counters.lock();
+        if counters
+            .highest_received_seq_num
+            .map_or(true, |highest| seq_num.serial_diff(highest.into()) > 0)
+        {
+            counters.highest_received_seq_num = Some(seq_num.to_u32());
+        }
+    }
+
+    /// Allocate the reliable frame index of the next outbound reliable frame
+    pub(crate) fn next_reliable_index(&self) -> u24 {
+        let mut counters = self.seq_counters.lock();
+        let index = u24::from(counters.next_reliable_index);
+        counters.next_reliable_index = index.wrapping_add(1).to_u32();
+        index
+    }
+
+    /// Return the snapshot of the sequence counters
+    pub(crate) fn seq_counters(&self) -> SeqCounters {
+        *self.seq_counters.lock()
+    }
+
+    pub(crate) fn publish_unacked(&self, resend: &mut ResendMap) {
+        if !resend.is_changed() {
+            return;
+        }
+        let Some(mut unacked) = self.unacked.try_lock() else {
+            return;
+        };
+        unacked.clear();
+        unacked.extend(resend.seq_nums());
+        unacked.sort_unstable();
+        resend.clear_changed();
+    }
+
+    /// Return the snapshot of the unacknowledged sequence numbers in ascending order
+    pub(crate) fn unacked_seqs(&self) -> Vec<u24> {
+        self.unacked.lock().clone()
+    }
+
+    /// Publish the effective base RTO
+    pub(crate) fn update_rto(&self, rto: Duration) {
+        self.rto.store(rto.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Return the effective base RTO
+    pub(crate) fn rto(&self) -> Duration {
+        Duration::from_micros(self.rto.load(Ordering::Relaxed))
+    }
+
+    /// Measure the RTT by the pong echoing the timestamp of the ping, the sample is taken by the
+    /// outgoing guard
+    pub(crate) fn receive_pong(&self, ping_timestamp: i64) {
+        let rtt = timestamp() - ping_timestamp;
+        if rtt < 0 {
+            return;
+        }
+        let rtt = Duration::from_millis(rtt as u64);
+        let _ = self.pong_rtt.force_push(rtt);
+        *self.last_pong.lock() = Some((ping_timestamp, rtt));
+    }
+
+    /// The RTT measured by the last pong if its ping is sent at or after the timestamp
+    pub(crate) fn pong_since(&self, timestamp: i64) -> Option<Duration> {
+        self.last_pong
+            .lock()
+            .filter(|(ping_timestamp, _)| *ping_timestamp >= timestamp)
+            .map(|(_, rtt)| rtt)
+    }
+
+    /// Publish the snapshot of the RTT estimation
+    pub(crate) fn update_rtt(&self, rtt: &RFC6298Impl) {
+        let Some(var) = rtt.jitter() else {
+            return;
+        };
+        self.rtt.store(rtt.get().as_micros() as u64, Ordering::Relaxed);
+        self.rtt_var.store(var.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Return the smoothed RTT, `None` if no sample is taken yet
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        load_rtt(&self.rtt)
+    }
+
+    /// Return the RTT variance, `None` if no sample is taken yet
+    pub(crate) fn jitter(&self) -> Option<Duration> {
+        load_rtt(&self.rtt_var)
+    }
+
+    /// Publish the transfer counters
+    pub(crate) fn update_transfer_stats(&self, stats: TransferStats) {
+        self.transfer.store(stats);
+    }
+
+    /// Return the snapshot of the link quality
+    pub(crate) fn stats(&self) -> ConnectionStats {
+        let transfer = self.transfer.load();
+        ConnectionStats {
+            rtt: self.rtt(),
+            rtt_var: self.jitter(),
+            rto: self.rto(),
+            packets_sent: transfer.sent,
+            packets_acked: transfer.acked,
+            packets_resent: transfer.resent,
+            nacks_received: transfer.nacked,
+        }
+    }
+
+    pub(crate) fn role(&self) -> RoleContext {
+        self.role
+    }
+
+    /// Record the guid of the peer, only the first call takes effect
+    pub(crate) fn set_peer_guid(&self, guid: u64) {
+        let _ = self.peer_guid.set(guid);
+    }
+
+    /// Return the guid of the peer if it is known
+    pub(crate) fn peer_guid(&self) -> Option<u64> {
+        self.peer_guid.get().copied()
+    }
+
+    /// Record the address of the peer, only the first call takes effect
+    pub(crate) fn set_peer_addr(&self, addr: SocketAddr) {
+        let _ = self.peer_addr.set(addr);
+    }
+
+    /// Return the address of the peer if it is known
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr.get().copied()
+    }
+
+    /// Return the instant when the online handshake completed
+    pub(crate) fn connected_at(&self) -> Option<Instant> {
+        self.connected_at.get().copied()
+    }
+
+    /// The capability flags of this side advertised in the online handshake
+    pub(crate) fn capabilities(&self) -> u8 {
+        let mut capabilities = 0;
+        #[cfg(feature = "compression")]
+        if self.compression_threshold.get().is_some() {
+            capabilities |= connected::CAPABILITY_COMPRESSION;
+        }
+        if self.propagate_trace_id.load(Ordering::Relaxed) {
+            capabilities |= connected::CAPABILITY_TRACE_ID;
+        }
+        if self.mtu_update.load(Ordering::Relaxed) {
+            capabilities |= connected::CAPABILITY_MTU_UPDATE;
+        }
+        capabilities
+    }
+
+    /// Negotiate with the capability flags of the peer, return the agreed ones
+    pub(crate) fn negotiate(&self, peer_capabilities: u8) -> u8 {
+        let agreed = self.capabilities() & peer_capabilities;
+        let _ = self.agreed.set(agreed);
+        #[cfg(feature = "compression")]
+        if agreed & connected::CAPABILITY_COMPRESSION != 0 {
+            debug!("[{}] compression negotiated", self.role);
+            self.compression.store(true, Ordering::Relaxed);
+        }
+        if agreed & connected::CAPABILITY_TRACE_ID != 0 {
+            debug!("[{}] trace id propagation negotiated", self.role);
+            self.trace_id_negotiated.store(true, Ordering::Relaxed);
+        }
+        if agreed & connected::CAPABILITY_MTU_UPDATE != 0 {
+            debug!("[{}] mtu update negotiated", self.role);
+            self.mtu_update_negotiated.store(true, Ordering::Relaxed);
+        }
+        agreed
+    }
+
+    /// Whether the user data starting with the header of the capability is escaped. The client
+    /// takes the offered capabilities as agreed until the server replies, since it may send
+    /// before the online handshake completes and the server negotiates before receiving them.
+    /// If the server does not agree, the user data starting with the header sent in the meantime
+    /// arrives escaped.
+    pub(crate) fn capability_applies(&self, capability: u8) -> bool {
+        let agreed = self
+            .agreed
+            .get()
+            .copied()
+            .unwrap_or_else(|| self.capabilities());
+        agreed & capability != 0
+    }
+
+    /// Propagate the trace ids of the messages if the peer agrees
+    pub(crate) fn enable_trace_id_propagation(&self) {
+        self.propagate_trace_id.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the trace id propagation is negotiated
+    pub(crate) fn trace_id_negotiated(&self) -> bool {
+        self.trace_id_negotiated.load(Ordering::Relaxed)
+    }
+
+    /// Record the trace id carried by the message just received, `None` if it carries none
+    pub(crate) fn receive_trace_id(&self, trace_id: Option<u64>) {
+        *self.received_trace_id.lock() = trace_id;
+    }
+
+    /// The trace id carried by the last received message
+    pub(crate) fn received_trace_id(&self) -> Option<u64> {
+        *self.received_trace_id.lock()
+    }
+
+    /// Record the order channel of the message just received, `None` if it is not reliable
+    /// ordered
+    pub(crate) fn receive_order_channel(&self, channel: Option<u8>) {
+        *self.received_order_channel.lock() = channel;
+    }
+
+    /// The order channel of the last received message if it is reliable ordered
+    pub(crate) fn received_order_channel(&self) -> Option<u8> {
+        *self.received_order_channel.lock()
+    }
+
+    /// Enable compressing the frame bodies larger than the threshold if the peer agrees, only the
+    /// first call takes effect
+    #[cfg(feature = "compression")]
+    pub(crate) fn enable_compression(&self, threshold: usize) {
+        let _ = self.compression_threshold.set(threshold);
+    }
+
+    /// Return the threshold of compressing frame bodies if the compression is negotiated
+    #[cfg(feature = "compression")]
+    pub(crate) fn compression_threshold(&self) -> Option<usize> {
+        if !self.compression.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.compression_threshold.get().copied()
+    }
+
+    /// Require encrypting the user data by the cipher, only the first call takes effect
+    #[cfg(feature = "encryption")]
+    pub(crate) fn enable_encryption(&self, cipher: Arc<dyn Cipher>) {
+        let _ = self.cipher.set(cipher);
+    }
+
+    /// Whether the encryption is required by this side
+    #[cfg_attr(not(feature = "encryption"), allow(clippy::unused_self))]
+    pub(crate) fn encryption_enabled(&self) -> bool {
+        #[allow(unused_mut)]
+        let mut enabled = false;
+        #[cfg(feature = "encryption")]
+        {
+            enabled = self.cipher.get().is_some();
+        }
+        enabled
+    }
+
+    /// Start the key exchange if the encryption is required, return the key sent to the peer
+    #[cfg_attr(not(feature = "encryption"), allow(clippy::unused_self))]
+    pub(crate) fn start_key_exchange(&self) -> Bytes {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = self.cipher.get() {
+            let exchange = cipher.key_exchange();
+            let key = exchange.local_key();
+            *self.key_exchange.lock() = Some(exchange);
+            return key;
+        }
+        Bytes::new()
+    }
+
+    /// Establish the session encrypting the user data by the key of the peer if the encryption
+    /// is required, return `false` if the key is rejected
+    #[cfg_attr(not(feature = "encryption"), allow(clippy::unused_self))]
+    pub(crate) fn establish_session(&self, peer_key: &[u8]) -> bool {
+        #[cfg(feature = "encryption")]
+        if self.encryption_enabled() {
+            let Some(session) = self
+                .key_exchange
+                .lock()
+                .take()
+                .and_then(|exchange| exchange.establish(peer_key))
+            else {
+                return false;
+            };
+            debug!("[{}] encryption established", self.role);
+            let _ = self.cipher_session.set(session);
+            self.session_waker.wake();
+        }
+        let _ = peer_key;
+        true
+    }
+
+    /// Return the session encrypting the user data if it is established
+    #[cfg(feature = "encryption")]
+    pub(crate) fn cipher_session(&self) -> Option<&dyn CipherSession> {
+        self.cipher_session.get().map(AsRef::as_ref)
+    }
+
+    /// Whether the user data is held until the session is established, the client may send
+    /// before the online handshake completes
+    #[cfg_attr(not(feature = "encryption"), allow(clippy::unused_self))]
+    pub(crate) fn awaits_session(&self) -> bool {
+        #[allow(unused_mut)]
+        let mut awaits = false;
+        #[cfg(feature = "encryption")]
+        {
+            awaits = self.encryption_enabled()
+                && self.cipher_session.get().is_none()
+                && !self.is_failed();
+        }
+        awaits
+    }
+
+    /// Wake the task once the session is established or the connection fails
+    #[cfg(feature = "encryption")]
+    pub(crate) fn register_session_waker(&self, waker: &Waker) {
+        self.session_waker.register(waker);
+    }
+
+    /// Record the counters of the connection into the `metrics` crate labeled by the id, only the
+    /// first call takes effect
+    #[cfg(feature = "metrics")]
+    pub(crate) fn enable_metrics(&self, id: String) {
+        let _ = self.metrics.set(Metrics::new(id));
+    }
+
+    /// The counters of the connection if the metrics are enabled
+    #[cfg(feature = "metrics")]
+    pub(crate) fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.get()
+    }
 }
 
 /// Router for incoming packets
@@ -211,25 +1345,41 @@ impl Router {
         )
     }
 
+    /// The link of the routed connection
+    pub(crate) fn link(&self) -> &SharedLink {
+        &self.link
+    }
+
     /// Deliver the packet to the corresponding route. Return false if the connection was dropped.
     pub(crate) fn deliver(&mut self, pack: connected::Packet<FramesMut>) -> bool {
         if self.router_tx.is_closed() {
             debug_assert!(Arc::strong_count(&self.link) == 1);
             return false;
         }
+        self.link.feed_watchdog();
         match pack {
             connected::Packet::FrameSet(frames) => {
                 self.link.outgoing_ack(frames.seq_num);
+                metric!(
+                    self.link.metrics(),
+                    bytes_recv,
+                    crate::packet::FRAME_SET_HEADER_SIZE
+                        + frames.set.iter().map(|frame| frame.size()).sum::<usize>()
+                );
 
                 let seq_num = frames.seq_num;
                 let pre_read = self.seq_read;
-                if pre_read <= seq_num {
-                    self.seq_read = seq_num + 1;
-                    let nack = pre_read.to_u32()..seq_num.to_u32();
-                    if !nack.is_empty() {
-                        self.link.outgoing_nack_batch(nack.map(u24::from));
+                // the sequence number wraps around, the stale ones are behind the pre read
+                let gap = seq_num.serial_diff(pre_read);
+                if gap >= 0 {
+                    self.seq_read = seq_num.wrapping_add(1);
+                    self.link.publish_received_seq_num(seq_num);
+                    if gap > 0 {
+                        self.link
+                            .outgoing_nack_batch((0..gap as u32).map(|i| pre_read.wrapping_add(i)));
                     }
                 }
+                self.link.reorder_received(seq_num);
 
                 return self.router_tx.try_send(frames).is_ok();
             }
@@ -239,3 +1389,265 @@ impl Router {
         true
     }
 }
+
+#[cfg(test)]
+impl TransferLink {
+    /// Fast-forward the next reliable frame index, test only
+    pub(crate) fn fast_forward_reliable_index(&self, index: u24) {
+        self.seq_counters.lock().next_reliable_index = index.to_u32();
+    }
+}
+
+#[cfg(test)]
+impl Router {
+    /// Fast-forward the next expected sequence number, test only
+    pub(crate) fn fast_forward_seq_read(&mut self, seq_num: u24) {
+        self.seq_read = seq_num;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packet::connected::Flags;
+    use crate::Reliability;
+
+    #[test]
+    fn test_unacked_seqs_works() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let mut resend = ResendMap::new(RoleContext::test_server());
+        assert!(link.unacked_seqs().is_empty());
+
+        for seq_num in [2, 0, 1, 3] {
+            resend.record(seq_num.into(), vec![]);
+        }
+        link.publish_unacked(&mut resend);
+        assert_eq!(
+            link.unacked_seqs(),
+            vec![0.into(), 1.into(), 2.into(), 3.into()]
+        );
+
+        resend.on_ack(AckOrNack {
+            records: vec![Record::Range(1.into(), 2.into())],
+        });
+        // skipped while reading the snapshot
+        {
+            let _reading = link.unacked.lock();
+            link.publish_unacked(&mut resend);
+        }
+        assert_eq!(
+            link.unacked_seqs(),
+            vec![0.into(), 1.into(), 2.into(), 3.into()]
+        );
+        link.publish_unacked(&mut resend);
+        assert_eq!(link.unacked_seqs(), vec![0.into(), 3.into()]);
+    }
+
+    #[test]
+    fn test_raw_frames_reliable_index() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        link.fast_forward_reliable_index(u24::MAX);
+        let frame = |reliability: Reliability, index: Option<u24>| Frame {
+            flags: Flags::new(reliability, false),
+            reliable_frame_index: index,
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            body: Bytes::from_static(b"forwarded"),
+        };
+        // the reliable index of another connection is far ahead
+        link.send_raw_frames(vec![
+            frame(Reliability::Reliable, Some(1000.into())),
+            frame(Reliability::Unreliable, None),
+            frame(Reliability::Reliable, Some(1001.into())),
+        ]);
+        let (frames, reliable) = link.process_raw_frames().unwrap();
+        assert!(reliable);
+        assert_eq!(
+            frames
+                .iter()
+                .map(|frame| frame.reliable_frame_index)
+                .collect::<Vec<_>>(),
+            [Some(u24::MAX), None, Some(0.into())]
+        );
+        assert_eq!(link.seq_counters().next_reliable_index, 1);
+    }
+
+    #[test]
+    fn test_black_holed_lowers_mtu() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        link.set_negotiated_mtu(1400);
+        // without the ladder
+        link.black_holed(1400);
+        assert_eq!(link.peer_mtu(), 1400);
+
+        link.enable_mtu_ladder(vec![576, 1300, 1200]);
+        link.black_holed(1250);
+        assert_eq!(link.peer_mtu(), 1200);
+        // the peer does not agree, keep it silent
+        assert!(link.frame_body_empty());
+        // packed before the mtu is lowered
+        link.black_holed(1250);
+        assert_eq!(link.peer_mtu(), 1200);
+
+        link.negotiate(connected::CAPABILITY_MTU_UPDATE);
+        assert!(link.mtu_update_negotiated());
+        link.black_holed(1200);
+        assert_eq!(link.peer_mtu(), 576);
+        assert!(matches!(
+            link.frame_body.pop(),
+            Ok(FrameBody::MtuUpdate { mtu: 576 })
+        ));
+        // the last step
+        link.black_holed(576);
+        assert_eq!(link.peer_mtu(), 576);
+        assert!(link.frame_body_empty());
+    }
+
+    #[test]
+    fn test_anomalies_works() {
+        let parent = Arc::new(AnomalyCounters::default());
+        let link = TransferLink::new_arc_with_parent(
+            RoleContext::test_server(),
+            Some(Arc::clone(&parent)),
+        );
+        let mut resend = ResendMap::new(RoleContext::test_server());
+
+        // 1025th ack overflows the buffer
+        for i in 0..1025 {
+            link.incoming_ack(AckOrNack {
+                records: vec![Record::Single(i.into())],
+            });
+        }
+        // acks of seq_num larger than 9 are out of window
+        link.process_ack(&mut resend, 10.into());
+        link.record_anomaly(Anomaly::DecodeError);
+        link.record_anomaly(Anomaly::UnexpectedPacket);
+        link.record_anomaly(Anomaly::UnexpectedPacket);
+        link.record_anomaly(Anomaly::UnknownGamePacket);
+        link.record_anomaly(Anomaly::Stall);
+
+        let expected = Anomalies {
+            decode_errors: 1,
+            unexpected_packets: 2,
+            out_of_window_acks: 1024 - 9,
+            ack_overflows: 1,
+            unknown_game_packets: 1,
+            stalls: 1,
+        };
+        assert_eq!(link.anomalies(), expected);
+        assert_eq!(parent.snapshot(), expected);
+
+        link.reset_anomalies();
+        assert_eq!(link.anomalies(), Anomalies::default());
+        assert_eq!(parent.snapshot(), expected);
+
+        link.record_anomaly(Anomaly::DecodeError);
+        assert_eq!(link.anomalies().decode_errors, 1);
+        assert_eq!(parent.snapshot().decode_errors, 2);
+    }
+
+    #[test]
+    fn test_ack_delay_coalescing() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        link.enable_ack_delay(Duration::from_secs(10), 4);
+        // the delay is capped at half of the rto
+        link.update_rto(Duration::from_millis(100));
+
+        // the duplicated frame set is acknowledged once
+        for seq_num in [0, 1, 1] {
+            link.outgoing_ack(seq_num.into());
+        }
+        assert!(link.process_outgoing_ack(1400).is_none());
+        assert!(link.control_flush_empty());
+
+        // the threshold is reached
+        link.outgoing_ack(2.into());
+        assert!(!link.control_flush_empty());
+        let ack = link.process_outgoing_ack(1400).unwrap();
+        assert!(ack.records == vec![Record::Range(0.into(), 2.into())]);
+
+        link.outgoing_ack(4.into());
+        link.outgoing_ack(5.into());
+        assert!(link.process_outgoing_ack(1400).is_none());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!link.control_flush_empty());
+        let ack = link.process_outgoing_ack(1400).unwrap();
+        assert!(ack.records == vec![Record::Range(4.into(), 5.into())]);
+        assert!(link.control_flush_empty());
+
+        // sent at once after disabling it
+        link.disable_ack_delay();
+        link.outgoing_ack(7.into());
+        let ack = link.process_outgoing_ack(1400).unwrap();
+        assert!(ack.records == vec![Record::Single(7.into())]);
+    }
+
+    #[test]
+    fn test_nack_reorder_window() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        link.enable_reorder_window(2, Duration::from_millis(50));
+        let (mut router, _route) = Router::new(Arc::clone(&link));
+        let frame_set = |seq_num: u32| {
+            connected::Packet::FrameSet(FrameSet {
+                seq_num: seq_num.into(),
+                set: vec![],
+            })
+        };
+
+        // the reordered one is not nacked
+        assert!(router.deliver(frame_set(0)));
+        assert!(router.deliver(frame_set(2)));
+        assert!(link.process_outgoing_nack(1400).is_none());
+        assert!(router.deliver(frame_set(1)));
+
+        // nacked once more than 2 frame sets are received after it
+        assert!(router.deliver(frame_set(4)));
+        assert!(router.deliver(frame_set(5)));
+        assert!(link.process_outgoing_nack(1400).is_none());
+        assert!(router.deliver(frame_set(6)));
+        let nack = link.process_outgoing_nack(1400).unwrap();
+        assert!(nack.records == vec![Record::Single(3.into())]);
+
+        // nacked after the delay
+        assert!(router.deliver(frame_set(8)));
+        assert!(link.process_outgoing_nack(1400).is_none());
+        std::thread::sleep(Duration::from_millis(60));
+        let nack = link.process_outgoing_nack(1400).unwrap();
+        assert!(nack.records == vec![Record::Single(7.into())]);
+    }
+
+    #[test]
+    fn test_router_seq_num_wrap_around() {
+        let link = TransferLink::new_arc(RoleContext::test_server());
+        let (mut router, _route) = Router::new(Arc::clone(&link));
+        router.fast_forward_seq_read(u24::MAX - 1);
+        let frame_set = |seq_num: u24| {
+            connected::Packet::FrameSet(FrameSet {
+                seq_num,
+                set: vec![],
+            })
+        };
+
+        // the frame set of u24::MAX is missing
+        assert!(router.deliver(frame_set(u24::MAX - 1)));
+        assert!(router.deliver(frame_set(0.into())));
+        assert!(router.deliver(frame_set(1.into())));
+        let nack = link.process_outgoing_nack(1400).unwrap();
+        assert!(nack.records == vec![Record::Single(u24::MAX)]);
+        assert_eq!(link.seq_counters().highest_received_seq_num, Some(1));
+
+        // the late one is acknowledged without any nack
+        assert!(router.deliver(frame_set(u24::MAX)));
+        assert!(link.process_outgoing_nack(1400).is_none());
+        assert_eq!(link.seq_counters().highest_received_seq_num, Some(1));
+        let ack = link.process_outgoing_ack(1400).unwrap();
+        assert!(
+            ack.records
+                == vec![
+                    Record::Range(0.into(), 1.into()),
+                    Record::Range(u24::MAX - 1, u24::MAX)
+                ]
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use std::fmt;
+use std::str::FromStr;
+
+use bytes::Bytes;
+
+use crate::errors::CodecError;
+
+/// The advertisement in the Minecraft Bedrock Edition format, the fields are separated by `;`:
+///
+/// `MCPE;<line1>;<protocol>;<version>;<player_count>;<max_players>;<server_guid>;<world_name>;
+/// <gamemode>;<gamemode_id>;<port_v4>;<port_v6>;`
+///
+/// The text fields must not contain `;`, it can not be escaped in this format.
+///
+/// ```
+/// use raknet_rs::motd::Motd;
+///
+/// let motd = Motd::new()
+///     .line1("Hello, I am server")
+///     .protocol(686)
+///     .version("1.21.2")
+///     .player_count(1)
+///     .max_players(10)
+///     .server_guid(114514);
+/// let advertisement = motd.to_bytes(); // passed to `server::Config::advertisement`
+/// assert_eq!(Motd::parse(&advertisement).unwrap(), motd);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Motd {
+    /// The edition, `MCPE` for the Bedrock Edition and `MCEE` for the Education Edition
+    pub edition: String,
+    /// The first line shown in the server list
+    pub line1: String,
+    /// The network protocol version of the game
+    pub protocol: u32,
+    /// The game version, e.g. `1.21.2`
+    pub version: String,
+    /// The count of the online players
+    pub player_count: u32,
+    /// The maximum players
+    pub max_players: u32,
+    /// The guid of the server
+    pub server_guid: u64,
+    /// The world name, shown as the second line in the server list
+    pub world_name: String,
+    /// The gamemode, e.g. `Survival`
+    pub gamemode: String,
+    /// The numeric gamemode, e.g. 1 for `Survival`
+    pub gamemode_id: Option<u8>,
+    /// The IPv4 port of the server
+    pub port_v4: Option<u16>,
+    /// The IPv6 port of the server
+    pub port_v6: Option<u16>,
+}
+
+impl Default for Motd {
+    fn default() -> Self {
+        Self {
+            edition: "MCPE".to_owned(),
+            line1: String::new(),
+            protocol: 0,
+            version: String::new(),
+            player_count: 0,
+            max_players: 0,
+            server_guid: 0,
+            world_name: String::new(),
+            gamemode: "Survival".to_owned(),
+            gamemode_id: None,
+            port_v4: None,
+            port_v6: None,
+        }
+    }
+}
+
+impl Motd {
+    /// Create a MOTD of the Bedrock Edition in the survival gamemode
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the edition.
+    /// The default value is `MCPE`
+    pub fn edition(mut self, edition: impl Into<String>) -> Self {
+        self.edition = edition.into();
+        self
+    }
+
+    /// Set the first line
+    pub fn line1(mut self, line1: impl Into<String>) -> Self {
+        self.line1 = line1.into();
+        self
+    }
+
+    /// Set the network protocol version
+    pub fn protocol(mut self, protocol: u32) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Set the game version
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    /// Set the count of the online players, it can be updated by [`Advertise`] later
+    ///
+    /// [`Advertise`]: crate::server::Advertise
+    pub fn player_count(mut self, player_count: u32) -> Self {
+        self.player_count = player_count;
+        self
+    }
+
+    /// Set the maximum players
+    pub fn max_players(mut self, max_players: u32) -> Self {
+        self.max_players = max_players;
+        self
+    }
+
+    /// Set the guid of the server, it should be the same as the one of the server config
+    pub fn server_guid(mut self, server_guid: u64) -> Self {
+        self.server_guid = server_guid;
+        self
+    }
+
+    /// Set the world name
+    pub fn world_name(mut self, world_name: impl Into<String>) -> Self {
+        self.world_name = world_name.into();
+        self
+    }
+
+    /// Set the gamemode.
+    /// The default value is `Survival`
+    pub fn gamemode(mut self, gamemode: impl Into<String>) -> Self {
+        self.gamemode = gamemode.into();
+        self
+    }
+
+    /// Set the numeric gamemode.
+    /// The default value is absent
+    pub fn gamemode_id(mut self, gamemode_id: u8) -> Self {
+        self.gamemode_id = Some(gamemode_id);
+        self
+    }
+
+    /// Set the IPv4 and IPv6 ports.
+    /// The default value is absent
+    pub fn ports(mut self, port_v4: u16, port_v6: u16) -> Self {
+        self.port_v4 = Some(port_v4);
+        self.port_v6 = Some(port_v6);
+        self
+    }
+
+    /// Serialize it into the advertisement
+    pub fn to_bytes(&self) -> Bytes {
+        Bytes::from(self.to_string())
+    }
+
+    /// Parse the advertisement in the pong. The fields after `max_players` are absent in the
+    /// older servers, they fall back to the default values.
+    ///
+    /// # Errors
+    /// Returns [`CodecError::InvalidMotd`] if the advertisement is not in the format.
+    pub fn parse(advertisement: &[u8]) -> Result<Self, CodecError> {
+        std::str::from_utf8(advertisement)
+            .map_err(|_| CodecError::InvalidMotd("not utf-8"))?
+            .parse()
+    }
+}
+
+impl fmt::Display for Motd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{};{};{};{};{};{};{};{};{};",
+            self.edition,
+            self.line1,
+            self.protocol,
+            self.version,
+            self.player_count,
+            self.max_players,
+            self.server_guid,
+            self.world_name,
+            self.gamemode
+        )?;
+        // the fields are positional, the numeric gamemode is written if the ports are present
+        if self.gamemode_id.is_some() || self.port_v4.is_some() || self.port_v6.is_some() {
+            write!(f, "{};", self.gamemode_id.unwrap_or(1))?;
+        }
+        if self.port_v4.is_some() || self.port_v6.is_some() {
+            write!(
+                f,
+                "{};{};",
+                self.port_v4.unwrap_or(19132),
+                self.port_v6.unwrap_or(19133)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Motd {
+    type Err = CodecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn num<T: FromStr>(field: Option<&str>, name: &'static str) -> Result<T, CodecError> {
+            field
+                .ok_or(CodecError::InvalidMotd(name))?
+                .parse()
+                .map_err(|_| CodecError::InvalidMotd(name))
+        }
+
+        fn opt_num<T: FromStr>(
+            field: Option<&str>,
+            name: &'static str,
+        ) -> Result<Option<T>, CodecError> {
+            match field {
+                None | Some("") => Ok(None),
+                some => num(some, name).map(Some),
+            }
+        }
+
+        fn text(field: Option<&str>, name: &'static str) -> Result<String, CodecError> {
+            field
+                .map(str::to_owned)
+                .ok_or(CodecError::InvalidMotd(name))
+        }
+
+        let mut fields = s.split(';');
+        let default = Self::default();
+        Ok(Self {
+            edition: text(fields.next(), "edition")?,
+            line1: text(fields.next(), "line1")?,
+            protocol: num(fields.next(), "protocol")?,
+            version: text(fields.next(), "version")?,
+            player_count: num(fields.next(), "player_count")?,
+            max_players: num(fields.next(), "max_players")?,
+            server_guid: opt_num(fields.next(), "server_guid")?.unwrap_or(default.server_guid),
+            world_name: fields.next().map_or(default.world_name, str::to_owned),
+            gamemode: fields
+                .next()
+                .filter(|gamemode| !gamemode.is_empty())
+                .map_or(default.gamemode, str::to_owned),
+            gamemode_id: opt_num(fields.next(), "gamemode_id")?,
+            port_v4: opt_num(fields.next(), "port_v4")?,
+            port_v6: opt_num(fields.next(), "port_v6")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Motd;
+    use crate::errors::CodecError;
+
+    #[test]
+    fn test_motd_serialize() {
+        let motd = Motd::new()
+            .line1("Dedicated Server")
+            .protocol(686)
+            .version("1.21.2")
+            .player_count(3)
+            .max_players(10)
+            .server_guid(13253860892328930865)
+            .world_name("Bedrock level");
+        assert_eq!(
+            motd.to_string(),
+            "MCPE;Dedicated Server;686;1.21.2;3;10;13253860892328930865;Bedrock level;Survival;"
+        );
+
+        let motd = motd.gamemode_id(1).ports(19132, 19133);
+        assert_eq!(
+            motd.to_string(),
+            "MCPE;Dedicated Server;686;1.21.2;3;10;13253860892328930865;Bedrock level;Survival;1;19132;19133;"
+        );
+        assert_eq!(Motd::parse(&motd.to_bytes()).unwrap(), motd);
+    }
+
+    #[test]
+    fn test_motd_parse() {
+        // the older servers only have the fields up to max_players
+        let motd = Motd::parse(b"MCPE;Old Server;70;0.14.0;0;20").unwrap();
+        assert_eq!(motd.line1, "Old Server");
+        assert_eq!(motd.protocol, 70);
+        assert_eq!(motd.version, "0.14.0");
+        assert_eq!(motd.max_players, 20);
+        assert_eq!(motd.server_guid, 0);
+        assert_eq!(motd.gamemode, "Survival");
+        assert_eq!(motd.gamemode_id, None);
+
+        let motd =
+            Motd::parse(b"MCEE;Class;686;1.21.2;1;30;42;World;Creative;0;19132;19133;").unwrap();
+        assert_eq!(motd.edition, "MCEE");
+        assert_eq!(motd.gamemode, "Creative");
+        assert_eq!(motd.gamemode_id, Some(0));
+        assert_eq!((motd.port_v4, motd.port_v6), (Some(19132), Some(19133)));
+
+        assert!(matches!(
+            Motd::parse(b"MCPE;Server;not a number;1.21.2;0;10"),
+            Err(CodecError::InvalidMotd("protocol"))
+        ));
+        assert!(matches!(
+            Motd::parse(b"MCPE;Server;686;1.21.2"),
+            Err(CodecError::InvalidMotd("player_count"))
+        ));
+        assert!(matches!(
+            Motd::parse(b"\xff\xfe"),
+            Err(CodecError::InvalidMotd("not utf-8"))
+        ));
+    }
+}
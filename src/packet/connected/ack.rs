@@ -54,7 +54,7 @@ impl AckOrNack {
             let Some(seq_num) = sorted_seq_nums.next() else {
                 break;
             };
-            if seq_num == last + 1 {
+            if seq_num == last.wrapping_add(1) {
                 if upgrade_flag {
                     mtu -= 3;
                     upgrade_flag = false;
@@ -126,14 +126,20 @@ pub(crate) enum Record {
 
 impl Record {
     fn read(buf: &mut BytesMut) -> Result<Self, CodecError> {
-        let record_type = read_buf!(buf, 1, buf.get_u8());
+        let record_type = read_buf!(buf, 1, "ack record type", buf.get_u8());
         match record_type {
             RECORD_RANGE => read_buf!(
                 buf,
                 6,
+                "ack range record",
                 Ok(Record::Range(buf.get_u24_le(), buf.get_u24_le()))
             ),
-            RECORD_SINGLE => read_buf!(buf, 3, Ok(Record::Single(buf.get_u24_le()))),
+            RECORD_SINGLE => read_buf!(
+                buf,
+                3,
+                "ack single record",
+                Ok(Record::Single(buf.get_u24_le()))
+            ),
             _ => Err(CodecError::InvalidRecordType(record_type)),
         }
     }
@@ -152,9 +158,18 @@ impl Record {
         }
     }
 
+    /// The sequence numbers of the record, the range may wrap around at the boundary of u24
+    pub(crate) fn seq_nums(&self) -> impl Iterator<Item = u24> {
+        let (start, cnt) = match *self {
+            Record::Range(start, _) => (start, self.ack_cnt()),
+            Record::Single(seq_num) => (seq_num, 1),
+        };
+        (0..cnt as u32).map(move |i| start.wrapping_add(i))
+    }
+
     fn ack_cnt(&self) -> usize {
         match self {
-            Record::Range(start, end) => usize::from(end - start + 1),
+            Record::Range(start, end) => end.wrapping_sub(start.to_u32()).to_usize() + 1,
             Record::Single(_) => 1,
         }
     }
@@ -200,4 +215,18 @@ mod test {
             assert_eq!(seq_nums.len(), remain);
         }
     }
+
+    #[test]
+    fn test_record_wrap_around() {
+        let record = Record::Range(u24::MAX - 1, 1.into());
+        assert_eq!(record.ack_cnt(), 4);
+        assert_eq!(
+            record.seq_nums().collect::<Vec<_>>(),
+            vec![u24::MAX - 1, u24::MAX, 0.into(), 1.into()]
+        );
+        assert_eq!(
+            Record::Single(3.into()).seq_nums().collect::<Vec<_>>(),
+            vec![3.into()]
+        );
+    }
 }
@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
@@ -27,13 +27,18 @@ pub(crate) struct FrameSet<S> {
 
 impl FrameSet<FramesMut> {
     pub(super) fn read(buf: &mut BytesMut) -> Result<Self, CodecError> {
-        let seq_num = read_buf!(buf, 3, buf.get_u24_le());
+        let seq_num = read_buf!(buf, 3, "frame set seq num", buf.get_u24_le());
         let mut frames = vec![];
         while buf.has_remaining() {
             frames.push(Frame::read(buf)?);
         }
         if frames.is_empty() {
-            return Err(CodecError::InvalidPacketLength("frame set"));
+            // at least one frame header
+            return Err(CodecError::InvalidPacketLength {
+                expected: 3,
+                got: 0,
+                context: "frame set",
+            });
         }
         Ok(FrameSet {
             seq_num,
@@ -91,6 +96,12 @@ impl FrameMut {
         }
     }
 
+    /// Freeze the frames in place. The frames have the same layout after freezing, so the
+    /// collecting reuses the allocation of the vector instead of allocating a new one.
+    pub(crate) fn freeze_all(frames: FramesMut) -> Frames {
+        frames.into_iter().map(FrameMut::freeze).collect()
+    }
+
     /// Remove the parted flags & fragment payload
     pub(crate) fn reassembled(mut self) -> Self {
         if !self.flags.parted {
@@ -103,12 +114,16 @@ impl FrameMut {
     }
 
     fn read(buf: &mut BytesMut) -> Result<Self, CodecError> {
-        let (flags, length) = read_buf!(buf, 3, {
+        let (flags, length) = read_buf!(buf, 3, "frame header", {
             let flags = Flags::read(buf);
             // length in bytes
             let length = buf.get_u16() >> 3;
             if length == 0 {
-                return Err(CodecError::InvalidPacketLength("frame length"));
+                return Err(CodecError::InvalidPacketLength {
+                    expected: 1,
+                    got: 0,
+                    context: "frame body length",
+                });
             }
             (flags, length as usize)
         });
@@ -120,18 +135,19 @@ impl FrameMut {
         let mut fragment = None;
 
         if reliability.is_reliable() {
-            reliable_frame_index = read_buf!(buf, 3, Some(buf.get_u24_le()));
+            reliable_frame_index =
+                read_buf!(buf, 3, "reliable frame index", Some(buf.get_u24_le()));
         }
         if reliability.is_sequenced() {
-            seq_frame_index = read_buf!(buf, 3, Some(buf.get_u24_le()));
+            seq_frame_index = read_buf!(buf, 3, "sequenced frame index", Some(buf.get_u24_le()));
         }
         if reliability.is_sequenced_or_ordered() {
-            ordered = read_buf!(buf, 4, Some(Ordered::read(buf)));
+            ordered = read_buf!(buf, 4, "ordered frame index", Some(Ordered::read(buf)));
         }
         if flags.parted {
-            fragment = read_buf!(buf, 10, Some(Fragment::read(buf)));
+            fragment = read_buf!(buf, 10, "fragment", Some(Fragment::read(buf)));
         }
-        let body = read_buf!(buf, length, buf.split_to(length));
+        let body = read_buf!(buf, length, "frame body", buf.split_to(length));
         Ok(Frame {
             flags,
             reliable_frame_index,
@@ -192,8 +208,10 @@ pub(crate) struct Flags {
 }
 
 impl Flags {
+    /// Create the flags of a frame being sent, the `reliability` is kept locally while the base
+    /// reliability is written
     pub(crate) fn new(reliability: Reliability, parted: bool) -> Self {
-        let mut raw = (reliability as u8) << 5;
+        let mut raw = (reliability.on_wire() as u8) << 5;
         raw |= NEEDS_B_AND_AS_FLAG;
         if parted {
             raw |= PARTED_FLAG;
@@ -271,10 +289,55 @@ impl Ordered {
     }
 }
 
+/// The capability flag of compressing frame bodies, negotiated in the online handshake
+#[cfg_attr(not(feature = "compression"), allow(dead_code))]
+pub(crate) const CAPABILITY_COMPRESSION: u8 = 0x01;
+
+/// The capability flag of propagating the trace ids of the messages, negotiated in the online
+/// handshake
+pub(crate) const CAPABILITY_TRACE_ID: u8 = 0x02;
+
+/// The capability flag of informing the peer of the lowered mtu, negotiated in the online
+/// handshake
+pub(crate) const CAPABILITY_MTU_UPDATE: u8 = 0x04;
+
+/// The flag following the header of an internal packet wrapping the user data once negotiated,
+/// e.g. [`PackType::Compressed`], telling a raw body escaped for starting with the header
+///
+/// [`PackType::Compressed`]: crate::packet::PackType::Compressed
+pub(crate) const HEADER_ESCAPED: u8 = 0x00;
+
+/// The flag following the header of an internal packet wrapping the user data once negotiated,
+/// telling a body wrapped by the packet
+pub(crate) const HEADER_WRAPPED: u8 = 0x01;
+
 // The max number of addresses from a peer, constant here to avoid alloc heap memory
-const MAX_SYSTEM_ADDRESSES_ENDPOINTS: usize = 20;
+pub(crate) const MAX_SYSTEM_ADDRESSES_ENDPOINTS: usize = 20;
+
+/// Make the system addresses list with the given address at the head, the rest of the list is
+/// filled with the unspecified address in the same family.
+pub(crate) fn system_addresses(head: SocketAddr) -> [SocketAddr; MAX_SYSTEM_ADDRESSES_ENDPOINTS] {
+    let unspecified = if head.is_ipv6() {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+    } else {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+    };
+    let mut addresses = [unspecified; MAX_SYSTEM_ADDRESSES_ENDPOINTS];
+    addresses[0] = head;
+    addresses
+}
+
+/// Put the key exchanged in the online handshake prefixed by its length, nothing if it is empty
+fn put_key(buf: &mut BytesMut, key: &[u8]) {
+    if key.is_empty() {
+        return;
+    }
+    debug_assert!(key.len() <= usize::from(u16::MAX), "the key is too long");
+    buf.put_u16(key.len() as u16);
+    buf.put_slice(key);
+}
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub(crate) enum FrameBody {
     ConnectedPing {
         client_timestamp: i64,
@@ -287,6 +350,10 @@ pub(crate) enum FrameBody {
         client_guid: u64,
         request_timestamp: i64,
         use_encryption: bool,
+        // the trailing capability flags, not a part of the original raknet protocol
+        capabilities: u8,
+        // the key exchanged if the encryption is used, it follows the capability flags
+        key: Bytes,
     },
     ConnectionRequestAccepted {
         client_address: std::net::SocketAddr,
@@ -294,6 +361,10 @@ pub(crate) enum FrameBody {
         system_addresses: [std::net::SocketAddr; MAX_SYSTEM_ADDRESSES_ENDPOINTS],
         request_timestamp: i64,
         accepted_timestamp: i64,
+        // the trailing capability flags, not a part of the original raknet protocol
+        capabilities: u8,
+        // the key exchanged if the encryption is used, it follows the capability flags
+        key: Bytes,
     },
     NewIncomingConnection {
         server_address: std::net::SocketAddr,
@@ -301,8 +372,13 @@ pub(crate) enum FrameBody {
         request_timestamp: i64,
         accepted_timestamp: i64,
     },
+    // the server rejects the connection request, e.g. it is full or shutting down
+    ConnectionRequestFailed,
     DisconnectNotification,
     DetectLostConnections,
+    MtuUpdate {
+        mtu: u16,
+    },
     // User Packet
     User(Bytes),
 }
@@ -315,8 +391,10 @@ impl std::fmt::Debug for FrameBody {
             Self::ConnectionRequest { .. } => write!(f, "ConnectionRequest"),
             Self::ConnectionRequestAccepted { .. } => write!(f, "ConnectionRequestAccepted"),
             Self::NewIncomingConnection { .. } => write!(f, "NewIncomingConnection"),
+            Self::ConnectionRequestFailed => write!(f, "ConnectionRequestFailed"),
             Self::DisconnectNotification => write!(f, "Disconnect"),
             Self::DetectLostConnections => write!(f, "DetectLostConnections"),
+            Self::MtuUpdate { mtu } => write!(f, "MtuUpdate({mtu})"),
             Self::User(data) => write!(f, "User(size:{})", data.len()),
         }
     }
@@ -325,7 +403,9 @@ impl std::fmt::Debug for FrameBody {
 impl FrameBody {
     pub(crate) fn read(mut buf: Bytes) -> Result<Self, CodecError> {
         fn parse_system_addresses(buf: &mut Bytes) -> Result<[SocketAddr; 20], CodecError> {
-            let mut addresses = [buf.get_socket_addr()?; MAX_SYSTEM_ADDRESSES_ENDPOINTS];
+            // the count of addresses varies between implementations, the missing ones are left
+            // unspecified
+            let mut addresses = system_addresses(buf.get_socket_addr()?);
             #[allow(clippy::needless_range_loop)] // do not tech me
             for i in 1..MAX_SYSTEM_ADDRESSES_ENDPOINTS {
                 if buf.remaining() > 16 {
@@ -336,12 +416,33 @@ impl FrameBody {
                 break;
             }
             if buf.remaining() < 16 {
-                return Err(CodecError::InvalidPacketLength("frame body"));
+                return Err(CodecError::InvalidPacketLength {
+                    expected: 16,
+                    got: buf.remaining(),
+                    context: "connection timestamps",
+                });
             }
-            debug_assert!(buf.remaining() == 16);
             Ok(addresses)
         }
 
+        // the capability flags are absent if the peer does not support any of them
+        fn parse_capabilities(buf: &mut Bytes) -> u8 {
+            if buf.has_remaining() {
+                buf.get_u8()
+            } else {
+                0
+            }
+        }
+
+        // the key is absent if the encryption is not used
+        fn parse_key(buf: &mut Bytes) -> Result<Bytes, CodecError> {
+            if !buf.has_remaining() {
+                return Ok(Bytes::new());
+            }
+            let len = read_buf!(buf, 2, "key length", buf.get_u16() as usize);
+            Ok(read_buf!(buf, len, "key", buf.split_to(len)))
+        }
+
         // checked in FrameSet, length is always greater than 0
         let Ok(id) = PackType::from_u8(buf.chunk()[0]) else {
             return Ok(Self::User(buf));
@@ -349,24 +450,26 @@ impl FrameBody {
 
         match id {
             PackType::ConnectedPing => Ok(Self::ConnectedPing {
-                client_timestamp: read_buf!(buf, 9, {
+                client_timestamp: read_buf!(buf, 9, "connected ping", {
                     buf.advance(1); // 1
                     buf.get_i64() // 8
                 }),
             }),
-            PackType::ConnectedPong => Ok(read_buf!(buf, 17, {
+            PackType::ConnectedPong => Ok(read_buf!(buf, 17, "connected pong", {
                 buf.advance(1); // 1
                 Self::ConnectedPong {
                     client_timestamp: buf.get_i64(), // 8,
                     server_timestamp: buf.get_i64(), // 8
                 }
             })),
-            PackType::ConnectionRequest => Ok(read_buf!(buf, 18, {
+            PackType::ConnectionRequest => Ok(read_buf!(buf, 18, "connection request", {
                 buf.advance(1); // 1
                 Self::ConnectionRequest {
-                    client_guid: buf.get_u64(),        // 8
-                    request_timestamp: buf.get_i64(),  // 8
-                    use_encryption: buf.get_u8() != 0, // 1
+                    client_guid: buf.get_u64(),                 // 8
+                    request_timestamp: buf.get_i64(),           // 8
+                    use_encryption: buf.get_u8() != 0,          // 1
+                    capabilities: parse_capabilities(&mut buf), // 0 or 1
+                    key: parse_key(&mut buf)?,                  // 0 or 2 + key
                 }
             })),
             PackType::ConnectionRequestAccepted => Ok(Self::ConnectionRequestAccepted {
@@ -374,10 +477,17 @@ impl FrameBody {
                     buf.advance(1);
                     buf.get_socket_addr()?
                 },
-                system_index: read_buf!(buf, 2, buf.get_u16()),
+                system_index: read_buf!(
+                    buf,
+                    2,
+                    "connection request accepted system index",
+                    buf.get_u16()
+                ),
                 system_addresses: parse_system_addresses(&mut buf)?,
                 request_timestamp: buf.get_i64(),
                 accepted_timestamp: buf.get_i64(),
+                capabilities: parse_capabilities(&mut buf),
+                key: parse_key(&mut buf)?,
             }),
             PackType::NewIncomingConnection => Ok(Self::NewIncomingConnection {
                 server_address: {
@@ -388,8 +498,18 @@ impl FrameBody {
                 request_timestamp: buf.get_i64(),
                 accepted_timestamp: buf.get_i64(),
             }),
+            // the trailing magic and server guid are ignored if present
+            PackType::ConnectionRequestFailed => Ok(Self::ConnectionRequestFailed),
             PackType::DisconnectNotification => Ok(Self::DisconnectNotification),
             PackType::DetectLostConnections => Ok(Self::DetectLostConnections),
+            PackType::MtuUpdate => Ok(read_buf!(buf, 4, "mtu update", {
+                buf.advance(1); // 1
+                let flag = buf.get_u8(); // 1
+                if flag != HEADER_WRAPPED {
+                    return Err(CodecError::InvalidPacketType(flag));
+                }
+                Self::MtuUpdate { mtu: buf.get_u16() } // 2
+            })),
             _ => Ok(Self::User(buf)),
         }
     }
@@ -412,11 +532,18 @@ impl FrameBody {
                 client_guid,
                 request_timestamp,
                 use_encryption,
+                capabilities,
+                key,
             } => {
                 buf.put_u8(PackType::ConnectionRequest as u8);
                 buf.put_u64(client_guid);
                 buf.put_i64(request_timestamp);
                 buf.put_u8(u8::from(use_encryption));
+                // keep the same as the original raknet protocol if there is no capability
+                if capabilities != 0 || !key.is_empty() {
+                    buf.put_u8(capabilities);
+                }
+                put_key(buf, &key);
             }
             FrameBody::ConnectionRequestAccepted {
                 client_address,
@@ -424,6 +551,8 @@ impl FrameBody {
                 system_addresses,
                 request_timestamp,
                 accepted_timestamp,
+                capabilities,
+                key,
             } => {
                 buf.put_u8(PackType::ConnectionRequestAccepted as u8);
                 buf.put_socket_addr(client_address);
@@ -433,6 +562,10 @@ impl FrameBody {
                 }
                 buf.put_i64(request_timestamp);
                 buf.put_i64(accepted_timestamp);
+                if capabilities != 0 || !key.is_empty() {
+                    buf.put_u8(capabilities);
+                }
+                put_key(buf, &key);
             }
             FrameBody::NewIncomingConnection {
                 server_address,
@@ -448,15 +581,329 @@ impl FrameBody {
                 buf.put_i64(request_timestamp);
                 buf.put_i64(accepted_timestamp);
             }
+            FrameBody::ConnectionRequestFailed => {
+                buf.put_u8(PackType::ConnectionRequestFailed as u8);
+            }
             FrameBody::DisconnectNotification => {
                 buf.put_u8(PackType::DisconnectNotification as u8);
             }
             FrameBody::DetectLostConnections => {
                 buf.put_u8(PackType::DetectLostConnections as u8);
             }
+            FrameBody::MtuUpdate { mtu } => {
+                buf.put_u8(PackType::MtuUpdate as u8);
+                buf.put_u8(HEADER_WRAPPED);
+                buf.put_u16(mtu);
+            }
             FrameBody::User(data) => {
                 buf.put(data);
             }
         }
     }
 }
+
+/// Micro bench helper, compares freezing the frames in place with collecting into a new vector
+#[cfg(feature = "micro-bench")]
+pub mod micro_bench {
+    use bytes::{Bytes, BytesMut};
+
+    use super::{FrameBody, FrameMut, FrameSet, Frames, FramesMut};
+    use crate::packet::connected::Flags;
+    use crate::Reliability;
+
+    #[derive(Debug, Clone)]
+    pub struct FreezeBench {
+        frames: FramesMut,
+    }
+
+    impl FreezeBench {
+        pub fn new(frame_cnt: usize) -> Self {
+            let frames = (0..frame_cnt)
+                .map(|idx| FrameMut {
+                    flags: Flags::new(Reliability::Reliable, false),
+                    reliable_frame_index: Some(idx.into()),
+                    seq_frame_index: None,
+                    ordered: None,
+                    fragment: None,
+                    body: BytesMut::from(&b"1145141919810"[..]),
+                })
+                .collect();
+            Self { frames }
+        }
+
+        /// Freeze the frames in place
+        pub fn freeze_in_place(self) -> usize {
+            FrameMut::freeze_all(self.frames).len()
+        }
+
+        /// Freeze the frames into a newly allocated vector
+        pub fn freeze_collect_new(self) -> usize {
+            let mut frozen: Frames = Vec::with_capacity(self.frames.len());
+            frozen.extend(self.frames.into_iter().map(FrameMut::freeze));
+            frozen.len()
+        }
+    }
+
+    /// The datagrams carrying a single user message each
+    #[derive(Debug, Clone)]
+    pub struct ReceiveBench {
+        datagrams: Vec<BytesMut>,
+    }
+
+    impl ReceiveBench {
+        pub fn new(body_size: usize, datagram_cnt: usize) -> Self {
+            let datagrams = (0..datagram_cnt)
+                .map(|idx| {
+                    let mut body = BytesMut::zeroed(body_size);
+                    body[0] = 0xfe;
+                    let frame = FrameMut {
+                        flags: Flags::new(Reliability::Reliable, false),
+                        reliable_frame_index: Some(idx.into()),
+                        seq_frame_index: None,
+                        ordered: None,
+                        fragment: None,
+                        body,
+                    };
+                    let mut buf = BytesMut::new();
+                    FrameSet {
+                        seq_num: idx.into(),
+                        set: &[frame][..],
+                    }
+                    .write(&mut buf);
+                    buf
+                })
+                .collect();
+            Self { datagrams }
+        }
+
+        fn receive(self, f: impl Fn(Bytes) -> Bytes) -> usize {
+            let mut received = 0;
+            for mut datagram in self.datagrams {
+                let frame_set = FrameSet::read(&mut datagram).expect("valid frame set");
+                for frame in frame_set.set {
+                    if let Ok(FrameBody::User(data)) = FrameBody::read(frame.freeze().body) {
+                        received += f(data).len();
+                    }
+                }
+            }
+            received
+        }
+
+        /// Receive the messages as the views into the datagrams
+        pub fn receive_zero_copy(self) -> usize {
+            self.receive(|data| data)
+        }
+
+        /// Receive the messages by copying them out of the datagrams
+        pub fn receive_copied(self) -> usize {
+            self.receive(|data| Bytes::copy_from_slice(&data))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_new_incoming_connection_round_trip() {
+        let server_address: SocketAddr = "10.0.0.1:19132".parse().unwrap();
+        let mut addresses = system_addresses("192.168.1.2:50000".parse().unwrap());
+        addresses[1] = "172.16.0.3:50000".parse().unwrap();
+        let body = FrameBody::NewIncomingConnection {
+            server_address,
+            system_addresses: addresses,
+            request_timestamp: 114514,
+            accepted_timestamp: 1919810,
+        };
+
+        let mut buf = BytesMut::new();
+        body.clone().write(&mut buf);
+        // 1B id + 7B server address + 20 * 7B system addresses + 2 * 8B timestamps
+        assert_eq!(buf.len(), 1 + 7 + MAX_SYSTEM_ADDRESSES_ENDPOINTS * 7 + 16);
+        assert_eq!(FrameBody::read(buf.freeze()).unwrap(), body);
+    }
+
+    #[test]
+    fn test_freeze_all_in_place() {
+        let frames: FramesMut = (0..16_u32)
+            .map(|idx| FrameMut {
+                flags: Flags::new(Reliability::Reliable, false),
+                reliable_frame_index: Some(idx.into()),
+                seq_frame_index: None,
+                ordered: None,
+                fragment: None,
+                body: BytesMut::from(&b"hello"[..]),
+            })
+            .collect();
+        let ptr = frames.as_ptr() as usize;
+        let frozen = FrameMut::freeze_all(frames);
+        assert_eq!(frozen.as_ptr() as usize, ptr);
+        assert_eq!(frozen.len(), 16);
+        assert!(frozen
+            .iter()
+            .all(|frame| frame.body == Bytes::from_static(b"hello")));
+    }
+
+    #[test]
+    fn test_user_body_zero_copy() {
+        let frame = FrameMut {
+            flags: Flags::new(Reliability::Reliable, false),
+            reliable_frame_index: Some(0.into()),
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            body: BytesMut::from(&b"\xfehello"[..]),
+        };
+        let mut buf = BytesMut::new();
+        FrameSet {
+            seq_num: 0.into(),
+            set: &[frame][..],
+        }
+        .write(&mut buf);
+        let datagram = buf.as_ptr_range();
+
+        // the single-frame message is a view into the datagram
+        let read = FrameSet::read(&mut buf).unwrap().set.pop().unwrap();
+        let FrameBody::User(data) = FrameBody::read(read.freeze().body).unwrap() else {
+            panic!("expect user data");
+        };
+        assert_eq!(data, Bytes::from_static(b"\xfehello"));
+        assert!(datagram.contains(&data.as_ptr()));
+    }
+
+    #[test]
+    fn test_flags_round_trip() {
+        // (reliability, the reliability bits written)
+        for (reliability, bits) in [
+            (Reliability::Unreliable, 0b000),
+            (Reliability::UnreliableSequenced, 0b001),
+            (Reliability::Reliable, 0b010),
+            (Reliability::ReliableOrdered, 0b011),
+            (Reliability::ReliableSequenced, 0b100),
+            (Reliability::UnreliableWithAckReceipt, 0b000),
+            (Reliability::ReliableWithAckReceipt, 0b010),
+            (Reliability::ReliableOrderedWithAckReceipt, 0b011),
+        ] {
+            for parted in [false, true] {
+                let frame = FrameMut {
+                    flags: Flags::new(reliability, parted),
+                    reliable_frame_index: reliability.is_reliable().then_some(1.into()),
+                    seq_frame_index: reliability.is_sequenced().then_some(2.into()),
+                    ordered: reliability.is_sequenced_or_ordered().then_some(Ordered {
+                        frame_index: 3.into(),
+                        channel: 4,
+                    }),
+                    fragment: parted.then_some(Fragment {
+                        parted_size: 2,
+                        parted_id: 5,
+                        parted_index: 1,
+                    }),
+                    body: BytesMut::from(&b"hello"[..]),
+                };
+                let mut buf = BytesMut::new();
+                FrameSet {
+                    seq_num: 0.into(),
+                    set: &[frame.clone()][..],
+                }
+                .write(&mut buf);
+                assert_eq!(buf.len(), 3 + frame.size());
+                assert_eq!(buf[3] >> 5, bits);
+                assert_eq!(buf[3] & PARTED_FLAG != 0, parted);
+
+                let read = FrameSet::read(&mut buf).unwrap().set.pop().unwrap();
+                assert_eq!(read.flags, Flags::parse(frame.flags.raw));
+                assert_eq!(read.flags.reliability, reliability.on_wire());
+                assert_eq!(read.flags.parted, parted);
+                assert_eq!(read.reliable_frame_index, frame.reliable_frame_index);
+                assert_eq!(read.seq_frame_index, frame.seq_frame_index);
+                assert_eq!(read.ordered, frame.ordered);
+                assert_eq!(read.fragment, frame.fragment);
+                assert_eq!(read.body, frame.body);
+            }
+        }
+    }
+
+    #[test]
+    fn test_connection_request_capabilities() {
+        // no trailing capabilities as the original raknet protocol if there is no capability
+        for (capabilities, len) in [(0, 18), (CAPABILITY_COMPRESSION, 19)] {
+            let body = FrameBody::ConnectionRequest {
+                client_guid: 114514,
+                request_timestamp: 1919810,
+                use_encryption: false,
+                capabilities,
+                key: Bytes::new(),
+            };
+            let mut buf = BytesMut::new();
+            body.clone().write(&mut buf);
+            assert_eq!(buf.len(), len);
+            assert_eq!(FrameBody::read(buf.freeze()).unwrap(), body);
+        }
+    }
+
+    #[test]
+    fn test_connection_request_key() {
+        // the capability flags are written before the key even if there is none
+        let body = FrameBody::ConnectionRequest {
+            client_guid: 114514,
+            request_timestamp: 1919810,
+            use_encryption: true,
+            capabilities: 0,
+            key: Bytes::from_static(b"public key"),
+        };
+        let mut buf = BytesMut::new();
+        body.clone().write(&mut buf);
+        assert_eq!(buf.len(), 18 + 1 + 2 + 10);
+        assert_eq!(FrameBody::read(buf.clone().freeze()).unwrap(), body);
+
+        // truncated key
+        buf.truncate(buf.len() - 1);
+        assert!(matches!(
+            FrameBody::read(buf.freeze()),
+            Err(CodecError::InvalidPacketLength {
+                expected: 10,
+                got: 9,
+                context: "key"
+            })
+        ));
+    }
+
+    #[test]
+    fn test_new_incoming_connection_short_address_list() {
+        let server_address: SocketAddr = "10.0.0.1:19132".parse().unwrap();
+        let internal: SocketAddr = "192.168.1.2:50000".parse().unwrap();
+
+        // stock RakNet only sends 10 system addresses
+        let mut buf = BytesMut::new();
+        buf.put_u8(PackType::NewIncomingConnection as u8);
+        buf.put_socket_addr(server_address);
+        buf.put_socket_addr(internal);
+        for _ in 1..10 {
+            buf.put_socket_addr("0.0.0.0:0".parse().unwrap());
+        }
+        buf.put_i64(1);
+        buf.put_i64(2);
+
+        let FrameBody::NewIncomingConnection {
+            server_address: addr,
+            system_addresses: addresses,
+            request_timestamp,
+            accepted_timestamp,
+        } = FrameBody::read(buf.freeze()).unwrap()
+        else {
+            panic!("not a NewIncomingConnection")
+        };
+        assert_eq!(addr, server_address);
+        assert_eq!(addresses, system_addresses(internal));
+        assert_eq!((request_timestamp, accepted_timestamp), (1, 2));
+
+        // truncated timestamps
+        let mut truncated = BytesMut::new();
+        truncated.put_u8(PackType::NewIncomingConnection as u8);
+        truncated.put_socket_addr(server_address);
+        truncated.put_socket_addr(internal);
+        truncated.put_i64(1);
+        assert!(FrameBody::read(truncated.freeze()).is_err());
+    }
+}
@@ -8,10 +8,18 @@ use connected::{FramesMut, FramesRef};
 
 use crate::errors::CodecError;
 
+/// Evaluate the expression if the buffer holds at least `len` bytes, otherwise return the
+/// `InvalidPacketLength` error along with the context being read
 macro_rules! read_buf {
-    ($buf:expr, $len:expr, $exp:expr) => {{
-        if $buf.remaining() < $len {
-            return Err(CodecError::InvalidPacketLength("certain sized pack"));
+    ($buf:expr, $len:expr, $context:expr, $exp:expr) => {{
+        let expected = $len;
+        let got = $buf.remaining();
+        if got < expected {
+            return Err(CodecError::InvalidPacketLength {
+                expected,
+                got,
+                context: $context,
+            });
         }
         $exp
     }};
@@ -33,6 +41,13 @@ pub(crate) const FRAME_SET_HEADER_SIZE: usize = 4;
 // u32 + u16 + u32
 pub(crate) const FRAGMENT_PART_SIZE: usize = 10;
 
+// The minimum MTU that the peer can lower to (the minimum datagram size every IPv4 host must
+// accept)
+pub(crate) const MIN_MTU: u16 = 576;
+
+// The maximum ordered channels, i.e. the ordering streams of RakNet
+pub(crate) const MAX_ORDER_CHANNELS: usize = 32;
+
 /// Packet Types. These packets play important role in raknet protocol.
 /// Some of them appear at the first byte of a UDP data packet (like `UnconnectedPing1`), while
 /// others are encapsulated in a `FrameSet` data packet and appear as the first byte of the body
@@ -64,6 +79,22 @@ pub(crate) enum PackType {
     UnconnectedPong = 0x1c,
     AdvertiseSystem = 0x1d,
 
+    /// Internal control packet informing the peer that the MTU is lowered, it is not a part of the
+    /// original raknet protocol. The user data starting with it is escaped once negotiated.
+    MtuUpdate = 0x7f,
+
+    /// Internal packet wrapping a compressed frame body, it is only sent when the compression is
+    /// negotiated with the peer.
+    Compressed = 0x7e,
+
+    /// Internal packet wrapping an encrypted frame body, it is only sent when the encryption is
+    /// required by both sides. The user data is held until the session is established.
+    Encrypted = 0x7d,
+
+    /// Internal packet wrapping a frame body with the trace id of its message, it is only sent
+    /// when the trace id propagation is negotiated with the peer.
+    Traced = 0x7c,
+
     /// The types of these three packets form a range, and only the one with the flag will be used
     /// here.
     Ack = ACK_FLAG,
@@ -97,6 +128,10 @@ impl PackType {
             0x1b => Ok(PackType::Timestamp),
             0x1c => Ok(PackType::UnconnectedPong),
             0x1d => Ok(PackType::AdvertiseSystem),
+            0x7c => Ok(PackType::Traced),
+            0x7d => Ok(PackType::Encrypted),
+            0x7e => Ok(PackType::Compressed),
+            0x7f => Ok(PackType::MtuUpdate),
             ACK_FLAG.. => Ok(PackType::Ack),
             NACK_FLAG.. => Ok(PackType::Nack),
             VALID_FLAG.. => Ok(PackType::FrameSet),
@@ -172,7 +207,7 @@ impl Packet<FramesMut> {
             return Ok(None);
         }
 
-        let pack_type: PackType = read_buf!(buf, 1, buf.get_u8().try_into()?);
+        let pack_type: PackType = read_buf!(buf, 1, "packet id", buf.get_u8().try_into()?);
         if pack_type.is_frame_set() {
             return Ok(Some(Self::Connected(connected::Packet::read_frame_set(
                 buf,
@@ -186,15 +221,26 @@ impl Packet<FramesMut> {
         }
         match pack_type {
             PackType::UnconnectedPing1 | PackType::UnconnectedPing2 => {
-                read_buf!(buf, 32, unconnected::Packet::read_unconnected_ping(buf))
+                read_buf!(
+                    buf,
+                    32,
+                    "unconnected ping",
+                    unconnected::Packet::read_unconnected_ping(buf)
+                )
             }
             PackType::UnconnectedPong => {
-                read_buf!(buf, 34, unconnected::Packet::read_unconnected_pong(buf))
+                read_buf!(
+                    buf,
+                    34,
+                    "unconnected pong",
+                    unconnected::Packet::read_unconnected_pong(buf)
+                )
             }
             PackType::OpenConnectionRequest1 => {
                 read_buf!(
                     buf,
                     19,
+                    "open connection request1",
                     unconnected::Packet::read_open_connection_request1(buf)
                 )
             }
@@ -202,6 +248,7 @@ impl Packet<FramesMut> {
                 read_buf!(
                     buf,
                     27,
+                    "open connection reply1",
                     unconnected::Packet::read_open_connection_reply1(buf)
                 )
             }
@@ -209,19 +256,58 @@ impl Packet<FramesMut> {
                 read_buf!(
                     buf,
                     25,
+                    "incompatible protocol version",
                     unconnected::Packet::read_incompatible_protocol(buf)
                 )
             }
             PackType::AlreadyConnected => {
-                read_buf!(buf, 24, unconnected::Packet::read_already_connected(buf))
+                read_buf!(
+                    buf,
+                    24,
+                    "already connected",
+                    unconnected::Packet::read_already_connected(buf)
+                )
             }
             PackType::ConnectionRequestFailed => {
                 read_buf!(
                     buf,
                     24,
+                    "connection request failed",
                     unconnected::Packet::read_connection_request_failed(buf)
                 )
             }
+            PackType::NoFreeIncomingConnections => {
+                read_buf!(
+                    buf,
+                    24,
+                    "no free incoming connections",
+                    unconnected::Packet::read_no_free_incoming_connections(buf)
+                )
+            }
+            PackType::ConnectionBanned => {
+                read_buf!(
+                    buf,
+                    24,
+                    "connection banned",
+                    unconnected::Packet::read_connection_banned(buf)
+                )
+            }
+            PackType::IpRecentlyConnected => {
+                read_buf!(
+                    buf,
+                    24,
+                    "ip recently connected",
+                    unconnected::Packet::read_ip_recently_connected(buf)
+                )
+            }
+            PackType::AdvertiseSystem => {
+                read_buf!(
+                    buf,
+                    16,
+                    "advertise system",
+                    unconnected::Packet::read_advertise_system(buf)
+                )
+            }
             PackType::OpenConnectionRequest2 => {
                 unconnected::Packet::read_open_connection_request2(buf)
             }
@@ -248,16 +334,17 @@ pub(crate) trait MagicWrite {
 }
 
 impl<B: Buf> MagicRead for B {
-    #![allow(clippy::needless_range_loop)]
     fn get_checked_magic(&mut self) -> Result<(), CodecError> {
-        for i in 0..MAGIC.len() {
-            let byte = self.chunk()[i];
-            if byte != MAGIC[i] {
-                return Err(CodecError::MagicNotMatched(i, byte));
+        read_buf!(self, MAGIC.len(), "magic", {
+            // the chunk may not hold the whole magic, read it byte by byte
+            for (i, expected) in MAGIC.iter().enumerate() {
+                let byte = self.get_u8();
+                if byte != *expected {
+                    return Err(CodecError::MagicNotMatched(i, byte));
+                }
             }
-        }
-        self.advance(MAGIC.len());
-        Ok(())
+            Ok(())
+        })
     }
 }
 
@@ -267,6 +354,11 @@ impl<B: BufMut> MagicWrite for B {
     }
 }
 
+/// The AF_INET6 on Windows, written by the reference implementation
+const AF_INET6_WINDOWS: u16 = 0x17;
+/// The AF_INET6 on Linux
+const AF_INET6_LINUX: u16 = 0x0a;
+
 pub(crate) trait SocketAddrRead {
     fn get_socket_addr(&mut self) -> Result<SocketAddr, CodecError>;
 }
@@ -277,20 +369,21 @@ pub(crate) trait SocketAddrWrite {
 
 impl<B: Buf> SocketAddrRead for B {
     fn get_socket_addr(&mut self) -> Result<SocketAddr, CodecError> {
-        let ver = read_buf!(self, 1, self.get_u8());
+        let ver = read_buf!(self, 1, "address version", self.get_u8());
         match ver {
             4 => {
-                read_buf!(self, 6, {
+                read_buf!(self, 6, "ipv4 address", {
                     let ip = Ipv4Addr::from_bits(self.get_u32());
                     let port = self.get_u16();
                     Ok(SocketAddr::V4(SocketAddrV4::new(ip, port)))
                 })
             }
             6 => {
-                // TODO: to be determined
-                read_buf!(self, 28, {
-                    let family = self.get_u16();
-                    if family != 0x17 {
+                read_buf!(self, 28, "ipv6 address", {
+                    // the family of sockaddr_in6 is written in little endian, it is the AF_INET6
+                    // of the platform the peer runs on
+                    let family = self.get_u16_le();
+                    if family != AF_INET6_WINDOWS && family != AF_INET6_LINUX {
                         return Err(CodecError::InvalidIPV6Family(family));
                     }
                     let port = self.get_u16();
@@ -317,7 +410,7 @@ impl<B: BufMut> SocketAddrWrite for B {
             }
             SocketAddr::V6(v6) => {
                 self.put_u8(6);
-                self.put_u16(0x17);
+                self.put_u16_le(AF_INET6_WINDOWS);
                 self.put_u16(v6.port());
                 self.put_u32(v6.flowinfo());
                 self.put_slice(&v6.ip().octets());
@@ -326,3 +419,202 @@ impl<B: BufMut> SocketAddrWrite for B {
         }
     }
 }
+
+/// Read a socket address in the raknet wire form, e.g. the addresses in
+/// `ConnectionRequestAccepted`
+///
+/// # Errors
+/// Returns [`CodecError`] if the buffer is too short or the address is malformed.
+pub fn raknet_read_addr(buf: &mut impl Buf) -> Result<SocketAddr, CodecError> {
+    buf.get_socket_addr()
+}
+
+/// Write a socket address in the raknet wire form
+pub fn raknet_write_addr(buf: &mut impl BufMut, addr: SocketAddr) {
+    buf.put_socket_addr(addr);
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+    use bytes::{Buf, Bytes, BytesMut};
+
+    use super::{
+        raknet_read_addr, raknet_write_addr, unconnected, MagicRead, MagicWrite, PackType, Packet,
+        MAGIC,
+    };
+    use crate::errors::CodecError;
+
+    #[test]
+    fn test_addr_round_trip() {
+        let addrs = [
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 19132)),
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, u16::MAX)),
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 19133, 0, 0)),
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
+            SocketAddr::V6(SocketAddrV6::new(
+                "fe80::1:2:3:4".parse().unwrap(),
+                19132,
+                0x000a_bcde,
+                3,
+            )),
+            SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from_bits(u128::MAX),
+                u16::MAX,
+                u32::MAX,
+                u32::MAX,
+            )),
+        ];
+        for addr in addrs {
+            let mut buf = BytesMut::new();
+            raknet_write_addr(&mut buf, addr);
+            let expected_len = if addr.is_ipv4() { 7 } else { 29 };
+            assert_eq!(buf.len(), expected_len);
+            assert_eq!(raknet_read_addr(&mut buf).unwrap(), addr);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_addr_wire_form() {
+        let mut buf = BytesMut::new();
+        raknet_write_addr(
+            &mut buf,
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 19132, 0, 0)),
+        );
+        // version, family in little endian, port in big endian
+        assert_eq!(&buf[..5], &[6, 0x17, 0x00, 0x4a, 0xbc]);
+
+        let mut truncated = &buf[..20];
+        assert!(matches!(
+            raknet_read_addr(&mut truncated),
+            Err(CodecError::InvalidPacketLength {
+                expected: 28,
+                got: 19,
+                context: "ipv6 address"
+            })
+        ));
+        let mut unknown = &[5_u8, 0, 0][..];
+        assert!(matches!(
+            raknet_read_addr(&mut unknown),
+            Err(CodecError::InvalidIPVer(5))
+        ));
+    }
+
+    #[test]
+    fn test_addr_ipv6_family() {
+        let addr = SocketAddr::V6(SocketAddrV6::new(
+            "2001:db8::1".parse().unwrap(),
+            19132,
+            0x0001_2345,
+            7,
+        ));
+        let mut buf = BytesMut::new();
+        raknet_write_addr(&mut buf, addr);
+
+        // the peer on Linux writes its AF_INET6
+        let mut linux = buf.clone();
+        linux[1] = 0x0a;
+        assert_eq!(raknet_read_addr(&mut linux).unwrap(), addr);
+        assert!(linux.is_empty());
+
+        // AF_INET on Windows
+        let mut unknown = buf.clone();
+        unknown[1] = 0x02;
+        assert!(matches!(
+            raknet_read_addr(&mut unknown),
+            Err(CodecError::InvalidIPV6Family(0x02))
+        ));
+    }
+
+    #[test]
+    fn test_magic_short_buffer() {
+        let mut short = &MAGIC[..5];
+        assert!(matches!(
+            short.get_checked_magic(),
+            Err(CodecError::InvalidPacketLength {
+                expected: 16,
+                got: 5,
+                context: "magic"
+            })
+        ));
+
+        // the magic split across chunks
+        let mut chunked = (&MAGIC[..3]).chain(&MAGIC[3..]);
+        chunked.get_checked_magic().unwrap();
+        assert!(!chunked.has_remaining());
+
+        let mut buf = BytesMut::new();
+        buf.put_magic();
+        buf[7] ^= 0xff;
+        assert!(matches!(
+            buf.get_checked_magic(),
+            Err(CodecError::MagicNotMatched(7, _))
+        ));
+    }
+
+    #[test]
+    fn test_open_connection_cookie_round_trip() {
+        let server_addrs: [SocketAddr; 2] = [
+            "127.0.0.1:19132".parse().unwrap(),
+            "[::1]:19132".parse().unwrap(),
+        ];
+        // the first byte of the cookie looks like the ip version
+        for cookie in [None, Some(0x0607_0809)] {
+            let reply = unconnected::Packet::OpenConnectionReply1 {
+                magic: (),
+                server_guid: 114514,
+                cookie,
+                mtu: 1400,
+            };
+            let mut buf = BytesMut::new();
+            reply.clone().write(&mut buf);
+            assert_eq!(
+                Packet::read(&mut buf).unwrap(),
+                Some(Packet::Unconnected(reply))
+            );
+            assert!(buf.is_empty());
+
+            for server_address in server_addrs {
+                let request = unconnected::Packet::OpenConnectionRequest2 {
+                    magic: (),
+                    cookie,
+                    server_address,
+                    mtu: 1400,
+                    client_guid: 1919810,
+                };
+                let mut buf = BytesMut::new();
+                request.clone().write(&mut buf);
+                assert_eq!(
+                    Packet::read(&mut buf).unwrap(),
+                    Some(Packet::Unconnected(request))
+                );
+                assert!(buf.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_advertise_system_round_trip() {
+        for data in [
+            Bytes::new(),
+            Bytes::from_static(b"MCPE;Dedicated Server;686;1.21.2;"),
+        ] {
+            let advertise = unconnected::Packet::AdvertiseSystem { magic: (), data };
+            let mut buf = BytesMut::new();
+            advertise.clone().write(&mut buf);
+            assert_eq!(buf[0], PackType::AdvertiseSystem as u8);
+            assert_eq!(
+                Packet::read(&mut buf).unwrap(),
+                Some(Packet::Unconnected(advertise))
+            );
+            assert!(buf.is_empty());
+        }
+
+        // the magic is required
+        let mut buf = BytesMut::from(&[PackType::AdvertiseSystem as u8, 0x00, 0xff][..]);
+        assert!(Packet::read(&mut buf).is_err());
+    }
+}
@@ -27,11 +27,13 @@ pub(crate) enum Packet {
     OpenConnectionReply1 {
         magic: (),
         server_guid: u64,
-        use_encryption: bool,
+        // the security flag is set if the cookie presents, the client echoes it back
+        cookie: Option<u32>,
         mtu: u16,
     },
     OpenConnectionRequest2 {
         magic: (),
+        cookie: Option<u32>,
         server_address: SocketAddr,
         mtu: u16,
         client_guid: u64,
@@ -56,6 +58,25 @@ pub(crate) enum Packet {
         magic: (),
         server_guid: u64,
     },
+    NoFreeIncomingConnections {
+        magic: (),
+        server_guid: u64,
+    },
+    ConnectionBanned {
+        magic: (),
+        server_guid: u64,
+    },
+    IpRecentlyConnected {
+        magic: (),
+        server_guid: u64,
+        // the extension carrying when the client may retry, absent in the original protocol
+        retry_after_ms: Option<u32>,
+    },
+    AdvertiseSystem {
+        magic: (),
+        // the rest of the datagram, not prefixed by the length unlike the pong
+        data: Bytes,
+    },
 }
 
 impl Packet {
@@ -75,6 +96,10 @@ impl Packet {
             Packet::IncompatibleProtocol { .. } => PackType::IncompatibleProtocolVersion,
             Packet::AlreadyConnected { .. } => PackType::AlreadyConnected,
             Packet::ConnectionRequestFailed { .. } => PackType::ConnectionRequestFailed,
+            Packet::NoFreeIncomingConnections { .. } => PackType::NoFreeIncomingConnections,
+            Packet::ConnectionBanned { .. } => PackType::ConnectionBanned,
+            Packet::IpRecentlyConnected { .. } => PackType::IpRecentlyConnected,
+            Packet::AdvertiseSystem { .. } => PackType::AdvertiseSystem,
         }
     }
 
@@ -93,7 +118,7 @@ impl Packet {
             magic: buf.get_checked_magic()?, // 16
             data: {
                 let len = buf.get_u16() as usize;
-                read_buf!(buf, len, {
+                read_buf!(buf, len, "unconnected pong data", {
                     let data = Bytes::copy_from_slice(&buf.chunk()[..len]);
                     buf.advance(len);
                     data
@@ -112,29 +137,66 @@ impl Packet {
 
     pub(super) fn read_open_connection_reply1(buf: &mut BytesMut) -> Result<Self, CodecError> {
         Ok(Packet::OpenConnectionReply1 {
-            magic: buf.get_checked_magic()?,   // 16
-            server_guid: buf.get_u64(),        // 8
-            use_encryption: buf.get_u8() != 0, // 1
-            mtu: buf.get_u16(),                // 2
+            magic: buf.get_checked_magic()?, // 16
+            server_guid: buf.get_u64(),      // 8
+            cookie: if buf.get_u8() != 0 {
+                Some(read_buf!(
+                    buf,
+                    6,
+                    "open connection reply1 cookie",
+                    buf.get_u32()
+                ))
+            } else {
+                None
+            }, // 1 + 4 (optional)
+            mtu: buf.get_u16(),              // 2
         })
     }
 
     pub(super) fn read_open_connection_request2(buf: &mut BytesMut) -> Result<Self, CodecError> {
         Ok(Packet::OpenConnectionRequest2 {
-            magic: read_buf!(buf, 16, buf.get_checked_magic())?,
+            magic: read_buf!(
+                buf,
+                16,
+                "open connection request2 magic",
+                buf.get_checked_magic()
+            )?,
+            // the cookie and the challenge flag are told by the length, the rest is the ipv4 or
+            // ipv6 address along with the mtu and the guid
+            cookie: matches!(buf.remaining(), 22 | 44).then(|| {
+                let cookie = buf.get_u32();
+                // the challenge is never written, it is only for the libcat security
+                buf.advance(1);
+                cookie
+            }),
             server_address: buf.get_socket_addr()?,
-            mtu: read_buf!(buf, 2, buf.get_u16()),
-            client_guid: read_buf!(buf, 8, buf.get_u64()),
+            mtu: read_buf!(buf, 2, "open connection request2 mtu", buf.get_u16()),
+            client_guid: read_buf!(
+                buf,
+                8,
+                "open connection request2 client guid",
+                buf.get_u64()
+            ),
         })
     }
 
     pub(super) fn read_open_connection_reply2(buf: &mut BytesMut) -> Result<Self, CodecError> {
         Ok(Packet::OpenConnectionReply2 {
-            magic: read_buf!(buf, 16, buf.get_checked_magic())?,
-            server_guid: read_buf!(buf, 8, buf.get_u64()),
+            magic: read_buf!(
+                buf,
+                16,
+                "open connection reply2 magic",
+                buf.get_checked_magic()
+            )?,
+            server_guid: read_buf!(buf, 8, "open connection reply2 server guid", buf.get_u64()),
             client_address: buf.get_socket_addr()?,
-            mtu: read_buf!(buf, 2, buf.get_u16()),
-            encryption_enabled: read_buf!(buf, 1, buf.get_u8() != 0),
+            mtu: read_buf!(buf, 2, "open connection reply2 mtu", buf.get_u16()),
+            encryption_enabled: read_buf!(
+                buf,
+                1,
+                "open connection reply2 encryption",
+                buf.get_u8() != 0
+            ),
         })
     }
 
@@ -160,7 +222,39 @@ impl Packet {
         })
     }
 
+    pub(super) fn read_no_free_incoming_connections(
+        buf: &mut BytesMut,
+    ) -> Result<Self, CodecError> {
+        Ok(Packet::NoFreeIncomingConnections {
+            magic: buf.get_checked_magic()?, // 16
+            server_guid: buf.get_u64(),      // 8
+        })
+    }
+
+    pub(super) fn read_connection_banned(buf: &mut BytesMut) -> Result<Self, CodecError> {
+        Ok(Packet::ConnectionBanned {
+            magic: buf.get_checked_magic()?, // 16
+            server_guid: buf.get_u64(),      // 8
+        })
+    }
+
+    pub(super) fn read_ip_recently_connected(buf: &mut BytesMut) -> Result<Self, CodecError> {
+        Ok(Packet::IpRecentlyConnected {
+            magic: buf.get_checked_magic()?,                               // 16
+            server_guid: buf.get_u64(),                                    // 8
+            retry_after_ms: (buf.remaining() >= 4).then(|| buf.get_u32()), // 4 (optional)
+        })
+    }
+
+    pub(super) fn read_advertise_system(buf: &mut BytesMut) -> Result<Self, CodecError> {
+        Ok(Packet::AdvertiseSystem {
+            magic: buf.get_checked_magic()?,        // 16
+            data: buf.split_to(buf.len()).freeze(), // the rest
+        })
+    }
+
     pub(crate) fn write(self, buf: &mut BytesMut) {
+        let start = buf.len();
         // Fixed id (type)
         buf.put_u8(self.pack_type().into());
         match self {
@@ -193,25 +287,37 @@ impl Packet {
                 buf.put_magic();
                 buf.put_u8(protocol_version);
                 buf.put_u16(mtu);
+                // padded to the mtu to probe the path, the trailing zeros are skipped when reading
+                let len = buf.len() - start;
+                buf.put_bytes(0, usize::from(mtu).saturating_sub(len));
             }
             Packet::OpenConnectionReply1 {
                 magic: _magic,
                 server_guid,
-                use_encryption: _use_encryption,
+                cookie,
                 mtu,
             } => {
                 buf.put_magic();
                 buf.put_u64(server_guid);
-                buf.put_u8(0);
+                buf.put_u8(u8::from(cookie.is_some()));
+                if let Some(cookie) = cookie {
+                    buf.put_u32(cookie);
+                }
                 buf.put_u16(mtu);
             }
             Packet::OpenConnectionRequest2 {
                 magic: _magic,
+                cookie,
                 server_address,
                 mtu,
                 client_guid,
             } => {
                 buf.put_magic();
+                if let Some(cookie) = cookie {
+                    buf.put_u32(cookie);
+                    // the client did not write the challenge
+                    buf.put_u8(0);
+                }
                 buf.put_socket_addr(server_address);
                 buf.put_u16(mtu);
                 buf.put_u64(client_guid);
@@ -248,10 +354,36 @@ impl Packet {
             Packet::ConnectionRequestFailed {
                 magic: _magic,
                 server_guid,
+            }
+            | Packet::NoFreeIncomingConnections {
+                magic: _magic,
+                server_guid,
+            }
+            | Packet::ConnectionBanned {
+                magic: _magic,
+                server_guid,
             } => {
                 buf.put_magic();
                 buf.put_u64(server_guid);
             }
+            Packet::IpRecentlyConnected {
+                magic: _magic,
+                server_guid,
+                retry_after_ms,
+            } => {
+                buf.put_magic();
+                buf.put_u64(server_guid);
+                if let Some(retry_after_ms) = retry_after_ms {
+                    buf.put_u32(retry_after_ms);
+                }
+            }
+            Packet::AdvertiseSystem {
+                magic: _magic,
+                data,
+            } => {
+                buf.put_magic();
+                buf.put(data);
+            }
         }
     }
 }
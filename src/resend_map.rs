@@ -4,95 +4,462 @@ use std::time::{Duration, Instant};
 
 use log::trace;
 
+use crate::congestion::NewReno;
+use crate::estimator::{LossEstimator, RFC6298Impl, INITIAL_RTT, LOSS_THRESHOLD, MAX_ACK_DELAY};
 use crate::packet::connected::{AckOrNack, Frame, Frames, Record};
-use crate::utils::{u24, Reactor};
+use crate::packet::FRAME_SET_HEADER_SIZE;
+#[cfg(feature = "metrics")]
+use crate::utils::Metrics;
+use crate::utils::{metric, now, u24};
 use crate::RoleContext;
 
-// TODO: use RTTEstimator to get adaptive RTO
-const RTO: Duration = Duration::from_secs(1);
+/// The RTO before any RTT sample is taken
+pub(crate) const RTO: Duration = Duration::from_secs(1);
+/// The default lower bound of the RTO computed from the RTT estimation
+pub(crate) const MIN_RTO: Duration = Duration::from_millis(200);
+/// The default upper bound of the RTO computed from the RTT estimation
+pub(crate) const MAX_RTO: Duration = Duration::from_secs(10);
+/// The frame sets going stale this times are suspected to be black-holed by the path for their
+/// size
+const BLACK_HOLE_RETRIES: u32 = 2;
 
 struct ResendEntry {
     frames: Option<Frames>,
+    // the size of the frame set carrying the frames
+    size: usize,
+    sent_at: Instant,
     expired_at: Instant,
+    // carries the retransmitted frames, its acknowledgement is not sampled (Karn's rule)
+    retransmitted: bool,
+    // the times its frames went stale, the most of them
+    retries: u32,
+    // deemed lost by the selective acknowledgements
+    lost: bool,
+}
+
+/// The transfer counters of the frame sets of a connection
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct TransferStats {
+    pub(crate) sent: u64,
+    pub(crate) acked: u64,
+    pub(crate) resent: u64,
+    pub(crate) nacked: u64,
+}
+
+/// Identify a reliable message by its frames, i.e. the reliable index of the single frame or the
+/// parted id of the split frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum MessageKey {
+    Single(u24),
+    Parted(u16),
+}
+
+/// Track the reliable messages until all of their frames are acknowledged
+#[derive(Default)]
+struct InflightMessages {
+    // the message of each unacknowledged reliable frame
+    frames: HashMap<u24, MessageKey>,
+    // the count of unacknowledged frames of each message
+    messages: HashMap<MessageKey, u32>,
+    // the messages whose frames are all acknowledged, taken by the link
+    acked: Vec<MessageKey>,
+}
+
+impl InflightMessages {
+    fn on_send(&mut self, frame: &Frame) {
+        let Some(index) = frame.reliable_frame_index else {
+            return;
+        };
+        let key = frame
+            .fragment
+            .as_ref()
+            .map_or(MessageKey::Single(index), |fragment| {
+                MessageKey::Parted(fragment.parted_id)
+            });
+        self.frames.insert(index, key);
+        *self.messages.entry(key).or_default() += 1;
+    }
+
+    fn on_ack(&mut self, frame: &Frame) {
+        let Some(key) = frame
+            .reliable_frame_index
+            .and_then(|index| self.frames.remove(&index))
+        else {
+            return;
+        };
+        let remain = self.messages.get_mut(&key).unwrap();
+        *remain -= 1;
+        if *remain == 0 {
+            self.messages.remove(&key);
+            self.acked.push(key);
+        }
+    }
 }
 
 pub(crate) struct ResendMap {
     map: HashMap<u24, ResendEntry>,
+    inflight: InflightMessages,
     role: RoleContext,
     last_record_expired_at: Instant,
+    largest_acked: Option<u24>,
+    loss: LossEstimator,
+    rtt: RFC6298Impl,
+    // the bounds of the RTO computed from the RTT estimation
+    min_rto: Duration,
+    max_rto: Duration,
+    // the reliable indices of the frames taken out for resending along with their retries, the
+    // frame set carrying them next time is marked as retransmitted
+    resending: HashMap<u24, u32>,
+    // the peer is deemed unreachable once the frames go stale more than this times
+    max_retries: Option<u32>,
+    unreachable: bool,
+    // the largest frame set suspected to be black-holed since the last check
+    black_hole: Option<usize>,
+    // the scaling of the base RTO under connection-wide loss
+    rto_loss_scale: f64,
+    // resend the frame set once a frame set sent this count later is acknowledged, 0 means
+    // disabled
+    fast_retransmit_threshold: u32,
+    // limit the frame sets awaiting acknowledgement if it is enabled
+    congestion: Option<NewReno>,
+    // the sequence number of the last recorded frame set
+    last_recorded: Option<u24>,
+    // the count of the frames awaiting acknowledgement
+    unacked_frames: usize,
+    stats: TransferStats,
+    // whether the entries changed since the last snapshot
+    changed: bool,
+    // the receipt ids of the tagged unreliable frames of each frame set, they are never resent
+    receipts: HashMap<u24, Vec<u64>>,
+    // the receipt ids whose frame sets are acknowledged, taken by the link
+    acked_receipts: Vec<u64>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Metrics>,
 }
 
 impl ResendMap {
     pub(crate) fn new(role: RoleContext) -> Self {
         Self {
             map: HashMap::new(),
+            inflight: InflightMessages::default(),
             role,
-            last_record_expired_at: Instant::now(),
+            last_record_expired_at: now(),
+            largest_acked: None,
+            loss: LossEstimator::default(),
+            rtt: RFC6298Impl::new(INITIAL_RTT),
+            min_rto: MIN_RTO,
+            max_rto: MAX_RTO,
+            resending: HashMap::new(),
+            max_retries: None,
+            unreachable: false,
+            black_hole: None,
+            rto_loss_scale: 0.0,
+            fast_retransmit_threshold: 0,
+            congestion: None,
+            last_recorded: None,
+            unacked_frames: 0,
+            stats: TransferStats::default(),
+            changed: false,
+            receipts: HashMap::new(),
+            acked_receipts: vec![],
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Record the resent frames into the counters of the connection
+    #[cfg(feature = "metrics")]
+    pub(crate) fn with_metrics(mut self, metrics: Option<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Scale the base RTO by `1 + scale * loss_rate` with the recent loss rate of the connection
+    pub(crate) fn with_rto_loss_scale(mut self, scale: f64) -> Self {
+        self.rto_loss_scale = scale;
+        self
+    }
+
+    /// Resend the frame set without waiting for the NACK or RTO once a frame set sent `threshold`
+    /// later is acknowledged, 0 means disabled
+    pub(crate) fn with_fast_retransmit_threshold(mut self, threshold: u32) -> Self {
+        self.fast_retransmit_threshold = threshold;
+        self
+    }
+
+    /// Limit the frame sets awaiting acknowledgement by the NewReno congestion window
+    pub(crate) fn with_congestion_control(mut self, enabled: bool) -> Self {
+        self.congestion = enabled.then(NewReno::default);
+        self
+    }
+
+    /// Return whether a new frame set is allowed to be sent by the congestion window
+    pub(crate) fn has_window(&self) -> bool {
+        self.congestion
+            .as_ref()
+            .map_or(true, |congestion| self.map.len() < congestion.cwnd())
+    }
+
+    /// The congestion window, `None` if the congestion control is disabled
+    pub(crate) fn cwnd(&self) -> Option<usize> {
+        self.congestion.as_ref().map(NewReno::cwnd)
+    }
+
+    /// Notify the congestion controller of the lost frame set
+    fn on_lost(congestion: &mut Option<NewReno>, last_recorded: Option<u24>, seq_num: u24) {
+        if let Some(congestion) = congestion {
+            let next = last_recorded.unwrap_or(seq_num).wrapping_add(1);
+            congestion.on_loss(seq_num, next);
         }
     }
 
+    /// Clamp the RTO computed from the RTT estimation between `min` and `max`
+    pub(crate) fn with_rto_bounds(mut self, min: Duration, max: Duration) -> Self {
+        self.min_rto = min;
+        self.max_rto = max;
+        self
+    }
+
+    /// Deem the peer unreachable once the frames go stale more than `max` times
+    pub(crate) fn with_max_retries(mut self, max: Option<u32>) -> Self {
+        self.max_retries = max;
+        self
+    }
+
+    /// The effective base RTO. It follows the RTT estimation once sampled, grows under the
+    /// connection-wide loss and recovers after that.
+    pub(crate) fn base_rto(&self) -> Duration {
+        let rto = if self.rtt.jitter().is_some() {
+            self.rtt.pto_base()
+        } else {
+            RTO
+        };
+        rto.clamp(self.min_rto, self.max_rto)
+            .mul_f64(1.0 + self.rto_loss_scale * self.loss.recent_loss_rate())
+            .min(self.max_rto)
+    }
+
+    /// The RTT estimation of the connection
+    pub(crate) fn rtt(&self) -> &RFC6298Impl {
+        &self.rtt
+    }
+
+    /// Update the RTT estimation with a sample measured out of the acknowledgements, e.g. the
+    /// pongs
+    pub(crate) fn sample_rtt(&mut self, ack_delay: Duration, rtt: Duration) {
+        self.rtt.update(ack_delay, rtt);
+    }
+
     pub(crate) fn record(&mut self, seq_num: u24, frames: Frames) {
+        let now = now();
+        let mut retransmitted = false;
+        let mut retries = 0;
+        if !self.resending.is_empty() {
+            for index in frames.iter().filter_map(|frame| frame.reliable_frame_index) {
+                // the retries are kept by each frame, the frames sent along with the fresh ones or
+                // split from the partially acknowledged ones do not start over
+                if let Some(frame_retries) = self.resending.remove(&index) {
+                    retransmitted = true;
+                    retries = retries.max(frame_retries);
+                    metric!(self.metrics.as_ref(), frames_resent, 1);
+                }
+            }
+        }
+        self.stats.resent += u64::from(retransmitted);
+        self.last_recorded = Some(seq_num);
+        self.unacked_frames += frames.len();
+        let size = FRAME_SET_HEADER_SIZE + frames.iter().map(Frame::size).sum::<usize>();
         self.map.insert(
             seq_num,
             ResendEntry {
                 frames: Some(frames),
-                expired_at: Instant::now() + RTO,
+                size,
+                sent_at: now,
+                expired_at: now + self.base_rto(),
+                retransmitted,
+                retries,
+                lost: false,
             },
         );
+        self.changed = true;
+    }
+
+    /// Take the frames of the entry for resending, remembering their reliable indices
+    fn take_for_resend(
+        resending: &mut HashMap<u24, u32>,
+        unacked_frames: &mut usize,
+        entry: &mut ResendEntry,
+        retries: u32,
+        buffer: &mut VecDeque<Frame>,
+    ) {
+        let frames = entry.frames.take().unwrap();
+        *unacked_frames -= frames.len();
+        resending.extend(
+            frames
+                .iter()
+                .filter_map(|frame| frame.reliable_frame_index)
+                .map(|index| (index, retries)),
+        );
+        buffer.extend(frames);
     }
 
+    /// Sample the RTT from the latest sent frame set in the ack. The frame sets carrying the
+    /// retransmitted frames are skipped (Karn's rule).
     pub(crate) fn on_ack(&mut self, ack: AckOrNack) {
+        let mut largest = self.largest_acked;
+        let mut latest_sent_at: Option<Instant> = None;
         for record in ack.records {
-            match record {
-                Record::Range(start, end) => {
-                    for i in start.to_u32()..=end.to_u32() {
-                        self.map.remove(&i.into());
+            let (Record::Range(_, end) | Record::Single(end)) = record;
+            // the range may wrap around at the boundary of u24
+            for seq_num in record.seq_nums() {
+                if let Some(ids) = self.receipts.remove(&seq_num) {
+                    self.acked_receipts.extend(ids);
+                }
+                let Some(entry) = self.map.remove(&seq_num) else {
+                    continue;
+                };
+                self.changed = true;
+                self.stats.acked += 1;
+                self.unacked_frames -= entry.frames.as_ref().map_or(0, Vec::len);
+                if let Some(congestion) = &mut self.congestion {
+                    congestion.on_ack();
+                }
+                if !entry.retransmitted && latest_sent_at.map_or(true, |at| entry.sent_at > at) {
+                    latest_sent_at = Some(entry.sent_at);
+                }
+                if !self.inflight.frames.is_empty() {
+                    for frame in entry.frames.iter().flatten() {
+                        self.inflight.on_ack(frame);
                     }
                 }
-                Record::Single(seq_num) => {
-                    self.map.remove(&seq_num);
+                if entry.lost {
+                    self.loss.on_spurious_loss();
+                } else {
+                    self.loss.on_ack();
                 }
             }
+            if largest.map_or(true, |prev| end.serial_diff(prev) > 0) {
+                largest = Some(end);
+            }
+        }
+        if largest != self.largest_acked {
+            self.largest_acked = largest;
+            self.detect_loss();
+        }
+        if let Some(sent_at) = latest_sent_at {
+            // the peer may delay the ack until it flushes
+            self.sample_rtt(MAX_ACK_DELAY, sent_at.elapsed());
+        }
+    }
+
+    /// Frame sets sent before the largest acknowledged one by [`LOSS_THRESHOLD`] are deemed lost.
+    /// The lost frame sets are still waiting to be resent after RTO or received NACK, while the
+    /// receipts of their unreliable frames are dropped.
+    fn detect_loss(&mut self) {
+        let Some(largest) = self.largest_acked else {
+            return;
+        };
+        self.receipts
+            .retain(|seq_num, _| largest.serial_diff(*seq_num) < LOSS_THRESHOLD as i32);
+        for (seq_num, entry) in &mut self.map {
+            if !entry.lost && largest.serial_diff(*seq_num) >= LOSS_THRESHOLD as i32 {
+                entry.lost = true;
+                self.loss.on_loss();
+                trace!("[{}]: seq_num {seq_num} is deemed lost", self.role);
+            }
         }
     }
 
     pub(crate) fn on_nack_into(&mut self, nack: AckOrNack, buffer: &mut VecDeque<Frame>) {
+        self.stats.nacked += nack.total_cnt() as u64;
         for record in nack.records {
-            match record {
-                Record::Range(start, end) => {
-                    for i in start.to_u32()..=end.to_u32() {
-                        if let Some(entry) = self.map.remove(&i.into()) {
-                            buffer.extend(entry.frames.unwrap());
-                        }
-                    }
-                }
-                Record::Single(seq_num) => {
-                    if let Some(entry) = self.map.remove(&seq_num) {
-                        buffer.extend(entry.frames.unwrap());
-                    }
+            // the range may wrap around at the boundary of u24
+            for seq_num in record.seq_nums() {
+                self.receipts.remove(&seq_num);
+                if let Some(mut entry) = self.map.remove(&seq_num) {
+                    let retries = entry.retries;
+                    Self::take_for_resend(
+                        &mut self.resending,
+                        &mut self.unacked_frames,
+                        &mut entry,
+                        retries,
+                        buffer,
+                    );
+                    Self::on_lost(&mut self.congestion, self.last_recorded, seq_num);
+                    self.changed = true;
                 }
             }
         }
     }
 
+    /// Collect the frames of the frame sets exceeding the fast retransmit threshold into buffer
+    pub(crate) fn fast_retransmit_into(&mut self, buffer: &mut VecDeque<Frame>) {
+        let threshold = self.fast_retransmit_threshold;
+        if threshold == 0 {
+            return;
+        }
+        let Some(largest) = self.largest_acked else {
+            return;
+        };
+        let len = self.map.len();
+        self.map.retain(|seq_num, entry| {
+            if largest.serial_diff(*seq_num) < threshold as i32 {
+                return true;
+            }
+            trace!("[{}]: fast retransmit seq_num {seq_num}", self.role);
+            let retries = entry.retries;
+            Self::take_for_resend(
+                &mut self.resending,
+                &mut self.unacked_frames,
+                entry,
+                retries,
+                buffer,
+            );
+            false
+        });
+        self.changed |= self.map.len() != len;
+    }
+
     /// `process_stales` collect all stale frames into buffer and remove the expired entries
     pub(crate) fn process_stales(&mut self, buffer: &mut VecDeque<Frame>) {
-        let now = Instant::now();
+        let now = now();
         if now < self.last_record_expired_at {
             // probably no stale entries, skip scanning the map
             return;
         }
         // find the first expired_at larger than now
-        let mut min_expired_at = now + RTO;
-        self.map.retain(|_, entry| {
+        let mut min_expired_at = now + self.base_rto();
+        let len = self.map.len();
+        self.map.retain(|seq_num, entry| {
             if entry.expired_at <= now {
-                buffer.extend(entry.frames.take().unwrap());
+                Self::on_lost(&mut self.congestion, self.last_recorded, *seq_num);
+                let retries = entry.retries + 1;
+                if self.max_retries.is_some_and(|max| retries > max) {
+                    trace!(
+                        "[{}]: seq_num {seq_num} exceeds the max retries, drop it",
+                        self.role
+                    );
+                    self.unreachable = true;
+                    self.unacked_frames -= entry.frames.as_ref().map_or(0, Vec::len);
+                    return false;
+                }
+                if retries >= BLACK_HOLE_RETRIES {
+                    self.black_hole = self.black_hole.max(Some(entry.size));
+                }
+                Self::take_for_resend(
+                    &mut self.resending,
+                    &mut self.unacked_frames,
+                    entry,
+                    retries,
+                    buffer,
+                );
                 false
             } else {
                 min_expired_at = min_expired_at.min(entry.expired_at);
                 true
             }
         });
+        self.changed |= self.map.len() != len;
         debug_assert!(min_expired_at > now);
         trace!(
             "[{}]: process stales, {} entries left, next expired at {:?}",
@@ -103,15 +470,82 @@ impl ResendMap {
         self.last_record_expired_at = min_expired_at;
     }
 
+    /// Take the size of the largest frame set going stale repeatedly since the last call, it is
+    /// suspected to be black-holed by the path
+    pub(crate) fn take_black_hole(&mut self) -> Option<usize> {
+        self.black_hole.take()
+    }
+
+    /// Return whether the frames went stale more than the max retries, they are dropped instead of
+    /// being resent
+    pub(crate) fn is_unreachable(&self) -> bool {
+        self.unreachable
+    }
+
+    /// Count a frame set sent, including the ones not kept for resending
+    pub(crate) fn on_sent(&mut self) {
+        self.stats.sent += 1;
+    }
+
+    pub(crate) fn stats(&self) -> TransferStats {
+        self.stats
+    }
+
     pub(crate) fn is_empty(&self) -> bool {
         self.map.is_empty()
     }
 
+    /// The count of the reliable frames awaiting acknowledgement
+    pub(crate) fn unacked_frames(&self) -> usize {
+        self.unacked_frames
+    }
+
+    /// Track the reliable message of the frame being sent, it is counted as inflight until all
+    /// of its frames are acknowledged
+    pub(crate) fn track_message(&mut self, frame: &Frame) {
+        self.inflight.on_send(frame);
+    }
+
+    /// Take the tracked reliable messages whose frames are all acknowledged
+    pub(crate) fn take_acked_messages(&mut self) -> Vec<MessageKey> {
+        std::mem::take(&mut self.inflight.acked)
+    }
+
+    /// Await the acknowledgement of the frame set carrying the tagged unreliable frames to deliver
+    /// their receipts. The receipts are dropped if the frame set is lost.
+    pub(crate) fn record_receipts(&mut self, seq_num: u24, ids: Vec<u64>) {
+        self.receipts.insert(seq_num, ids);
+    }
+
+    /// Take the receipt ids of the tagged unreliable frames whose frame sets are acknowledged
+    pub(crate) fn take_acked_receipts(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.acked_receipts)
+    }
+
+    /// The count of the tracked reliable messages awaiting acknowledgement
+    pub(crate) fn inflight_messages(&self) -> usize {
+        self.inflight.messages.len()
+    }
+
+    /// Return whether the entries changed since the last [`ResendMap::clear_changed`]
+    pub(crate) fn is_changed(&self) -> bool {
+        self.changed
+    }
+
+    pub(crate) fn clear_changed(&mut self) {
+        self.changed = false;
+    }
+
+    /// The sequence numbers of the frame sets awaiting acknowledgement, in arbitrary order
+    pub(crate) fn seq_nums(&self) -> impl Iterator<Item = u24> + '_ {
+        self.map.keys().copied()
+    }
+
     /// `poll_wait` suspends the task when the resend map needs to wait for the next resend
     pub(crate) fn poll_wait(&self, cx: &mut Context<'_>) -> Poll<()> {
         let expired_at;
         let seq_num;
-        let now = Instant::now();
+        let now = now();
         if let Some((seq, entry)) = self.map.iter().min_by_key(|(_, entry)| entry.expired_at)
             && entry.expired_at > now
         {
@@ -126,7 +560,9 @@ impl ResendMap {
             seq_num,
             expired_at - now
         );
-        Reactor::get().insert_timer(self.role.guid(), expired_at, cx.waker());
+        self.role
+            .reactor()
+            .insert_timer(self.role.guid(), expired_at, cx.waker());
         Poll::Pending
     }
 }
@@ -139,9 +575,11 @@ mod test {
 
     use bytes::Bytes;
 
-    use super::ResendMap;
-    use crate::packet::connected::{AckOrNack, Flags, Frame};
-    use crate::utils::tests::{test_trace_log_setup, TestWaker};
+    use super::{ResendMap, MIN_RTO, RTO};
+    use crate::packet::connected::{AckOrNack, Flags, Frame, Record};
+    use crate::packet::FRAME_SET_HEADER_SIZE;
+    use crate::utils::tests::{test_trace_log_setup, MockClock, TestWaker};
+    use crate::utils::u24;
     use crate::{Reliability, RoleContext};
 
     const TEST_RTO: Duration = Duration::from_millis(1200);
@@ -201,26 +639,90 @@ mod test {
         assert_eq!(buffer.pop_front().unwrap().body, Bytes::from_static(b"3"));
     }
 
+    #[test]
+    fn test_resend_map_receipts() {
+        let mut map = ResendMap::new(RoleContext::test_server());
+        map.record_receipts(0.into(), vec![0, 1]);
+        map.record_receipts(1.into(), vec![2]);
+        map.record_receipts(2.into(), vec![3]);
+        map.on_ack(AckOrNack::extend_from([0].into_iter().map(Into::into), 100).unwrap());
+        assert_eq!(map.take_acked_receipts(), vec![0, 1]);
+        assert!(map.take_acked_receipts().is_empty());
+
+        // the receipts of the nacked frame set never arrive, no frames are resent
+        let mut buffer = VecDeque::default();
+        map.on_nack_into(
+            AckOrNack::extend_from([1].into_iter().map(Into::into), 100).unwrap(),
+            &mut buffer,
+        );
+        assert!(buffer.is_empty());
+        map.on_ack(AckOrNack::extend_from([1, 2].into_iter().map(Into::into), 100).unwrap());
+        assert_eq!(map.take_acked_receipts(), vec![3]);
+
+        // the receipts of the frame set deemed lost are dropped
+        map.record_receipts(3.into(), vec![4]);
+        map.on_ack(AckOrNack::extend_from([20].into_iter().map(Into::into), 100).unwrap());
+        map.on_ack(AckOrNack::extend_from([3].into_iter().map(Into::into), 100).unwrap());
+        assert!(map.take_acked_receipts().is_empty());
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_resend_map_wrapped_records() {
+        let frame = || Frame {
+            flags: Flags::new(Reliability::Reliable, false),
+            reliable_frame_index: Some(0.into()),
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            body: Bytes::from_static(b"1"),
+        };
+        let mut map = ResendMap::new(RoleContext::test_server());
+        for seq_num in [u24::MAX - 1, u24::MAX, 0.into(), 1.into()] {
+            map.record(seq_num, vec![frame()]);
+        }
+        map.on_ack(AckOrNack {
+            records: vec![Record::Range(u24::MAX - 1, 0.into())],
+        });
+        assert_eq!(map.map.len(), 1);
+        assert_eq!(map.largest_acked, Some(0.into()));
+
+        for seq_num in [2.into(), u24::MAX - 1, u24::MAX] {
+            map.record(seq_num, vec![frame()]);
+        }
+        let mut buffer = VecDeque::default();
+        map.on_nack_into(
+            AckOrNack {
+                records: vec![Record::Range(u24::MAX - 1, 2.into())],
+            },
+            &mut buffer,
+        );
+        assert!(map.is_empty());
+        assert_eq!(buffer.len(), 4);
+    }
+
     #[test]
     fn test_resend_map_stales() {
+        let clock = MockClock::install();
         let mut map = ResendMap::new(RoleContext::test_server());
         map.record(0.into(), vec![]);
         map.record(1.into(), vec![]);
         map.record(2.into(), vec![]);
-        std::thread::sleep(TEST_RTO);
+        clock.advance(TEST_RTO);
         map.record(3.into(), vec![]);
         let mut buffer = VecDeque::default();
         map.process_stales(&mut buffer);
         assert_eq!(map.map.len(), 1);
     }
 
-    #[tokio::test]
-    async fn test_resend_map_poll_wait() {
+    #[test]
+    fn test_resend_map_poll_wait() {
         let _guard = test_trace_log_setup();
 
+        let clock = MockClock::install();
         let mut map = ResendMap::new(RoleContext::test_server());
         map.record(0.into(), vec![]);
-        std::thread::sleep(TEST_RTO);
+        clock.advance(TEST_RTO);
         map.record(1.into(), vec![]);
         map.record(2.into(), vec![]);
         map.record(3.into(), vec![]);
@@ -233,8 +735,215 @@ mod test {
         map.process_stales(&mut buffer);
         assert_eq!(map.map.len(), 3);
 
-        std::future::poll_fn(|cx| map.poll_wait(cx)).await;
+        let mut cx = Context::from_waker(&TestWaker::create());
+        assert!(map.poll_wait(&mut cx).is_pending());
+        clock.advance(TEST_RTO);
+        assert!(map.poll_wait(&mut cx).is_ready());
         map.process_stales(&mut buffer);
         assert!(map.map.len() < 3);
     }
+
+    #[test]
+    fn test_resend_map_loss_estimation() {
+        let mut map = ResendMap::new(RoleContext::test_server());
+        for i in 0..100 {
+            map.record(i.into(), vec![]);
+        }
+        // every 10th frame set is lost
+        map.on_ack(
+            AckOrNack::extend_from((0..100).filter(|i| i % 10 != 0).map(Into::into), 1500).unwrap(),
+        );
+        assert_eq!(map.map.len(), 10);
+        assert!((map.loss.loss_rate() - 0.1).abs() < f64::EPSILON);
+
+        // spurious loss
+        map.on_ack(AckOrNack::extend_from([90].into_iter().map(Into::into), 1500).unwrap());
+        assert!((map.loss.loss_rate() - 0.09).abs() < f64::EPSILON);
+
+        // not exceed the threshold
+        for i in 100..105 {
+            map.record(i.into(), vec![]);
+        }
+        map.on_ack(AckOrNack::extend_from([101, 102].into_iter().map(Into::into), 1500).unwrap());
+        assert!(!map.map[&100.into()].lost);
+        map.on_ack(AckOrNack::extend_from([103].into_iter().map(Into::into), 1500).unwrap());
+        assert!(map.map[&100.into()].lost);
+    }
+
+    #[test]
+    fn test_resend_map_rto_scales_with_loss() {
+        // the instant acks keep the rto at the lower bound
+        let mut map = ResendMap::new(RoleContext::test_server())
+            .with_rto_loss_scale(2.0)
+            .with_rto_bounds(RTO, RTO * 10);
+        assert_eq!(map.base_rto(), RTO);
+
+        // sustained loss, every other frame set is lost except the tail
+        for i in 0..200 {
+            map.record(i.into(), vec![]);
+        }
+        map.on_ack(
+            AckOrNack::extend_from(
+                (0..200).filter(|i| i % 2 == 0 || *i >= 190).map(Into::into),
+                1500,
+            )
+            .unwrap(),
+        );
+        let lossy_rto = map.base_rto();
+        assert!(lossy_rto > RTO.mul_f64(1.5), "{lossy_rto:?}");
+        assert!(lossy_rto <= RTO * 3);
+
+        // recovers after the loss stops
+        for i in 200..400 {
+            map.record(i.into(), vec![]);
+        }
+        map.on_ack(AckOrNack::extend_from((200..400).map(Into::into), 1500).unwrap());
+        let recovered_rto = map.base_rto();
+        assert!(recovered_rto < RTO.mul_f64(1.1), "{recovered_rto:?}");
+
+        // not scaled by default
+        let mut unscaled =
+            ResendMap::new(RoleContext::test_server()).with_rto_bounds(RTO, RTO * 10);
+        for i in 0..200 {
+            unscaled.record(i.into(), vec![]);
+        }
+        unscaled.on_ack(
+            AckOrNack::extend_from((0..200).filter(|i| i % 2 == 0).map(Into::into), 1500).unwrap(),
+        );
+        assert_eq!(unscaled.base_rto(), RTO);
+    }
+
+    fn reliable_frame(index: u32) -> Frame {
+        Frame {
+            flags: Flags::new(Reliability::Reliable, false),
+            reliable_frame_index: Some(index.into()),
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            body: Bytes::from_static(b"1"),
+        }
+    }
+
+    #[test]
+    fn test_resend_map_adaptive_rto() {
+        let clock = MockClock::install();
+        let mut map = ResendMap::new(RoleContext::test_server())
+            .with_rto_bounds(Duration::from_millis(10), Duration::from_secs(2));
+        assert_eq!(map.base_rto(), RTO);
+
+        map.record(0.into(), vec![reliable_frame(0)]);
+        clock.advance(Duration::from_millis(50));
+        map.on_ack(AckOrNack::extend_from([0].into_iter().map(Into::into), 100).unwrap());
+        let smoothed = map.rtt().get();
+        assert!(smoothed >= Duration::from_millis(50), "{smoothed:?}");
+        let rto = map.base_rto();
+        assert!(rto > smoothed && rto < RTO, "{rto:?}");
+
+        // Karn's rule, the ack of the retransmitted frame is not sampled
+        map.record(1.into(), vec![reliable_frame(1)]);
+        let mut buffer = VecDeque::default();
+        map.on_nack_into(
+            AckOrNack::extend_from([1].into_iter().map(Into::into), 100).unwrap(),
+            &mut buffer,
+        );
+        map.record(2.into(), buffer.drain(..).collect());
+        clock.advance(Duration::from_millis(300));
+        map.on_ack(AckOrNack::extend_from([2].into_iter().map(Into::into), 100).unwrap());
+        assert!(map.is_empty());
+        assert_eq!(map.rtt().get(), smoothed);
+
+        // the frame sent afterwards is sampled again
+        map.record(3.into(), vec![reliable_frame(1)]);
+        clock.advance(Duration::from_millis(300));
+        map.on_ack(AckOrNack::extend_from([3].into_iter().map(Into::into), 100).unwrap());
+        assert!(map.rtt().get() > smoothed);
+
+        let stats = map.stats();
+        assert_eq!(stats.acked, 3);
+        assert_eq!(stats.resent, 1);
+        assert_eq!(stats.nacked, 1);
+    }
+
+    #[test]
+    fn test_resend_map_rto_bounds() {
+        let clock = MockClock::install();
+        // the instant ack is clamped by the lower bound
+        let mut map = ResendMap::new(RoleContext::test_server());
+        map.record(0.into(), vec![reliable_frame(0)]);
+        map.on_ack(AckOrNack::extend_from([0].into_iter().map(Into::into), 100).unwrap());
+        assert_eq!(map.base_rto(), MIN_RTO);
+
+        // the slow ack is clamped by the upper bound
+        let max = Duration::from_millis(100);
+        let mut map = ResendMap::new(RoleContext::test_server())
+            .with_rto_loss_scale(2.0)
+            .with_rto_bounds(Duration::from_millis(10), max);
+        map.record(0.into(), vec![reliable_frame(0)]);
+        clock.advance(Duration::from_millis(200));
+        map.on_ack(AckOrNack::extend_from([0].into_iter().map(Into::into), 100).unwrap());
+        assert_eq!(map.base_rto(), max);
+
+        // the upper bound also applies before any sample
+        let map = ResendMap::new(RoleContext::test_server())
+            .with_rto_bounds(Duration::from_millis(10), max);
+        assert_eq!(map.base_rto(), max);
+    }
+
+    #[test]
+    fn test_resend_map_max_retries() {
+        let clock = MockClock::install();
+        let mut map = ResendMap::new(RoleContext::test_server())
+            .with_rto_bounds(Duration::from_millis(10), Duration::from_millis(10))
+            .with_max_retries(Some(1));
+        let stale = |map: &mut ResendMap, buffer: &mut VecDeque<Frame>| {
+            clock.advance(Duration::from_millis(20));
+            map.process_stales(buffer);
+        };
+        let mut buffer = VecDeque::default();
+
+        map.record(0.into(), vec![reliable_frame(0), reliable_frame(1)]);
+        stale(&mut map, &mut buffer);
+        assert_eq!(buffer.len(), 2);
+        assert!(!map.is_unreachable());
+
+        // the frames are resent separately, the acknowledged one does not reset the other
+        map.record(1.into(), vec![buffer.pop_front().unwrap()]);
+        map.record(2.into(), vec![buffer.pop_front().unwrap()]);
+        map.on_ack(AckOrNack::extend_from([1].into_iter().map(Into::into), 100).unwrap());
+        // resent along with a fresh frame
+        let mut nacked = VecDeque::default();
+        map.on_nack_into(
+            AckOrNack::extend_from([2].into_iter().map(Into::into), 100).unwrap(),
+            &mut nacked,
+        );
+        nacked.push_back(reliable_frame(2));
+        map.record(3.into(), nacked.drain(..).collect());
+
+        stale(&mut map, &mut buffer);
+        assert!(buffer.is_empty());
+        assert!(map.is_unreachable());
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_resend_map_black_hole() {
+        let clock = MockClock::install();
+        let mut map = ResendMap::new(RoleContext::test_server())
+            .with_rto_bounds(Duration::from_millis(10), Duration::from_millis(10));
+        let frames = vec![reliable_frame(0), reliable_frame(1)];
+        let size = FRAME_SET_HEADER_SIZE + frames.iter().map(Frame::size).sum::<usize>();
+        let mut buffer = VecDeque::default();
+
+        map.record(0.into(), frames);
+        clock.advance(Duration::from_millis(20));
+        map.process_stales(&mut buffer);
+        // a single loss is not suspected
+        assert_eq!(map.take_black_hole(), None);
+
+        map.record(1.into(), buffer.drain(..).collect());
+        clock.advance(Duration::from_millis(20));
+        map.process_stales(&mut buffer);
+        assert_eq!(map.take_black_hole(), Some(size));
+        assert_eq!(map.take_black_hole(), None);
+    }
 }
@@ -0,0 +1,77 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+
+use bytes::BytesMut;
+
+/// The runtime of smol
+#[cfg(feature = "smol")]
+mod smol;
+
+/// The runtime of tokio
+#[cfg(feature = "tokio")]
+mod tokio;
+
+#[cfg(feature = "smol")]
+pub use self::smol::Smol;
+#[cfg(feature = "tokio")]
+pub use self::tokio::Tokio;
+
+/// The async runtime spawning the background tasks of the connections, e.g. the task routing the
+/// datagrams received by the client socket.
+pub trait Runtime {
+    /// Spawn the task detached, it runs until it completes
+    fn spawn(task: impl Future<Output = ()> + Send + 'static);
+}
+
+/// The UDP socket registered with an async runtime, the clients and the servers are made over
+/// it by [`ConnectTo`] and [`MakeIncoming`].
+///
+/// The timers of the connections are driven by the timer thread of this crate, only the socket
+/// and the spawning are required from the runtime.
+///
+/// It is the seam for running RakNet over other transports, e.g. the WebTransport datagrams or an
+/// in-process channel, see `examples/channel.rs`. RakNet is datagram-oriented, the packets are not
+/// delimited by the length but by the datagram boundaries, so each call must carry exactly one
+/// datagram, and the datagrams up to the mtu must not be split or coalesced. Wrap the stream
+/// transports with a length-delimited framing to keep the boundaries. The datagrams may be lost,
+/// duplicated or reordered, the connections recover from them.
+///
+/// [`ConnectTo`]: crate::client::ConnectTo
+/// [`MakeIncoming`]: crate::server::MakeIncoming
+pub trait AsyncUdpSocket: Send + Sync + 'static {
+    /// The runtime the socket is registered with
+    type Runtime: Runtime;
+
+    /// Receive a datagram into the spare capacity of `buf`, return the address it comes from
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut BytesMut,
+    ) -> Poll<io::Result<SocketAddr>>;
+
+    /// Send the datagram to the target, return the count of the bytes sent
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>>;
+
+    /// The address the socket is bound to
+    ///
+    /// # Errors
+    /// Returns the error of the underlying socket.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Mark the outgoing datagrams with the DSCP
+    ///
+    /// # Errors
+    /// Returns [`io::ErrorKind::Unsupported`] if the socket or the platform does not support it,
+    /// which is the default.
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        let _ = dscp;
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
@@ -0,0 +1,105 @@
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::task::{ready, Context, Poll};
+
+use bytes::BytesMut;
+use smol::Async;
+
+use super::{AsyncUdpSocket, Runtime};
+
+/// The smol runtime, the tasks are spawned on the global executor
+#[derive(Debug, Clone, Copy)]
+pub struct Smol;
+
+impl Runtime for Smol {
+    fn spawn(task: impl Future<Output = ()> + Send + 'static) {
+        smol::spawn(task).detach();
+    }
+}
+
+/// The socket is bound by `Async::<UdpSocket>::bind`, `smol::net::UdpSocket` does not expose the
+/// readiness to poll.
+impl AsyncUdpSocket for Async<UdpSocket> {
+    type Runtime = Smol;
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut BytesMut,
+    ) -> Poll<io::Result<SocketAddr>> {
+        loop {
+            let start = buf.len();
+            // the std socket only reads into the initialized buffer
+            buf.resize(buf.capacity(), 0);
+            let res = self.get_ref().recv_from(&mut buf[start..]);
+            buf.truncate(start + res.as_ref().map_or(0, |(len, _)| *len));
+            match res {
+                Ok((_, addr)) => return Poll::Ready(Ok(addr)),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(self.poll_readable(cx))?;
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            match self.get_ref().send_to(buf, target) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(self.poll_writable(cx))?;
+                }
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use smol::Async;
+
+    use crate::client::{self, ConnectTo};
+    use crate::server::{self, MakeIncoming};
+
+    #[test]
+    fn test_smol_echo() {
+        smol::block_on(async {
+            let socket = Async::<UdpSocket>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let addr = socket.get_ref().local_addr().unwrap();
+            let mut incoming = socket.make_incoming(server::Config::new().server_guid(1919810));
+            smol::spawn(async move {
+                let io = incoming.next().await.unwrap();
+                futures::pin_mut!(io);
+                while let Some(data) = io.next().await {
+                    io.send(data).await.unwrap();
+                }
+            })
+            .detach();
+
+            let io = Async::<UdpSocket>::bind(([127, 0, 0, 1], 0))
+                .unwrap()
+                .connect_to(addr, client::Config::new().client_guid(114514))
+                .await
+                .unwrap();
+            futures::pin_mut!(io);
+            let data = Bytes::from_static(b"\xfehello smol");
+            io.send(data.clone()).await.unwrap();
+            assert_eq!(io.next().await.unwrap(), data);
+        });
+    }
+}
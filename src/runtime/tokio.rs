@@ -0,0 +1,145 @@
+use std::future::Future;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::SocketAddr;
+use std::task::{ready, Context, Poll};
+
+use bytes::{BufMut, BytesMut};
+use tokio::net::UdpSocket as TokioUdpSocket;
+
+use super::{AsyncUdpSocket, Runtime};
+
+/// The tokio runtime, the tasks are spawned on the current runtime
+#[derive(Debug, Clone, Copy)]
+pub struct Tokio;
+
+impl Runtime for Tokio {
+    fn spawn(task: impl Future<Output = ()> + Send + 'static) {
+        tokio::spawn(task);
+    }
+}
+
+impl AsyncUdpSocket for TokioUdpSocket {
+    type Runtime = Tokio;
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        rd: &mut BytesMut,
+    ) -> Poll<io::Result<SocketAddr>> {
+        // Safety: `chunk_mut()` returns a `&mut UninitSlice`, and `UninitSlice` is a
+        // transparent wrapper around `[MaybeUninit<u8>]`.
+        let buf = unsafe { &mut *(rd.chunk_mut() as *mut _ as *mut [MaybeUninit<u8>]) };
+        let mut read = tokio::io::ReadBuf::uninit(buf);
+        let ptr = read.filled().as_ptr();
+        let res = ready!(TokioUdpSocket::poll_recv_from(self, cx, &mut read));
+
+        assert_eq!(ptr, read.filled().as_ptr());
+        let addr = res?;
+
+        // Safety: This is guaranteed to be the number of initialized (and read) bytes due
+        // to the invariants provided by `ReadBuf::filled`.
+        unsafe { rd.advance_mut(read.filled().len()) };
+
+        Poll::Ready(Ok(addr))
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        TokioUdpSocket::poll_send_to(self, cx, buf, target)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TokioUdpSocket::local_addr(self)
+    }
+
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        // DSCP is the upper 6 bits of the TOS/traffic class field
+        let tos = u32::from(dscp) << 2;
+        if TokioUdpSocket::local_addr(self)?.is_ipv6() {
+            set_tclass_v6(self, tos)
+        } else {
+            set_tos_v4(self, tos)
+        }
+    }
+}
+
+#[cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+    target_os = "wasi",
+)))]
+fn set_tos_v4(socket: &TokioUdpSocket, tos: u32) -> io::Result<()> {
+    socket.set_tos_v4(tos)
+}
+
+#[cfg(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+    target_os = "wasi",
+))]
+fn set_tos_v4(_socket: &TokioUdpSocket, _tos: u32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "cygwin",
+))]
+fn set_tclass_v6(socket: &TokioUdpSocket, tclass: u32) -> io::Result<()> {
+    socket.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "cygwin",
+)))]
+fn set_tclass_v6(_socket: &TokioUdpSocket, _tclass: u32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod test {
+    use tokio::net::UdpSocket as TokioUdpSocket;
+
+    use crate::runtime::AsyncUdpSocket;
+
+    #[tokio::test]
+    async fn test_set_dscp() {
+        let v4 = TokioUdpSocket::bind("127.0.0.1:0").await.unwrap();
+        v4.set_dscp(46).unwrap();
+        assert_eq!(v4.tos_v4().unwrap(), 46 << 2);
+
+        let Ok(v6) = TokioUdpSocket::bind("[::1]:0").await else {
+            // IPv6 is unavailable
+            return;
+        };
+        v6.set_dscp(10).unwrap();
+        assert_eq!(v6.tclass_v6().unwrap(), 10 << 2);
+    }
+}
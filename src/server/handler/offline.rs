@@ -1,9 +1,14 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroUsize;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
 use bytes::Bytes;
 use futures::{ready, Sink, Stream};
 use log::{debug, error, trace, warn};
@@ -14,23 +19,78 @@ use pin_project_lite::pin_project;
 use crate::errors::CodecError;
 use crate::packet::connected::{self, FramesMut};
 use crate::packet::{unconnected, Packet};
+use crate::utils::Reactor;
 use crate::{PeerContext, RoleContext};
 
 #[derive(Debug, Clone)]
 pub(crate) struct Config {
-    pub(crate) sever_guid: u64,
-    pub(crate) advertisement: Bytes,
+    pub(crate) server_guid: u64,
+    // Shared with the incoming to be updated, the pings read it without locking
+    pub(crate) advertisement: Arc<ArcSwap<Bytes>>,
     pub(crate) min_mtu: u16,
     pub(crate) max_mtu: u16,
     // Supported raknet versions, sorted
     pub(crate) support_version: Vec<u8>,
     pub(crate) max_pending: usize,
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) recently_connected_cooldown: Option<Duration>,
+    // The maximum replies waiting to be sent
+    pub(crate) reply_backlog: usize,
+    // Require the client to echo the cookie in the open connection request 2
+    pub(crate) require_cookie: bool,
+    // Advertise the encryption of the user data in the open connection reply 2
+    pub(crate) encryption: bool,
+    // Only accept the open connection request 2 targeting this address, `None` accepts any
+    pub(crate) server_address: Option<SocketAddr>,
+    pub(crate) reactor: &'static Reactor,
 }
 
-enum OfflineState {
-    Listening,
-    SendingPrepare(Option<(unconnected::Packet, SocketAddr)>),
-    SendingFlush,
+/// The cookies of the open connection handshake, a keyed hash (SipHash with the random keys) of
+/// the client address within the time window. The client echoes the cookie back to prove it owns
+/// the address, so no state is kept for the forged addresses.
+struct Cookies {
+    key: RandomState,
+    since: Instant,
+}
+
+impl Cookies {
+    /// The cookie is valid in its window and the next one
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    fn new() -> Self {
+        Self {
+            key: RandomState::new(),
+            since: Instant::now(),
+        }
+    }
+
+    fn window(&self) -> u64 {
+        self.since.elapsed().as_secs() / Self::WINDOW.as_secs()
+    }
+
+    fn make(&self, addr: SocketAddr, window: u64) -> u32 {
+        let mut hasher = self.key.build_hasher();
+        addr.hash(&mut hasher);
+        window.hash(&mut hasher);
+        hasher.finish() as u32
+    }
+
+    fn issue(&self, addr: SocketAddr) -> u32 {
+        self.make(addr, self.window())
+    }
+
+    fn verify(&self, addr: SocketAddr, cookie: u32) -> bool {
+        let window = self.window();
+        cookie == self.make(addr, window) || window > 0 && cookie == self.make(addr, window - 1)
+    }
+}
+
+/// Whether the client targets the address the server is bound to, only the port is compared if
+/// it is bound to the unspecified address
+fn targets(local: SocketAddr, server_address: SocketAddr) -> bool {
+    local.port() == server_address.port()
+        && (local.ip().is_unspecified()
+            || local.ip().to_canonical() == server_address.ip().to_canonical())
 }
 
 pin_project! {
@@ -39,10 +99,17 @@ pin_project! {
         #[pin]
         frame: F,
         config: Config,
-        // Half-connected queue
+        // Half-connected queue, it is not used if the cookie is required
         pending: lru::LruCache<SocketAddr, u8>,
+        cookies: Option<Cookies>,
         connected: HashMap<SocketAddr, PeerContext>,
-        state: OfflineState,
+        // The ips whose handshakes are answered with `ConnectionBanned`
+        banned: HashSet<IpAddr>,
+        // The instants of the last connections from each ip, tracked if the cooldown is set
+        recent: lru::LruCache<IpAddr, Instant>,
+        // The replies waiting to be sent, it keeps reading until it is full
+        replies: VecDeque<(unconnected::Packet, SocketAddr)>,
+        flushing: bool,
         role: RoleContext,
         read_span: Option<Span>,
     }
@@ -54,17 +121,25 @@ where
         + Sink<(unconnected::Packet, SocketAddr), Error = CodecError>,
 {
     pub(crate) fn new(frame: F, config: Config) -> Self {
+        assert!(config.reply_backlog > 0, "reply_backlog > 0");
         Self {
             frame,
             pending: lru::LruCache::new(
                 NonZeroUsize::new(config.max_pending).expect("max_pending > 0"),
             ),
+            recent: lru::LruCache::new(
+                NonZeroUsize::new(config.max_pending).expect("max_pending > 0"),
+            ),
             role: RoleContext::Server {
-                guid: config.sever_guid,
+                guid: config.server_guid,
+                reactor: config.reactor,
             },
+            replies: VecDeque::with_capacity(config.reply_backlog),
+            cookies: config.require_cookie.then(Cookies::new),
             config,
             connected: HashMap::new(),
-            state: OfflineState::Listening,
+            banned: HashSet::new(),
+            flushing: false,
             read_span: None,
         }
     }
@@ -75,25 +150,87 @@ where
         this.connected.remove(addr);
     }
 
+    /// The addresses of the peers finished the offline handshake
+    pub(crate) fn connected_addrs(&self) -> Vec<SocketAddr> {
+        self.connected.keys().copied().collect()
+    }
+
+    /// The advertisement replied in the pongs
+    pub(crate) fn advertisement(&self) -> &Arc<ArcSwap<Bytes>> {
+        &self.config.advertisement
+    }
+
+    /// The addresses of the pending handshakes, the most recent first
+    pub(crate) fn pending_addrs(&self) -> Vec<SocketAddr> {
+        self.pending.iter().map(|(addr, _)| *addr).collect()
+    }
+
+    /// Cancel the pending handshake, the peer is replied with `ConnectionRequestFailed` on the
+    /// next poll. Return false if it is not pending.
+    pub(crate) fn cancel_pending(self: Pin<&mut Self>, addr: &SocketAddr) -> bool {
+        let this = self.project();
+        if this.pending.pop(addr).is_none() {
+            return false;
+        }
+        debug!("[{}] cancel the pending handshake of {addr}", this.role);
+        this.replies
+            .push_back((Self::make_connection_request_failed(this.config), *addr));
+        true
+    }
+
+    /// Ban the ip, the handshakes from it are answered with `ConnectionBanned` on any port
+    pub(crate) fn ban(self: Pin<&mut Self>, ip: IpAddr) {
+        let this = self.project();
+        debug!("[{}] ban {ip}", this.role);
+        this.banned.insert(ip);
+    }
+
+    /// Lift the ban of the ip, return false if it is not banned
+    pub(crate) fn unban(self: Pin<&mut Self>, ip: IpAddr) -> bool {
+        self.project().banned.remove(&ip)
+    }
+
     fn make_incompatible_version(config: &Config) -> unconnected::Packet {
         unconnected::Packet::IncompatibleProtocol {
             server_protocol: *config.support_version.last().unwrap(),
             magic: (),
-            server_guid: config.sever_guid,
+            server_guid: config.server_guid,
         }
     }
 
     fn make_already_connected(config: &Config) -> unconnected::Packet {
         unconnected::Packet::AlreadyConnected {
             magic: (),
-            server_guid: config.sever_guid,
+            server_guid: config.server_guid,
+        }
+    }
+
+    fn make_ip_recently_connected(config: &Config, retry_after: Duration) -> unconnected::Packet {
+        unconnected::Packet::IpRecentlyConnected {
+            magic: (),
+            server_guid: config.server_guid,
+            retry_after_ms: Some(retry_after.as_millis().try_into().unwrap_or(u32::MAX)),
+        }
+    }
+
+    fn make_connection_banned(config: &Config) -> unconnected::Packet {
+        unconnected::Packet::ConnectionBanned {
+            magic: (),
+            server_guid: config.server_guid,
+        }
+    }
+
+    fn make_no_free_incoming_connections(config: &Config) -> unconnected::Packet {
+        unconnected::Packet::NoFreeIncomingConnections {
+            magic: (),
+            server_guid: config.server_guid,
         }
     }
 
     fn make_connection_request_failed(config: &Config) -> unconnected::Packet {
         unconnected::Packet::ConnectionRequestFailed {
             magic: (),
-            server_guid: config.sever_guid,
+            server_guid: config.server_guid,
         }
     }
 }
@@ -108,28 +245,18 @@ where
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
         loop {
-            match this.state {
-                OfflineState::Listening => {}
-                OfflineState::SendingPrepare(pack) => {
-                    if let Err(err) = ready!(this.frame.as_mut().poll_ready(cx)) {
-                        error!("[{}] send error: {err}", this.role);
-                        *this.state = OfflineState::Listening;
-                        continue;
-                    }
-                    if let Err(err) = this.frame.as_mut().start_send(pack.take().unwrap()) {
-                        error!("[{}] send error: {err}", this.role);
-                        *this.state = OfflineState::Listening;
-                        continue;
-                    }
-                    *this.state = OfflineState::SendingFlush;
-                    continue;
-                }
-                OfflineState::SendingFlush => {
-                    if let Err(err) = ready!(this.frame.as_mut().poll_flush(cx)) {
-                        error!("[{}] send error: {err}", this.role);
-                    }
-                    *this.state = OfflineState::Listening;
-                }
+            // keep reading while the previous replies are draining unless the backlog is full
+            if poll_send(
+                this.frame.as_mut(),
+                this.replies,
+                this.flushing,
+                *this.role,
+                cx,
+            )
+            .is_pending()
+                && this.replies.len() >= this.config.reply_backlog
+            {
+                return Poll::Pending;
             }
 
             let guard = this
@@ -145,6 +272,13 @@ where
                 })
                 .set_local_parent();
             let Some((packet, addr)) = ready!(this.frame.as_mut().poll_next(cx)) else {
+                ready!(poll_send(
+                    this.frame.as_mut(),
+                    this.replies,
+                    this.flushing,
+                    *this.role,
+                    cx
+                ));
                 return Poll::Ready(None);
             };
 
@@ -161,20 +295,43 @@ where
                         this.role,
                         pack.pack_type()
                     );
-                    *this.state = OfflineState::SendingPrepare(Some((
-                        Self::make_connection_request_failed(this.config),
-                        addr,
-                    )));
+                    this.replies
+                        .push_back((Self::make_connection_request_failed(this.config), addr));
                     continue;
                 }
             };
+            if matches!(
+                pack,
+                unconnected::Packet::OpenConnectionRequest1 { .. }
+                    | unconnected::Packet::OpenConnectionRequest2 { .. }
+            ) && this.banned.contains(&addr.ip())
+            {
+                debug!("[{}] reject the handshake from banned {addr}", this.role);
+                this.pending.pop(&addr);
+                this.replies
+                    .push_back((Self::make_connection_banned(this.config), addr));
+                continue;
+            }
             let resp = match pack {
+                // our own broadcast looped back
+                unconnected::Packet::UnconnectedPing { client_guid, .. }
+                | unconnected::Packet::UnconnectedPong {
+                    server_guid: client_guid,
+                    ..
+                } if client_guid == this.config.server_guid => {
+                    trace!(
+                        "[{}] ignore looped-back {:?} from {addr}",
+                        this.role,
+                        pack.pack_type()
+                    );
+                    continue;
+                }
                 unconnected::Packet::UnconnectedPing { send_timestamp, .. } => {
                     unconnected::Packet::UnconnectedPong {
                         send_timestamp,
-                        server_guid: this.config.sever_guid,
+                        server_guid: this.config.server_guid,
                         magic: (),
-                        data: this.config.advertisement.clone(),
+                        data: Bytes::clone(&this.config.advertisement.load()),
                     }
                 }
                 unconnected::Packet::OpenConnectionRequest1 {
@@ -192,13 +349,31 @@ where
                             "[{}] received incompatible version({protocol_version}) from {addr}",
                             this.role
                         );
-                        *this.state = OfflineState::SendingPrepare(Some((
-                            Self::make_incompatible_version(this.config),
+                        this.replies
+                            .push_back((Self::make_incompatible_version(this.config), addr));
+                        continue;
+                    }
+                    if let Some(retry_after) =
+                        cooldown_remaining(this.recent, this.config, addr.ip())
+                    {
+                        debug!(
+                            "[{}] {addr} recently connected, retry after {retry_after:?}",
+                            this.role
+                        );
+                        this.replies.push_back((
+                            Self::make_ip_recently_connected(this.config, retry_after),
                             addr,
-                        )));
+                        ));
                         continue;
                     }
-                    if this.pending.put(addr, protocol_version).is_some() {
+                    // the cookie replaces the pending state, nothing is kept for the forged address
+                    let cookie = this.cookies.as_ref().map(|cookies| cookies.issue(addr));
+                    if cookie.is_some() {
+                        trace!(
+                            "[{}] received open connection request 1 from {addr}, reply the cookie",
+                            this.role,
+                        );
+                    } else if this.pending.put(addr, protocol_version).is_some() {
                         debug!(
                             "[{}] received duplicate open connection request 1 from {addr}",
                             this.role
@@ -213,44 +388,88 @@ where
                     let final_mtu = mtu.clamp(this.config.min_mtu, this.config.max_mtu);
                     unconnected::Packet::OpenConnectionReply1 {
                         magic: (),
-                        server_guid: this.config.sever_guid,
-                        use_encryption: false, // must set to false first
+                        server_guid: this.config.server_guid,
+                        cookie,
                         mtu: final_mtu,
                     }
                 }
-                unconnected::Packet::OpenConnectionRequest2 { mtu, .. } => {
-                    if this.pending.pop(&addr).is_none() {
+                unconnected::Packet::OpenConnectionRequest2 {
+                    cookie,
+                    server_address,
+                    mtu,
+                    client_guid,
+                    ..
+                } => {
+                    if let Some(cookies) = this.cookies {
+                        if !cookie.is_some_and(|cookie| cookies.verify(addr, cookie)) {
+                            // not replied, the address may be forged
+                            debug!("[{}] received open connection request 2 from {addr} without a valid cookie", this.role);
+                            continue;
+                        }
+                    } else if this.pending.pop(&addr).is_none() {
                         debug!("[{}] received open connection request 2 from {addr} without open connection request 1", this.role);
-                        *this.state = OfflineState::SendingPrepare(Some((
-                            Self::make_incompatible_version(this.config),
-                            addr,
-                        )));
+                        this.replies
+                            .push_back((Self::make_incompatible_version(this.config), addr));
                         continue;
                     }
                     trace!(
                         "[{}] received open connection request 2 from {addr}",
                         this.role
                     );
+                    if let Some(local) = this.config.server_address
+                        && !targets(local, server_address)
+                    {
+                        debug!(
+                            "[{}] received open connection request 2 from {addr} targeting {server_address}, not {local}",
+                            this.role
+                        );
+                        this.replies
+                            .push_back((Self::make_incompatible_version(this.config), addr));
+                        continue;
+                    }
                     // client should adjust the mtu
                     if mtu < this.config.min_mtu
                         || mtu > this.config.max_mtu
                         || this.connected.contains_key(&addr)
                     {
                         debug!("[{}] received unexpected mtu({mtu}) from {addr}", this.role);
-                        *this.state = OfflineState::SendingPrepare(Some((
-                            Self::make_already_connected(this.config),
+                        this.replies
+                            .push_back((Self::make_already_connected(this.config), addr));
+                        continue;
+                    }
+                    if this
+                        .config
+                        .max_connections
+                        .is_some_and(|max| this.connected.len() >= max)
+                    {
+                        debug!(
+                            "[{}] reject {addr}, no free incoming connections",
+                            this.role
+                        );
+                        this.replies.push_back((
+                            Self::make_no_free_incoming_connections(this.config),
                             addr,
-                        )));
+                        ));
                         continue;
                     }
                     debug!("[{}] client {addr} connected with mtu {mtu}", this.role);
-                    this.connected.insert(addr, PeerContext { addr, mtu });
+                    if this.config.recently_connected_cooldown.is_some() {
+                        this.recent.put(addr.ip(), Instant::now());
+                    }
+                    this.connected.insert(
+                        addr,
+                        PeerContext {
+                            addr,
+                            mtu,
+                            guid: Some(client_guid),
+                        },
+                    );
                     unconnected::Packet::OpenConnectionReply2 {
                         magic: (),
-                        server_guid: this.config.sever_guid,
+                        server_guid: this.config.server_guid,
                         client_address: addr,
                         mtu,
-                        encryption_enabled: false, // must set to false
+                        encryption_enabled: this.config.encryption,
                     }
                 }
                 _ => {
@@ -262,9 +481,53 @@ where
                     continue;
                 }
             };
-            *this.state = OfflineState::SendingPrepare(Some((resp, addr)));
+            this.replies.push_back((resp, addr));
+        }
+    }
+}
+
+/// Return the remaining cooldown of the ip if it connected recently
+fn cooldown_remaining(
+    recent: &mut lru::LruCache<IpAddr, Instant>,
+    config: &Config,
+    ip: IpAddr,
+) -> Option<Duration> {
+    let cooldown = config.recently_connected_cooldown?;
+    let elapsed = recent.get(&ip)?.elapsed();
+    if elapsed >= cooldown {
+        recent.pop(&ip);
+        return None;
+    }
+    Some(cooldown - elapsed)
+}
+
+/// Send the queued replies, return ready when all of them are sent and flushed
+fn poll_send<F>(
+    mut frame: Pin<&mut F>,
+    replies: &mut VecDeque<(unconnected::Packet, SocketAddr)>,
+    flushing: &mut bool,
+    role: RoleContext,
+    cx: &mut Context<'_>,
+) -> Poll<()>
+where
+    F: Sink<(unconnected::Packet, SocketAddr), Error = CodecError>,
+{
+    while !replies.is_empty() {
+        let res = ready!(frame.as_mut().poll_ready(cx));
+        let reply = replies.pop_front().unwrap();
+        if let Err(err) = res.and_then(|()| frame.as_mut().start_send(reply)) {
+            error!("[{role}] send error: {err}");
+            continue;
         }
+        *flushing = true;
     }
+    if *flushing {
+        if let Err(err) = ready!(frame.as_mut().poll_flush(cx)) {
+            error!("[{role}] send error: {err}");
+        }
+        *flushing = false;
+    }
+    Poll::Ready(())
 }
 
 #[cfg(test)]
@@ -347,6 +610,7 @@ mod test {
                 },
                 unconnected::Packet::OpenConnectionRequest2 {
                     magic: (),
+                    cookie: None,
                     server_address: "0.0.0.0:1".parse().unwrap(),
                     mtu: 1000,
                     client_guid: 114514,
@@ -367,12 +631,19 @@ mod test {
         let handler = OfflineHandler::new(
             test_case,
             Config {
-                sever_guid: 1919810,
-                advertisement: Bytes::from_static(b"hello"),
+                server_guid: 1919810,
+                advertisement: Arc::new(ArcSwap::from_pointee(Bytes::from_static(b"hello"))),
                 min_mtu: 800,
                 max_mtu: 1400,
                 support_version: vec![8, 11, 12],
                 max_pending: 10,
+                max_connections: None,
+                recently_connected_cooldown: None,
+                reply_backlog: 1,
+                require_cookie: false,
+                encryption: false,
+                server_address: None,
+                reactor: Reactor::get(),
             },
         );
         tokio::pin!(handler);
@@ -395,7 +666,7 @@ mod test {
                 unconnected::Packet::OpenConnectionReply1 {
                     magic: (),
                     server_guid: 1919810,
-                    use_encryption: false,
+                    cookie: None,
                     mtu: 1000
                 },
                 unconnected::Packet::OpenConnectionReply2 {
@@ -409,6 +680,65 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn test_offline_ignore_looped_back() {
+        let test_case = TestCase {
+            addr: "0.0.0.3:1".parse().unwrap(),
+            source: vec![
+                unconnected::Packet::UnconnectedPing {
+                    send_timestamp: 0,
+                    magic: (),
+                    client_guid: 1919810,
+                },
+                unconnected::Packet::UnconnectedPong {
+                    send_timestamp: 0,
+                    server_guid: 1919810,
+                    magic: (),
+                    data: Bytes::new(),
+                },
+                unconnected::Packet::UnconnectedPing {
+                    send_timestamp: 1,
+                    magic: (),
+                    client_guid: 114514,
+                },
+            ]
+            .into_iter()
+            .map(Packet::Unconnected)
+            .collect(),
+            dst: vec![],
+        };
+
+        let handler = OfflineHandler::new(
+            test_case,
+            Config {
+                server_guid: 1919810,
+                advertisement: Arc::new(ArcSwap::from_pointee(Bytes::new())),
+                min_mtu: 800,
+                max_mtu: 1400,
+                support_version: vec![11],
+                max_pending: 10,
+                max_connections: None,
+                recently_connected_cooldown: None,
+                reply_backlog: 1,
+                require_cookie: false,
+                encryption: false,
+                server_address: None,
+                reactor: Reactor::get(),
+            },
+        );
+        tokio::pin!(handler);
+        assert!(handler.next().await.is_none());
+        assert_eq!(
+            handler.project().frame.dst,
+            vec![unconnected::Packet::UnconnectedPong {
+                send_timestamp: 1,
+                server_guid: 1919810,
+                magic: (),
+                data: Bytes::new(),
+            }]
+        );
+    }
+
     #[tokio::test]
     async fn test_offline_reject_unconnected_packet() {
         let _guard = test_trace_log_setup();
@@ -426,12 +756,19 @@ mod test {
         let handler = OfflineHandler::new(
             test_case,
             Config {
-                sever_guid: 1919810,
-                advertisement: Bytes::from_static(b"hello"),
+                server_guid: 1919810,
+                advertisement: Arc::new(ArcSwap::from_pointee(Bytes::from_static(b"hello"))),
                 min_mtu: 800,
                 max_mtu: 1400,
                 support_version: vec![8, 11, 12],
                 max_pending: 10,
+                max_connections: None,
+                recently_connected_cooldown: None,
+                reply_backlog: 1,
+                require_cookie: false,
+                encryption: false,
+                server_address: None,
+                reactor: Reactor::get(),
             },
         );
         tokio::pin!(handler);
@@ -457,6 +794,7 @@ mod test {
                     source: vec![
                         unconnected::Packet::OpenConnectionRequest2 {
                             magic: (),
+                            cookie: None,
                             server_address: "0.0.0.0:1".parse().unwrap(),
                             mtu: 1000,
                             client_guid: 114514,
@@ -481,7 +819,7 @@ mod test {
                     unconnected::Packet::OpenConnectionReply1 {
                         magic: (),
                         server_guid: 1919810,
-                        use_encryption: false,
+                        cookie: None,
                         mtu: 1000,
                     },
                 ],
@@ -518,6 +856,7 @@ mod test {
                         },
                         unconnected::Packet::OpenConnectionRequest2 {
                             magic: (),
+                            cookie: None,
                             server_address: "0.0.0.0:1".parse().unwrap(),
                             mtu: 1000,
                             client_guid: 114514,
@@ -529,6 +868,7 @@ mod test {
                         },
                         unconnected::Packet::OpenConnectionRequest2 {
                             magic: (),
+                            cookie: None,
                             server_address: "0.0.0.0:1".parse().unwrap(),
                             mtu: 1000,
                             client_guid: 114514,
@@ -543,7 +883,7 @@ mod test {
                     unconnected::Packet::OpenConnectionReply1 {
                         magic: (),
                         server_guid: 1919810,
-                        use_encryption: false,
+                        cookie: None,
                         mtu: 1000,
                     },
                     unconnected::Packet::OpenConnectionReply2 {
@@ -556,7 +896,7 @@ mod test {
                     unconnected::Packet::OpenConnectionReply1 {
                         magic: (),
                         server_guid: 1919810,
-                        use_encryption: false,
+                        cookie: None,
                         mtu: 1000,
                     },
                     unconnected::Packet::AlreadyConnected {
@@ -577,6 +917,7 @@ mod test {
                         },
                         unconnected::Packet::OpenConnectionRequest2 {
                             magic: (),
+                            cookie: None,
                             server_address: "0.0.0.0:1".parse().unwrap(),
                             mtu: 10,
                             client_guid: 114514,
@@ -591,7 +932,7 @@ mod test {
                     unconnected::Packet::OpenConnectionReply1 {
                         magic: (),
                         server_guid: 1919810,
-                        use_encryption: false,
+                        cookie: None,
                         mtu: 800,
                     },
                     unconnected::Packet::AlreadyConnected {
@@ -606,12 +947,19 @@ mod test {
             let handler = OfflineHandler::new(
                 case,
                 Config {
-                    sever_guid: 1919810,
-                    advertisement: Bytes::from_static(b"hello"),
+                    server_guid: 1919810,
+                    advertisement: Arc::new(ArcSwap::from_pointee(Bytes::from_static(b"hello"))),
                     min_mtu: 800,
                     max_mtu: 1400,
                     support_version: vec![8, 11, 12],
                     max_pending: 10,
+                    max_connections: None,
+                    recently_connected_cooldown: None,
+                    reply_backlog: 1,
+                    require_cookie: false,
+                    encryption: false,
+                    server_address: None,
+                    reactor: Reactor::get(),
                 },
             );
             tokio::pin!(handler);
@@ -619,4 +967,479 @@ mod test {
             assert_eq!(handler.project().frame.dst, expect);
         }
     }
+
+    /// The sink is blocked until it is ready
+    struct SlowCase {
+        source: VecDeque<(Packet<FramesMut>, SocketAddr)>,
+        ready: bool,
+        dst: Vec<(unconnected::Packet, SocketAddr)>,
+    }
+
+    impl Stream for SlowCase {
+        type Item = (Packet<FramesMut>, SocketAddr);
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.source.pop_front())
+        }
+    }
+
+    impl Sink<(unconnected::Packet, SocketAddr)> for SlowCase {
+        type Error = CodecError;
+
+        fn poll_ready(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            if self.ready {
+                return Poll::Ready(Ok(()));
+            }
+            Poll::Pending
+        }
+
+        fn start_send(
+            mut self: Pin<&mut Self>,
+            item: (unconnected::Packet, SocketAddr),
+        ) -> Result<(), Self::Error> {
+            self.dst.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_offline_reply_backlog() {
+        let clients: Vec<SocketAddr> = (1..=3)
+            .map(|i| format!("0.0.0.{i}:1").parse().unwrap())
+            .collect();
+        // the count of the handshake packets read while the sink is blocked
+        for (backlog, read) in [(1, 1), (2, 2), (4, 3)] {
+            let source = clients
+                .iter()
+                .map(|addr| {
+                    let req = unconnected::Packet::OpenConnectionRequest1 {
+                        magic: (),
+                        protocol_version: 11,
+                        mtu: 1000,
+                    };
+                    (Packet::Unconnected(req), *addr)
+                })
+                .collect();
+            let mut handler = OfflineHandler::new(
+                SlowCase {
+                    source,
+                    ready: false,
+                    dst: vec![],
+                },
+                Config {
+                    server_guid: 1919810,
+                    advertisement: Arc::new(ArcSwap::from_pointee(Bytes::new())),
+                    min_mtu: 800,
+                    max_mtu: 1400,
+                    support_version: vec![11],
+                    max_pending: 10,
+                    max_connections: None,
+                    recently_connected_cooldown: None,
+                    reply_backlog: backlog,
+                    require_cookie: false,
+                    encryption: false,
+                    server_address: None,
+                    reactor: Reactor::get(),
+                },
+            );
+            let mut handler = Pin::new(&mut handler);
+            let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+            assert!(handler.as_mut().poll_next(&mut cx).is_pending());
+            assert_eq!(handler.frame.source.len(), clients.len() - read);
+            assert_eq!(handler.replies.len(), read);
+
+            // all replies are sent once the sink is ready
+            handler.as_mut().project().frame.get_mut().ready = true;
+            assert!(matches!(
+                handler.as_mut().poll_next(&mut cx),
+                Poll::Ready(None)
+            ));
+            let replied: Vec<_> = handler.frame.dst.iter().map(|(_, addr)| *addr).collect();
+            assert_eq!(replied, clients);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offline_cancel_pending() {
+        let client_addr = "0.0.0.5:1".parse().unwrap();
+        let test_case = TestCase {
+            addr: client_addr,
+            source: [unconnected::Packet::OpenConnectionRequest1 {
+                magic: (),
+                protocol_version: 11,
+                mtu: 1000,
+            }]
+            .into_iter()
+            .map(Packet::Unconnected)
+            .collect(),
+            dst: vec![],
+        };
+        let handler = OfflineHandler::new(
+            test_case,
+            Config {
+                server_guid: 1919810,
+                advertisement: Arc::new(ArcSwap::from_pointee(Bytes::new())),
+                min_mtu: 800,
+                max_mtu: 1400,
+                support_version: vec![11],
+                max_pending: 10,
+                max_connections: None,
+                recently_connected_cooldown: None,
+                reply_backlog: 1,
+                require_cookie: false,
+                encryption: false,
+                server_address: None,
+                reactor: Reactor::get(),
+            },
+        );
+        tokio::pin!(handler);
+        assert!(handler.next().await.is_none());
+        assert_eq!(handler.pending_addrs(), vec![client_addr]);
+
+        assert!(!handler.as_mut().cancel_pending(&"0.0.0.6:1".parse().unwrap()));
+        assert!(handler.as_mut().cancel_pending(&client_addr));
+        assert!(handler.pending_addrs().is_empty());
+        assert!(!handler.as_mut().cancel_pending(&client_addr));
+
+        // the reply is sent on the next poll
+        assert!(handler.next().await.is_none());
+        assert_eq!(
+            handler.project().frame.dst,
+            vec![
+                unconnected::Packet::OpenConnectionReply1 {
+                    magic: (),
+                    server_guid: 1919810,
+                    cookie: None,
+                    mtu: 1000
+                },
+                unconnected::Packet::ConnectionRequestFailed {
+                    magic: (),
+                    server_guid: 1919810,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_require_cookie() {
+        let client_addr: SocketAddr = "0.0.0.7:1".parse().unwrap();
+        let request2 = |cookie| {
+            Packet::Unconnected(unconnected::Packet::OpenConnectionRequest2 {
+                magic: (),
+                cookie,
+                server_address: "0.0.0.0:1".parse().unwrap(),
+                mtu: 1000,
+                client_guid: 114514,
+            })
+        };
+        let test_case = TestCase {
+            addr: client_addr,
+            source: vec![Packet::Unconnected(
+                unconnected::Packet::OpenConnectionRequest1 {
+                    magic: (),
+                    protocol_version: 11,
+                    mtu: 1000,
+                },
+            )]
+            .into_iter()
+            .collect(),
+            dst: vec![],
+        };
+        let handler = OfflineHandler::new(
+            test_case,
+            Config {
+                server_guid: 1919810,
+                advertisement: Arc::new(ArcSwap::from_pointee(Bytes::new())),
+                min_mtu: 800,
+                max_mtu: 1400,
+                support_version: vec![11],
+                max_pending: 10,
+                max_connections: None,
+                recently_connected_cooldown: None,
+                reply_backlog: 1,
+                require_cookie: true,
+                encryption: false,
+                server_address: None,
+                reactor: Reactor::get(),
+            },
+        );
+        tokio::pin!(handler);
+        let cookie = handler.cookies.as_ref().unwrap().issue(client_addr);
+
+        // replied with the cookie, nothing is kept
+        assert!(handler.next().await.is_none());
+        assert!(handler.pending_addrs().is_empty());
+
+        // the requests without the cookie or with a wrong one are dropped silently
+        let source = &mut handler.as_mut().project().frame.source;
+        source.push_back(request2(None));
+        source.push_back(request2(Some(cookie.wrapping_add(1))));
+        assert!(handler.next().await.is_none());
+        assert!(handler.connected.is_empty());
+
+        // the echoed cookie completes the handshake
+        let source = &mut handler.as_mut().project().frame.source;
+        source.push_back(request2(Some(cookie)));
+        source.push_back(Packet::Connected(connected::Packet::FrameSet(FrameSet {
+            seq_num: 0.into(),
+            set: Frames::new(),
+        })));
+        assert!(handler.next().await.is_some());
+        assert_eq!(
+            handler.project().frame.dst,
+            vec![
+                unconnected::Packet::OpenConnectionReply1 {
+                    magic: (),
+                    server_guid: 1919810,
+                    cookie: Some(cookie),
+                    mtu: 1000
+                },
+                unconnected::Packet::OpenConnectionReply2 {
+                    magic: (),
+                    server_guid: 1919810,
+                    client_address: client_addr,
+                    mtu: 1000,
+                    encryption_enabled: false
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_strict_server_address() {
+        let client_addr: SocketAddr = "0.0.0.8:1".parse().unwrap();
+        let handshake = |server_address: &str| {
+            [
+                unconnected::Packet::OpenConnectionRequest1 {
+                    magic: (),
+                    protocol_version: 11,
+                    mtu: 1000,
+                },
+                unconnected::Packet::OpenConnectionRequest2 {
+                    magic: (),
+                    cookie: None,
+                    server_address: server_address.parse().unwrap(),
+                    mtu: 1000,
+                    client_guid: 114514,
+                },
+            ]
+            .map(Packet::Unconnected)
+        };
+        let test_case = TestCase {
+            addr: client_addr,
+            // targeting another address of the multi-homed host, then the right one
+            source: handshake("10.0.0.2:19132")
+                .into_iter()
+                .chain(handshake("10.0.0.1:19132"))
+                .collect(),
+            dst: vec![],
+        };
+        let handler = OfflineHandler::new(
+            test_case,
+            Config {
+                server_guid: 1919810,
+                advertisement: Arc::new(ArcSwap::from_pointee(Bytes::new())),
+                min_mtu: 800,
+                max_mtu: 1400,
+                support_version: vec![11],
+                max_pending: 10,
+                max_connections: None,
+                recently_connected_cooldown: None,
+                reply_backlog: 1,
+                require_cookie: false,
+                encryption: false,
+                server_address: Some("10.0.0.1:19132".parse().unwrap()),
+                reactor: Reactor::get(),
+            },
+        );
+        tokio::pin!(handler);
+        assert!(handler.next().await.is_none());
+        let reply1 = unconnected::Packet::OpenConnectionReply1 {
+            magic: (),
+            server_guid: 1919810,
+            cookie: None,
+            mtu: 1000,
+        };
+        assert_eq!(
+            handler.project().frame.dst,
+            vec![
+                reply1.clone(),
+                unconnected::Packet::IncompatibleProtocol {
+                    server_protocol: 11,
+                    magic: (),
+                    server_guid: 1919810,
+                },
+                reply1,
+                unconnected::Packet::OpenConnectionReply2 {
+                    magic: (),
+                    server_guid: 1919810,
+                    client_address: client_addr,
+                    mtu: 1000,
+                    encryption_enabled: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_targets_server_address() {
+        let addr = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+        assert!(targets(addr("10.0.0.1:19132"), addr("10.0.0.1:19132")));
+        assert!(targets(
+            addr("10.0.0.1:19132"),
+            addr("[::ffff:10.0.0.1]:19132")
+        ));
+        assert!(!targets(addr("10.0.0.1:19132"), addr("10.0.0.1:19133")));
+        // only the port is validated for the unspecified address
+        assert!(targets(addr("0.0.0.0:19132"), addr("10.0.0.2:19132")));
+        assert!(!targets(addr("[::]:19132"), addr("10.0.0.2:19133")));
+    }
+
+    #[tokio::test]
+    async fn test_offline_ban_ip() {
+        let request1 = || {
+            Packet::Unconnected(unconnected::Packet::OpenConnectionRequest1 {
+                magic: (),
+                protocol_version: 11,
+                mtu: 1000,
+            })
+        };
+        let test_case = TestCase {
+            addr: "0.0.0.8:1".parse().unwrap(),
+            source: vec![request1()].into_iter().collect(),
+            dst: vec![],
+        };
+        let handler = OfflineHandler::new(
+            test_case,
+            Config {
+                server_guid: 1919810,
+                advertisement: Arc::new(ArcSwap::from_pointee(Bytes::new())),
+                min_mtu: 800,
+                max_mtu: 1400,
+                support_version: vec![11],
+                max_pending: 10,
+                max_connections: None,
+                recently_connected_cooldown: None,
+                reply_backlog: 1,
+                require_cookie: false,
+                encryption: false,
+                server_address: None,
+                reactor: Reactor::get(),
+            },
+        );
+        tokio::pin!(handler);
+        handler.as_mut().ban("0.0.0.8".parse().unwrap());
+        assert!(handler.next().await.is_none());
+        assert!(handler.pending_addrs().is_empty());
+
+        // banned on any port
+        let frame = handler.as_mut().project().frame;
+        frame.addr = "0.0.0.8:2".parse().unwrap();
+        frame.source.push_back(request1());
+        assert!(handler.next().await.is_none());
+        assert!(handler.pending_addrs().is_empty());
+
+        assert!(handler.as_mut().unban("0.0.0.8".parse().unwrap()));
+        assert!(!handler.as_mut().unban("0.0.0.8".parse().unwrap()));
+        let frame = handler.as_mut().project().frame;
+        frame.source.push_back(request1());
+        assert!(handler.next().await.is_none());
+        assert_eq!(handler.pending_addrs().len(), 1);
+
+        let banned = unconnected::Packet::ConnectionBanned {
+            magic: (),
+            server_guid: 1919810,
+        };
+        assert_eq!(
+            handler.project().frame.dst,
+            vec![
+                banned.clone(),
+                banned,
+                unconnected::Packet::OpenConnectionReply1 {
+                    magic: (),
+                    server_guid: 1919810,
+                    cookie: None,
+                    mtu: 1000
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_update_advertisement() {
+        let ping = |send_timestamp| {
+            Packet::Unconnected(unconnected::Packet::UnconnectedPing {
+                send_timestamp,
+                magic: (),
+                client_guid: 114514,
+            })
+        };
+        let test_case = TestCase {
+            addr: "0.0.0.9:1".parse().unwrap(),
+            source: vec![ping(0)].into_iter().collect(),
+            dst: vec![],
+        };
+        let handler = OfflineHandler::new(
+            test_case,
+            Config {
+                server_guid: 1919810,
+                advertisement: Arc::new(ArcSwap::from_pointee(Bytes::from_static(b"hello"))),
+                min_mtu: 800,
+                max_mtu: 1400,
+                support_version: vec![11],
+                max_pending: 10,
+                max_connections: None,
+                recently_connected_cooldown: None,
+                reply_backlog: 1,
+                require_cookie: false,
+                encryption: false,
+                server_address: None,
+                reactor: Reactor::get(),
+            },
+        );
+        tokio::pin!(handler);
+        assert!(handler.next().await.is_none());
+
+        // the next pong carries the updated advertisement
+        handler
+            .advertisement()
+            .store(Arc::new(Bytes::from_static(b"world")));
+        handler.as_mut().project().frame.source.push_back(ping(1));
+        assert!(handler.next().await.is_none());
+        assert_eq!(
+            handler.project().frame.dst,
+            vec![
+                unconnected::Packet::UnconnectedPong {
+                    send_timestamp: 0,
+                    server_guid: 1919810,
+                    magic: (),
+                    data: Bytes::from_static(b"hello"),
+                },
+                unconnected::Packet::UnconnectedPong {
+                    send_timestamp: 1,
+                    server_guid: 1919810,
+                    magic: (),
+                    data: Bytes::from_static(b"world"),
+                },
+            ]
+        );
+    }
 }
@@ -1,24 +1,33 @@
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 use futures::Stream;
 use log::debug;
+use minitrace::collector::TraceId;
 use pin_project_lite::pin_project;
 
-use crate::link::SharedLink;
+use crate::errors::{Error, HandshakePhase};
+use crate::io::TraceInfo;
+use crate::link::{Anomaly, SharedLink};
 use crate::packet::connected::FrameBody;
-use crate::packet::unconnected;
+use crate::packet::MIN_MTU;
 use crate::utils::timestamp;
 use crate::RoleContext;
 
+/// Invoked once when the online handshake is finished
+pub(crate) type OnConnected = Box<dyn FnOnce() + Send + Sync>;
+
 pub(crate) trait HandleOnline: Sized {
     fn handle_online(
         self,
         role: RoleContext,
         client_addr: SocketAddr,
+        timeout: Option<Duration>,
         link: SharedLink,
+        on_connected: Option<OnConnected>,
     ) -> OnlineHandler<Self>;
 }
 
@@ -30,14 +39,18 @@ where
         self,
         role: RoleContext,
         client_addr: SocketAddr,
+        timeout: Option<Duration>,
         link: SharedLink,
+        on_connected: Option<OnConnected>,
     ) -> OnlineHandler<Self> {
         OnlineHandler {
             frame: self,
             role,
             client_addr,
             state: HandshakeState::WaitConnRequest,
+            deadline: timeout.map(|dur| Instant::now() + dur),
             link,
+            on_connected,
         }
     }
 }
@@ -49,7 +62,9 @@ pin_project! {
         role: RoleContext,
         client_addr: SocketAddr,
         state: HandshakeState,
+        deadline: Option<Instant>,
         link: SharedLink,
+        on_connected: Option<OnConnected>,
     }
 }
 
@@ -57,6 +72,39 @@ enum HandshakeState {
     WaitConnRequest,
     WaitNewIncomingConn,
     Connected,
+    Failed,
+}
+
+/// Poll the next frame body during the handshake, fail the connection if the deadline is reached
+fn poll_handshake<F>(
+    frame: Pin<&mut F>,
+    deadline: Option<Instant>,
+    role: RoleContext,
+    link: &SharedLink,
+    cx: &mut Context<'_>,
+) -> Poll<Option<FrameBody>>
+where
+    F: Stream<Item = FrameBody>,
+{
+    if let Poll::Ready(body) = frame.poll_next(cx) {
+        return Poll::Ready(body);
+    }
+    let Some(deadline) = deadline else {
+        return Poll::Pending;
+    };
+    ready!(role.reactor().poll_deadline(role.guid(), deadline, cx));
+    link.fail(Error::HandshakeTimeout {
+        phase: HandshakePhase::Online,
+    });
+    Poll::Ready(None)
+}
+
+impl<F> TraceInfo for OnlineHandler<F> {
+    fn last_trace_id(&self) -> Option<TraceId> {
+        self.link
+            .received_trace_id()
+            .map(|trace_id| TraceId(u128::from(trace_id)))
+    }
 }
 
 impl<F> Stream for OnlineHandler<F>
@@ -70,22 +118,37 @@ where
         loop {
             match this.state {
                 HandshakeState::WaitConnRequest => {
-                    let Some(body) = ready!(this.frame.as_mut().poll_next(cx)) else {
+                    let Some(body) = ready!(poll_handshake(
+                        this.frame.as_mut(),
+                        *this.deadline,
+                        *this.role,
+                        this.link,
+                        cx
+                    )) else {
+                        *this.state = HandshakeState::Failed;
                         return Poll::Ready(None);
                     };
                     if let FrameBody::ConnectionRequest {
                         request_timestamp,
                         use_encryption,
+                        capabilities,
+                        key,
                         ..
                     } = body
                     {
-                        if use_encryption {
-                            this.link.send_unconnected(
-                                unconnected::Packet::ConnectionRequestFailed {
-                                    magic: (),
-                                    server_guid: this.role.guid(),
-                                },
+                        // both sides must require the encryption
+                        let local_key = this.link.start_key_exchange();
+                        if use_encryption != this.link.encryption_enabled()
+                            || !this.link.establish_session(&key)
+                        {
+                            debug!(
+                                "[{}] reject the connection request of {addr}, the encryption \
+                                 is not agreed",
+                                this.role,
+                                addr = this.client_addr
                             );
+                            this.link
+                                .send_frame_body(FrameBody::ConnectionRequestFailed);
                             continue;
                         }
                         let system_addr = if this.client_addr.is_ipv6() {
@@ -103,18 +166,43 @@ where
                                 system_addresses: [system_addr; 20],
                                 request_timestamp,
                                 accepted_timestamp: timestamp(),
+                                capabilities: this.link.negotiate(capabilities),
+                                key: local_key,
                             });
                         *this.state = HandshakeState::WaitNewIncomingConn;
                         continue;
                     }
+                    this.link.record_anomaly(Anomaly::UnexpectedPacket);
                     debug!("[{}] ignore packet {body:?} on WaitConnRequest", this.role);
                 }
                 HandshakeState::WaitNewIncomingConn => {
-                    let Some(body) = ready!(this.frame.as_mut().poll_next(cx)) else {
+                    let Some(body) = ready!(poll_handshake(
+                        this.frame.as_mut(),
+                        *this.deadline,
+                        *this.role,
+                        this.link,
+                        cx
+                    )) else {
+                        *this.state = HandshakeState::Failed;
                         return Poll::Ready(None);
                     };
-                    if let FrameBody::NewIncomingConnection { .. } = body {
-                        debug!("[{}] accept new incoming connection", this.role);
+                    if let FrameBody::NewIncomingConnection {
+                        system_addresses, ..
+                    } = body
+                    {
+                        // the internal addresses of client are informational only
+                        let internal = system_addresses
+                            .iter()
+                            .filter(|addr| !addr.ip().is_unspecified())
+                            .collect::<Vec<_>>();
+                        debug!(
+                            "[{}] accept new incoming connection, client internal addresses: {internal:?}",
+                            this.role
+                        );
+                        this.link.mark_connected();
+                        if let Some(on_connected) = this.on_connected.take() {
+                            on_connected();
+                        }
                         *this.state = HandshakeState::Connected;
                         continue;
                     }
@@ -123,6 +211,7 @@ where
                     match body {
                         FrameBody::User(data) => return Poll::Ready(Some(data)),
                         _ => {
+                            this.link.record_anomaly(Anomaly::UnexpectedPacket);
                             debug!(
                                 "[{}] ignore packet {body:?} on WaitNewIncomingConn",
                                 this.role
@@ -131,8 +220,14 @@ where
                     }
                 }
                 HandshakeState::Connected => {
-                    let Some(body) = ready!(this.frame.as_mut().poll_next(cx)) else {
-                        return Poll::Ready(None);
+                    let body = match this.frame.as_mut().poll_next(cx) {
+                        Poll::Ready(Some(body)) => body,
+                        Poll::Ready(None) => return Poll::Ready(None),
+                        Poll::Pending => {
+                            ready!(this.link.poll_keepalive(cx));
+                            *this.state = HandshakeState::Failed;
+                            continue;
+                        }
                     };
                     match body {
                         FrameBody::ConnectedPing { client_timestamp } => {
@@ -141,12 +236,23 @@ where
                                 server_timestamp: timestamp(),
                             });
                         }
+                        FrameBody::ConnectedPong {
+                            client_timestamp, ..
+                        } => {
+                            // the response of the ping
+                            this.link.receive_pong(client_timestamp);
+                        }
+                        FrameBody::MtuUpdate { mtu } if mtu >= MIN_MTU => {
+                            this.link.update_mtu(mtu);
+                        }
                         FrameBody::User(data) => return Poll::Ready(Some(data)),
                         _ => {
+                            this.link.record_anomaly(Anomaly::UnexpectedPacket);
                             debug!("[{}] ignore packet {body:?} on Connected", this.role);
                         }
                     }
                 }
+                HandshakeState::Failed => return Poll::Ready(None),
             }
         }
     }
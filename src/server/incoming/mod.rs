@@ -1,13 +1,79 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
 use bytes::{Buf, Bytes};
 use futures::Stream;
 
 use super::handler::offline;
-use crate::io::IO;
-use crate::{codec, RoleContext};
+use crate::errors::{CodecError, Error};
+use crate::io::{AnomalyInfo, CloseReason, OrderGapPolicy, Ping, RawFrameSet, IO};
+use crate::packet::{MAX_ORDER_CHANNELS, MIN_MTU};
+use crate::resend_map::{MAX_RTO, MIN_RTO};
+use crate::{codec, Reactor, Reliability, RoleContext};
+
+/// Incoming implementation over the UDP socket of any async runtime
+mod udp;
+
+/// Replay the captured datagrams for regression tests
+#[cfg(feature = "replay")]
+mod replay;
+
+#[cfg(feature = "replay")]
+pub use self::replay::*;
+
+/// The peer finished the online handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    /// The address of the peer
+    pub addr: SocketAddr,
+    /// The mtu negotiated in the offline handshake
+    pub mtu: u16,
+    /// The guid announced by the peer in the offline handshake
+    pub guid: Option<u64>,
+}
 
-/// Incoming implementation by using tokio's UDP framework
-#[cfg(feature = "tokio-udp")]
-mod tokio;
+/// The event of the connections of the incoming
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnEvent {
+    /// The peer finished the online handshake
+    Connected {
+        /// The address of the peer
+        addr: SocketAddr,
+        /// The guid announced by the peer in the offline handshake
+        guid: Option<u64>,
+        /// The mtu negotiated in the offline handshake
+        mtu: u16,
+    },
+    /// The connection is closed and reaped by the incoming
+    Disconnected {
+        /// The address of the peer
+        addr: SocketAddr,
+        /// The reason of the close
+        reason: CloseReason,
+    },
+}
+
+/// The callback shared by all connections
+#[derive(Clone)]
+pub(crate) struct Callback<T, R = ()>(Arc<dyn Fn(T) -> R + Send + Sync>);
+
+impl<T, R> Callback<T, R> {
+    pub(crate) fn call(&self, arg: T) -> R {
+        (self.0)(arg)
+    }
+}
+
+impl<T, R> fmt::Debug for Callback<T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Callback")
+    }
+}
 
 /// Incoming config
 #[derive(Debug, Clone)]
@@ -15,7 +81,7 @@ pub struct Config {
     /// The send buffer of each IO polled by the incoming
     send_buf_cap: usize,
     /// The server guid, used to identify the server, initialized by random
-    sever_guid: u64,
+    server_guid: u64,
     /// The advertisement, sent to the client when the client pings the server
     advertisement: Bytes,
     /// The minimum mtu, the default value is 510
@@ -26,6 +92,8 @@ pub struct Config {
     support_version: Vec<u8>,
     /// The maximum pending(aka. half-opened connections)
     max_pending: usize,
+    /// The maximum connections, `None` means no limit
+    max_connections: Option<usize>,
     /// Limit the max size of a parted frames set, 0 means no limit
     /// It will abort the split frame if the `parted_size` reaches limit.
     /// Enable it to avoid `DoS` attack.
@@ -38,6 +106,115 @@ pub struct Config {
     max_parted_count: usize,
     /// Maximum ordered channel, the value should be less than 256
     max_channels: usize,
+    /// The default reliabilities of the messages sent on each order channel
+    channel_reliabilities: HashMap<u8, Reliability>,
+    /// Limit the max count of parted frames sets being reassembled across **all** connections, 0
+    /// means no limit.
+    /// New parted frames sets will be rejected if the limit is reached.
+    /// Enable it to avoid `DoS` attack.
+    max_parted_count_total: usize,
+    /// The timeout of the online handshake, `None` means no timeout
+    online_handshake_timeout: Option<Duration>,
+    /// The timeout of receiving nothing while awaiting acknowledgement, `None` means the
+    /// watchdog is disabled
+    stall_timeout: Option<Duration>,
+    /// The grace period of closing the stalled connection, `None` means never close it
+    stall_close_grace: Option<Duration>,
+    /// The timeout of receiving nothing from the peer, `None` means the keepalive is disabled
+    idle_timeout: Option<Duration>,
+    /// The maximum count of reliable messages awaiting acknowledgement, `None` means no limit
+    max_inflight_messages: Option<usize>,
+    /// The lower bound of the RTO computed from the RTT estimation
+    min_rto: Duration,
+    /// The upper bound of the RTO computed from the RTT estimation
+    max_rto: Duration,
+    /// The scaling of the base RTO under the connection-wide loss, 0 means disabled
+    rto_loss_scale: f64,
+    /// The maximum times of resending the stale frames, `None` means no limit
+    max_retries: Option<u32>,
+    /// Resend the frame set once a frame set sent this count later is acknowledged, 0 means
+    /// disabled
+    fast_retransmit_threshold: u32,
+    /// Limit the frame sets awaiting acknowledgement by the NewReno congestion window
+    congestion_control: bool,
+    /// The DSCP marked on the outgoing datagrams, `None` means the socket default
+    dscp: Option<u8>,
+    /// The allowed game packet ids sorted, `None` means all are allowed
+    game_packet_ids: Option<Vec<u8>>,
+    /// The cooldown of connecting again from the same ip, `None` means disabled
+    recently_connected_cooldown: Option<Duration>,
+    /// The maximum offline handshake replies waiting to be sent
+    reply_backlog: usize,
+    /// Require the client to echo the cookie of the open connection reply 1
+    require_cookie: bool,
+    /// Reject the open connection request 2 not targeting the address the socket is bound to
+    strict_server_address: bool,
+    /// The callback invoked when a peer finished the online handshake
+    on_connect: Option<Callback<Peer>>,
+    /// The callback invoked when a connection is closed
+    on_disconnect: Option<Callback<(SocketAddr, CloseReason)>>,
+    /// The handling of the ordered frames stuck behind a missing index when the connection is
+    /// closing
+    order_gap_policy: OrderGapPolicy,
+    /// The count of the reliable frame indices tracked by the deduplication past the first
+    /// missing one
+    dedup_window: usize,
+    /// The callback observing the errors of decoding the frames, `None` means they are only
+    /// logged
+    on_codec_error: Option<codec::OnCodecError>,
+    /// The cap of the outgoing datagram size below the mtu, `None` means no cap
+    max_datagram_size: Option<u16>,
+    /// The delay of holding the outgoing acknowledgements, `None` means they are sent on each
+    /// flush
+    ack_delay: Option<Duration>,
+    /// The count of the received frame sets sending the held acknowledgements before the delay
+    ack_threshold: usize,
+    /// The reorder window of the frame sets and the delay before nacking the missing ones,
+    /// `None` means they are nacked at once
+    nack_reorder_window: Option<(u32, Duration)>,
+    /// Coalesce the messages buffered between the flushes into the frame sets filled up to the
+    /// mtu
+    coalesce: bool,
+    /// The interval of flushing automatically while the IO is polled, `None` means the caller
+    /// flushes manually
+    auto_flush_interval: Option<Duration>,
+    /// The timeout of closing the connection, `None` means waiting until the disconnect
+    /// notification is acknowledged
+    close_timeout: Option<Duration>,
+    /// The reactor driving the timers, `None` means the global one
+    reactor: Option<&'static Reactor>,
+    /// Propagate the trace ids of the messages to the peer
+    propagate_trace_id: bool,
+    /// The threshold of compressing frame bodies, `None` means the compression is disabled
+    #[cfg(feature = "compression")]
+    compression_threshold: Option<usize>,
+    /// The cipher encrypting the user data, `None` means the encryption is disabled
+    #[cfg(feature = "encryption")]
+    encryption: Option<crate::cipher::Encryption>,
+    /// The chaos injected into the outgoing datagrams, `None` means reading it from the
+    /// environment
+    #[cfg(feature = "chaos")]
+    chaos: Option<crate::Chaos>,
+    /// The id labeling the counters of each connection by its address, `None` means the peer
+    /// address
+    #[cfg(feature = "metrics")]
+    metrics_id: Option<Callback<SocketAddr, String>>,
+}
+
+/// Derive the guid from the address by the FNV-1a hash, it does not depend on the version of the
+/// std like the `DefaultHasher`
+fn guid_from_addr(addr: SocketAddr) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    };
+    ip.iter()
+        .chain(&addr.port().to_be_bytes())
+        .fold(OFFSET, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+        })
 }
 
 impl Default for Config {
@@ -50,33 +227,94 @@ impl Config {
     pub fn new() -> Self {
         Self {
             send_buf_cap: 1024,
-            sever_guid: rand::random(),
+            server_guid: rand::random(),
             advertisement: Bytes::new(),
             min_mtu: 510,
             max_mtu: 1500,
             support_version: vec![9, 11, 13],
             max_pending: 1024,
+            max_connections: None,
             max_parted_size: 256,
             max_parted_count: 256,
             max_channels: 1,
+            channel_reliabilities: HashMap::new(),
+            max_parted_count_total: 0,
+            online_handshake_timeout: None,
+            stall_timeout: None,
+            stall_close_grace: None,
+            idle_timeout: None,
+            max_inflight_messages: None,
+            min_rto: MIN_RTO,
+            max_rto: MAX_RTO,
+            rto_loss_scale: 0.0,
+            max_retries: None,
+            fast_retransmit_threshold: 0,
+            congestion_control: false,
+            dscp: None,
+            game_packet_ids: None,
+            recently_connected_cooldown: None,
+            reply_backlog: 16,
+            require_cookie: false,
+            strict_server_address: false,
+            on_connect: None,
+            on_disconnect: None,
+            order_gap_policy: OrderGapPolicy::Discard,
+            dedup_window: 65536,
+            on_codec_error: None,
+            max_datagram_size: None,
+            ack_delay: None,
+            ack_threshold: 32,
+            nack_reorder_window: None,
+            coalesce: false,
+            auto_flush_interval: None,
+            close_timeout: None,
+            reactor: None,
+            propagate_trace_id: false,
+            #[cfg(feature = "compression")]
+            compression_threshold: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            #[cfg(feature = "metrics")]
+            metrics_id: None,
         }
     }
 
     /// Set the send buffer of each IO polled by the incoming
     /// The default value is 1024
+    ///
+    /// It bounds the buffered frames along with the frames awaiting acknowledgement, the IO stops
+    /// accepting messages until the peer acknowledges some of them. A message split into more
+    /// frames than the cap is still accepted once the previous ones are all acknowledged.
     pub fn send_buf_cap(mut self, cap: usize) -> Self {
         self.send_buf_cap = cap;
         self
     }
 
-    /// Set the server guid
+    /// Set the server guid, the clients tell the servers apart by it, e.g. deduplicating the
+    /// pongs in the LAN discovery
     /// The default value is random
-    pub fn sever_guid(mut self, guid: u64) -> Self {
-        self.sever_guid = guid;
+    pub fn server_guid(mut self, guid: u64) -> Self {
+        self.server_guid = guid;
         self
     }
 
-    /// Set the advertisement
+    /// Set the server guid
+    #[deprecated(note = "use `server_guid` instead")]
+    pub fn sever_guid(self, guid: u64) -> Self {
+        self.server_guid(guid)
+    }
+
+    /// Set the server guid derived from the address the server binds to, it is stable across the
+    /// restarts and distinct among the servers bound to the distinct addresses, e.g. the
+    /// replicas of a cluster. The servers bound to the unspecified address on the same port get
+    /// the same guid, pass the address they are reached by instead.
+    pub fn server_guid_from_addr(self, addr: SocketAddr) -> Self {
+        self.server_guid(guid_from_addr(addr))
+    }
+
+    /// Set the advertisement, it can be updated by [`Advertise`] after the incoming is made
     /// The default value is empty
     pub fn advertisement(mut self, advertisement: impl Buf) -> Self {
         self.advertisement = Bytes::copy_from_slice(advertisement.chunk());
@@ -93,6 +331,10 @@ impl Config {
     /// Set the maximum mtu
     /// The default value is 1500
     pub fn max_mtu(mut self, mtu: u16) -> Self {
+        assert!(
+            self.max_datagram_size.map_or(true, |size| size <= mtu),
+            "max_datagram_size should not exceed the max mtu"
+        );
         self.max_mtu = mtu;
         self
     }
@@ -112,6 +354,14 @@ impl Config {
         self
     }
 
+    /// Set the maximum connections finished the offline handshake. The clients connecting beyond
+    /// it are rejected with `NoFreeIncomingConnections` until some connections are closed.
+    /// The default value is no limit
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
     /// Set the maximum parted size
     /// The default value is 256
     /// The maximum number of inflight parted frames is `max_parted_size`*`max_parted_count`nt
@@ -128,25 +378,441 @@ impl Config {
         self
     }
 
-    /// Set the maximum channels
+    /// Set the maximum channels, the sequenced and ordered messages on different channels do not
+    /// block each other
     /// The default value is 1
-    /// The maximum value should be less than 256
+    /// The maximum value is 32
     /// # Panics
-    /// Panics if the channels is greater than 256
+    /// Panics if the channels is 0 or greater than 32
     pub fn max_channels(mut self, channels: usize) -> Self {
-        assert!(channels < 256, "max_channels should be less than 256");
+        assert!(
+            (1..=MAX_ORDER_CHANNELS).contains(&channels),
+            "max_channels should be in [1, {MAX_ORDER_CHANNELS}]"
+        );
+        assert!(
+            self.channel_reliabilities
+                .keys()
+                .all(|&channel| usize::from(channel) < channels),
+            "max_channels should cover the channels with default reliability"
+        );
         self.max_channels = channels;
         self
     }
 
+    /// Set the default reliability of the messages sent on the order channel, it takes precedence
+    /// over the default reliability of the connection when the channel is the default order
+    /// channel, e.g. channel 0 reliable ordered for the state and channel 1 unreliable sequenced
+    /// for the position.
+    /// The default value is the default reliability of the connection
+    /// # Panics
+    /// Panics if the channel is not less than `max_channels`
+    pub fn channel_reliability(mut self, channel: u8, reliability: Reliability) -> Self {
+        assert!(
+            usize::from(channel) < self.max_channels,
+            "channel should be less than max_channels"
+        );
+        self.channel_reliabilities.insert(channel, reliability);
+        self
+    }
+
+    /// Set the maximum parted count across all connections, 0 means no limit
+    /// The default value is 0
+    pub fn max_parted_count_total(mut self, count: usize) -> Self {
+        self.max_parted_count_total = count;
+        self
+    }
+
+    /// Set the timeout of the online handshake, the connection is closed if the client does not
+    /// finish the handshake in time
+    /// The default value is no timeout
+    pub fn online_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.online_handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Enable the watchdog of each connection. A connection is deemed stalled if nothing is
+    /// received from the peer within the timeout while awaiting acknowledgement, the stall is
+    /// counted in [`Anomalies::stalls`].
+    /// The default value is disabled
+    ///
+    /// [`Anomalies::stalls`]: crate::io::Anomalies::stalls
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.stall_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the grace period of closing the stalled connection detected by the watchdog, the
+    /// connection fails with `ConnectionStalled` if it is still stalling after that. It takes
+    /// effect only if [`Config::stall_timeout`] is set.
+    /// The default value is never closing it
+    pub fn stall_close_grace(mut self, grace: Duration) -> Self {
+        self.stall_close_grace = Some(grace);
+        self
+    }
+
+    /// Enable the keepalive of each connection. The peer is pinged while nothing is received from
+    /// it, and the connection fails with [`Error::IdleTimeout`] if nothing is received within
+    /// the timeout, then the IO yields the end of the stream. The pings are sent on the flush,
+    /// and the peer is released from the connected ones once the IO is dropped.
+    /// The default value is disabled
+    ///
+    /// [`Error::IdleTimeout`]: crate::errors::Error::IdleTimeout
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the maximum count of reliable messages awaiting acknowledgement. Sending applies
+    /// backpressure once the limit is reached until all frames of an earlier message are
+    /// acknowledged, regardless of how many frames each message is split into.
+    /// The default value is no limit
+    /// # Panics
+    /// Panics if the limit is 0
+    pub fn max_inflight_messages(mut self, max: usize) -> Self {
+        assert!(max > 0, "max_inflight_messages should be greater than 0");
+        self.max_inflight_messages = Some(max);
+        self
+    }
+
+    /// Set the lower bound of the RTO computed from the RTT estimation. It keeps the frame sets
+    /// from being resent too eagerly on a fast link.
+    /// The default value is 200ms
+    /// # Panics
+    /// Panics if it is larger than `max_rto`
+    pub fn min_rto(mut self, min: Duration) -> Self {
+        assert!(min <= self.max_rto, "min_rto should not be larger than max_rto");
+        self.min_rto = min;
+        self
+    }
+
+    /// Set the upper bound of the RTO computed from the RTT estimation, including the scaling
+    /// under the connection-wide loss.
+    /// The default value is 10s
+    /// # Panics
+    /// Panics if it is less than `min_rto`
+    pub fn max_rto(mut self, max: Duration) -> Self {
+        assert!(max >= self.min_rto, "max_rto should not be less than min_rto");
+        self.max_rto = max;
+        self
+    }
+
+    /// Set the scaling of the base RTO under the connection-wide loss. The base RTO is
+    /// multiplied by `1 + scale * loss_rate`, where the loss rate is smoothed over the recent
+    /// frame sets, so that it grows under sustained loss and recovers after that.
+    /// The default value is 0, i.e. the base RTO does not scale
+    /// # Panics
+    /// Panics if the scale is negative or not finite
+    pub fn rto_loss_scale(mut self, scale: f64) -> Self {
+        assert!(
+            scale.is_finite() && scale >= 0.0,
+            "rto_loss_scale should be a non-negative number"
+        );
+        self.rto_loss_scale = scale;
+        self
+    }
+
+    /// Set the maximum times of resending the frames that go stale, i.e. not acknowledged within
+    /// the RTO. The connection fails with [`crate::errors::Error::PeerUnreachable`] once they go
+    /// stale again instead of being resent, so the dead peer is detected deterministically. The
+    /// resending on the NACK or the fast retransmit does not count.
+    /// The default value is no limit
+    pub fn max_retries(mut self, max: u32) -> Self {
+        self.max_retries = Some(max);
+        self
+    }
+
+    /// Set the fast retransmit threshold. A frame set is resent immediately once a frame set sent
+    /// `threshold` later is acknowledged, without waiting for the NACK from the peer or the RTO.
+    /// The frame sets in the NACK are always resent immediately.
+    /// The default value is 0, i.e. the fast retransmit is disabled
+    pub fn fast_retransmit_threshold(mut self, threshold: u32) -> Self {
+        self.fast_retransmit_threshold = threshold;
+        self
+    }
+
+    /// Enable the NewReno congestion control. The reliable frame sets awaiting acknowledgement
+    /// are limited by the congestion window, which grows on the acknowledgements and halves on
+    /// the losses (the NACKs or the RTOs), so the in-flight data is bounded on congested links.
+    /// The sending blocks when the window and the send buffer are both full.
+    /// The default value is false
+    pub fn congestion_control(mut self, enabled: bool) -> Self {
+        self.congestion_control = enabled;
+        self
+    }
+
+    /// Set the DSCP marked on the outgoing datagrams for the networks classifying the traffic by
+    /// it. It is applied on the socket, and ignored silently where it is unsupported.
+    /// The default value is the socket default
+    /// # Panics
+    /// Panics if the dscp is not less than 64
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        assert!(dscp < 64, "dscp should be less than 64");
+        self.dscp = Some(dscp);
+        self
+    }
+
+    /// Set the allowed game packet ids, i.e. the first byte of the user packets. The user packets
+    /// with other ids from the connected clients are dropped before reaching the application,
+    /// they are still acknowledged and counted in [`Anomalies::unknown_game_packets`].
+    /// The default value is all allowed
+    ///
+    /// [`Anomalies::unknown_game_packets`]: crate::io::Anomalies::unknown_game_packets
+    pub fn game_packet_ids(mut self, mut ids: Vec<u8>) -> Self {
+        ids.sort_unstable();
+        ids.dedup();
+        self.game_packet_ids = Some(ids);
+        self
+    }
+
+    /// Set the cooldown of connecting from the same ip. The clients connecting again within the
+    /// cooldown are rejected with `IpRecentlyConnected` carrying the remaining cooldown.
+    /// The default value is disabled
+    pub fn recently_connected_cooldown(mut self, cooldown: Duration) -> Self {
+        self.recently_connected_cooldown = Some(cooldown);
+        self
+    }
+
+    /// Set the maximum offline handshake replies waiting to be sent. The incoming keeps reading
+    /// the handshake packets while the previous replies are draining, until the backlog is full.
+    /// The default value is 16
+    /// # Panics
+    /// Panics if the backlog is 0
+    pub fn reply_backlog(mut self, backlog: usize) -> Self {
+        assert!(backlog > 0, "reply_backlog should be greater than 0");
+        self.reply_backlog = backlog;
+        self
+    }
+
+    /// Require the client to echo the cookie carried by the open connection reply 1 in its open
+    /// connection request 2, like the SYN cookie. The cookie is derived from the client address,
+    /// so the handshakes from the forged addresses never complete, and no state is kept for them
+    /// before the cookie is echoed back. The pending handshakes are not tracked if it is enabled.
+    /// The clients not echoing the cookie are rejected, only enable it if all clients support it.
+    /// The default value is false
+    pub fn require_cookie(mut self, require: bool) -> Self {
+        self.require_cookie = require;
+        self
+    }
+
+    /// Validate the server address carried by the open connection request 2 against the address
+    /// the socket is bound to, e.g. rejecting the requests forwarded by the misconfigured proxies
+    /// to a multi-homed server. Only the port is validated if the socket is bound to the
+    /// unspecified address. The rejected clients are replied with `IncompatibleProtocolVersion`.
+    /// The default value is false
+    pub fn strict_server_address(mut self, strict: bool) -> Self {
+        self.strict_server_address = strict;
+        self
+    }
+
+    /// Set the callback invoked when a peer finished the online handshake. It is invoked while
+    /// polling the connection, so it should return quickly without blocking.
+    /// The default value is none
+    pub fn on_connect(mut self, f: impl Fn(Peer) + Send + Sync + 'static) -> Self {
+        self.on_connect = Some(Callback(Arc::new(f)));
+        self
+    }
+
+    /// Set the callback invoked when a connection is closed, i.e. the closed or dropped connection
+    /// is reaped by the incoming. It is invoked while polling the incoming, so it should return
+    /// quickly without blocking.
+    /// The default value is none
+    pub fn on_disconnect(
+        mut self,
+        f: impl Fn(SocketAddr, CloseReason) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_disconnect = Some(Callback(Arc::new(move |(addr, reason)| f(addr, reason))));
+        self
+    }
+
+    /// Set the handling of the ordered frames stuck behind a missing ordered frame index when the
+    /// connection is closed by the peer, the missing one never arrives if the peer gave up
+    /// resending it.
+    /// The default value is [`OrderGapPolicy::Discard`]
+    pub fn order_gap_policy(mut self, policy: OrderGapPolicy) -> Self {
+        self.order_gap_policy = policy;
+        self
+    }
+
+    /// Set the maximum size of the outgoing datagrams. It caps the frame sets and the fragments
+    /// below the negotiated mtu without renegotiating it, working around the paths fragmenting
+    /// the datagrams smaller than the mtu. It takes effect only if it is below the negotiated mtu.
+    /// The default value is no cap
+    /// # Panics
+    /// Panics if the size is less than 576 or exceeds the max mtu
+    pub fn max_datagram_size(mut self, size: u16) -> Self {
+        assert!(size >= MIN_MTU, "max_datagram_size should not be less than {MIN_MTU}");
+        assert!(size <= self.max_mtu, "max_datagram_size should not exceed the max mtu");
+        self.max_datagram_size = Some(size);
+        self
+    }
+
+    /// Hold the outgoing acknowledgements for the delay, coalescing the received frame sets into
+    /// fewer acks on the high-throughput connections. They are sent by the first flush after the
+    /// delay or once [`Config::ack_threshold`] frame sets are received, whichever comes first.
+    /// The delay is capped at half of the RTO to keep the peer from resending spuriously.
+    /// The default value is disabled, the acks are sent on each flush
+    pub fn ack_delay(mut self, delay: Duration) -> Self {
+        self.ack_delay = Some(delay);
+        self
+    }
+
+    /// Set the count of the received frame sets sending the held acknowledgements before the
+    /// delay elapses. It takes effect only if [`Config::ack_delay`] is set.
+    /// The default value is 32
+    /// # Panics
+    /// Panics if the threshold is 0
+    pub fn ack_threshold(mut self, threshold: usize) -> Self {
+        assert!(threshold > 0, "ack_threshold should be greater than 0");
+        self.ack_threshold = threshold;
+        self
+    }
+
+    /// Tolerate the reordering of the incoming frame sets before nacking the missing ones. A
+    /// missing frame set is nacked once more than `frame_sets` frame sets are received after it
+    /// or the delay elapses since it is detected, whichever comes first, so that the reordered
+    /// ones are not resent by the peer. The nacks are sent on the flush.
+    /// The default value is disabled, the missing frame sets are nacked at once
+    pub fn nack_reorder_window(mut self, frame_sets: u32, delay: Duration) -> Self {
+        self.nack_reorder_window = Some((frame_sets, delay));
+        self
+    }
+
+    /// Coalesce the messages buffered between the flushes into the frame sets filled up to the
+    /// mtu. Without it, the buffered messages are sent when the sink is polled for the next one,
+    /// i.e. feeding N tiny messages produces N datagrams. The frame sets are sent once they are
+    /// filled, on the flush, or when an [`Priority::Immediate`] message is buffered.
+    /// The default value is disabled
+    ///
+    /// [`Priority::Immediate`]: crate::Priority::Immediate
+    pub fn coalesce(mut self, coalesce: bool) -> Self {
+        self.coalesce = coalesce;
+        self
+    }
+
+    /// Flush the buffered messages and the pending acknowledgements every interval while the IO
+    /// is polled, e.g. awaiting the next message, so that the caller need not drive the flush
+    /// periodically. The timer is registered in the shared reactor instead of spawning a task.
+    /// The default value is disabled, the caller flushes manually
+    pub fn auto_flush_interval(mut self, interval: Duration) -> Self {
+        self.auto_flush_interval = Some(interval);
+        self
+    }
+
+    /// Set the timeout of closing the connection by [`SinkExt::close`]. The disconnect
+    /// notification is sent after the buffered frames are acknowledged, and the closing resolves
+    /// once the peer acknowledges it or the timeout (e.g. 2MSL) elapses, so that a peer gone away
+    /// never blocks it. [`IO::close_with_timeout`] tells whether it is acknowledged.
+    /// The default value is waiting until it is acknowledged
+    ///
+    /// [`SinkExt::close`]: futures::SinkExt::close
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the reactor driving the timers of the connections, e.g. a dedicated one spawned by
+    /// [`Reactor::spawn`] with a coarse granularity to wake up less often under many connections.
+    /// The default value is the global reactor with the finest granularity
+    pub fn reactor(mut self, reactor: &'static Reactor) -> Self {
+        self.reactor = Some(reactor);
+        self
+    }
+
+    /// Set the count of the reliable frame indices tracked past the first missing one to drop the
+    /// redelivered reliable frames, e.g. resent after the acknowledgement is lost. An index
+    /// beyond the window gives up the oldest missing ones, which are dropped if they arrive
+    /// later, so it should cover the indices sent within a few RTOs.
+    /// The default value is 65536
+    /// # Panics
+    /// Panics if it is 0
+    pub fn dedup_window(mut self, window: usize) -> Self {
+        assert!(window > 0, "dedup_window should not be 0");
+        self.dedup_window = window;
+        self
+    }
+
+    /// Set the callback invoked with the errors of decoding the frames of the connections along
+    /// with the peer address, e.g. to count and classify the malformed packets. It is invoked
+    /// while polling the connection, so it should return quickly without blocking. The errors
+    /// are logged regardless.
+    /// The default value is none
+    pub fn on_codec_error(
+        mut self,
+        f: impl Fn(&CodecError, SocketAddr) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_codec_error = Some(codec::OnCodecError(Arc::new(f)));
+        self
+    }
+
+    /// Propagate the trace ids attached by [`Message::with_trace_id`] to the peer if the peer also
+    /// enables it, the peer reports them by [`TraceInfo::last_trace_id`]. It is negotiated in the
+    /// online handshake.
+    /// The default value is false
+    ///
+    /// [`Message::with_trace_id`]: crate::Message::with_trace_id
+    /// [`TraceInfo::last_trace_id`]: crate::io::TraceInfo::last_trace_id
+    pub fn propagate_trace_id(mut self, enabled: bool) -> Self {
+        self.propagate_trace_id = enabled;
+        self
+    }
+
+    /// Enable compressing the frame bodies not smaller than the threshold if the peer also enables
+    /// it. The compression is negotiated in the online handshake. The threshold is at least 64
+    /// bytes to avoid expanding tiny frame bodies.
+    /// The default value is disabled
+    #[cfg(feature = "compression")]
+    pub fn compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = Some(threshold.max(crate::codec::MIN_COMPRESSION_THRESHOLD));
+        self
+    }
+
+    /// Encrypt the user data by the cipher, the keys are exchanged in the online handshake. The
+    /// client must also enable it, otherwise the connection request fails.
+    /// The default value is disabled
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, cipher: impl crate::cipher::Cipher) -> Self {
+        self.encryption = Some(crate::cipher::Encryption(Arc::new(cipher)));
+        self
+    }
+
+    /// Inject the chaos into the outgoing connected datagrams for the resilience testing. It
+    /// falls back to [`Chaos::from_env`] if it is not set.
+    /// The default value is no chaos
+    ///
+    /// [`Chaos::from_env`]: crate::Chaos::from_env
+    #[cfg(feature = "chaos")]
+    pub fn chaos(mut self, chaos: crate::Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Record the counters of each connection into the `metrics` crate, labeled by the id made of
+    /// the address of the peer as `connection`.
+    /// The default value is the address of the peer
+    #[cfg(feature = "metrics")]
+    pub fn metrics_id(mut self, f: impl Fn(SocketAddr) -> String + Send + Sync + 'static) -> Self {
+        self.metrics_id = Some(Callback(Arc::new(f)));
+        self
+    }
+
     fn offline_config(&self) -> offline::Config {
         offline::Config {
-            sever_guid: self.sever_guid,
-            advertisement: self.advertisement.clone(),
+            server_guid: self.server_guid,
+            advertisement: Arc::new(ArcSwap::from_pointee(self.advertisement.clone())),
             min_mtu: self.min_mtu,
             max_mtu: self.max_mtu,
             support_version: self.support_version.clone(),
             max_pending: self.max_pending,
+            max_connections: self.max_connections,
+            recently_connected_cooldown: self.recently_connected_cooldown,
+            reply_backlog: self.reply_backlog,
+            require_cookie: self.require_cookie,
+            #[cfg(feature = "encryption")]
+            encryption: self.encryption.is_some(),
+            #[cfg(not(feature = "encryption"))]
+            encryption: false,
+            server_address: None,
+            reactor: self.reactor(),
         }
     }
 
@@ -155,16 +821,146 @@ impl Config {
             max_parted_count: self.max_parted_count,
             max_parted_size: self.max_parted_size,
             max_channels: self.max_channels,
+            reassembly_quota: None,
+            game_packet_ids: self.game_packet_ids.clone(),
+            order_gap_policy: self.order_gap_policy,
+            dedup_window: self.dedup_window,
+            on_error: self.on_codec_error.clone(),
         }
     }
 
+    fn reassembly_quota(&self) -> Option<codec::ReassemblyQuota> {
+        (self.max_parted_count_total != 0)
+            .then(|| codec::ReassemblyQuota::new(self.max_parted_count_total))
+    }
+
     fn server_role(&self) -> RoleContext {
         RoleContext::Server {
-            guid: self.sever_guid,
+            guid: self.server_guid,
+            reactor: self.reactor(),
         }
     }
+
+    fn reactor(&self) -> &'static Reactor {
+        self.reactor.unwrap_or_else(Reactor::get)
+    }
+}
+
+/// Pending handshake extension of the incoming. The pending handshakes are the half-open
+/// connections that sent the first open connection request but not the second one yet.
+pub trait PendingHandshakes {
+    /// The addresses of the pending handshakes, the most recent first
+    fn pending_handshakes(&self) -> Vec<SocketAddr>;
+
+    /// Cancel the pending handshake of the address, the peer is replied with
+    /// `ConnectionRequestFailed` when the incoming is polled next. Return false if it is not
+    /// pending.
+    fn cancel_handshake(self: Pin<&mut Self>, addr: SocketAddr) -> bool;
+}
+
+/// Ban list extension of the incoming. The handshakes from the banned ips are answered with
+/// `ConnectionBanned` regardless of the port, the established connections are not affected.
+pub trait BanList {
+    /// Ban the ip
+    fn ban(self: Pin<&mut Self>, ip: IpAddr);
+
+    /// Lift the ban of the ip, return false if it is not banned
+    fn unban(self: Pin<&mut Self>, ip: IpAddr) -> bool;
+}
+
+/// The handle updating the advertisement of the incoming, e.g. the player count in the MOTD.
+/// It is shared with the incoming and can be moved to other tasks. The pings read the
+/// advertisement without locking.
+#[derive(Debug, Clone)]
+pub struct Advertiser(Arc<ArcSwap<Bytes>>);
+
+impl Advertiser {
+    /// Replace the advertisement, the following `UnconnectedPong` carry it
+    pub fn set(&self, advertisement: Bytes) {
+        self.0.store(Arc::new(advertisement));
+    }
+
+    /// The current advertisement
+    pub fn get(&self) -> Bytes {
+        Bytes::clone(&self.0.load())
+    }
+}
+
+/// Advertisement extension of the incoming
+pub trait Advertise {
+    /// Replace the advertisement, the following `UnconnectedPong` carry it
+    fn set_advertisement(&self, advertisement: Bytes) {
+        self.advertiser().set(advertisement);
+    }
+
+    /// The handle updating the advertisement
+    fn advertiser(&self) -> Advertiser;
+
+    /// Send an `AdvertiseSystem` carrying the data to the address without being pinged, e.g.
+    /// announcing the server to a broadcast address. It is sent by the socket directly.
+    fn advertise(
+        self: Pin<&mut Self>,
+        addr: SocketAddr,
+        data: Bytes,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// Graceful shutdown extension of the incoming
+pub trait Shutdown {
+    /// Send a reliable `DisconnectNotification` to every connected peer and flush them, so the
+    /// peers close immediately rather than timing out, e.g. during the server restarts. It
+    /// resolves with the count of the notified peers once all notifications are sent or the
+    /// timeout elapses.
+    ///
+    /// The notifications are sent once and never resent, the IOs polled by the incoming fail
+    /// with [`Error::ConnectionClosed`] afterwards.
+    fn shutdown(
+        self: Pin<&mut Self>,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<usize, Error>> + Send;
+}
+
+/// Connection events extension of the incoming
+pub trait ConnEvents {
+    /// Subscribe the connection events, they are buffered until received. Subscribing again ends
+    /// the previous stream. A connection failed in the online handshake is only reported by
+    /// [`ConnEvent::Disconnected`].
+    fn events(&self) -> impl Stream<Item = ConnEvent> + Send + 'static;
 }
 
 pub trait MakeIncoming: Sized {
-    fn make_incoming(self, config: Config) -> impl Stream<Item = impl IO>;
+    fn make_incoming(
+        self,
+        config: Config,
+    ) -> impl Stream<Item = impl IO + Ping + RawFrameSet>
+           + AnomalyInfo
+           + PendingHandshakes
+           + BanList
+           + Advertise
+           + Shutdown
+           + ConnEvents;
+}
+
+#[cfg(test)]
+mod test {
+    use super::guid_from_addr;
+
+    #[test]
+    fn test_guid_from_addr() {
+        let addr = "10.0.0.1:19132".parse().unwrap();
+        // pinned, the guid must not change across the releases
+        assert_eq!(guid_from_addr(addr), 0x87d2_40db_781a_1740);
+        assert_eq!(
+            guid_from_addr("[::ffff:10.0.0.1]:19132".parse().unwrap()),
+            guid_from_addr(addr)
+        );
+        assert_ne!(
+            guid_from_addr("10.0.0.2:19132".parse().unwrap()),
+            guid_from_addr(addr)
+        );
+        assert_ne!(
+            guid_from_addr("10.0.0.1:19133".parse().unwrap()),
+            guid_from_addr(addr)
+        );
+    }
 }
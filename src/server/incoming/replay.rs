@@ -0,0 +1,223 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::pin::{pin, Pin};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures::{Sink, Stream, StreamExt};
+
+use super::Config;
+use crate::codec::{self, Decoded};
+use crate::errors::CodecError;
+use crate::link::{Router, TransferLink};
+use crate::packet::connected::FramesMut;
+use crate::packet::{unconnected, Packet};
+use crate::server::handler::offline::OfflineHandler;
+use crate::server::handler::online::HandleOnline;
+use crate::state::IncomingStateManage;
+
+/// The results of replaying the captured datagrams
+#[derive(Debug, Default)]
+pub struct Replayed {
+    /// The datagrams replied by the offline handshake
+    pub replies: Vec<Bytes>,
+    /// The data delivered to the application after the online handshake
+    pub delivered: Vec<Bytes>,
+    /// The errors of decoding the captured datagrams
+    pub errors: Vec<CodecError>,
+}
+
+/// Replay the datagrams captured from a single client, feeding them through the server
+/// pipeline as if they were received. It is deterministic, the handshake timeout is ignored
+/// and the connected replies (e.g. acknowledgements) are discarded.
+pub async fn replay(datagrams: &[Vec<u8>], config: Config) -> Replayed {
+    let peer = SocketAddr::from(([127, 0, 0, 1], 19132));
+    let role = config.server_role();
+    let mut replayed = Replayed::default();
+
+    let link = TransferLink::new_arc(role);
+    if config.propagate_trace_id {
+        link.enable_trace_id_propagation();
+    }
+    link.enable_mtu_update();
+    #[cfg(feature = "compression")]
+    if let Some(threshold) = config.compression_threshold {
+        link.enable_compression(threshold);
+    }
+    #[cfg(feature = "encryption")]
+    if let Some(encryption) = &config.encryption {
+        link.enable_encryption(Arc::clone(&encryption.0));
+    }
+    let (mut router, route) = Router::new(Arc::clone(&link));
+    let codec_config = codec::Config {
+        reassembly_quota: config.reassembly_quota(),
+        ..config.codec_config()
+    };
+    let src = route
+        .frame_decoded(codec_config, Arc::clone(&link), role)
+        .manage_incoming_state(Arc::clone(&link))
+        .handle_online(role, peer, None, link, None);
+
+    let mut offline = pin!(OfflineHandler::new(
+        ReplayFrame {
+            datagrams: datagrams.iter().map(|d| BytesMut::from(&d[..])).collect(),
+            peer,
+            replied: &mut replayed.replies,
+            errors: &mut replayed.errors,
+        },
+        config.offline_config(),
+    ));
+    while let Some((pack, _)) = offline.next().await {
+        router.deliver(pack);
+    }
+    // close the route so that the pipeline ends after the delivered data is drained
+    drop(router);
+    replayed.delivered = src.collect().await;
+    replayed
+}
+
+/// Yield the captured datagrams and record the replies
+struct ReplayFrame<'a> {
+    datagrams: VecDeque<BytesMut>,
+    peer: SocketAddr,
+    replied: &'a mut Vec<Bytes>,
+    errors: &'a mut Vec<CodecError>,
+}
+
+impl<'a> Stream for ReplayFrame<'a> {
+    type Item = (Packet<FramesMut>, SocketAddr);
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        while let Some(datagram) = this.datagrams.front_mut() {
+            match Packet::read(datagram) {
+                Ok(Some(pack)) => return Poll::Ready(Some((pack, this.peer))),
+                Ok(None) => {}
+                Err(err) => this.errors.push(err),
+            }
+            this.datagrams.pop_front();
+        }
+        Poll::Ready(None)
+    }
+}
+
+impl<'a> Sink<(unconnected::Packet, SocketAddr)> for ReplayFrame<'a> {
+    type Error = CodecError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        (pack, _): (unconnected::Packet, SocketAddr),
+    ) -> Result<(), Self::Error> {
+        let mut buf = BytesMut::new();
+        pack.write(&mut buf);
+        self.get_mut().replied.push(buf.freeze());
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use bytes::{Bytes, BytesMut};
+
+    use super::{replay, Config};
+    use crate::packet::connected::{self, Flags, Frame, FrameBody, FrameSet};
+    use crate::packet::{unconnected, PackType, Packet};
+    use crate::Reliability;
+
+    fn datagram(pack: Packet<connected::FramesRef<'_>>) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        pack.write(&mut buf);
+        buf.to_vec()
+    }
+
+    fn frame_set(seq_num: u32, body: FrameBody) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        body.write(&mut buf);
+        let frames = [Frame {
+            flags: Flags::new(Reliability::ReliableOrdered, false),
+            reliable_frame_index: Some(seq_num.into()),
+            seq_frame_index: None,
+            ordered: Some(connected::Ordered {
+                frame_index: seq_num.into(),
+                channel: 0,
+            }),
+            fragment: None,
+            body: buf.freeze(),
+        }];
+        datagram(Packet::Connected(connected::Packet::FrameSet(FrameSet {
+            seq_num: seq_num.into(),
+            set: &frames[..],
+        })))
+    }
+
+    /// The datagrams sent by a client during the handshake and the first message
+    fn recorded_handshake() -> Vec<Vec<u8>> {
+        let server_address: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let system_addresses = [SocketAddr::from(([0, 0, 0, 0], 0)); 20];
+        vec![
+            datagram(Packet::Unconnected(
+                unconnected::Packet::OpenConnectionRequest1 {
+                    magic: (),
+                    protocol_version: 11,
+                    mtu: 1000,
+                },
+            )),
+            datagram(Packet::Unconnected(
+                unconnected::Packet::OpenConnectionRequest2 {
+                    magic: (),
+                    cookie: None,
+                    server_address,
+                    mtu: 1000,
+                    client_guid: 114514,
+                },
+            )),
+            frame_set(
+                0,
+                FrameBody::ConnectionRequest {
+                    client_guid: 114514,
+                    request_timestamp: 0,
+                    use_encryption: false,
+                    capabilities: 0,
+                    key: Bytes::new(),
+                },
+            ),
+            frame_set(
+                1,
+                FrameBody::NewIncomingConnection {
+                    server_address,
+                    system_addresses,
+                    request_timestamp: 0,
+                    accepted_timestamp: 0,
+                },
+            ),
+            frame_set(2, FrameBody::User(Bytes::from_static(b"hello"))),
+            // a truncated datagram
+            vec![PackType::OpenConnectionRequest2 as u8],
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_replay_handshake() {
+        let replayed = replay(&recorded_handshake(), Config::new()).await;
+        assert_eq!(replayed.replies.len(), 2);
+        assert_eq!(replayed.replies[0][0], PackType::OpenConnectionReply1 as u8);
+        assert_eq!(replayed.replies[1][0], PackType::OpenConnectionReply2 as u8);
+        assert_eq!(replayed.delivered, vec![Bytes::from_static(b"hello")]);
+        assert_eq!(replayed.errors.len(), 1);
+    }
+}
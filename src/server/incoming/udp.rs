@@ -0,0 +1,408 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::{pin, Pin};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use concurrent_queue::ConcurrentQueue;
+use futures::{Sink, SinkExt, Stream};
+use log::{debug, error};
+use minitrace::collector::SpanContext;
+use minitrace::Span;
+use pin_project_lite::pin_project;
+
+use super::{
+    Advertise, Advertiser, BanList, Config, ConnEvent, ConnEvents, MakeIncoming, Peer,
+    PendingHandshakes, Shutdown,
+};
+#[cfg(feature = "chaos")]
+use crate::codec::chaos::ChaosSocket;
+use crate::codec::frame::Framed;
+use crate::codec::{self, Decoded, Encoded, ReassemblyQuota};
+use crate::errors::{CodecError, Error};
+use crate::guard::HandleOutgoing;
+use crate::io::{Anomalies, AnomalyInfo, Ping, RawFrameSet, SeparatedIO, SeqCounters, IO};
+use crate::link::{AnomalyCounters, Router, TransferLink};
+use crate::packet::connected::{self, Flags, Frame, FrameSet, FramesRef};
+use crate::packet::{unconnected, PackType, Packet};
+use crate::resend_map::ResendMap;
+use crate::runtime::AsyncUdpSocket;
+use crate::server::handler::offline::OfflineHandler;
+use crate::server::handler::online::{HandleOnline, OnConnected};
+use crate::state::{CloseOnDrop, IncomingStateManage, OutgoingStateManage};
+use crate::utils::TraceStreamExt;
+use crate::Reliability;
+
+pin_project! {
+    struct Incoming<S> {
+        #[pin]
+        offline: OfflineHandler<Framed<Arc<S>>>,
+        config: Config,
+        socket: Arc<S>,
+        routers: HashMap<SocketAddr, Router>,
+        close_events: Arc<ConcurrentQueue<SocketAddr>>,
+        reassembly_quota: Option<ReassemblyQuota>,
+        anomalies: Arc<AnomalyCounters>,
+        events: Arc<EventSender>,
+    }
+}
+
+/// The sender of the connection events, shared with the online handlers
+#[derive(Debug, Default)]
+struct EventSender(parking_lot::Mutex<Option<async_channel::Sender<ConnEvent>>>);
+
+impl EventSender {
+    fn subscribe(&self) -> async_channel::Receiver<ConnEvent> {
+        let (tx, rx) = async_channel::unbounded();
+        // the previous receiver is closed along with the sender
+        *self.0.lock() = Some(tx);
+        rx
+    }
+
+    fn send(&self, event: ConnEvent) {
+        let mut sender = self.0.lock();
+        if let Some(tx) = sender.as_ref()
+            && tx.try_send(event).is_err()
+        {
+            // the receiver is dropped
+            *sender = None;
+        }
+    }
+}
+
+impl<S: AsyncUdpSocket> MakeIncoming for S {
+    fn make_incoming(
+        self,
+        config: Config,
+    ) -> impl Stream<Item = impl IO + Ping + RawFrameSet>
+           + AnomalyInfo
+           + PendingHandshakes
+           + BanList
+           + Advertise
+           + Shutdown
+           + ConnEvents {
+        if let Some(dscp) = config.dscp
+            && let Err(err) = self.set_dscp(dscp)
+        {
+            debug!("failed to set dscp {dscp} on the socket: {err}");
+        }
+        let socket = Arc::new(self);
+        let mut offline_config = config.offline_config();
+        if config.strict_server_address {
+            match socket.local_addr() {
+                Ok(addr) => offline_config.server_address = Some(addr),
+                Err(err) => debug!("failed to validate the server address: {err}"),
+            }
+        }
+        Incoming {
+            offline: OfflineHandler::new(
+                Framed::new(Arc::clone(&socket), config.max_mtu as usize),
+                offline_config,
+            ),
+            socket,
+            reassembly_quota: config.reassembly_quota(),
+            config,
+            routers: HashMap::new(),
+            close_events: Arc::new(ConcurrentQueue::unbounded()),
+            anomalies: Arc::new(AnomalyCounters::default()),
+            events: Arc::new(EventSender::default()),
+        }
+    }
+}
+
+impl<S> AnomalyInfo for Incoming<S> {
+    fn anomalies(&self) -> Anomalies {
+        self.anomalies.snapshot()
+    }
+
+    fn reset_anomalies(&self) {
+        self.anomalies.reset();
+    }
+}
+
+impl<S: AsyncUdpSocket> PendingHandshakes for Incoming<S> {
+    fn pending_handshakes(&self) -> Vec<SocketAddr> {
+        self.offline.pending_addrs()
+    }
+
+    fn cancel_handshake(self: Pin<&mut Self>, addr: SocketAddr) -> bool {
+        self.project().offline.cancel_pending(&addr)
+    }
+}
+
+impl<S: AsyncUdpSocket> BanList for Incoming<S> {
+    fn ban(self: Pin<&mut Self>, ip: IpAddr) {
+        self.project().offline.ban(ip);
+    }
+
+    fn unban(self: Pin<&mut Self>, ip: IpAddr) -> bool {
+        self.project().offline.unban(ip)
+    }
+}
+
+impl<S: AsyncUdpSocket> Advertise for Incoming<S> {
+    fn advertiser(&self) -> Advertiser {
+        Advertiser(Arc::clone(self.offline.advertisement()))
+    }
+
+    async fn advertise(self: Pin<&mut Self>, addr: SocketAddr, data: Bytes) -> Result<(), Error> {
+        let this = self.project();
+        let mut frame = pin!(Framed::new(
+            Arc::clone(this.socket),
+            this.config.max_mtu as usize
+        ));
+        frame
+            .send((
+                unconnected::Packet::AdvertiseSystem { magic: (), data },
+                addr,
+            ))
+            .await?;
+        Ok(())
+    }
+}
+
+impl<S: AsyncUdpSocket> ConnEvents for Incoming<S> {
+    fn events(&self) -> impl Stream<Item = ConnEvent> + Send + 'static {
+        self.events.subscribe()
+    }
+}
+
+impl<S: AsyncUdpSocket> Shutdown for Incoming<S> {
+    async fn shutdown(self: Pin<&mut Self>, timeout: Duration) -> Result<usize, Error> {
+        let this = self.project();
+        let deadline = Instant::now() + timeout;
+        // the peers without the router have not received any frame set yet
+        let mut peers: VecDeque<_> = this
+            .offline
+            .connected_addrs()
+            .into_iter()
+            .map(|addr| {
+                let counters = this
+                    .routers
+                    .get(&addr)
+                    .map(|router| router.link().seq_counters())
+                    .unwrap_or_default();
+                (addr, counters)
+            })
+            .collect();
+        let total = peers.len();
+        // send by the socket directly, the IOs may not be polled by anyone
+        let mut frame = pin!(Framed::new(
+            Arc::clone(this.socket),
+            this.config.max_mtu as usize
+        ));
+        let role = this.config.server_role();
+        let res = futures::future::poll_fn(|cx| {
+            if let Poll::Ready(res) = poll_notify_disconnect(frame.as_mut(), &mut peers, cx) {
+                return Poll::Ready(res);
+            }
+            if role
+                .reactor()
+                .poll_deadline(role.guid(), deadline, cx)
+                .is_ready()
+            {
+                return Poll::Ready(Ok(()));
+            }
+            Poll::Pending
+        })
+        .await;
+        for router in this.routers.values() {
+            router.link().fail(Error::ConnectionClosed);
+        }
+        res?;
+        let notified = total - peers.len();
+        debug!(
+            "[{}] shutdown, {notified} of {total} peers notified",
+            this.config.server_role()
+        );
+        Ok(notified)
+    }
+}
+
+/// Send a reliable disconnect notification to each peer following the sequence counters of the
+/// connection, the peers are popped once their notifications are sent
+fn poll_notify_disconnect<S: AsyncUdpSocket>(
+    mut frame: Pin<&mut Framed<Arc<S>>>,
+    peers: &mut VecDeque<(SocketAddr, SeqCounters)>,
+    cx: &mut Context<'_>,
+) -> Poll<Result<(), CodecError>> {
+    while let Some((addr, counters)) = peers.front() {
+        ready!(Sink::<(Packet<FramesRef<'_>>, SocketAddr)>::poll_ready(
+            frame.as_mut(),
+            cx
+        ))?;
+        let frames = [Frame {
+            flags: Flags::new(Reliability::Reliable, false),
+            reliable_frame_index: Some(counters.next_reliable_index.into()),
+            seq_frame_index: None,
+            ordered: None,
+            fragment: None,
+            body: Bytes::from_static(&[PackType::DisconnectNotification as u8]),
+        }];
+        let frame_set = FrameSet {
+            seq_num: counters.next_seq_num.into(),
+            set: &frames[..],
+        };
+        frame.as_mut().start_send((
+            Packet::Connected(connected::Packet::FrameSet(frame_set)),
+            *addr,
+        ))?;
+        peers.pop_front();
+    }
+    Sink::<(Packet<FramesRef<'_>>, SocketAddr)>::poll_flush(frame, cx)
+}
+
+impl<S: AsyncUdpSocket> Stream for Incoming<S> {
+    type Item = impl IO + Ping + RawFrameSet;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+
+        let role = this.config.server_role();
+        for ev in this.close_events.try_iter() {
+            let router = this
+                .routers
+                .remove(&ev)
+                .expect("closed a non-exist connection");
+            this.offline.as_mut().disconnect(&ev);
+            let reason = router.link().close_reason();
+            debug!("[{role}] connection closed: {ev}, reason: {reason:?}");
+            if let Some(on_disconnect) = &this.config.on_disconnect {
+                on_disconnect.call((ev, reason));
+            }
+            this.events
+                .send(ConnEvent::Disconnected { addr: ev, reason });
+        }
+
+        loop {
+            let Some((pack, peer)) = ready!(this.offline.as_mut().poll_next(cx)) else {
+                return Poll::Ready(None);
+            };
+            if let Some(entry) = this.routers.get_mut(&peer.addr) {
+                if !entry.deliver(pack) {
+                    error!("[{role}] connection was dropped before closed");
+                    this.routers.remove(&peer.addr);
+                    this.offline.as_mut().disconnect(&peer.addr);
+                }
+                continue;
+            }
+
+            let link = TransferLink::new_arc_with_parent(role, Some(Arc::clone(this.anomalies)));
+            if let Some(guid) = peer.guid {
+                link.set_peer_guid(guid);
+            }
+            link.set_peer_addr(peer.addr);
+            link.set_negotiated_mtu(peer.mtu);
+            if let Some(timeout) = this.config.stall_timeout {
+                link.enable_watchdog(timeout, this.config.stall_close_grace);
+            }
+            if let Some(timeout) = this.config.idle_timeout {
+                link.enable_keepalive(timeout);
+            }
+            if let Some(size) = this.config.max_datagram_size {
+                link.cap_datagram_size(size);
+            }
+            if let Some(delay) = this.config.ack_delay {
+                link.enable_ack_delay(delay, this.config.ack_threshold);
+            }
+            if let Some((frame_sets, delay)) = this.config.nack_reorder_window {
+                link.enable_reorder_window(frame_sets, delay);
+            }
+            if this.config.propagate_trace_id {
+                link.enable_trace_id_propagation();
+            }
+            // follow the mtu lowered by the clients
+            link.enable_mtu_update();
+            #[cfg(feature = "compression")]
+            if let Some(threshold) = this.config.compression_threshold {
+                link.enable_compression(threshold);
+            }
+            #[cfg(feature = "encryption")]
+            if let Some(encryption) = &this.config.encryption {
+                link.enable_encryption(Arc::clone(&encryption.0));
+            }
+            #[cfg(feature = "metrics")]
+            link.enable_metrics(this.config.metrics_id.as_ref().map_or_else(
+                || peer.addr.to_string(),
+                |metrics_id| metrics_id.call(peer.addr),
+            ));
+            let (mut entry, route) = Router::new(Arc::clone(&link));
+            entry.deliver(pack);
+            this.routers.insert(peer.addr, entry);
+
+            #[cfg(feature = "chaos")]
+            let dst_socket = ChaosSocket::new(Arc::clone(this.socket), this.config.chaos);
+            #[cfg(not(feature = "chaos"))]
+            let dst_socket = Arc::clone(this.socket);
+            let dst = Framed::new(dst_socket, this.config.max_mtu as usize)
+                .handle_outgoing(
+                    Arc::clone(&link),
+                    this.config.send_buf_cap,
+                    this.config.max_inflight_messages,
+                    ResendMap::new(role)
+                        .with_rto_bounds(this.config.min_rto, this.config.max_rto)
+                        .with_rto_loss_scale(this.config.rto_loss_scale)
+                        .with_max_retries(this.config.max_retries)
+                        .with_fast_retransmit_threshold(this.config.fast_retransmit_threshold)
+                        .with_congestion_control(this.config.congestion_control),
+                    peer.clone(),
+                    role,
+                )
+                .with_coalesce(this.config.coalesce)
+                .frame_encoded(peer.mtu, this.config.codec_config(), Arc::clone(&link))
+                .manage_outgoing_state(
+                    Some(CloseOnDrop::new(peer.addr, Arc::clone(this.close_events))),
+                    Arc::clone(&link),
+                );
+
+            let on_connect = this.config.on_connect.clone();
+            let events = Arc::clone(this.events);
+            let connected = Peer {
+                addr: peer.addr,
+                mtu: peer.mtu,
+                guid: peer.guid,
+            };
+            let on_connected: OnConnected = Box::new(move || {
+                if let Some(on_connect) = on_connect {
+                    on_connect.call(connected);
+                }
+                events.send(ConnEvent::Connected {
+                    addr: connected.addr,
+                    guid: connected.guid,
+                    mtu: connected.mtu,
+                });
+            });
+            let codec_config = codec::Config {
+                reassembly_quota: this.reassembly_quota.clone(),
+                ..this.config.codec_config()
+            };
+            let src = route
+                .frame_decoded(codec_config, Arc::clone(&link), role)
+                .manage_incoming_state(Arc::clone(&link))
+                .handle_online(
+                    role,
+                    peer.addr,
+                    this.config.online_handshake_timeout,
+                    Arc::clone(&link),
+                    Some(on_connected),
+                )
+                .enter_on_item(move || {
+                    Span::root("online", SpanContext::random()).with_properties(|| {
+                        [
+                            ("peer", peer.addr.to_string()),
+                            ("mtu", peer.mtu.to_string()),
+                        ]
+                    })
+                });
+
+            return Poll::Ready(Some(
+                SeparatedIO::new(src, dst, link, Arc::clone(this.socket) as _)
+                    .with_channel_reliabilities(this.config.channel_reliabilities.clone())
+                    .with_auto_flush(this.config.auto_flush_interval)
+                    .with_close_timeout(this.config.close_timeout),
+            ));
+        }
+    }
+}
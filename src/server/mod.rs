@@ -1,4 +1,4 @@
-mod handler;
+pub(crate) mod handler;
 mod incoming;
 
 pub use self::incoming::*;
@@ -0,0 +1,675 @@
+//! Deterministic simulation of the full pipeline.
+//!
+//! A client and a server run on the current thread over an in-memory network. The network
+//! delays all datagrams by a fixed latency, and drops, duplicates and reorders the connected
+//! datagrams by a seeded rng. The resend timers and the reactor deadlines read the mock clock.
+//! [`Sim::step`] polls the offline, online and resend state machines of both peers in a fixed order
+//! and then moves the network and the clock forward by one tick, so a failure is reproduced by its
+//! seed.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use bytes::{Bytes, BytesMut};
+use futures::{Future, Sink, Stream};
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::client::handler::offline::{self as client_offline, OfflineHandler as ClientOffline};
+use crate::client::handler::online::HandleOnline as ClientOnline;
+use crate::codec::frame::Framed;
+use crate::codec::{self, AsyncSocket, Decoded, Encoded};
+use crate::errors::Error;
+use crate::guard::HandleOutgoing;
+use crate::io::OrderGapPolicy;
+use crate::link::{Router, SharedLink, TransferLink};
+use crate::packet::connected::{self, FrameSet, FramesMut};
+use crate::resend_map::ResendMap;
+use crate::server::handler::offline::{self as server_offline, OfflineHandler as ServerOffline};
+use crate::server::handler::online::HandleOnline as ServerOnline;
+use crate::state::{IncomingStateManage, OutgoingStateManage};
+use crate::utils::tests::{MockClock, TestWaker};
+use crate::utils::Reactor;
+use crate::{Message, PeerContext, RoleContext};
+
+/// The time elapsed in each step
+const TICK: Duration = Duration::from_millis(10);
+
+/// The maximum extra ticks a reordered datagram is delayed
+const MAX_REORDER_TICKS: u64 = 5;
+
+const MTU: u16 = 1400;
+const SEND_BUF_CAP: usize = 1024;
+const CLIENT_GUID: u64 = 114514;
+const SERVER_GUID: u64 = 1919810;
+const PROTOCOL_VERSION: u8 = 11;
+
+fn client_addr() -> SocketAddr {
+    "10.0.0.1:19132".parse().unwrap()
+}
+
+fn server_addr() -> SocketAddr {
+    "10.0.0.2:19132".parse().unwrap()
+}
+
+fn codec_config() -> codec::Config {
+    codec::Config {
+        max_parted_size: 256,
+        max_parted_count: 256,
+        max_channels: 1,
+        reassembly_quota: None,
+        game_packet_ids: None,
+        order_gap_policy: OrderGapPolicy::Discard,
+        dedup_window: 65536,
+        on_error: None,
+    }
+}
+
+struct Datagram {
+    // the tick it arrives at
+    at: u64,
+    from: SocketAddr,
+    to: SocketAddr,
+    data: Bytes,
+}
+
+struct NetState {
+    rng: StdRng,
+    tick: u64,
+    loss: f64,
+    reorder: f64,
+    duplicate: f64,
+    // the ticks every datagram takes to arrive besides the first one
+    latency: u64,
+    // in the sending order
+    in_flight: Vec<Datagram>,
+    dropped: usize,
+    duplicated: usize,
+}
+
+/// The in-memory network shared by the sockets of the simulation
+#[derive(Clone)]
+pub(crate) struct SimNet {
+    state: Arc<Mutex<NetState>>,
+}
+
+impl SimNet {
+    fn new(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(NetState {
+                rng: StdRng::seed_from_u64(seed),
+                tick: 0,
+                loss: 0.0,
+                reorder: 0.0,
+                duplicate: 0.0,
+                latency: 0,
+                in_flight: Vec::new(),
+                dropped: 0,
+                duplicated: 0,
+            })),
+        }
+    }
+
+    fn socket(&self, addr: SocketAddr) -> SimSocket {
+        SimSocket {
+            net: self.clone(),
+            addr,
+        }
+    }
+
+    fn advance(&self) {
+        self.state.lock().tick += 1;
+    }
+
+    /// The count of the datagrams dropped by the network
+    pub(crate) fn dropped(&self) -> usize {
+        self.state.lock().dropped
+    }
+
+    /// The count of the datagrams duplicated by the network
+    pub(crate) fn duplicated(&self) -> usize {
+        self.state.lock().duplicated
+    }
+}
+
+/// The socket bound to an address of the in-memory network
+pub(crate) struct SimSocket {
+    net: SimNet,
+    addr: SocketAddr,
+}
+
+impl AsyncSocket for SimSocket {
+    fn poll_recv_from(
+        &self,
+        _cx: &mut Context<'_>,
+        buf: &mut BytesMut,
+    ) -> Poll<io::Result<SocketAddr>> {
+        let mut net = self.net.state.lock();
+        let tick = net.tick;
+        let Some(pos) = net
+            .in_flight
+            .iter()
+            .position(|datagram| datagram.to == self.addr && datagram.at <= tick)
+        else {
+            // the simulation polls every peer in each step, no need to wake it up
+            return Poll::Pending;
+        };
+        let datagram = net.in_flight.remove(pos);
+        buf.extend_from_slice(&datagram.data);
+        Poll::Ready(Ok(datagram.from))
+    }
+
+    fn poll_send_to(
+        &self,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        let mut net = self.net.state.lock();
+        // the offline handshake is not retried, only the connected datagrams are faulted
+        let connected = buf.first().is_some_and(|id| id & 0x80 != 0);
+        let (loss, reorder, duplicate) = (net.loss, net.reorder, net.duplicate);
+        if connected && net.rng.gen_bool(loss) {
+            net.dropped += 1;
+            return Poll::Ready(Ok(buf.len()));
+        }
+        let data = Bytes::copy_from_slice(buf);
+        let copies = if connected && net.rng.gen_bool(duplicate) {
+            net.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut at = net.tick + 1 + net.latency;
+            if connected && net.rng.gen_bool(reorder) {
+                at += net.rng.gen_range(1..=MAX_REORDER_TICKS);
+            }
+            net.in_flight.push(Datagram {
+                at,
+                from: self.addr,
+                to: target,
+                data: data.clone(),
+            });
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+}
+
+/// A connected peer of the simulation
+pub(crate) struct Endpoint {
+    link: SharedLink,
+    src: Pin<Box<dyn Stream<Item = Bytes>>>,
+    dst: Pin<Box<dyn Sink<Message, Error = Error>>>,
+    sending: VecDeque<Message>,
+    received: Vec<Bytes>,
+}
+
+impl Endpoint {
+    fn new(
+        socket: SimSocket,
+        link: SharedLink,
+        peer: PeerContext,
+        role: RoleContext,
+        src: Pin<Box<dyn Stream<Item = Bytes>>>,
+    ) -> Self {
+        let dst = Framed::new(socket, MTU as usize)
+            .handle_outgoing(
+                Arc::clone(&link),
+                SEND_BUF_CAP,
+                None,
+                ResendMap::new(role),
+                peer.clone(),
+                role,
+            )
+            .frame_encoded(peer.mtu, codec_config(), Arc::clone(&link))
+            .manage_outgoing_state(None, Arc::clone(&link));
+        Self {
+            link,
+            src,
+            dst: Box::pin(dst),
+            sending: VecDeque::new(),
+            received: Vec::new(),
+        }
+    }
+
+    /// Queue the message, it is sent in the following steps
+    pub(crate) fn send(&mut self, msg: Message) {
+        self.sending.push_back(msg);
+    }
+
+    /// The user data received in the arriving order
+    pub(crate) fn received(&self) -> &[Bytes] {
+        &self.received
+    }
+
+    pub(crate) fn link(&self) -> &SharedLink {
+        &self.link
+    }
+
+    fn is_connected(&self) -> bool {
+        self.link.connected_at().is_some()
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) {
+        while let Poll::Ready(Some(data)) = self.src.as_mut().poll_next(cx) {
+            self.received.push(data);
+        }
+        while !self.sending.is_empty() {
+            match self.dst.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    let msg = self.sending.pop_front().unwrap();
+                    self.dst.as_mut().start_send(msg).unwrap();
+                }
+                Poll::Ready(Err(err)) => panic!("failed to send: {err}"),
+                Poll::Pending => break,
+            }
+        }
+        // deliver the acks and the resends, it is pending while any frame is not acknowledged
+        if let Poll::Ready(Err(err)) = self.dst.as_mut().poll_flush(cx) {
+            panic!("failed to flush: {err}");
+        }
+    }
+}
+
+enum Client {
+    Offline(Pin<Box<ClientOffline<Framed<SimSocket>>>>),
+    Online {
+        packets: Pin<Box<dyn Stream<Item = connected::Packet<FramesMut>>>>,
+        router: Router,
+        endpoint: Endpoint,
+    },
+}
+
+struct Server {
+    offline: Pin<Box<ServerOffline<Framed<SimSocket>>>>,
+    router: Option<Router>,
+    endpoint: Option<Endpoint>,
+}
+
+/// The simulation of a client connecting to a server
+pub(crate) struct Sim {
+    net: SimNet,
+    clock: MockClock,
+    waker: Waker,
+    client: Client,
+    server: Server,
+}
+
+impl Sim {
+    /// Create the simulation on the current thread, the faults of the network are drawn from
+    /// the seed
+    pub(crate) fn new(seed: u64) -> Self {
+        let net = SimNet::new(seed);
+        let client = ClientOffline::new(
+            Framed::new(net.socket(client_addr()), MTU as usize),
+            vec![server_addr()],
+            client_offline::Config {
+                mtu: MTU,
+                client_guid: CLIENT_GUID,
+                protocol_version: PROTOCOL_VERSION,
+                version_fallback: vec![],
+                verify_advertisement: None,
+                mtu_ladder: vec![],
+                mtu_probe_timeout: Duration::from_secs(1),
+                connect_retries: 0,
+                connect_retry_backoff: Duration::from_millis(500),
+                reactor: Reactor::get(),
+            },
+        );
+        let server = ServerOffline::new(
+            Framed::new(net.socket(server_addr()), MTU as usize),
+            server_offline::Config {
+                server_guid: SERVER_GUID,
+                advertisement: Arc::new(ArcSwap::from_pointee(Bytes::from_static(b"sim"))),
+                min_mtu: 510,
+                max_mtu: MTU,
+                support_version: vec![9, 11, 13],
+                max_pending: 16,
+                max_connections: None,
+                recently_connected_cooldown: None,
+                reply_backlog: 16,
+                require_cookie: false,
+                encryption: false,
+                server_address: None,
+                reactor: Reactor::get(),
+            },
+        );
+        Self {
+            net,
+            clock: MockClock::install(),
+            waker: TestWaker::create(),
+            client: Client::Offline(Box::pin(client)),
+            server: Server {
+                offline: Box::pin(server),
+                router: None,
+                endpoint: None,
+            },
+        }
+    }
+
+    /// Drop the fraction `loss` of the connected datagrams and delay the fraction `reorder` of
+    /// them by a few ticks
+    pub(crate) fn faults(self, loss: f64, reorder: f64) -> Self {
+        {
+            let mut net = self.net.state.lock();
+            net.loss = loss;
+            net.reorder = reorder;
+        }
+        self
+    }
+
+    /// Deliver the fraction `duplicate` of the connected datagrams twice, and delay all datagrams
+    /// by `latency` ticks
+    pub(crate) fn duplicates(self, duplicate: f64, latency: u64) -> Self {
+        {
+            let mut net = self.net.state.lock();
+            net.duplicate = duplicate;
+            net.latency = latency;
+        }
+        self
+    }
+
+    pub(crate) fn net(&self) -> &SimNet {
+        &self.net
+    }
+
+    /// # Panics
+    /// Panics if the client is not connected
+    pub(crate) fn client(&mut self) -> &mut Endpoint {
+        match &mut self.client {
+            Client::Online { endpoint, .. } => endpoint,
+            Client::Offline(_) => panic!("client is not connected"),
+        }
+    }
+
+    /// # Panics
+    /// Panics if the server has not accepted the client
+    pub(crate) fn server(&mut self) -> &mut Endpoint {
+        self.server
+            .endpoint
+            .as_mut()
+            .expect("server has not accepted the client")
+    }
+
+    fn endpoints(&self) -> Option<(&Endpoint, &Endpoint)> {
+        let Client::Online { endpoint, .. } = &self.client else {
+            return None;
+        };
+        Some((endpoint, self.server.endpoint.as_ref()?))
+    }
+
+    fn is_connected(&self) -> bool {
+        self.endpoints()
+            .is_some_and(|(client, server)| client.is_connected() && server.is_connected())
+    }
+
+    /// The count of the user data received by the client and the server
+    pub(crate) fn received_count(&self) -> (usize, usize) {
+        self.endpoints().map_or((0, 0), |(client, server)| {
+            (client.received.len(), server.received.len())
+        })
+    }
+
+    /// Step until both peers finish the handshake
+    ///
+    /// # Panics
+    /// Panics if they are not connected within `max_steps`
+    pub(crate) fn connect(&mut self, max_steps: usize) {
+        assert!(
+            self.run_until(max_steps, Self::is_connected),
+            "not connected within {max_steps} steps"
+        );
+    }
+
+    /// Step until the condition holds, return false if it does not hold within `max_steps`
+    pub(crate) fn run_until(
+        &mut self,
+        max_steps: usize,
+        mut cond: impl FnMut(&Self) -> bool,
+    ) -> bool {
+        for _ in 0..max_steps {
+            if cond(self) {
+                return true;
+            }
+            self.step();
+        }
+        cond(self)
+    }
+
+    /// Poll the server, then the client, and move the network and the clock by one tick
+    pub(crate) fn step(&mut self) {
+        let waker = self.waker.clone();
+        let mut cx = Context::from_waker(&waker);
+        self.poll_server(&mut cx);
+        self.poll_client(&mut cx);
+        self.net.advance();
+        self.clock.advance(TICK);
+    }
+
+    fn poll_server(&mut self, cx: &mut Context<'_>) {
+        let server = &mut self.server;
+        while let Poll::Ready(Some((pack, peer))) = server.offline.as_mut().poll_next(cx) {
+            if let Some(router) = &mut server.router {
+                router.deliver(pack);
+                continue;
+            }
+            let role = RoleContext::Server {
+                guid: SERVER_GUID,
+                reactor: Reactor::get(),
+            };
+            let link = TransferLink::new_arc(role);
+            if let Some(guid) = peer.guid {
+                link.set_peer_guid(guid);
+            }
+            let (mut router, route) = Router::new(Arc::clone(&link));
+            router.deliver(pack);
+            let src = ServerOnline::handle_online(
+                incoming(route, &link, role),
+                role,
+                peer.addr,
+                None,
+                Arc::clone(&link),
+                None,
+            );
+            server.router = Some(router);
+            server.endpoint = Some(Endpoint::new(
+                self.net.socket(server_addr()),
+                link,
+                peer,
+                role,
+                Box::pin(src),
+            ));
+        }
+        if let Some(endpoint) = &mut server.endpoint {
+            endpoint.poll(cx);
+        }
+    }
+
+    fn poll_client(&mut self, cx: &mut Context<'_>) {
+        if let Client::Offline(offline) = &mut self.client {
+            let Poll::Ready(res) = offline.as_mut().poll(cx) else {
+                return;
+            };
+            let (packets, _, mtu, server_guid) = res.expect("offline handshake failed");
+            let role = RoleContext::Client {
+                guid: CLIENT_GUID,
+                reactor: Reactor::get(),
+            };
+            let link = TransferLink::new_arc(role);
+            let (router, route) = Router::new(Arc::clone(&link));
+            let src = ClientOnline::handle_online(
+                incoming(route, &link, role),
+                server_addr(),
+                client_addr(),
+                CLIENT_GUID,
+                None,
+                Arc::clone(&link),
+            );
+            let peer = PeerContext {
+                addr: server_addr(),
+                mtu,
+                guid: Some(server_guid),
+            };
+            self.client = Client::Online {
+                packets: Box::pin(packets),
+                router,
+                endpoint: Endpoint::new(
+                    self.net.socket(client_addr()),
+                    link,
+                    peer,
+                    role,
+                    Box::pin(src),
+                ),
+            };
+        }
+        let Client::Online {
+            packets,
+            router,
+            endpoint,
+        } = &mut self.client
+        else {
+            unreachable!("client is online")
+        };
+        while let Poll::Ready(Some(pack)) = packets.as_mut().poll_next(cx) {
+            router.deliver(pack);
+        }
+        endpoint.poll(cx);
+    }
+}
+
+fn incoming(
+    route: impl Stream<Item = FrameSet<FramesMut>> + 'static,
+    link: &SharedLink,
+    role: RoleContext,
+) -> impl Stream<Item = connected::FrameBody> + 'static {
+    route
+        .frame_decoded(codec_config(), Arc::clone(link), role)
+        .manage_incoming_state(Arc::clone(link))
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BufMut, Bytes, BytesMut};
+
+    use super::Sim;
+    use crate::{Message, Reliability};
+
+    const SEEDS: std::ops::Range<u64> = 0..8;
+
+    fn payload(i: u32) -> Bytes {
+        let mut buf = BytesMut::with_capacity(5);
+        // the game packet id, not to be mistaken for an internal frame body
+        buf.put_u8(0xfe);
+        buf.put_u32(i);
+        buf.freeze()
+    }
+
+    #[test]
+    fn test_sim_lossless_delivery() {
+        let mut sim = Sim::new(0);
+        sim.connect(1000);
+        for i in 0..100 {
+            sim.client()
+                .send(Message::new(Reliability::ReliableOrdered, 0, payload(i)));
+        }
+        assert!(sim.run_until(1000, |sim| sim.received_count() == (0, 100)));
+        let expected: Vec<_> = (0..100).map(payload).collect();
+        assert_eq!(sim.server().received(), expected);
+        assert_eq!(sim.net().dropped(), 0);
+        assert_eq!(sim.client().link().stats().packets_resent, 0);
+    }
+
+    #[test]
+    fn test_sim_reliable_ordered_exactly_once_in_order() {
+        const COUNT: u32 = 200;
+
+        for seed in SEEDS {
+            let mut sim = Sim::new(seed).faults(0.2, 0.2);
+            sim.connect(10_000);
+            for i in 0..COUNT {
+                sim.client()
+                    .send(Message::new(Reliability::ReliableOrdered, 0, payload(i)));
+                sim.server()
+                    .send(Message::new(Reliability::ReliableOrdered, 0, payload(i)));
+            }
+            let delivered = sim.run_until(100_000, |sim| {
+                let (client, server) = sim.received_count();
+                client >= COUNT as usize && server >= COUNT as usize
+            });
+            assert!(delivered, "seed {seed}: not delivered");
+            // keep stepping to catch any late duplicate
+            for _ in 0..1000 {
+                sim.step();
+            }
+
+            let expected: Vec<_> = (0..COUNT).map(payload).collect();
+            assert_eq!(sim.server().received(), expected, "seed {seed}");
+            assert_eq!(sim.client().received(), expected, "seed {seed}");
+            assert!(sim.net().dropped() > 0, "seed {seed}: no loss injected");
+            assert!(
+                sim.client().link().stats().packets_resent > 0,
+                "seed {seed}: nothing resent"
+            );
+        }
+    }
+
+    #[test]
+    fn test_sim_reliable_exactly_once() {
+        const COUNT: u32 = 200;
+
+        for seed in SEEDS {
+            let mut sim = Sim::new(seed).faults(0.3, 0.3);
+            sim.connect(10_000);
+            for i in 0..COUNT {
+                sim.client()
+                    .send(Message::new(Reliability::Reliable, 0, payload(i)));
+            }
+            let delivered = sim.run_until(100_000, |sim| sim.received_count().1 >= COUNT as usize);
+            assert!(delivered, "seed {seed}: not delivered");
+            for _ in 0..1000 {
+                sim.step();
+            }
+
+            let mut received = sim.server().received().to_vec();
+            assert_eq!(received.len(), COUNT as usize, "seed {seed}: duplicated");
+            received.sort();
+            let expected: Vec<_> = (0..COUNT).map(payload).collect();
+            assert_eq!(received, expected, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_sim_duplicated_and_delayed() {
+        const COUNT: u32 = 200;
+
+        for seed in SEEDS {
+            let mut sim = Sim::new(seed).faults(0.1, 0.2).duplicates(0.3, 3);
+            sim.connect(10_000);
+            for i in 0..COUNT {
+                sim.client()
+                    .send(Message::new(Reliability::ReliableOrdered, 0, payload(i)));
+            }
+            let delivered = sim.run_until(100_000, |sim| sim.received_count().1 >= COUNT as usize);
+            assert!(delivered, "seed {seed}: not delivered");
+            for _ in 0..1000 {
+                sim.step();
+            }
+
+            // the duplicated datagrams are deduplicated by the reliable frame index
+            let expected: Vec<_> = (0..COUNT).map(payload).collect();
+            assert_eq!(sim.server().received(), expected, "seed {seed}");
+            assert!(
+                sim.net().duplicated() > 0,
+                "seed {seed}: no duplicate injected"
+            );
+        }
+    }
+}
@@ -13,6 +13,7 @@ use log::warn;
 use pin_project_lite::pin_project;
 
 use crate::errors::{CodecError, Error};
+use crate::link::SharedLink;
 use crate::packet::connected::FrameBody;
 use crate::Message;
 
@@ -67,6 +68,8 @@ pin_project! {
         frame: F,
         state: S,
         close_on_drop: Option<CloseOnDrop>,
+        // report the error when the connection failed, or record the peer closed
+        link: Option<SharedLink>,
     }
 }
 
@@ -74,9 +77,11 @@ pub(crate) trait OutgoingStateManage: Sized {
     /// Manage the outgoing state of the connection.
     /// Take a sink of `FrameBody` and `Message` and return a sink of `FrameBody` and `Message`,
     /// mapping the `CodecError` to the `Error`.
+    /// The error of a failed connection will be reported by the sink once, and then it is closed.
     fn manage_outgoing_state(
         self,
         close_on_drop: Option<CloseOnDrop>,
+        link: SharedLink,
     ) -> impl Sink<FrameBody, Error = Error> + Sink<Message, Error = Error>;
 }
 
@@ -87,11 +92,13 @@ where
     fn manage_outgoing_state(
         self,
         close_on_drop: Option<CloseOnDrop>,
+        link: SharedLink,
     ) -> impl Sink<FrameBody, Error = Error> + Sink<Message, Error = Error> {
         StateManager {
             frame: self,
             state: OutgoingState::Connecting,
             close_on_drop,
+            link: Some(link),
         }
     }
 }
@@ -106,36 +113,54 @@ pub(crate) trait IncomingStateManage: Sized {
     /// the peer. This will ensure that the ack you sent to acknowledge the `DisconnectNotification`
     /// can be received by the the peer (i.e. ensuring that the the peer's `poll_close` call
     /// returns successfully).
-    fn manage_incoming_state(self) -> impl Stream<Item = FrameBody>;
+    fn manage_incoming_state(self, link: SharedLink) -> impl Stream<Item = FrameBody>;
 }
 
 impl<F> IncomingStateManage for F
 where
     F: Stream<Item = FrameBody>,
 {
-    fn manage_incoming_state(self) -> impl Stream<Item = FrameBody> {
+    fn manage_incoming_state(self, link: SharedLink) -> impl Stream<Item = FrameBody> {
         StateManager {
             frame: self,
             state: IncomingState::Connecting,
             close_on_drop: None,
+            link: Some(link),
         }
     }
 }
 
+impl<F> StateManager<F, OutgoingState> {
+    /// Check whether the connection is failed, take the error and close the connection if so.
+    fn check_failed(self: Pin<&mut Self>) -> Result<(), Error> {
+        let this = self.project();
+        if let Some(link) = this.link
+            && link.is_failed()
+        {
+            let err = link.take_error().unwrap_or(Error::ConnectionClosed);
+            *this.state = OutgoingState::Closed;
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
 impl<F> Sink<FrameBody> for StateManager<F, OutgoingState>
 where
     F: Sink<FrameBody, Error = CodecError>,
 {
     type Error = Error;
 
-    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.as_mut().check_failed()?;
         if !self.state.before_finish() {
             return Poll::Ready(Err(Error::ConnectionClosed));
         }
         self.project().frame.poll_ready(cx).map_err(Into::into)
     }
 
-    fn start_send(self: Pin<&mut Self>, item: FrameBody) -> Result<(), Self::Error> {
+    fn start_send(mut self: Pin<&mut Self>, item: FrameBody) -> Result<(), Self::Error> {
+        self.as_mut().check_failed()?;
         if !self.state.before_finish() {
             return Err(Error::ConnectionClosed);
         }
@@ -147,7 +172,8 @@ where
         self.project().frame.poll_flush(cx).map_err(Into::into)
     }
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.as_mut().check_failed()?;
         let mut this = self.project();
         if matches!(this.state, OutgoingState::Closed) {
             return Poll::Ready(Err(Error::ConnectionClosed));
@@ -171,11 +197,15 @@ where
                     *this.state = OutgoingState::SecondCloseWait;
                 }
                 OutgoingState::SecondCloseWait => {
-                    // second wait the DisconnectNotification to receive by the peer
+                    // second wait the DisconnectNotification to receive by the peer, it is resent
+                    // until acknowledged
                     ready!(this.frame.as_mut().poll_close(cx)?);
                     *this.state = OutgoingState::Closed;
                 }
                 OutgoingState::Closed => {
+                    if let Some(link) = this.link {
+                        link.mark_closed();
+                    }
                     // send close event
                     let _ = this.close_on_drop.take();
                     return Poll::Ready(Ok(()));
@@ -185,14 +215,42 @@ where
     }
 }
 
+impl<F> StateManager<F, OutgoingState>
+where
+    F: Sink<Message, Error = CodecError>,
+{
+    fn poll_ready_0(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.as_mut().check_failed()?;
+        if !self.state.before_finish() {
+            return Poll::Ready(Err(Error::ConnectionClosed));
+        }
+        // the messages may be held by the layers, e.g. until the session is established
+        Sink::<Message>::poll_ready(self.project().frame, cx).map_err(Into::into)
+    }
+
+    /// Send the first message of the fast open queued on the link ahead of the other messages
+    fn poll_first_msg(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if !self.link.as_ref().is_some_and(|link| link.has_first_msg()) {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(self.as_mut().poll_ready_0(cx))?;
+        let this = self.project();
+        if let Some(msg) = this.link.as_ref().and_then(|link| link.take_first_msg()) {
+            this.frame.start_send(msg)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
 impl<F> Sink<Message> for StateManager<F, OutgoingState>
 where
     F: Sink<FrameBody, Error = CodecError> + Sink<Message, Error = CodecError>,
 {
     type Error = Error;
 
-    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Sink::<FrameBody>::poll_ready(self, cx)
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_first_msg(cx))?;
+        self.poll_ready_0(cx)
     }
 
     fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
@@ -200,11 +258,13 @@ where
         Ok(())
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_first_msg(cx))?;
         Sink::<FrameBody>::poll_flush(self, cx)
     }
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_first_msg(cx))?;
         Sink::<FrameBody>::poll_close(self, cx)
     }
 }
@@ -229,6 +289,9 @@ where
         };
         if matches!(body, FrameBody::DisconnectNotification) {
             // The peer no longer sends any data.
+            if let Some(link) = this.link {
+                link.mark_peer_closed();
+            }
             *this.state = IncomingState::Closed;
             return Poll::Ready(None);
         }
@@ -322,6 +385,7 @@ mod test {
             frame: DstSink::default(),
             state: crate::state::OutgoingState::Connecting,
             close_on_drop: Some(CloseOnDrop::new(addr, Arc::clone(&queue))),
+            link: None,
         };
         SinkExt::<FrameBody>::close(&mut goodbye).await.unwrap();
         assert_eq!(goodbye.frame.buf.len(), 1);
@@ -1,23 +1,35 @@
 #![allow(clippy::use_debug)]
 
 use std::iter::repeat;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-use bytes::Bytes;
-use futures::{SinkExt, StreamExt};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{SinkExt, Stream, StreamExt};
 use log::info;
 use tokio::net::UdpSocket;
 
-use crate::client::{self, ConnectTo};
-use crate::io::{Ping, TraceInfo, IO};
-use crate::server::{self, MakeIncoming};
+use crate::client::{self, ConnectTo, PingServer};
+use crate::codec::frame::Framed;
+use crate::endpoint::IntoEndpoint;
+use crate::errors::{CodecError, Error, HandshakePhase};
+use crate::io::{
+    AnomalyInfo, CloseOutcome, CloseReason, MapSend, Ping, RawFrameSet, SeqCounters, TraceInfo, IO,
+};
+use crate::packet::connected::{self, Flags, Fragment, Frame, FrameSet};
+use crate::packet::{unconnected, PackType, Packet};
+use crate::server::{self, Advertise, BanList, ConnEvent, ConnEvents, MakeIncoming, Shutdown};
 use crate::utils::tests::test_trace_log_setup;
+use crate::utils::Reactor;
 use crate::{Message, Reliability};
 
 fn make_server_conf() -> server::Config {
     server::Config::new()
         .send_buf_cap(1024)
-        .sever_guid(1919810)
+        .server_guid(1919810)
         .advertisement(&b"123456"[..])
         .max_mtu(1500)
         .min_mtu(510)
@@ -33,6 +45,97 @@ fn make_client_conf() -> client::Config {
         .protocol_version(11)
 }
 
+/// Spawn an echo server listening on a random port
+async fn spawn_echo_server(config: server::Config) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    spawn_echo(socket.make_incoming(config));
+    addr
+}
+
+/// Echo the connections of the incoming
+fn spawn_echo(mut incoming: impl Stream<Item = impl IO + Send + 'static> + Unpin + Send + 'static) {
+    tokio::spawn(async move {
+        while let Some(io) = incoming.next().await {
+            tokio::spawn(async move {
+                tokio::pin!(io);
+                let mut ticker = tokio::time::interval(Duration::from_millis(10));
+                loop {
+                    tokio::select! {
+                        res = io.next() => {
+                            let Some(data) = res else {
+                                break;
+                            };
+                            io.feed(data).await.unwrap();
+                        }
+                        _ = ticker.tick() => {
+                            io.flush().await.unwrap();
+                        }
+                    };
+                }
+            });
+        }
+    });
+}
+
+/// Spawn a server only finishing the offline handshake, it ignores all connected packets
+async fn spawn_offline_only_server() -> SocketAddr {
+    spawn_offline_server(None).await
+}
+
+/// Spawn a server finishing the offline handshake, it replies each connected frame set with an
+/// unreliable frame carrying the `connected_reply` if it is given
+async fn spawn_offline_server(connected_reply: Option<&'static [u8]>) -> SocketAddr {
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let addr = socket.local_addr().unwrap();
+    let mut framed = Framed::new(socket, 1500);
+    tokio::spawn(async move {
+        while let Some((pack, from)) = framed.next().await {
+            let reply = match pack {
+                Packet::Unconnected(unconnected::Packet::OpenConnectionRequest1 {
+                    mtu, ..
+                }) => unconnected::Packet::OpenConnectionReply1 {
+                    magic: (),
+                    server_guid: 1919810,
+                    cookie: None,
+                    mtu,
+                },
+                Packet::Unconnected(unconnected::Packet::OpenConnectionRequest2 {
+                    mtu, ..
+                }) => unconnected::Packet::OpenConnectionReply2 {
+                    magic: (),
+                    server_guid: 1919810,
+                    client_address: from,
+                    mtu,
+                    encryption_enabled: false,
+                },
+                Packet::Connected(connected::Packet::FrameSet(_)) => {
+                    let Some(body) = connected_reply else {
+                        continue;
+                    };
+                    let frames = [Frame {
+                        flags: Flags::new(Reliability::Unreliable, false),
+                        reliable_frame_index: None,
+                        seq_frame_index: None,
+                        ordered: None,
+                        fragment: None,
+                        body: Bytes::from_static(body),
+                    }];
+                    let pack = Packet::Connected(connected::Packet::FrameSet(FrameSet {
+                        seq_num: 0.into(),
+                        set: &frames[..],
+                    }));
+                    framed.send((pack, from)).await.unwrap();
+                    continue;
+                }
+                _ => continue,
+            };
+            framed.send((reply, from)).await.unwrap();
+        }
+    });
+    addr
+}
+
 #[tokio::test(unhandled_panic = "shutdown_runtime")]
 async fn test_tokio_udp_works() {
     let _guard = test_trace_log_setup();
@@ -119,86 +222,2393 @@ async fn test_tokio_udp_works() {
 async fn test_4way_handshake_client_close() {
     let _guard = test_trace_log_setup();
 
-    let server = async {
-        let mut incoming = UdpSocket::bind("0.0.0.0:19133")
+    let mut incoming = UdpSocket::bind("0.0.0.0:19133")
+        .await
+        .unwrap()
+        .make_incoming(
+            make_server_conf()
+                .auto_flush_interval(Duration::from_millis(5))
+                .close_timeout(Duration::from_millis(200)),
+        );
+    let server = tokio::spawn(async move {
+        let io = incoming.next().await.unwrap();
+        tokio::pin!(io);
+        while let Some(res) = io.next().await {
+            io.feed(res).await.unwrap();
+        }
+        info!("connection closed by client, close the io");
+        assert_eq!(io.close_reason(), Some(CloseReason::PeerClosed));
+        // the client may not acknowledge it after closing, it is given up after the timeout
+        io.close().await.unwrap();
+        info!("io closed");
+    });
+
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(
+            "127.0.0.1:19133",
+            make_client_conf().close_timeout(Duration::from_millis(200)),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    let huge_msg = Bytes::from_iter(repeat(0xfe).take(2048));
+    io.send(huge_msg.clone()).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), huge_msg);
+    assert_eq!(io.close_reason(), None);
+
+    io.close().await.unwrap();
+    assert_eq!(io.close_reason(), Some(CloseReason::Closed));
+    server.await.unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_simultaneous_close() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf());
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let server = tokio::spawn(async move {
+        let io = incoming.next().await.unwrap();
+        tokio::pin!(io);
+        let data = io.next().await.unwrap();
+        io.send(data).await.unwrap();
+        rx.await.unwrap();
+        io.as_mut()
+            .close_with_timeout(Duration::from_secs(5))
             .await
             .unwrap()
-            .make_incoming(make_server_conf());
-        loop {
-            let io = incoming.next().await.unwrap();
+    });
+
+    let io = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+
+    // both sides send the disconnect notification at once, each acknowledges the one of the
+    // other while waiting for its own
+    tx.send(()).unwrap();
+    let outcome = io
+        .as_mut()
+        .close_with_timeout(Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(outcome, CloseOutcome::Acknowledged);
+    assert_eq!(server.await.unwrap(), CloseOutcome::Acknowledged);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_uptime_increases() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    assert!(io.connected_at().is_none());
+    assert!(io.uptime().is_none());
+
+    // the online handshake of client is finished on receiving the first packet
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+
+    let connected_at = io.connected_at().unwrap();
+    let first = io.uptime().unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let second = io.uptime().unwrap();
+    assert!(second > first);
+    assert!(second >= Duration::from_millis(50));
+    assert_eq!(io.connected_at().unwrap(), connected_at);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_online_handshake_timeout() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_offline_only_server().await;
+    let start = std::time::Instant::now();
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(
+            addr,
+            make_client_conf().online_handshake_timeout(Duration::from_millis(100)),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    // send the ConnectionRequest, the server never responds
+    io.flush().await.unwrap();
+    let res = tokio::time::timeout(Duration::from_secs(1), io.next()).await;
+    assert!(matches!(res, Ok(None)), "expect the stream ends in time");
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    let err = io.send(Bytes::from_static(b"hello")).await.unwrap_err();
+    assert!(matches!(
+        err,
+        Error::HandshakeTimeout {
+            phase: HandshakePhase::Online
+        }
+    ));
+    assert!(matches!(
+        io.send(Bytes::from_static(b"hello")).await.unwrap_err(),
+        Error::ConnectionClosed
+    ));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_send_raw_frameset() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+
+    // a frame set encoded by another connection, its sequence number and reliable index are far
+    // away from the ones of this connection, the reliable index is reassigned
+    let frames = [Frame {
+        flags: Flags::new(Reliability::Reliable, false),
+        reliable_frame_index: Some(1000.into()),
+        seq_frame_index: None,
+        ordered: None,
+        fragment: None,
+        body: Bytes::from_static(b"forwarded"),
+    }];
+    let mut buf = BytesMut::new();
+    Packet::Connected(connected::Packet::FrameSet(FrameSet {
+        seq_num: 4096.into(),
+        set: &frames[..],
+    }))
+    .write(&mut buf);
+
+    io.as_mut().send_raw_frameset(buf.freeze()).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"forwarded"));
+
+    // the reliable frames of this connection are not dropped as duplicated
+    for _ in 0..3 {
+        io.send(Bytes::from_static(b"hello")).await.unwrap();
+        let echoed = tokio::time::timeout(Duration::from_secs(5), io.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(echoed, Bytes::from_static(b"hello"));
+    }
+
+    // not a frame set
+    let err = io
+        .as_mut()
+        .send_raw_frameset(Bytes::from_static(&[0x01]))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Codec(_)));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_on_codec_error() {
+    let _guard = test_trace_log_setup();
+
+    let (tx, mut rx) = futures::channel::mpsc::unbounded();
+    let addr = spawn_echo_server(make_server_conf().on_codec_error(move |err, addr| {
+        let _ = tx.unbounded_send((matches!(err, CodecError::PartedFrame(_)), addr));
+    }))
+    .await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = socket.local_addr().unwrap();
+    let io = socket.connect_to(addr, make_client_conf()).await.unwrap();
+    tokio::pin!(io);
+
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+
+    // the parted index exceeds the parted size
+    let frames = [Frame {
+        flags: Flags::new(Reliability::Reliable, true),
+        reliable_frame_index: Some(1000.into()),
+        seq_frame_index: None,
+        ordered: None,
+        fragment: Some(Fragment {
+            parted_size: 2,
+            parted_id: 0,
+            parted_index: 2,
+        }),
+        body: Bytes::from_static(b"malformed"),
+    }];
+    let mut buf = BytesMut::new();
+    Packet::Connected(connected::Packet::FrameSet(FrameSet {
+        seq_num: 4096.into(),
+        set: &frames[..],
+    }))
+    .write(&mut buf);
+    io.as_mut().send_raw_frameset(buf.freeze()).await.unwrap();
+
+    let (parted, from) = tokio::time::timeout(Duration::from_secs(5), rx.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(parted);
+    assert_eq!(from, local_addr);
+
+    // the connection survives the malformed frame
+    io.send(Bytes::from_static(b"world")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"world"));
+}
+
+#[cfg(feature = "compression")]
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_compression_round_trip() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf().compression_threshold(128)).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf().compression_threshold(128))
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    // larger than the mtu before compressing
+    let compressible = Bytes::from(b"hello world, ".repeat(1000));
+    let tiny = Bytes::from_static(b"hello");
+    // starting with the header of the compressed frame bodies
+    let headed = Bytes::from_static(b"\x7ehello");
+    io.send(compressible.clone()).await.unwrap();
+    io.send(tiny.clone()).await.unwrap();
+    io.send(headed.clone()).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), compressible);
+    assert_eq!(io.next().await.unwrap(), tiny);
+    assert_eq!(io.next().await.unwrap(), headed);
+}
+
+#[cfg(feature = "compression")]
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_compression_send_before_accepted() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf().compression_threshold(128)).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf().compression_threshold(128))
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    // the connection request accepted is not processed until the stream is polled, while the
+    // server has negotiated the compression
+    let headed = Bytes::from_static(b"\x7ehello");
+    io.send(headed.clone()).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), headed);
+    io.send(headed.clone()).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), headed);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_views() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf());
+    tokio::spawn(async move {
+        while let Some(io) = incoming.next().await {
+            // echo each view on itself
+            let views = io.views();
+            for mut view in [views.datagrams(), views.stream(1)] {
+                tokio::spawn(async move {
+                    while let Some(data) = view.next().await {
+                        view.send(data).await.unwrap();
+                    }
+                });
+            }
+        }
+    });
+
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    let views = io.views();
+    let mut datagrams = views.datagrams();
+    let mut rpc = views.stream(1);
+
+    rpc.send(Bytes::from_static(b"\xferpc")).await.unwrap();
+    datagrams
+        .send(Bytes::from_static(b"\xfesnapshot"))
+        .await
+        .unwrap();
+    // routed to their own views regardless of the arrival order
+    assert_eq!(
+        datagrams.next().await.unwrap(),
+        Bytes::from_static(b"\xfesnapshot")
+    );
+    assert_eq!(rpc.next().await.unwrap(), Bytes::from_static(b"\xferpc"));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_trace_id_propagation() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf().propagate_trace_id(true));
+    tokio::spawn(async move {
+        while let Some(io) = incoming.next().await {
             tokio::spawn(async move {
-                tokio::pin!(io);
-                let mut ticker = tokio::time::interval(Duration::from_millis(5));
-                loop {
-                    tokio::select! {
-                        res = io.next() => {
-                            if let Some(res) = res {
-                                io.feed(res).await.unwrap();
-                            } else {
-                                break;
-                            }
-                        }
-                        _ = ticker.tick() => {
-                            // flush periodically to ensure all missing packets/ack are sent
-                            io.flush().await.unwrap();
-                        }
-                    };
+                let (src, dst) = io.split();
+                tokio::pin!(src);
+                tokio::pin!(dst);
+                while let Some(data) = src.next().await {
+                    // reply along with the next trace id
+                    let trace_id = src.last_trace_id().unwrap().0 as u64;
+                    let reply = Message::new(Reliability::ReliableOrdered, 0, data)
+                        .with_trace_id(trace_id + 1);
+                    dst.send(reply).await.unwrap();
                 }
-                info!("connection closed by client, close the io");
-                io.close().await.unwrap();
-                info!("io closed");
             });
         }
-    };
+    });
 
-    let client = async {
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf().propagate_trace_id(true))
+        .await
+        .unwrap();
+    let (src, dst) = io.split();
+    tokio::pin!(src);
+    tokio::pin!(dst);
+
+    // larger than the mtu, the trace id survives the fragmentation
+    let large = Bytes::from(b"hello world, ".repeat(1000));
+    for (trace_id, data) in [(114514, Bytes::from_static(b"hello")), (1919810, large)] {
+        let msg =
+            Message::new(Reliability::ReliableOrdered, 0, data.clone()).with_trace_id(trace_id);
+        dst.send(msg).await.unwrap();
+        assert_eq!(src.next().await.unwrap(), data);
+        assert_eq!(src.last_trace_id().unwrap().0, u128::from(trace_id + 1));
+    }
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_trace_id_send_before_accepted() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf().propagate_trace_id(true)).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf().propagate_trace_id(true))
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    // the connection request accepted is not processed until the stream is polled, while the
+    // server has negotiated the propagation
+    let headed = Bytes::from_static(b"\x7chello");
+    io.send(headed.clone()).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), headed);
+    io.send(headed.clone()).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), headed);
+}
+
+#[cfg(feature = "encryption")]
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_encryption_round_trip() {
+    use crate::cipher::XorCipher;
+
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf().encryption(XorCipher(0x11))).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to_with_data(
+            addr,
+            make_client_conf().encryption(XorCipher(0x22)),
+            Bytes::from_static(b"hello"),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    // larger than the mtu
+    let large = Bytes::from(b"hello world, ".repeat(1000));
+    io.send(large.clone()).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+    assert_eq!(io.next().await.unwrap(), large);
+}
+
+#[cfg(feature = "encryption")]
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_encryption_send_after_connect() {
+    use crate::cipher::XorCipher;
+
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf().encryption(XorCipher(0x11))).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf().encryption(XorCipher(0x22)))
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    // may be sent before the online handshake completes, held until the session is established
+    let msgs = [
+        Bytes::from_static(b"hello"),
+        Bytes::from_static(b"\x7dworld"),
+    ];
+    for msg in &msgs {
+        io.send(msg.clone()).await.unwrap();
+    }
+    for msg in &msgs {
+        let echoed = tokio::time::timeout(Duration::from_secs(5), io.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&echoed, msg);
+    }
+    // encrypted once the session is established
+    let large = Bytes::from(b"hello world, ".repeat(1000));
+    io.send(large.clone()).await.unwrap();
+    let echoed = tokio::time::timeout(Duration::from_secs(5), io.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, large);
+}
+
+#[cfg(feature = "encryption")]
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_encryption_required_by_both() {
+    use crate::cipher::XorCipher;
+
+    let _guard = test_trace_log_setup();
+
+    let encrypted = spawn_echo_server(make_server_conf().encryption(XorCipher(0x11))).await;
+    let plaintext = spawn_echo_server(make_server_conf()).await;
+    for (addr, config) in [
+        (encrypted, make_client_conf()),
+        (plaintext, make_client_conf().encryption(XorCipher(0x22))),
+    ] {
         let io = UdpSocket::bind("0.0.0.0:0")
             .await
             .unwrap()
-            .connect_to("127.0.0.1:19133", make_client_conf())
+            .connect_to(addr, config)
             .await
             .unwrap();
+        tokio::pin!(io);
+
+        let err = tokio::time::timeout(Duration::from_secs(1), io.as_mut().ready())
+            .await
+            .expect("expect the rejection is reported promptly")
+            .unwrap_err();
+        assert!(matches!(err, Error::ConnectionRequestFailed));
+    }
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_version_fallback() {
+    let _guard = test_trace_log_setup();
 
-        let (src, dst) = IO::split(io);
+    let addr = spawn_echo_server(make_server_conf()).await;
 
-        tokio::pin!(src);
-        tokio::pin!(dst);
+    // the server replies with its latest supported version 13, which is not in the allowlist
+    for fallback in [vec![], vec![11]] {
+        let err = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .unwrap()
+            .connect_to(
+                addr,
+                make_client_conf()
+                    .protocol_version(10)
+                    .version_fallback(fallback),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            Error::IncompatibleProtocol {
+                server_protocol: 13
+            }
+        ));
+    }
 
-        let huge_msg = Bytes::from_iter(repeat(0xfe).take(2048));
-        dst.send(Message::new(
-            Reliability::ReliableOrdered,
-            0,
-            huge_msg.clone(),
-        ))
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(
+            addr,
+            make_client_conf()
+                .protocol_version(10)
+                .version_fallback(vec![11, 13]),
+        )
         .await
         .unwrap();
-        assert_eq!(src.next().await.unwrap(), huge_msg);
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+}
 
-        dst.close().await.unwrap();
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_ready_before_send() {
+    let _guard = test_trace_log_setup();
 
-        info!("client closed the connection, wait for server to close");
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    assert!(io.connected_at().is_none());
 
-        let mut ticker = tokio::time::interval(Duration::from_millis(10));
-        let mut last_2msl = false;
-        let last_timer = tokio::time::sleep(Duration::from_millis(288));
-        tokio::pin!(last_timer);
-        loop {
-            tokio::select! {
-                None = src.next(), if !last_2msl => {
-                    info!("received close notification from server, wait for 200ms(2MSL for test purpose)");
-                    last_2msl = true;
-                }
-                _ = ticker.tick() => {
-                    // flush periodically to ensure all missing packets/ack are sent
-                    dst.flush().await.unwrap();
-                }
-                _ = &mut last_timer, if last_2msl => {
-                    break;
-                }
-            };
+    io.as_mut().ready().await.unwrap();
+    assert!(io.connected_at().is_some());
+    // resolves immediately once ready
+    io.as_mut().ready().await.unwrap();
+
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_ready_handshake_timeout() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_offline_only_server().await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(
+            addr,
+            make_client_conf().online_handshake_timeout(Duration::from_millis(100)),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    let err = io.as_mut().ready().await.unwrap_err();
+    assert!(matches!(
+        err,
+        Error::HandshakeTimeout {
+            phase: HandshakePhase::Online
         }
-    };
+    ));
+}
 
-    tokio::spawn(server);
-    tokio::spawn(client).await.unwrap();
+#[test]
+fn test_message_try_new() {
+    let data = Bytes::from_static(b"hello");
+
+    let msg = Message::try_new(Reliability::ReliableOrdered, 3, data.clone(), 4).unwrap();
+    assert_eq!(msg.get_order_channel(), 3);
+    assert_eq!(msg.get_reliability(), Reliability::ReliableOrdered);
+    assert_eq!(msg.get_data(), &data);
+
+    for reliability in [
+        Reliability::ReliableOrdered,
+        Reliability::ReliableSequenced,
+        Reliability::UnreliableSequenced,
+        Reliability::ReliableOrderedWithAckReceipt,
+    ] {
+        let err = Message::try_new(reliability, 4, data.clone(), 4).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidOrderChannel {
+                channel: 4,
+                max_channels: 4
+            }
+        ));
+    }
+
+    // channel is not used by these reliabilities
+    for reliability in [
+        Reliability::Unreliable,
+        Reliability::Reliable,
+        Reliability::UnreliableWithAckReceipt,
+        Reliability::ReliableWithAckReceipt,
+    ] {
+        assert!(Message::try_new(reliability, 255, data.clone(), 1).is_ok());
+    }
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_game_packet_ids_allowlist() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf().game_packet_ids(vec![0xfe])).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    io.send(Bytes::from_static(&[0x01, 1])).await.unwrap();
+    io.send(Bytes::from_static(&[0xfe, 2])).await.unwrap();
+    // the unknown one is dropped by the server but acknowledged
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(&[0xfe, 2]));
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !io.unacked_seqs().is_empty() {
+            io.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_close_with_timeout_acknowledged() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf());
+    tokio::spawn(async move {
+        while let Some(io) = incoming.next().await {
+            tokio::spawn(async move {
+                tokio::pin!(io);
+                let mut ticker = tokio::time::interval(Duration::from_millis(10));
+                loop {
+                    tokio::select! {
+                        res = io.next() => {
+                            let Some(data) = res else {
+                                break;
+                            };
+                            io.feed(data).await.unwrap();
+                        }
+                        _ = ticker.tick() => {
+                            io.flush().await.unwrap();
+                        }
+                    };
+                }
+                // deliver the ack of the disconnect notification
+                io.close().await.unwrap();
+            });
+        }
+    });
+
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+
+    let outcome = io
+        .as_mut()
+        .close_with_timeout(Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(outcome, CloseOutcome::Acknowledged);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_close_with_timeout_timed_out() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_offline_only_server().await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    // the server never acknowledges the frames
+    let outcome = io
+        .as_mut()
+        .close_with_timeout(Duration::from_millis(200))
+        .await
+        .unwrap();
+    assert_eq!(outcome, CloseOutcome::TimedOut);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_server_reads_client_guid() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf());
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let io = incoming.next().await.unwrap();
+        tx.send(io.peer_guid()).unwrap();
+        // drive the online handshake while routing the packets
+        tokio::spawn(async move {
+            tokio::pin!(io);
+            io.as_mut().ready().await.unwrap();
+            while io.next().await.is_some() {}
+        });
+        while incoming.next().await.is_some() {}
+    });
+
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    assert_eq!(io.peer_guid(), None);
+    io.as_mut().ready().await.unwrap();
+    assert_eq!(rx.await.unwrap(), Some(114514));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_map_send() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    let framed = |data: Bytes| {
+        let mut buf = BytesMut::with_capacity(data.len() + 1);
+        buf.put_u8(data.len() as u8);
+        buf.put(data);
+        buf.freeze()
+    };
+
+    let io = io.map_send(framed);
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"\x05hello"));
+    io.send(Bytes::from_static(b"raknet")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"\x06raknet"));
+
+    // composed with the message based sending
+    let another = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    let (src, dst) = IO::split(another);
+    tokio::pin!(src);
+    let dst = dst.map_send(framed);
+    tokio::pin!(dst);
+    dst.send(Message::new(
+        Reliability::ReliableOrdered,
+        0,
+        Bytes::from_static(b"world"),
+    ))
+    .await
+    .unwrap();
+    assert_eq!(src.next().await.unwrap(), Bytes::from_static(b"\x05world"));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_seq_counters() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    assert_eq!(io.seq_counters(), SeqCounters::default());
+
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+    let first = io.seq_counters();
+    assert!(first.next_seq_num > 0);
+    assert!(first.next_reliable_index > 0);
+    assert!(first.highest_received_seq_num.is_some());
+
+    for _ in 0..3 {
+        io.send(Bytes::from_static(b"hello")).await.unwrap();
+        assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+    }
+    let second = io.seq_counters();
+    assert!(second.next_seq_num >= first.next_seq_num + 3);
+    assert!(second.next_reliable_index >= first.next_reliable_index + 3);
+    assert!(second.highest_received_seq_num > first.highest_received_seq_num);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connect_to_with_data() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf());
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let io = incoming.next().await.unwrap();
+        tokio::spawn(async move {
+            tokio::pin!(io);
+            io.as_mut().ready().await.unwrap();
+            tx.send(io.next().await).unwrap();
+            while io.next().await.is_some() {}
+        });
+        while incoming.next().await.is_some() {}
+    });
+
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to_with_data(addr, make_client_conf(), Bytes::from_static(b"hello"))
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    // the first message is sent along with the handshake, nothing else is sent
+    io.as_mut().ready().await.unwrap();
+    assert_eq!(rx.await.unwrap(), Some(Bytes::from_static(b"hello")));
+}
+
+#[cfg(feature = "compression")]
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connect_to_with_data_compression() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf().compression_threshold(128)).await;
+    // starting with the header of the compressed frame bodies, and a compressible one
+    let headed = Bytes::from_static(b"\x7ehello");
+    let compressible = Bytes::from(b"hello world, ".repeat(1000));
+    for first_msg in [headed, compressible] {
+        let io = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .unwrap()
+            .connect_to_with_data(
+                addr,
+                make_client_conf().compression_threshold(128),
+                first_msg.clone(),
+            )
+            .await
+            .unwrap();
+        tokio::pin!(io);
+        io.send(Bytes::from_static(b"world")).await.unwrap();
+        assert_eq!(io.next().await.unwrap(), first_msg);
+        assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"world"));
+    }
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_recently_connected_cooldown() {
+    let _guard = test_trace_log_setup();
+
+    let addr =
+        spawn_echo_server(make_server_conf().recently_connected_cooldown(Duration::from_secs(10)))
+            .await;
+    let _io = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+
+    // connect again from the same ip
+    let err = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .err()
+        .unwrap();
+    let Error::RecentlyConnected {
+        retry_after: Some(retry_after),
+    } = err
+    else {
+        panic!("unexpected error {err:?}");
+    };
+    assert!(retry_after <= Duration::from_secs(10));
+    assert!(retry_after > Duration::from_secs(9));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connect_disconnect_callbacks() {
+    let _guard = test_trace_log_setup();
+
+    let (connect_tx, mut connect_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (disconnect_tx, mut disconnect_rx) = tokio::sync::mpsc::unbounded_channel();
+    let addr = spawn_echo_server(
+        make_server_conf()
+            .on_connect(move |peer| connect_tx.send(peer).unwrap())
+            .on_disconnect(move |addr, reason| disconnect_tx.send((addr, reason)).unwrap()),
+    )
+    .await;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = socket.local_addr().unwrap();
+    let io = socket.connect_to(addr, make_client_conf()).await.unwrap();
+    tokio::pin!(io);
+    // not connected until the online handshake is finished
+    assert!(connect_rx.try_recv().is_err());
+
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+    // send the NewIncomingConnection to finish the online handshake
+    io.flush().await.unwrap();
+    assert_eq!(
+        connect_rx.recv().await.unwrap(),
+        server::Peer {
+            addr: local_addr,
+            mtu: 1000,
+            guid: Some(114514),
+        }
+    );
+    assert!(disconnect_rx.try_recv().is_err());
+
+    // the echo server drops the connection once it receives the disconnect notification, without
+    // acknowledging it
+    let outcome = io
+        .as_mut()
+        .close_with_timeout(Duration::from_millis(100))
+        .await
+        .unwrap();
+    assert_eq!(outcome, CloseOutcome::TimedOut);
+    // the closed connections are reaped when the incoming is polled, wake it by another client
+    let another = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(another);
+    another.as_mut().ready().await.unwrap();
+    assert_eq!(
+        disconnect_rx.recv().await.unwrap(),
+        (local_addr, CloseReason::PeerClosed)
+    );
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_conn_events() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let incoming = socket.make_incoming(make_server_conf());
+    let mut events = incoming.events();
+    spawn_echo(incoming);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = socket.local_addr().unwrap();
+    let io = socket.connect_to(addr, make_client_conf()).await.unwrap();
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+    io.flush().await.unwrap();
+    assert_eq!(
+        events.next().await.unwrap(),
+        ConnEvent::Connected {
+            addr: local_addr,
+            guid: Some(114514),
+            mtu: 1000,
+        }
+    );
+
+    io.as_mut()
+        .close_with_timeout(Duration::from_millis(100))
+        .await
+        .unwrap();
+    // the closed connections are reaped when the incoming is polled, wake it by another client
+    let another = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(another);
+    another.as_mut().ready().await.unwrap();
+    assert_eq!(
+        events.next().await.unwrap(),
+        ConnEvent::Disconnected {
+            addr: local_addr,
+            reason: CloseReason::PeerClosed,
+        }
+    );
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_peer_mtu_and_guid() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf());
+    let server = tokio::spawn(async move {
+        let io = incoming.next().await.unwrap();
+        (
+            io.peer_mtu(),
+            io.peer_guid(),
+            io.local_guid(),
+            io.peer_addr(),
+        )
+    });
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let io = client.connect_to(addr, make_client_conf()).await.unwrap();
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.peer_mtu(), 1000);
+    assert_eq!(io.peer_guid(), Some(1919810));
+    assert_eq!(io.local_guid(), 114514);
+    assert_eq!(io.peer_addr(), Some(addr));
+    assert_eq!(
+        server.await.unwrap(),
+        (1000, Some(114514), 1919810, Some(client_addr))
+    );
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connect_races_addresses() {
+    let _guard = test_trace_log_setup();
+
+    // the first address is a blackhole never replying
+    let blackhole = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let addrs = [blackhole.local_addr().unwrap(), addr];
+    let io = tokio::time::timeout(
+        Duration::from_secs(1),
+        UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(&addrs[..], make_client_conf()),
+    )
+    .await
+    .expect("the blackhole should not block the handshake")
+    .unwrap();
+    tokio::pin!(io);
+    assert_eq!(io.peer_addr(), Some(addr));
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_endpoint_serves_and_connects() {
+    let _guard = test_trace_log_setup();
+
+    let server_addr = spawn_echo_server(make_server_conf()).await;
+    let mut endpoint = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .into_endpoint(make_server_conf());
+    let endpoint_addr = endpoint.local_addr().unwrap();
+
+    // connect out over the socket of the endpoint
+    let outgoing = endpoint
+        .connect(server_addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(outgoing);
+    assert!(matches!(
+        endpoint.connect(server_addr, make_client_conf()).await,
+        Err(Error::IO(_))
+    ));
+
+    // and accept the connections from the others on the same port
+    let client = tokio::spawn(async move {
+        let io = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(endpoint_addr, make_client_conf())
+            .await
+            .unwrap();
+        tokio::pin!(io);
+        io.send(Bytes::from_static(b"inbound")).await.unwrap();
+        io.next().await
+    });
+    let inbound = endpoint.accept().await.unwrap();
+    tokio::spawn(async move { while endpoint.accept().await.is_some() {} });
+    tokio::pin!(inbound);
+    assert_eq!(
+        inbound.next().await.unwrap(),
+        Bytes::from_static(b"inbound")
+    );
+
+    outgoing
+        .send(Bytes::from_static(b"outbound"))
+        .await
+        .unwrap();
+    assert_eq!(
+        outgoing.next().await.unwrap(),
+        Bytes::from_static(b"outbound")
+    );
+
+    client.abort();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_client_honors_lowered_mtu() {
+    let _guard = test_trace_log_setup();
+
+    // the server clamps the mtu to 600 and truncates the larger datagrams
+    let addr = spawn_echo_server(make_server_conf().max_mtu(600)).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf().mtu(1000))
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    let huge_msg = Bytes::from_iter(repeat(0xfe).take(2048));
+    io.send(huge_msg.clone()).await.unwrap();
+    let echoed = tokio::time::timeout(Duration::from_secs(5), io.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, huge_msg);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_watchdog_closes_stalled_connection() {
+    let _guard = test_trace_log_setup();
+
+    // the server never acknowledges the frames
+    let addr = spawn_offline_only_server().await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(
+            addr,
+            make_client_conf()
+                .stall_timeout(Duration::from_millis(100))
+                .stall_close_grace(Duration::from_millis(100)),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.anomalies().stalls, 0);
+    let err = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            // the failure is reported when sending
+            if let Err(err) = io.send(Bytes::from_static(b"hello")).await {
+                break err;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert!(matches!(err, Error::ConnectionStalled));
+    assert_eq!(io.anomalies().stalls, 1);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_ping_measures_rtt() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    io.as_mut().ready().await.unwrap();
+
+    // the echoed message arrives while pinging
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    let rtt = tokio::time::timeout(Duration::from_secs(5), io.as_mut().ping())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(rtt < Duration::from_secs(1), "unexpected rtt {rtt:?}");
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_idle_timeout_closes_silent_peer() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf());
+    tokio::spawn(async move {
+        let io = incoming.next().await.unwrap();
+        tokio::pin!(io);
+        let mut ticker = tokio::time::interval(Duration::from_millis(10));
+        // finish the handshake and receive the first message, then go silent
+        loop {
+            tokio::select! {
+                _ = incoming.next() => {}
+                res = io.next() => {
+                    assert_eq!(res.unwrap(), Bytes::from_static(b"hello"));
+                    io.flush().await.unwrap();
+                    break;
+                }
+                _ = ticker.tick() => {
+                    io.flush().await.unwrap();
+                }
+            };
+        }
+        // keep routing the packets to the connection never flushed
+        while incoming.next().await.is_some() {}
+    });
+
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(
+            addr,
+            make_client_conf().idle_timeout(Duration::from_millis(300)),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    let mut ticker = tokio::time::interval(Duration::from_millis(10));
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            tokio::select! {
+                res = io.next() => {
+                    assert!(res.is_none());
+                    break;
+                }
+                _ = ticker.tick() => {
+                    io.flush().await.unwrap();
+                }
+            };
+        }
+    })
+    .await
+    .unwrap();
+    let err = io.send(Bytes::from_static(b"hello")).await.unwrap_err();
+    assert!(matches!(err, Error::IdleTimeout));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_keepalive_pings_idle_connection() {
+    let _guard = test_trace_log_setup();
+
+    let idle_timeout = Duration::from_millis(300);
+    let addr = spawn_echo_server(make_server_conf().idle_timeout(idle_timeout)).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf().idle_timeout(idle_timeout))
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+
+    // neither side sends any message, the pings keep the connection alive
+    let mut ticker = tokio::time::interval(Duration::from_millis(10));
+    let res = tokio::time::timeout(idle_timeout * 4, async {
+        loop {
+            tokio::select! {
+                res = io.next() => break res,
+                _ = ticker.tick() => {
+                    io.flush().await.unwrap();
+                }
+            };
+        }
+    })
+    .await;
+    assert!(res.is_err(), "unexpected {res:?}");
+
+    io.send(Bytes::from_static(b"world")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"world"));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_shutdown_notifies_peers() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf());
+    let (notified_tx, notified_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let io = incoming.next().await.unwrap();
+        tokio::pin!(io);
+        // echo the first message, then shut down with the connection idle
+        loop {
+            tokio::select! {
+                _ = incoming.next() => {}
+                res = io.next() => {
+                    io.send(res.unwrap()).await.unwrap();
+                    break;
+                }
+            };
+        }
+        let notified = Pin::new(&mut incoming)
+            .shutdown(Duration::from_secs(1))
+            .await
+            .unwrap();
+        notified_tx.send(notified).unwrap();
+        let err = io.send(Bytes::from_static(b"hello")).await.unwrap_err();
+        assert!(matches!(err, Error::ConnectionClosed));
+        // keep routing the acknowledgements
+        while incoming.next().await.is_some() {}
+    });
+
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+
+    // closed by the notification immediately rather than the timeout
+    let res = tokio::time::timeout(Duration::from_secs(1), io.next())
+        .await
+        .unwrap();
+    assert!(res.is_none());
+    assert_eq!(notified_rx.await.unwrap(), 1);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_require_cookie_handshake() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf().require_cookie(true)).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_max_connections() {
+    let _guard = test_trace_log_setup();
+
+    let max_connections = 2;
+    let addr = spawn_echo_server(make_server_conf().max_connections(max_connections)).await;
+    let mut ios = vec![];
+    for _ in 0..max_connections {
+        let io = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .unwrap()
+            .connect_to(addr, make_client_conf())
+            .await
+            .unwrap();
+        ios.push(Box::pin(io));
+    }
+
+    // the one beyond the limit is rejected in the offline handshake
+    let res = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await;
+    assert!(matches!(res, Err(Error::NoFreeIncomingConnections)));
+
+    // the connected ones are not affected
+    for io in &mut ios {
+        io.send(Bytes::from_static(b"hello")).await.unwrap();
+        assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+    }
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_banned_ip_rejected() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf());
+    Pin::new(&mut incoming).ban(addr.ip());
+    tokio::spawn(async move { while incoming.next().await.is_some() {} });
+
+    let res = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await;
+    assert!(matches!(res, Err(Error::ConnectionBanned)));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_advertise_system() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf());
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    Pin::new(&mut incoming)
+        .advertise(peer.local_addr().unwrap(), Bytes::from_static(b"hello"))
+        .await
+        .unwrap();
+
+    let mut buf = [0; 1500];
+    let len = peer.recv(&mut buf).await.unwrap();
+    assert_eq!(
+        Packet::read(&mut BytesMut::from(&buf[..len])).unwrap(),
+        Some(Packet::Unconnected(unconnected::Packet::AdvertiseSystem {
+            magic: (),
+            data: Bytes::from_static(b"hello"),
+        }))
+    );
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_ping_server() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let data = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .ping_server(addr, Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!(data, Bytes::from_static(b"123456"));
+
+    let pongs = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .ping_servers(addr, Duration::from_millis(200));
+    let pongs: Vec<_> = pongs.map(Result::unwrap).collect().await;
+    assert_eq!(pongs, vec![(addr, Bytes::from_static(b"123456"))]);
+
+    // nobody answers
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let res = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .ping_server(silent.local_addr().unwrap(), Duration::from_millis(100))
+        .await;
+    assert!(matches!(res, Err(Error::PingTimeout)));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_delivery_receipts() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let (tx, rx) = flume::unbounded();
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf().delivery_receipts(tx))
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    // the last message is split into several frames
+    let mut msgs: Vec<_> = (0..4_u8).map(|i| Bytes::from(vec![0xfe, i])).collect();
+    msgs.push(Bytes::from_iter(repeat(0xfe).take(4096)));
+    for msg in &msgs {
+        io.feed(msg.clone()).await.unwrap();
+    }
+    io.flush().await.unwrap();
+    for msg in &msgs {
+        let echoed = tokio::time::timeout(Duration::from_secs(5), io.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&echoed, msg);
+    }
+
+    let mut receipts = vec![];
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while receipts.len() < msgs.len() {
+            // the acknowledgements are processed when polling the sink
+            io.flush().await.unwrap();
+            receipts.extend(rx.try_iter());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    receipts.sort_unstable();
+    assert_eq!(receipts, vec![0, 1, 2, 3, 4]);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_unreliable_receipts() {
+    use futures::FutureExt;
+
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    let receipts = io.receipts();
+    let (src, dst) = io.split();
+    tokio::pin!(src);
+    tokio::pin!(dst);
+    tokio::pin!(receipts);
+
+    let msg =
+        |reliability, data: &'static [u8]| Message::new(reliability, 0, Bytes::from_static(data));
+    let mut tagged = msg(Reliability::Unreliable, b"\xfetagged");
+    tagged.set_ack_receipt(true);
+    let msgs = [
+        msg(Reliability::Reliable, b"\xfereliable"),
+        tagged,
+        msg(Reliability::Unreliable, b"\xfeuntagged"),
+    ];
+    for msg in msgs {
+        dst.send(msg).await.unwrap();
+    }
+    for _ in 0..3 {
+        tokio::time::timeout(Duration::from_secs(5), src.next())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    let mut ids = vec![];
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while ids.len() < 2 {
+            // the acknowledgements are processed when polling the sink
+            dst.flush().await.unwrap();
+            while let Some(Some(id)) = receipts.next().now_or_never() {
+                ids.push(id);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    ids.sort_unstable();
+    // the untagged unreliable message has no receipt
+    assert_eq!(ids, vec![0, 1]);
+    assert!(receipts.next().now_or_never().is_none());
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_cancel_connect_to() {
+    let _guard = test_trace_log_setup();
+
+    // the server never replies, the client is stuck in the offline handshake
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = socket.local_addr().unwrap();
+    let guid = 19260817;
+    let res = tokio::time::timeout(
+        Duration::from_millis(100),
+        socket.connect_to(
+            server.local_addr().unwrap(),
+            make_client_conf().client_guid(guid),
+        ),
+    )
+    .await;
+    assert!(res.is_err(), "expect the connecting is still in progress");
+
+    // no timers linger and the socket is released
+    assert!(!Reactor::get().has_timers(guid));
+    UdpSocket::bind(local_addr).await.unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_cancel_connect_to_shared_config() {
+    let _guard = test_trace_log_setup();
+
+    // the path drops the first open connection request, the client awaits the retry timer
+    let server_addr = spawn_echo_server(make_server_conf()).await;
+    let mut dropped = false;
+    let relay_addr = spawn_relay(server_addr, move |datagram| {
+        if datagram[0] == PackType::OpenConnectionRequest1 as u8 && !dropped {
+            dropped = true;
+            return false;
+        }
+        true
+    })
+    .await;
+    let config = make_client_conf()
+        .client_guid(20240817)
+        .connect_retries(2)
+        .connect_retry_backoff(Duration::from_millis(300));
+    let connecting = tokio::spawn({
+        let config = config.clone();
+        async move {
+            UdpSocket::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .connect_to(relay_addr, config)
+                .await
+                .map(|_| ())
+        }
+    });
+
+    // the other connection of the same config is dropped while the first awaits the timer
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let res = tokio::time::timeout(
+        Duration::from_millis(100),
+        UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .connect_to(server.local_addr().unwrap(), config),
+    )
+    .await;
+    assert!(res.is_err(), "expect the connecting is still in progress");
+
+    tokio::time::timeout(Duration::from_secs(5), connecting)
+        .await
+        .expect("the connecting should not stall")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_verify_advertisement() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let res = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(
+            addr,
+            make_client_conf().verify_advertisement(|data| data == b"impostor"),
+        )
+        .await;
+    assert!(matches!(res, Err(Error::AdvertisementRejected)));
+
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(
+            addr,
+            make_client_conf().verify_advertisement(|data| data == b"123456"),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    let echoed = tokio::time::timeout(Duration::from_secs(5), io.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, Bytes::from_static(b"hello"));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_local_addr() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    let local_addr = io.local_addr().unwrap();
+    assert!(local_addr.ip().is_unspecified());
+    assert_ne!(local_addr.port(), 0);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connection_request_failed() {
+    let _guard = test_trace_log_setup();
+
+    // the server rejects the ConnectionRequest
+    let addr = spawn_offline_server(Some(&[0x11])).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    let err = tokio::time::timeout(Duration::from_secs(1), io.as_mut().ready())
+        .await
+        .expect("expect the rejection is reported promptly")
+        .unwrap_err();
+    assert!(matches!(err, Error::ConnectionRequestFailed));
+    assert!(io.next().await.is_none());
+    assert!(matches!(
+        io.send(Bytes::from_static(b"hello")).await.unwrap_err(),
+        Error::ConnectionClosed
+    ));
+}
+
+/// Whether the datagram is a frame set carrying the disconnect notification
+fn is_disconnect_datagram(datagram: &[u8]) -> bool {
+    let mut buf = BytesMut::from(datagram);
+    let Ok(Some(Packet::Connected(connected::Packet::FrameSet(frame_set)))) =
+        Packet::read(&mut buf)
+    else {
+        return false;
+    };
+    frame_set
+        .set
+        .iter()
+        .any(|frame| frame.body.first() == Some(&(PackType::DisconnectNotification as u8)))
+}
+
+/// Spawn a relay forwarding the datagrams between the client and the server, the datagrams from
+/// the client are dropped if `forward` returns false
+async fn spawn_relay(
+    server: SocketAddr,
+    mut forward: impl FnMut(&[u8]) -> bool + Send + 'static,
+) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut client = None;
+        let mut buf = [0; 1500];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            if from == server {
+                if let Some(client) = client {
+                    socket.send_to(&buf[..len], client).await.unwrap();
+                }
+                continue;
+            }
+            client = Some(from);
+            if forward(&buf[..len]) {
+                socket.send_to(&buf[..len], server).await.unwrap();
+            }
+        }
+    });
+    addr
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_mtu_discovery() {
+    let _guard = test_trace_log_setup();
+
+    // the path drops the datagrams larger than 1200 bytes
+    let server_addr = spawn_echo_server(make_server_conf()).await;
+    let relay_addr = spawn_relay(server_addr, |datagram| datagram.len() <= 1200).await;
+
+    let io = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(
+            relay_addr,
+            make_client_conf()
+                .mtu(1400)
+                .mtu_ladder(vec![1300, 1200, 576])
+                .mtu_probe_timeout(Duration::from_millis(200)),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    assert_eq!(io.peer_mtu(), 1200);
+
+    let huge_msg = Bytes::from_iter(repeat(0xfe).take(4096));
+    io.send(huge_msg.clone()).await.unwrap();
+    let echoed = tokio::time::timeout(Duration::from_secs(5), io.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, huge_msg);
+
+    // give up after the last mtu of the ladder
+    let relay_addr = spawn_relay(server_addr, |_| false).await;
+    let err = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(
+            relay_addr,
+            make_client_conf()
+                .mtu_ladder(vec![576])
+                .mtu_probe_timeout(Duration::from_millis(100)),
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        Error::HandshakeTimeout {
+            phase: HandshakePhase::Offline
+        }
+    ));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_mtu_lowered_mid_session() {
+    let _guard = test_trace_log_setup();
+
+    // the path starts to drop the datagrams larger than 1200 bytes after connected
+    let black_hole = Arc::new(AtomicBool::new(false));
+    let server_addr = spawn_echo_server(make_server_conf()).await;
+    let relay_addr = spawn_relay(server_addr, {
+        let black_hole = Arc::clone(&black_hole);
+        move |datagram| !black_hole.load(Ordering::Relaxed) || datagram.len() <= 1200
+    })
+    .await;
+
+    let io = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(
+            relay_addr,
+            make_client_conf().mtu(1400).mtu_ladder(vec![1200, 576]),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    assert_eq!(io.peer_mtu(), 1400);
+
+    black_hole.store(true, Ordering::Relaxed);
+    // not blocking the order channel by the lost one
+    io.as_mut().set_default_reliability(Reliability::Reliable);
+    let huge_msg = Bytes::from_iter(repeat(0xfe).take(4096));
+    // lost in the black hole, it is resent in the sizes packed before the mtu is lowered
+    io.send(huge_msg.clone()).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while io.peer_mtu() != 1200 {
+            io.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    io.send(huge_msg.clone()).await.unwrap();
+    let echoed = tokio::time::timeout(Duration::from_secs(5), io.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, huge_msg);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_mtu_update_not_negotiated() {
+    let _guard = test_trace_log_setup();
+
+    // the original raknet uses the id of the mtu update for the user data
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let io = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    let data = Bytes::from_static(b"\x7f\x04\x00");
+    io.send(data.clone()).await.unwrap();
+    let echoed = tokio::time::timeout(Duration::from_secs(5), io.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, data);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_mtu_update_negotiated_user_data() {
+    let _guard = test_trace_log_setup();
+
+    // the user data starting with the id of the mtu update is escaped once negotiated, either
+    // sent before the online handshake completes or after it
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let io = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf().mtu_ladder(vec![576]))
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    let data = Bytes::from_static(&[0x7f, 0x05, 0xdc]);
+    for _ in 0..2 {
+        io.send(data.clone()).await.unwrap();
+        let echoed = tokio::time::timeout(Duration::from_secs(5), io.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(echoed, data);
+    }
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connect_retries() {
+    let _guard = test_trace_log_setup();
+
+    // the path drops the first open connection request of each kind
+    let server_addr = spawn_echo_server(make_server_conf()).await;
+    let mut dropped = vec![];
+    let relay_addr = spawn_relay(server_addr, move |datagram| {
+        let id = datagram[0];
+        if [
+            PackType::OpenConnectionRequest1 as u8,
+            PackType::OpenConnectionRequest2 as u8,
+        ]
+        .contains(&id)
+            && !dropped.contains(&id)
+        {
+            dropped.push(id);
+            return false;
+        }
+        true
+    })
+    .await;
+    let io = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(
+            relay_addr,
+            make_client_conf()
+                .connect_retries(2)
+                .connect_retry_backoff(Duration::from_millis(50)),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+
+    // give up after the last resend
+    let relay_addr = spawn_relay(server_addr, |_| false).await;
+    let err = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(
+            relay_addr,
+            make_client_conf()
+                .connect_retries(2)
+                .connect_retry_backoff(Duration::from_millis(50)),
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        Error::HandshakeTimeout {
+            phase: HandshakePhase::Offline
+        }
+    ));
+
+    // the incompatible protocol is terminal
+    let err = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(
+            server_addr,
+            make_client_conf()
+                .protocol_version(10)
+                .connect_retries(2)
+                .connect_retry_backoff(Duration::from_secs(10)),
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        Error::IncompatibleProtocol {
+            server_protocol: 13
+        }
+    ));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_recover_already_connected() {
+    let _guard = test_trace_log_setup();
+
+    // the clients behind the relay share its address, the session of the first one lingers
+    let server_addr = spawn_echo_server(make_server_conf()).await;
+    let relay_addr = spawn_relay(server_addr, |_| true).await;
+    let lingering = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(relay_addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(lingering);
+    lingering.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(
+        lingering.next().await.unwrap(),
+        Bytes::from_static(b"hello")
+    );
+    // acknowledge the echo, it is not resent to the clients coming later
+    lingering.flush().await.unwrap();
+
+    let err = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(relay_addr, make_client_conf().client_guid(1))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::AlreadyConnected));
+
+    let io = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(
+            relay_addr,
+            make_client_conf()
+                .client_guid(2)
+                .recover_already_connected(true)
+                .connect_retry_backoff(Duration::from_millis(100)),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"world")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"world"));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_disconnect_retransmitted_until_acked() {
+    let _guard = test_trace_log_setup();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = socket.local_addr().unwrap();
+    let mut incoming = socket.make_incoming(make_server_conf());
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let io = incoming.next().await.unwrap();
+        tokio::pin!(io);
+        let mut ticker = tokio::time::interval(Duration::from_millis(10));
+        loop {
+            tokio::select! {
+                res = io.next() => {
+                    let Some(data) = res else {
+                        break;
+                    };
+                    io.feed(data).await.unwrap();
+                }
+                _ = ticker.tick() => {
+                    io.flush().await.unwrap();
+                }
+            };
+        }
+        // the peer closed after the disconnect notification is received
+        tx.send(()).unwrap();
+        // deliver the ack of the disconnect notification
+        io.close().await.unwrap();
+    });
+    // drop the first disconnect notification from the client
+    let disconnects = Arc::new(AtomicUsize::new(0));
+    let seen = Arc::clone(&disconnects);
+    let relay_addr = spawn_relay(server_addr, move |datagram| {
+        !is_disconnect_datagram(datagram) || seen.fetch_add(1, Ordering::Relaxed) > 0
+    })
+    .await;
+
+    let io = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(relay_addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+
+    let outcome = io
+        .as_mut()
+        .close_with_timeout(Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(outcome, CloseOutcome::Acknowledged);
+    // the lost one is resent
+    assert!(disconnects.load(Ordering::Relaxed) >= 2);
+    rx.await.unwrap();
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_connection_stats() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf()).await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(addr, make_client_conf())
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    io.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"hello"));
+
+    // the acknowledgements are processed when the sink is polled
+    let stats = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            io.flush().await.unwrap();
+            let stats = io.stats();
+            if stats.packets_acked > 0 && stats.rtt.is_some() {
+                break stats;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(stats.packets_sent >= stats.packets_acked);
+    assert!(stats.rtt_var.is_some());
+    assert_eq!(stats.rto, io.rto());
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_channel_reliability() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(make_server_conf().max_channels(2)).await;
+    // record the reliability and the order channel of the user frames from the client
+    let sent = Arc::new(std::sync::Mutex::new(vec![]));
+    let record = Arc::clone(&sent);
+    let relay_addr = spawn_relay(addr, move |datagram| {
+        let mut buf = BytesMut::from(datagram);
+        if let Ok(Some(Packet::Connected(connected::Packet::FrameSet(frame_set)))) =
+            Packet::read(&mut buf)
+        {
+            for frame in frame_set.set {
+                if frame.body.starts_with(b"channel") {
+                    record.lock().unwrap().push((
+                        frame.body.freeze(),
+                        frame.flags.reliability,
+                        frame.ordered.map(|ordered| ordered.channel),
+                    ));
+                }
+            }
+        }
+        true
+    })
+    .await;
+
+    let io = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(
+            relay_addr,
+            make_client_conf()
+                .max_channels(2)
+                .channel_reliability(0, Reliability::ReliableOrdered)
+                .channel_reliability(1, Reliability::UnreliableSequenced),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"channel 0")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"channel 0"));
+    io.as_mut().set_default_order_channel(1);
+    io.send(Bytes::from_static(b"channel 1")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"channel 1"));
+
+    let sent = sent.lock().unwrap().clone();
+    assert_eq!(
+        sent,
+        vec![
+            (
+                Bytes::from_static(b"channel 0"),
+                Reliability::ReliableOrdered,
+                Some(0)
+            ),
+            (
+                Bytes::from_static(b"channel 1"),
+                Reliability::UnreliableSequenced,
+                Some(1)
+            ),
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "channel should be less than max_channels")]
+fn test_channel_reliability_out_of_range() {
+    let _ = make_client_conf().channel_reliability(1, Reliability::Reliable);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_max_retries_peer_unreachable() {
+    let _guard = test_trace_log_setup();
+
+    // the server never acknowledges the frames
+    let addr = spawn_offline_only_server().await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(
+            addr,
+            make_client_conf()
+                .min_rto(Duration::from_millis(10))
+                .max_rto(Duration::from_millis(50))
+                .max_retries(2),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    let err = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Err(err) = io.send(Bytes::from_static(b"hello")).await {
+                break err;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(matches!(err, Error::PeerUnreachable));
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_ack_delay() {
+    let _guard = test_trace_log_setup();
+
+    let addr = spawn_echo_server(
+        make_server_conf()
+            .ack_delay(Duration::from_millis(30))
+            .ack_threshold(8),
+    )
+    .await;
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(
+            addr,
+            make_client_conf()
+                .ack_delay(Duration::from_millis(30))
+                .ack_threshold(8),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+
+    for i in 0..32_u8 {
+        io.feed(Bytes::from(vec![0xfe, i])).await.unwrap();
+        io.flush().await.unwrap();
+    }
+    for i in 0..32_u8 {
+        assert_eq!(io.next().await.unwrap(), Bytes::from(vec![0xfe, i]));
+    }
+
+    // the held acknowledgements of the tail are sent by the ticking flush of the server
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            io.flush().await.unwrap();
+            let stats = io.stats();
+            if stats.packets_acked >= stats.packets_sent {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(io.stats().packets_resent, 0);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_coalesce() {
+    let _guard = test_trace_log_setup();
+
+    // count the frame sets sent by the client
+    let frame_sets = Arc::new(AtomicUsize::new(0));
+    let server_addr = spawn_echo_server(make_server_conf()).await;
+    let relay_addr = spawn_relay(server_addr, {
+        let frame_sets = Arc::clone(&frame_sets);
+        move |datagram| {
+            if (0x80..=0x8d).contains(&datagram[0]) {
+                frame_sets.fetch_add(1, Ordering::Relaxed);
+            }
+            true
+        }
+    })
+    .await;
+
+    let io = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .connect_to(relay_addr, make_client_conf().coalesce(true))
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    io.send(Bytes::from_static(b"\xfehello")).await.unwrap();
+    assert_eq!(io.next().await.unwrap(), Bytes::from_static(b"\xfehello"));
+
+    // the tiny messages within one flush are sent in one datagram
+    let before = frame_sets.load(Ordering::Relaxed);
+    for i in 0..10_u8 {
+        io.feed(Bytes::from(vec![0xfe, i])).await.unwrap();
+    }
+    io.flush().await.unwrap();
+    for i in 0..10_u8 {
+        assert_eq!(io.next().await.unwrap(), Bytes::from(vec![0xfe, i]));
+    }
+    assert_eq!(frame_sets.load(Ordering::Relaxed) - before, 1);
+}
+
+#[tokio::test(unhandled_panic = "shutdown_runtime")]
+async fn test_auto_flush() {
+    let _guard = test_trace_log_setup();
+
+    // the server echoes without flushing, the echoed messages and the acknowledgements are
+    // flushed by the timer
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut incoming =
+        socket.make_incoming(make_server_conf().auto_flush_interval(Duration::from_millis(10)));
+    tokio::spawn(async move {
+        let io = incoming.next().await.unwrap();
+        tokio::pin!(io);
+        while let Some(data) = io.next().await {
+            io.feed(data).await.unwrap();
+        }
+    });
+
+    let io = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .unwrap()
+        .connect_to(
+            addr,
+            make_client_conf().auto_flush_interval(Duration::from_millis(10)),
+        )
+        .await
+        .unwrap();
+    tokio::pin!(io);
+    for i in 0..4_u8 {
+        io.feed(Bytes::from(vec![0xfe, i])).await.unwrap();
+    }
+    for i in 0..4_u8 {
+        let echoed = tokio::time::timeout(Duration::from_secs(5), io.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(echoed, Bytes::from(vec![0xfe, i]));
+    }
 }
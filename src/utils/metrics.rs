@@ -0,0 +1,56 @@
+/// The counters of a connection recorded into the `metrics` crate, labeled by the connection id
+#[cfg(feature = "metrics")]
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    pub(crate) bytes_sent: ::metrics::Counter,
+    pub(crate) bytes_recv: ::metrics::Counter,
+    pub(crate) frames_sent: ::metrics::Counter,
+    pub(crate) frames_resent: ::metrics::Counter,
+    pub(crate) acks_sent: ::metrics::Counter,
+    pub(crate) nacks_recv: ::metrics::Counter,
+    pub(crate) fragments_assembled: ::metrics::Counter,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    /// Register the counters of the connection, the id is attached as the `connection` label
+    pub(crate) fn new(id: String) -> Self {
+        Self {
+            bytes_sent: ::metrics::counter!("raknet_bytes_sent", "connection" => id.clone()),
+            bytes_recv: ::metrics::counter!("raknet_bytes_recv", "connection" => id.clone()),
+            frames_sent: ::metrics::counter!("raknet_frames_sent", "connection" => id.clone()),
+            frames_resent: ::metrics::counter!("raknet_frames_resent", "connection" => id.clone()),
+            acks_sent: ::metrics::counter!("raknet_acks_sent", "connection" => id.clone()),
+            nacks_recv: ::metrics::counter!("raknet_nacks_recv", "connection" => id.clone()),
+            fragments_assembled: ::metrics::counter!(
+                "raknet_fragments_assembled",
+                "connection" => id
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+/// Increment the counter of the `Option<&Metrics>` by the value, the arguments are not even
+/// evaluated if the `metrics` feature is disabled
+#[cfg(feature = "metrics")]
+macro_rules! metric {
+    ($metrics:expr, $counter:ident, $value:expr) => {
+        if let Some(metrics) = $metrics {
+            metrics.$counter.increment($value as u64);
+        }
+    };
+}
+
+#[cfg(not(feature = "metrics"))]
+macro_rules! metric {
+    ($metrics:expr, $counter:ident, $value:expr) => {};
+}
+
+pub(crate) use metric;
@@ -67,8 +67,9 @@ where
     }
 }
 
-impl<T, O> TraceInfo for EnterOnItem<T, O> {
+impl<T: TraceInfo, O> TraceInfo for EnterOnItem<T, O> {
+    /// The trace id propagated by the peer takes precedence over the one of the local span
     fn last_trace_id(&self) -> Option<TraceId> {
-        self.last_trace_id
+        self.inner.last_trace_id().or(self.last_trace_id)
     }
 }
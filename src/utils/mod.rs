@@ -1,19 +1,38 @@
 mod bit_queue;
 mod log;
+mod metrics;
 mod minitrace;
 mod reactor;
+mod ring;
 mod seq_num;
 
 pub(crate) use self::bit_queue::*;
 pub(crate) use self::log::*;
+pub(crate) use self::metrics::*;
 pub(crate) use self::minitrace::*;
+#[cfg(feature = "micro-bench")]
+pub(crate) use self::reactor::micro_bench as reactor_micro_bench;
+pub use self::reactor::Reactor;
 pub(crate) use self::reactor::*;
+#[cfg(feature = "micro-bench")]
+pub(crate) use self::ring::micro_bench;
+pub(crate) use self::ring::*;
 pub(crate) use self::seq_num::*;
 
 /// Test utils.
 #[cfg(test)]
 pub(crate) mod tests;
 
+/// The current instant of the resend timers, it is driven by the mock clock in tests if any
+#[inline]
+pub(crate) fn now() -> std::time::Instant {
+    #[cfg(test)]
+    if let Some(now) = tests::MockClock::now() {
+        return now;
+    }
+    std::time::Instant::now()
+}
+
 #[inline]
 pub(crate) fn timestamp() -> i64 {
     std::time::SystemTime::now()
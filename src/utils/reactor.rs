@@ -1,54 +1,119 @@
 use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::OnceLock;
-use std::task::Waker;
+use std::task::{Context, Poll, Waker};
 use std::time::{Duration, Instant};
-use std::{mem, panic, thread};
+use std::{fmt, mem, panic, thread};
 
 /// Timers are in the order in which they fire. The `usize` in this type is a timer ID used to
 /// distinguish timers that fire at the same time. The `Waker` represents the task awaiting
 /// the timer.
 type Timers = BTreeMap<(Instant, usize), Waker>;
 
-/// A reactor that manages timers.
-pub(crate) struct Reactor {
+/// A reactor that manages timers. It is driven by a dedicated thread living as long as the
+/// process.
+///
+/// The deadlines are rounded up to the tick granularity of the reactor, the timers falling into
+/// the same tick fire in one wakeup. A fine granularity wakes the tasks precisely at the cost of
+/// waking the thread more often, a coarse one saves the CPU under many connections, but the
+/// timers fire up to a tick late, e.g. the resends are delayed by up to a tick.
+pub struct Reactor {
     /// Map of registered timers, distinguished by their guid.
     region_timers: parking_lot::Mutex<HashMap<u64, Timers>>,
     /// A condvar used to wake up the reactor when timers changed.
     cond: parking_lot::Condvar,
+    /// The tick the deadlines are rounded up to, zero means no rounding.
+    granularity: Duration,
+    /// The instant the ticks are counted from.
+    epoch: Instant,
+    /// The id of the next timer, the timers of the same tick are told apart by it.
+    next_id: AtomicUsize,
+    /// The count of the wakeups of the thread.
+    #[cfg(feature = "micro-bench")]
+    wakeups: AtomicUsize,
+}
+
+impl fmt::Debug for Reactor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reactor")
+            .field("granularity", &self.granularity)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Reactor {
+    /// The global reactor shared by the connections not configured with a dedicated one.
     pub(crate) fn get() -> &'static Reactor {
-        static REACTOR: OnceLock<Reactor> = OnceLock::new();
+        static REACTOR: OnceLock<&'static Reactor> = OnceLock::new();
+
+        REACTOR.get_or_init(|| Reactor::spawn(Duration::ZERO))
+    }
 
-        fn main_loop() {
-            let reactor = Reactor::get();
-            loop {
+    /// Spawn a dedicated reactor whose deadlines are rounded up to the `granularity`. The reactor
+    /// is never freed, create a few of them and share them across the configs.
+    ///
+    /// # Panics
+    /// Panics if the thread of the reactor cannot be spawned.
+    pub fn spawn(granularity: Duration) -> &'static Reactor {
+        let reactor: &'static Reactor = Box::leak(Box::new(Reactor {
+            region_timers: parking_lot::Mutex::new(HashMap::new()),
+            cond: parking_lot::Condvar::new(),
+            granularity,
+            epoch: Instant::now(),
+            next_id: AtomicUsize::new(0),
+            #[cfg(feature = "micro-bench")]
+            wakeups: AtomicUsize::new(0),
+        }));
+        // Spawn the daemon thread to motivate the reactor.
+        thread::Builder::new()
+            .name("timer-reactor".to_string())
+            .spawn(move || loop {
                 reactor.process_timers();
-            }
-        }
+            })
+            .expect("cannot spawn timer-reactor thread");
+        reactor
+    }
 
-        REACTOR.get_or_init(|| {
-            // Spawn the daemon thread to motivate the reactor.
-            thread::Builder::new()
-                .name("timer-reactor".to_string())
-                .spawn(main_loop)
-                .expect("cannot spawn timer-reactor thread");
+    /// The tick granularity of the reactor
+    pub fn granularity(&self) -> Duration {
+        self.granularity
+    }
 
-            Reactor {
-                region_timers: parking_lot::Mutex::new(HashMap::new()),
-                cond: parking_lot::Condvar::new(),
-            }
-        })
+    /// Round the instant up to the next tick
+    fn round_up(&self, when: Instant) -> Instant {
+        if self.granularity.is_zero() {
+            return when;
+        }
+        let granularity = self.granularity.as_nanos();
+        let elapsed = when.saturating_duration_since(self.epoch).as_nanos();
+        let ticks = elapsed.div_ceil(granularity);
+        self.epoch + Duration::from_nanos((ticks * granularity) as u64)
     }
 
     /// Insert a timer with the given `guid` and `when` to fire.
     pub(crate) fn insert_timer(&self, guid: u64, when: Instant, waker: &Waker) {
         let mut timers = self.region_timers.lock();
         let timers = timers.entry(guid).or_default();
-        let id = timers.len();
-        timers.insert((when, id), waker.clone());
-        self.cond.notify_one();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let key = (self.round_up(when), id);
+        timers.insert(key, waker.clone());
+        // the reactor already waits for an earlier timer of the region otherwise
+        if timers
+            .first_key_value()
+            .is_some_and(|(first, _)| *first == key)
+        {
+            self.cond.notify_one();
+        }
+    }
+
+    /// Return `Ready` if the `deadline` is reached, otherwise insert a timer to wake up the task
+    /// at the `deadline`. The deadline is checked against the mock clock in tests if any.
+    pub(crate) fn poll_deadline(&self, guid: u64, deadline: Instant, cx: &Context<'_>) -> Poll<()> {
+        if super::now() >= deadline {
+            return Poll::Ready(());
+        }
+        self.insert_timer(guid, deadline, cx.waker());
+        Poll::Pending
     }
 
     /// Cancel all timers with the given `guid`.
@@ -62,10 +127,18 @@ impl Reactor {
         res
     }
 
+    /// Return true if any timer with the given `guid` is registered, test only
+    #[cfg(test)]
+    pub(crate) fn has_timers(&self, guid: u64) -> bool {
+        self.region_timers.lock().contains_key(&guid)
+    }
+
     /// Processes ready timers and waits for the next timer changed.
     fn process_timers(&self) {
         let mut region_timers = self.region_timers.lock();
         let now = Instant::now();
+        #[cfg(feature = "micro-bench")]
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
 
         let mut dur: Option<Duration> = None;
 
@@ -90,6 +163,8 @@ impl Reactor {
                 _ => {}
             }
         }
+        // Drop the regions that have no timers left, the guid might never come back.
+        region_timers.retain(|_, timers| !timers.is_empty());
 
         if let Some(dur) = dur {
             self.cond.wait_for(&mut region_timers, dur);
@@ -99,6 +174,51 @@ impl Reactor {
     }
 }
 
+#[cfg(feature = "micro-bench")]
+pub mod micro_bench {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread::{self, Thread};
+    use std::time::{Duration, Instant};
+
+    use futures::task::{waker, ArcWake};
+
+    use super::Reactor;
+
+    struct Fired {
+        count: AtomicUsize,
+        thread: Thread,
+    }
+
+    impl ArcWake for Fired {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.count.fetch_add(1, Ordering::Relaxed);
+            arc_self.thread.unpark();
+        }
+    }
+
+    /// Register `count` timers spread evenly over the `window` on the reactor, return the count
+    /// of the wakeups of the reactor thread until all of them fire
+    pub fn fire_timers(reactor: &Reactor, count: usize, window: Duration) -> usize {
+        let fired = Arc::new(Fired {
+            count: AtomicUsize::new(0),
+            thread: thread::current(),
+        });
+        let waker = waker(Arc::clone(&fired));
+        let guid = rand::random();
+        let before = reactor.wakeups.load(Ordering::Relaxed);
+        let start = Instant::now();
+        for i in 0..count {
+            let when = start + window.mul_f64(i as f64 / count as f64);
+            reactor.insert_timer(guid, when, &waker);
+        }
+        while fired.count.load(Ordering::Relaxed) < count {
+            thread::park_timeout(window);
+        }
+        reactor.wakeups.load(Ordering::Relaxed) - before
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -121,8 +241,32 @@ mod test {
             let (waker, test) = TestWaker::pair();
             reactor.insert_timer(2, when, &waker);
             std::thread::sleep(dur + Duration::from_millis(10));
+            assert!(!reactor.has_timers(2));
             assert_eq!(reactor.cancel_all_timers(2).count(), 0);
             assert!(test.woken.load(std::sync::atomic::Ordering::Relaxed));
         }
     }
+
+    #[test]
+    fn test_granularity() {
+        let reactor = Reactor::spawn(Duration::from_millis(10));
+        let epoch = reactor.epoch;
+        assert_eq!(
+            reactor.round_up(epoch + Duration::from_millis(1)),
+            epoch + Duration::from_millis(10)
+        );
+        assert_eq!(
+            reactor.round_up(epoch + Duration::from_millis(10)),
+            epoch + Duration::from_millis(10)
+        );
+        assert_eq!(
+            reactor.round_up(epoch + Duration::from_micros(10_001)),
+            epoch + Duration::from_millis(20)
+        );
+
+        // the global one does not round
+        let reactor = Reactor::get();
+        let when = Instant::now() + Duration::from_micros(1234);
+        assert_eq!(reactor.round_up(when), when);
+    }
 }
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+
+use super::u24;
+
+/// A ring buffer holding the values of a sliding window, the slot of an index follows the slot of
+/// the base index, so the window slides across the wrap of u24 seamlessly. The indices beyond the
+/// window fall back to an overflow map holding at most `window` values, they are picked up once
+/// the window slides to them.
+#[derive(Debug)]
+pub(crate) struct Ring<T> {
+    // allocated on the first insertion
+    slots: Vec<Option<T>>,
+    window: usize,
+    base: u24,
+    // the slot of the base index
+    head: usize,
+    len: usize,
+    overflow: HashMap<u24, T>,
+}
+
+/// The reason of rejecting a value inserted into the ring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rejected {
+    /// The index has been popped or is occupied
+    Stale,
+    /// The index is beyond the window while the overflow is full
+    Overflow,
+}
+
+impl<T> Ring<T> {
+    pub(crate) fn new(window: usize) -> Self {
+        assert!(window > 0, "window > 0");
+        Self {
+            slots: Vec::new(),
+            window,
+            base: 0.into(),
+            head: 0,
+            len: 0,
+            overflow: HashMap::new(),
+        }
+    }
+
+    /// The count of the values waiting for the base index
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len + self.overflow.len()
+    }
+
+    /// The index of the next value to pop
+    #[inline]
+    pub(crate) fn base(&self) -> u24 {
+        self.base
+    }
+
+    /// Insert the value at the index, the index wraps around at the boundary of u24
+    pub(crate) fn insert(&mut self, index: u24, value: T) -> Result<(), Rejected> {
+        let offset = index.serial_diff(self.base);
+        if offset < 0 {
+            return Err(Rejected::Stale);
+        }
+        let offset = offset as usize;
+        if self.overflow.contains_key(&index) {
+            return Err(Rejected::Stale);
+        }
+        if offset >= self.window {
+            if self.overflow.len() >= self.window {
+                return Err(Rejected::Overflow);
+            }
+            self.overflow.insert(index, value);
+            return Ok(());
+        }
+        if self.slots.is_empty() {
+            self.slots.resize_with(self.window, || None);
+        }
+        let slot = &mut self.slots[(self.head + offset) % self.window];
+        if slot.is_some() {
+            return Err(Rejected::Stale);
+        }
+        *slot = Some(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Pop the value at the base index and slide the window
+    pub(crate) fn pop_front(&mut self) -> Option<T> {
+        let value = if let Some(value) = self.slots.get_mut(self.head).and_then(Option::take) {
+            self.len -= 1;
+            value
+        } else if !self.overflow.is_empty()
+            && let Some(value) = self.overflow.remove(&self.base)
+        {
+            value
+        } else {
+            return None;
+        };
+        self.base = self.base.wrapping_add(1);
+        self.head = (self.head + 1) % self.window;
+        Some(value)
+    }
+
+    /// Take all values in the order of their indices regardless of the missing ones, the window
+    /// slides past the last taken index
+    pub(crate) fn drain(&mut self) -> Vec<T> {
+        let mut values = Vec::with_capacity(self.len());
+        let mut next = 0;
+        for offset in 0..self.slots.len() {
+            if let Some(value) = self.slots[(self.head + offset) % self.window].take() {
+                values.push(value);
+                next = offset + 1;
+            }
+        }
+        let base = self.base;
+        let mut overflow: Vec<_> = self.overflow.drain().collect();
+        overflow.sort_unstable_by_key(|(index, _)| index.serial_diff(base));
+        if let Some((index, _)) = overflow.last() {
+            next = index.serial_diff(base) as usize + 1;
+        }
+        values.extend(overflow.into_iter().map(|(_, value)| value));
+        self.base = base.wrapping_add(next as u32);
+        self.head = (self.head + next) % self.window;
+        self.len = 0;
+        values
+    }
+}
+
+/// Micro bench helper, compares the ring with the map keyed by index
+#[cfg(feature = "micro-bench")]
+pub mod micro_bench {
+    use std::collections::HashMap;
+
+    use super::Ring;
+
+    /// Generate the indices that each chunk of `gap` indices arrives in reverse order
+    pub fn gen_indices(cnt: usize, gap: usize) -> Vec<u32> {
+        let mut indices: Vec<u32> = (0..cnt as u32).collect();
+        for chunk in indices.chunks_mut(gap.max(1)) {
+            chunk.reverse();
+        }
+        indices
+    }
+
+    /// Reorder the indices by the ring, return the count of delivered indices
+    pub fn reorder_ring(indices: &[u32], window: usize) -> usize {
+        let mut ring = Ring::new(window);
+        let mut delivered = 0;
+        for &index in indices {
+            let _ = ring.insert(index.into(), index);
+            while ring.pop_front().is_some() {
+                delivered += 1;
+            }
+        }
+        delivered
+    }
+
+    /// Reorder the indices by the map keyed by index, return the count of delivered indices
+    pub fn reorder_map(indices: &[u32]) -> usize {
+        let mut map = HashMap::with_capacity(64);
+        let mut read = 0;
+        for &index in indices {
+            if index < read {
+                continue;
+            }
+            map.insert(index, index);
+            while map.remove(&read).is_some() {
+                read += 1;
+            }
+        }
+        read as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Rejected, Ring};
+    use crate::utils::u24;
+
+    #[test]
+    fn test_ring_in_order() {
+        let mut ring = Ring::new(4);
+        for i in 0..10_u32 {
+            assert!(ring.insert(i.into(), i).is_ok());
+            assert_eq!(ring.pop_front(), Some(i));
+            assert_eq!(ring.pop_front(), None);
+        }
+        assert_eq!(ring.insert(9.into(), 9), Err(Rejected::Stale));
+        assert_eq!(ring.len(), 0);
+    }
+
+    #[test]
+    fn test_ring_wrap_boundary() {
+        let mut ring = Ring::new(4);
+        // slide the base to 3, so the window [3, 7) wraps around the slots
+        for i in 0..3_u32 {
+            assert!(ring.insert(i.into(), i).is_ok());
+            ring.pop_front();
+        }
+        assert!(ring.insert(6.into(), 6).is_ok());
+        assert!(ring.insert(4.into(), 4).is_ok());
+        assert!(ring.insert(5.into(), 5).is_ok());
+        assert_eq!(ring.pop_front(), None);
+        assert!(ring.insert(3.into(), 3).is_ok());
+        assert_eq!(ring.len(), 4);
+        for i in 3..7 {
+            assert_eq!(ring.pop_front(), Some(i));
+        }
+        assert_eq!(ring.pop_front(), None);
+        assert_eq!(ring.len(), 0);
+    }
+
+    #[test]
+    fn test_ring_u24_wrap_boundary() {
+        // the window of 3 slots does not divide 2 ^ 24
+        let mut ring = Ring::new(3);
+        let max = u24::MAX.to_u32();
+        ring.base = u24::MAX - 1;
+        for i in [1, 0, max, max - 1] {
+            assert!(ring.insert(i.into(), i).is_ok());
+        }
+        assert_eq!(ring.insert(u24::MAX, max), Err(Rejected::Stale));
+        assert_eq!(ring.insert(1.into(), 1), Err(Rejected::Stale));
+        assert_eq!(ring.len(), 4);
+        for i in [max - 1, max, 0, 1] {
+            assert_eq!(ring.pop_front(), Some(i));
+        }
+        assert_eq!(ring.pop_front(), None);
+        assert_eq!(ring.base().to_u32(), 2);
+        // popped before the wrap
+        assert_eq!(ring.insert(u24::MAX, max), Err(Rejected::Stale));
+        assert!(ring.insert(2.into(), 2).is_ok());
+        assert_eq!(ring.pop_front(), Some(2));
+    }
+
+    #[test]
+    fn test_ring_drain_u24_wrap_boundary() {
+        let mut ring = Ring::new(3);
+        let max = u24::MAX.to_u32();
+        ring.base = u24::MAX;
+        assert!(ring.insert(5.into(), 5).is_ok());
+        assert!(ring.insert(0.into(), 0).is_ok());
+        assert_eq!(ring.drain(), vec![0, 5]);
+        assert_eq!(ring.base().to_u32(), 6);
+        assert_eq!(ring.insert(u24::MAX, max), Err(Rejected::Stale));
+        assert!(ring.insert(6.into(), 6).is_ok());
+        assert_eq!(ring.pop_front(), Some(6));
+    }
+
+    #[test]
+    fn test_ring_overflow_bounded() {
+        let mut ring = Ring::new(2);
+        assert!(ring.insert(10.into(), 10).is_ok());
+        assert!(ring.insert(1000.into(), 1000).is_ok());
+        // the overflow holds at most the window of values
+        assert_eq!(ring.insert(11.into(), 11), Err(Rejected::Overflow));
+        assert!(ring.insert(1.into(), 1).is_ok());
+        assert_eq!(ring.len(), 3);
+    }
+
+    #[test]
+    fn test_ring_reject() {
+        let mut ring = Ring::new(4);
+        assert!(ring.insert(1.into(), 1).is_ok());
+        // occupied
+        assert_eq!(ring.insert(1.into(), 1), Err(Rejected::Stale));
+        assert!(ring.insert(0.into(), 0).is_ok());
+        assert_eq!(ring.pop_front(), Some(0));
+        assert_eq!(ring.pop_front(), Some(1));
+        // popped
+        assert_eq!(ring.insert(1.into(), 1), Err(Rejected::Stale));
+        assert_eq!(ring.insert(0.into(), 0), Err(Rejected::Stale));
+    }
+
+    #[test]
+    fn test_ring_exceed_window() {
+        let mut ring = Ring::new(4);
+        // the gap exceeds the window, they fall back to the overflow
+        assert!(ring.insert(9.into(), 9).is_ok());
+        assert!(ring.insert(4.into(), 4).is_ok());
+        assert_eq!(ring.insert(4.into(), 4), Err(Rejected::Stale));
+        assert_eq!(ring.len(), 2);
+        for i in 0..4_u32 {
+            assert!(ring.insert(i.into(), i).is_ok());
+        }
+        for i in 0..5 {
+            assert_eq!(ring.pop_front(), Some(i));
+        }
+        assert_eq!(ring.pop_front(), None);
+        for i in 5..9_u32 {
+            assert!(ring.insert(i.into(), i).is_ok());
+        }
+        for i in 5..10 {
+            assert_eq!(ring.pop_front(), Some(i));
+        }
+        assert_eq!(ring.len(), 0);
+    }
+
+    #[test]
+    fn test_ring_drain() {
+        let mut ring = Ring::new(4);
+        assert!(ring.insert(2.into(), 2).is_ok());
+        assert!(ring.insert(1.into(), 1).is_ok());
+        assert!(ring.insert(9.into(), 9).is_ok());
+        assert!(ring.insert(6.into(), 6).is_ok());
+        // the index 0 is missing
+        assert_eq!(ring.pop_front(), None);
+        assert_eq!(ring.drain(), vec![1, 2, 6, 9]);
+        assert_eq!(ring.len(), 0);
+        // the window slides past the drained indices
+        assert_eq!(ring.insert(9.into(), 9), Err(Rejected::Stale));
+        assert!(ring.insert(10.into(), 10).is_ok());
+        assert_eq!(ring.pop_front(), Some(10));
+    }
+}
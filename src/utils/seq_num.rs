@@ -1,12 +1,32 @@
 use bytes::{Buf, BufMut};
 
-/// Unsigned 24bits integer (actually occupied 32 bits) with litter endian and wrapping checking
-/// TODO: Can the sequence number wrap around?
+/// Unsigned 24bits integer (actually occupied 32 bits) with litter endian and wrapping checking.
+/// The arithmetic operators panic on overflow, the sequence numbers of frame sets wrap around by
+/// [`u24::wrapping_add`] and are compared by [`u24::serial_diff`].
 #[allow(non_camel_case_types)]
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Default)]
 pub(crate) struct u24(u32);
 
 impl u24 {
+    pub(crate) const MAX: u24 = u24((1 << 24) - 1);
+
+    /// Add with wrapping around at the boundary of u24
+    pub(crate) fn wrapping_add(self, rhs: u32) -> u24 {
+        u24(self.0.wrapping_add(rhs) & Self::MAX.0)
+    }
+
+    /// Subtract with wrapping around at the boundary of u24
+    pub(crate) fn wrapping_sub(self, rhs: u32) -> u24 {
+        u24(self.0.wrapping_sub(rhs) & Self::MAX.0)
+    }
+
+    /// The signed distance from `other` to `self` in the serial number arithmetic (RFC 1982),
+    /// it is positive if `self` is after `other`.
+    pub(crate) fn serial_diff(self, other: u24) -> i32 {
+        // shift into the high 24 bits so that the wrapping subtraction carries the sign
+        ((self.0 << 8).wrapping_sub(other.0 << 8) as i32) >> 8
+    }
+
     pub(crate) fn to_u32(self) -> u32 {
         self.0
     }
@@ -298,6 +318,22 @@ mod test {
         let _b1 = a1 - 1;
     }
 
+    #[test]
+    fn test_u24_wrapping() {
+        assert_eq!(u24::MAX.wrapping_add(1), 0.into());
+        assert_eq!(u24::MAX.wrapping_add(3), 2.into());
+        assert_eq!(u24::from(5).wrapping_add(1), 6.into());
+        assert_eq!(u24::from(0).wrapping_sub(1), u24::MAX);
+        assert_eq!(u24::from(1).wrapping_sub(3), u24::MAX - 1);
+
+        assert_eq!(u24::from(0).serial_diff(u24::MAX), 1);
+        assert_eq!(u24::MAX.serial_diff(0.into()), -1);
+        assert_eq!(u24::from(2).serial_diff(u24::MAX - 1), 4);
+        assert_eq!(u24::from(7).serial_diff(7.into()), 0);
+        assert_eq!(u24::from(3).serial_diff(10.into()), -7);
+        assert_eq!(u24::from(1 << 22).serial_diff(0.into()), 1 << 22);
+    }
+
     #[test]
     fn test_u24_works() {
         let a1: u24 = 1.into();
@@ -1,7 +1,10 @@
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::marker::PhantomData;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::task::Waker;
+use std::time::{Duration, Instant};
 
 use minitrace::collector::{SpanId, SpanRecord, TraceId};
 use parking_lot::Mutex;
@@ -135,3 +138,39 @@ impl TestWaker {
         (Waker::from(Arc::clone(&arc)), arc)
     }
 }
+
+thread_local! {
+    static MOCK_NOW: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// The mock clock of the current thread, [`super::now`] reads it instead of the system clock
+/// until it is dropped. It only moves when it is advanced.
+pub(crate) struct MockClock {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl MockClock {
+    pub(crate) fn install() -> Self {
+        MOCK_NOW.with(|now| {
+            assert!(now.get().is_none(), "mock clock is already installed");
+            now.set(Some(Instant::now()));
+        });
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+
+    pub(crate) fn advance(&self, dur: Duration) {
+        MOCK_NOW.with(|now| now.set(now.get().map(|at| at + dur)));
+    }
+
+    pub(crate) fn now() -> Option<Instant> {
+        MOCK_NOW.with(Cell::get)
+    }
+}
+
+impl Drop for MockClock {
+    fn drop(&mut self) {
+        MOCK_NOW.with(|now| now.set(None));
+    }
+}
@@ -0,0 +1,216 @@
+use std::time::{Duration, Instant};
+
+/// The verdict of checking a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    /// The connection is making progress, or it is idle
+    Healthy,
+    /// The connection just stalled, it is reported once for each stall
+    Stalled,
+    /// The connection is still stalling
+    Stalling,
+    /// The connection stalled beyond the grace period, it should be closed
+    Expired,
+}
+
+/// Watchdog of a connection. A connection is deemed stalled if it is awaiting the acknowledgement
+/// but receives nothing from the peer within the stall timeout. The current instant is passed in
+/// by the caller, so that it can be driven by any clock.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Watchdog {
+    stall_timeout: Duration,
+    // close the stalled connection after the grace period, `None` means never close it
+    close_grace: Option<Duration>,
+    last_progress: Instant,
+    stalled_at: Option<Instant>,
+}
+
+impl Watchdog {
+    pub(crate) fn new(
+        stall_timeout: Duration,
+        close_grace: Option<Duration>,
+        now: Instant,
+    ) -> Self {
+        Self {
+            stall_timeout,
+            close_grace,
+            last_progress: now,
+            stalled_at: None,
+        }
+    }
+
+    /// Feed the watchdog when receiving anything from the peer
+    pub(crate) fn progress(&mut self, now: Instant) {
+        self.last_progress = now;
+        self.stalled_at = None;
+    }
+
+    /// Check the connection, `awaiting` is whether any frame set is awaiting acknowledgement
+    pub(crate) fn check(&mut self, now: Instant, awaiting: bool) -> Verdict {
+        if !awaiting {
+            // an idle connection is not stalled
+            self.progress(now);
+            return Verdict::Healthy;
+        }
+        if now.saturating_duration_since(self.last_progress) < self.stall_timeout {
+            return Verdict::Healthy;
+        }
+        let Some(stalled_at) = self.stalled_at else {
+            self.stalled_at = Some(now);
+            return Verdict::Stalled;
+        };
+        match self.close_grace {
+            Some(grace) if now.saturating_duration_since(stalled_at) >= grace => Verdict::Expired,
+            _ => Verdict::Stalling,
+        }
+    }
+}
+
+/// The liveness of a connection checked by the keepalive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Liveness {
+    /// Something is received from the peer within the idle timeout, `ping` is whether to send a
+    /// ping to keep it alive, check it again at `wake_at`
+    Alive { ping: bool, wake_at: Instant },
+    /// Nothing is received from the peer within the idle timeout, it should be closed
+    Idle,
+}
+
+/// Keepalive of a connection. A connection is deemed idle if nothing is received from the peer
+/// within the idle timeout. The pings are sent every third of the timeout while nothing is
+/// received, so that the peer only receiving is still kept alive by the pongs.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Keepalive {
+    idle_timeout: Duration,
+    last_received: Instant,
+    last_ping: Instant,
+}
+
+impl Keepalive {
+    pub(crate) fn new(idle_timeout: Duration, now: Instant) -> Self {
+        Self {
+            idle_timeout,
+            last_received: now,
+            last_ping: now,
+        }
+    }
+
+    /// Feed the keepalive when receiving anything from the peer
+    pub(crate) fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    /// Check the connection
+    pub(crate) fn check(&mut self, now: Instant) -> Liveness {
+        let deadline = self.last_received + self.idle_timeout;
+        if now >= deadline {
+            return Liveness::Idle;
+        }
+        let interval = self.idle_timeout / 3;
+        let mut next_ping = self.last_received.max(self.last_ping) + interval;
+        let ping = now >= next_ping;
+        if ping {
+            self.last_ping = now;
+            next_ping = now + interval;
+        }
+        Liveness::Alive {
+            ping,
+            wake_at: next_ping.min(deadline),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Keepalive, Liveness, Verdict, Watchdog};
+
+    #[test]
+    fn test_watchdog_detects_stall() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut watchdog = Watchdog::new(
+            Duration::from_millis(100),
+            Some(Duration::from_millis(50)),
+            start,
+        );
+
+        assert_eq!(watchdog.check(at(50), true), Verdict::Healthy);
+        // idle for a long time is not a stall
+        assert_eq!(watchdog.check(at(500), false), Verdict::Healthy);
+        assert_eq!(watchdog.check(at(550), true), Verdict::Healthy);
+
+        // awaiting the acknowledgement without any progress
+        assert_eq!(watchdog.check(at(600), true), Verdict::Stalled);
+        assert_eq!(watchdog.check(at(620), true), Verdict::Stalling);
+        assert_eq!(watchdog.check(at(650), true), Verdict::Expired);
+
+        // recovered after receiving from the peer
+        watchdog.progress(at(660));
+        assert_eq!(watchdog.check(at(700), true), Verdict::Healthy);
+        assert_eq!(watchdog.check(at(760), true), Verdict::Stalled);
+    }
+
+    #[test]
+    fn test_watchdog_never_closes_without_grace() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(Duration::from_millis(100), None, start);
+        assert_eq!(
+            watchdog.check(start + Duration::from_millis(100), true),
+            Verdict::Stalled
+        );
+        assert_eq!(
+            watchdog.check(start + Duration::from_secs(100), true),
+            Verdict::Stalling
+        );
+    }
+
+    #[test]
+    fn test_keepalive_pings_and_expires() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut keepalive = Keepalive::new(Duration::from_millis(300), start);
+
+        assert_eq!(
+            keepalive.check(at(50)),
+            Liveness::Alive {
+                ping: false,
+                wake_at: at(100)
+            }
+        );
+        // ping every third of the timeout while nothing is received
+        assert_eq!(
+            keepalive.check(at(100)),
+            Liveness::Alive {
+                ping: true,
+                wake_at: at(200)
+            }
+        );
+        assert_eq!(
+            keepalive.check(at(150)),
+            Liveness::Alive {
+                ping: false,
+                wake_at: at(200)
+            }
+        );
+        assert_eq!(
+            keepalive.check(at(250)),
+            Liveness::Alive {
+                ping: true,
+                wake_at: at(300)
+            }
+        );
+
+        // the pong is received
+        keepalive.received(at(260));
+        assert_eq!(
+            keepalive.check(at(300)),
+            Liveness::Alive {
+                ping: false,
+                wake_at: at(360)
+            }
+        );
+        assert_eq!(keepalive.check(at(560)), Liveness::Idle);
+    }
+}